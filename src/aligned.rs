@@ -1,25 +1,66 @@
 use crc::crc32;
 use std::{
-    convert::TryInto,
+    alloc::{self, Layout},
+    convert::{TryFrom, TryInto},
     fmt,
-    ops::{Deref, DerefMut},
+    marker::PhantomData,
+    ops::{Deref, DerefMut, Range},
+    ptr::NonNull,
 };
+use twox_hash::xxh3;
 
+/// A page-aligned buffer of `N` bytes, for reading and writing pages with
+/// direct I/O.  `N` is the largest page size by default, and the storage
+/// and page types all use that; smaller buffers are for working with small
+/// pages, in tests for one.
 #[repr(C, align(4096))]
-#[derive(Clone)]
-pub struct Buffer {
-    data: [u8; crate::PAGESIZE],
+pub struct Buffer<const N: usize = { crate::PAGESIZE }> {
+    data: [u8; N],
 }
 
 impl Buffer {
     pub fn new() -> Box<Buffer> {
-        Box::new(Buffer::default())
+        Buffer::zeroed()
     }
 
-    pub fn with_value(val: u8) -> Buffer {
-        Buffer {
-            data: [val; crate::PAGESIZE],
+    pub fn with_value(val: u8) -> Box<Buffer> {
+        Buffer::filled(val)
+    }
+}
+
+impl<const N: usize> Buffer<N> {
+    /// Returns a zeroed buffer of any size.  `Buffer::new` is the same, for
+    /// buffers of the default size.
+    ///
+    /// The buffer is allocated zeroed straight on the heap, rather than built
+    /// on the stack and moved into a box, so large buffers can't overflow the
+    /// stack, and the allocator can hand out memory that is already zero.
+    pub fn zeroed() -> Box<Buffer<N>> {
+        let layout = Layout::new::<Buffer<N>>();
+        if layout.size() == 0 {
+            // SAFETY: a box of a zero sized type needs no allocation, only a
+            // well aligned pointer.
+            return unsafe { Box::from_raw(NonNull::dangling().as_ptr()) };
         }
+        // SAFETY: the layout isn't zero sized, and all zeroes is a valid
+        // `Buffer`, which is just bytes.  The pointer comes from the global
+        // allocator with `Buffer`'s layout, as `Box` requires.
+        unsafe {
+            let ptr = alloc::alloc_zeroed(layout) as *mut Buffer<N>;
+            if ptr.is_null() {
+                alloc::handle_alloc_error(layout);
+            }
+            Box::from_raw(ptr)
+        }
+    }
+
+    /// Returns a buffer of any size, with every byte set to `val`.
+    /// `Buffer::with_value` is the same, for buffers of the default size.
+    /// Like `zeroed`, it is built on the heap.
+    pub fn filled(val: u8) -> Box<Buffer<N>> {
+        let mut buffer = Buffer::zeroed();
+        buffer.data.fill(val);
+        buffer
     }
 
     pub fn copy_from_slice(&mut self, slice: &[u8]) {
@@ -29,13 +70,23 @@ impl Buffer {
     }
 }
 
-impl Default for Buffer {
-    fn default() -> Buffer {
-        Buffer::with_value(Default::default())
+// Buffers are only ever handed out boxed, so that they are never built on
+// the stack, where a large one could overflow it.
+impl<const N: usize> Default for Box<Buffer<N>> {
+    fn default() -> Box<Buffer<N>> {
+        Buffer::zeroed()
     }
 }
 
-impl Deref for Buffer {
+impl<const N: usize> Clone for Box<Buffer<N>> {
+    fn clone(&self) -> Box<Buffer<N>> {
+        let mut buffer = Buffer::zeroed();
+        buffer.data.copy_from_slice(&self.data);
+        buffer
+    }
+}
+
+impl<const N: usize> Deref for Buffer<N> {
     type Target = [u8];
 
     fn deref(&self) -> &[u8] {
@@ -43,13 +94,13 @@ impl Deref for Buffer {
     }
 }
 
-impl DerefMut for Buffer {
+impl<const N: usize> DerefMut for Buffer<N> {
     fn deref_mut(&mut self) -> &mut [u8] {
         &mut self.data
     }
 }
 
-impl<'a> IntoIterator for &'a Buffer {
+impl<'a, const N: usize> IntoIterator for &'a Buffer<N> {
     type IntoIter = std::slice::Iter<'a, u8>;
     type Item = &'a u8;
 
@@ -58,9 +109,171 @@ impl<'a> IntoIterator for &'a Buffer {
     }
 }
 
-fn check_crc(buffer: &Buffer) -> bool {
-    let crc: u32 = crc32::checksum_ieee(&buffer[4..]);
-    crc == u32::from_le_bytes(buffer[..4].try_into().unwrap())
+/// A free list of buffers, so that pages read and written over and over
+/// don't each need a new allocation, zeroed from scratch.  Buffers handed
+/// out again hold whatever was last in them, so they suit reading pages
+/// into; a page built from nothing should start from `Buffer::new`.
+pub struct BufferAllocator {
+    free: Vec<Box<Buffer>>,
+    limit: usize,
+}
+
+impl BufferAllocator {
+    /// Creates an allocator that keeps at most `limit` buffers on hand.
+    pub fn new(limit: usize) -> BufferAllocator {
+        BufferAllocator {
+            free: Vec::with_capacity(limit),
+            limit,
+        }
+    }
+
+    /// Returns a buffer from the free list, or a new one if it is empty.
+    pub fn allocate(&mut self) -> Box<Buffer> {
+        self.free.pop().unwrap_or_default()
+    }
+
+    /// Puts a buffer on the free list, or drops it if the list is full.
+    pub fn release(&mut self, buffer: Box<Buffer>) {
+        if self.free.len() < self.limit {
+            self.free.push(buffer);
+        }
+    }
+
+    /// Returns the number of buffers on the free list.
+    pub fn free_count(&self) -> usize {
+        self.free.len()
+    }
+}
+
+/// An integer type that can be stored in a page, little endian.
+pub(crate) trait FieldType: Copy {
+    /// The number of bytes the field takes up.
+    const SIZE: usize;
+
+    fn read(bytes: &[u8]) -> Self;
+
+    fn write(self, bytes: &mut [u8]);
+}
+
+macro_rules! field_type {
+    ($($int:ty),*) => {
+        $(impl FieldType for $int {
+            const SIZE: usize = std::mem::size_of::<$int>();
+
+            fn read(bytes: &[u8]) -> $int {
+                <$int>::from_le_bytes(bytes.try_into().expect("field is its type's size"))
+            }
+
+            fn write(self, bytes: &mut [u8]) {
+                bytes.copy_from_slice(&self.to_le_bytes())
+            }
+        })*
+    };
+}
+
+field_type!(u8, u16, u32, u64);
+
+/// A field at a fixed offset in a page.  Fields are declared as constants,
+/// so one that would run past the end of a page fails to compile, and
+/// reading or writing it can't go out of bounds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Field<T> {
+    offset: usize,
+    _type: PhantomData<T>,
+}
+
+impl<T: FieldType> Field<T> {
+    pub(crate) const fn at(offset: usize) -> Field<T> {
+        assert!(offset + T::SIZE <= crate::PAGESIZE, "field runs past the end of the page");
+        Field {
+            offset,
+            _type: PhantomData,
+        }
+    }
+
+    /// The bytes of the page the field takes up.
+    pub(crate) const fn range(self) -> Range<usize> {
+        self.offset..self.offset + T::SIZE
+    }
+
+    pub(crate) fn get(self, page: &[u8]) -> T {
+        T::read(&page[self.range()])
+    }
+
+    pub(crate) fn set(self, page: &mut [u8], value: T) {
+        value.write(&mut page[self.range()])
+    }
+}
+
+// The header every page starts with.
+pub(crate) const CRC: Field<u32> = Field::at(0x0);
+pub(crate) const PAGE_TYPE: Field<u16> = Field::at(0x4);
+
+/// How a page's checksum is computed.  Each database chooses one when it is
+/// created and records it in its master record; pages from before there was
+/// a choice, and the master record itself, use `Crc32`.
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Checksum {
+    /// CRC-32 (IEEE), computed in software.
+    #[default]
+    Crc32 = 0,
+    /// CRC-32C (Castagnoli), using SSE 4.2 or the ARM CRC instructions where
+    /// the CPU has them.
+    Crc32c = 1,
+    /// The low 32 bits of XXH3-64.
+    Xxh3 = 2,
+}
+
+impl Checksum {
+    /// The checksum of the rest of the page, as it would be stored.
+    pub fn compute(self, page: &[u8]) -> u32 {
+        let data = &page[CRC.range().end..];
+        match self {
+            Checksum::Crc32 => crc32::checksum_ieee(data),
+            Checksum::Crc32c => crc32c::crc32c(data),
+            Checksum::Xxh3 => xxh3::hash64(data) as u32,
+        }
+    }
+
+    /// Stores the checksum of the rest of the page at its start.
+    pub fn stamp(self, page: &mut [u8]) {
+        let checksum = self.compute(page);
+        CRC.set(page, checksum)
+    }
+
+    /// Whether the checksum stored at the start of the page is right.
+    pub fn verify(self, page: &[u8]) -> bool {
+        self.compute(page) == CRC.get(page)
+    }
+}
+
+/// Fails with the value itself if it isn't a known algorithm.
+impl TryFrom<u32> for Checksum {
+    type Error = u32;
+
+    fn try_from(val: u32) -> Result<Checksum, u32> {
+        match val {
+            0 => Ok(Checksum::Crc32),
+            1 => Ok(Checksum::Crc32c),
+            2 => Ok(Checksum::Xxh3),
+            _ => Err(val),
+        }
+    }
+}
+
+/// The CRC stored at the start of a page.
+pub(crate) fn stored_crc<const N: usize>(buffer: &Buffer<N>) -> u32 {
+    CRC.get(buffer)
+}
+
+/// The page's type, if it is one this crate knows.
+pub(crate) fn page_type(page: &[u8]) -> Option<crate::PageType> {
+    crate::PageType::try_from(PAGE_TYPE.get(page)).ok()
+}
+
+pub(crate) fn set_page_type(page: &mut [u8], page_type: crate::PageType) {
+    PAGE_TYPE.set(page, page_type as u16)
 }
 
 #[derive(Debug, Eq, PartialEq)]
@@ -83,22 +296,26 @@ impl fmt::Display for Error {
 
 impl std::error::Error for Error {}
 
-pub(crate) trait FromAligned: Sized {
-    fn expected_page_type() -> crate::PageType;
+/// A kind of page that starts with the common header: a checksum of the
+/// rest of the page, then the page's type.  `IntoAligned` stamps the header
+/// as a page is written, and `FromAligned` checks it as one is read.
+pub(crate) trait PageHeader {
+    /// The type stamped on pages of this kind.
+    const PAGE_TYPE: crate::PageType;
+}
 
+pub(crate) trait FromAligned: PageHeader + Sized {
     fn extra_constraints(_buffer: &Buffer) -> Result<(), Error> {
         Ok(())
     }
 
-    fn from_aligned(buffer: Box<Buffer>) -> Result<Self, Error> {
-        let page_type: crate::PageType = u16::from_le_bytes(buffer[4..6].try_into().unwrap())
-            .try_into()
-            .or(Err(Error::PageType))?;
-        if !check_crc(&buffer) {
+    /// Checks the page and wraps it.  Its checksum must have been computed
+    /// with `checksum`.
+    fn from_aligned(buffer: Box<Buffer>, checksum: Checksum) -> Result<Self, Error> {
+        let page_type = page_type(&buffer).ok_or(Error::PageType)?;
+        if !checksum.verify(&buffer) {
             Err(Error::CrcError)
-        } else if std::mem::size_of::<Self>() != std::mem::size_of::<Buffer>() {
-            Err(Error::SizeError)
-        } else if page_type != Self::expected_page_type() {
+        } else if page_type != Self::PAGE_TYPE {
             Err(Error::PageType)
         } else {
             <Self as FromAligned>::extra_constraints(&buffer)?;
@@ -106,9 +323,120 @@ pub(crate) trait FromAligned: Sized {
         }
     }
 
-    /// The body of this should just be `unsafe { std::mem::transmute }`,
-    /// since conditions have already been checked, but rust's type system
-    /// won't let us implement that on the trait since there is no compile-time
-    /// guarantee that Self is the right size.
+    /// Wraps a buffer that has already passed the checksum, page type and
+    /// extra constraint checks.
     fn transform(buffer: Box<Buffer>) -> Self;
 }
+
+pub(crate) trait IntoAligned: PageHeader + Sized {
+    /// The page's buffer, as it stands.
+    fn as_buffer(&self) -> &Buffer;
+
+    /// Gives up the page's buffer, as it stands.
+    fn into_buffer(self) -> Box<Buffer>;
+
+    /// Returns the page's buffer, with its type and a checksum computed with
+    /// `checksum` stamped in the header, ready to be written.
+    fn into_aligned(self, checksum: Checksum) -> Box<Buffer> {
+        let mut buffer = self.into_buffer();
+        set_page_type(&mut buffer, Self::PAGE_TYPE);
+        checksum.stamp(&mut buffer);
+        buffer
+    }
+
+    /// Like `into_aligned`, but stamps a copy, leaving `self` usable.
+    fn to_aligned(&self, checksum: Checksum) -> Box<Buffer> {
+        let mut buffer = Buffer::new();
+        buffer.copy_from_slice(self.as_buffer());
+        set_page_type(&mut buffer, Self::PAGE_TYPE);
+        checksum.stamp(&mut buffer);
+        buffer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn small_buffer() {
+        let mut small = Buffer::<4096>::zeroed();
+        assert_eq!(small.len(), 4096);
+        assert_eq!(small.as_ptr() as usize % 4096, 0);
+        small[4..].copy_from_slice(&[7; 4092]);
+        let crc = Checksum::Crc32.compute(&small);
+        small[..4].copy_from_slice(&crc.to_le_bytes());
+        assert_eq!(stored_crc(&*small), crc);
+
+        let large = Buffer::with_value(7);
+        assert_eq!(large.len(), crate::PAGESIZE);
+        assert_ne!(Checksum::Crc32.compute(&large), crc);
+    }
+
+    #[test]
+    fn heap_allocated() {
+        // Far more than the thread's stack could hold.
+        let handle = std::thread::Builder::new()
+            .stack_size(64 * 1024)
+            .spawn(|| {
+                let buffer = Buffer::<{ 1 << 24 }>::zeroed();
+                assert_eq!(buffer.as_ptr() as usize % 4096, 0);
+                assert!(buffer.iter().all(|&byte| byte == 0));
+
+                // And so is every other way of making one.
+                let filled = Buffer::<{ 1 << 24 }>::filled(7);
+                assert!(filled.clone().iter().all(|&byte| byte == 7));
+                let defaulted: Box<Buffer<{ 1 << 24 }>> = Default::default();
+                assert!(defaulted.iter().all(|&byte| byte == 0));
+            })
+            .expect("spawn thread");
+        handle.join().expect("allocate buffer");
+        assert_eq!(Buffer::<0>::zeroed().len(), 0);
+    }
+
+    #[test]
+    fn buffer_allocator() {
+        let mut allocator = BufferAllocator::new(1);
+        let mut first = allocator.allocate();
+        first[0] = 9;
+        let address = first.as_ptr();
+        allocator.release(first);
+        allocator.release(Buffer::new());
+        assert_eq!(allocator.free_count(), 1);
+
+        let reused = allocator.allocate();
+        assert_eq!((reused.as_ptr(), reused[0]), (address, 9));
+        assert_eq!(allocator.free_count(), 0);
+    }
+
+    #[test]
+    fn fields() {
+        const WIDE: Field<u64> = Field::at(crate::PAGESIZE - 8);
+        let mut page = Buffer::new();
+        WIDE.set(&mut page, 0x0102_0304_0506_0708);
+        assert_eq!(page[crate::PAGESIZE - 8], 0x08);
+        assert_eq!(WIDE.get(&page), 0x0102_0304_0506_0708);
+
+        PAGE_TYPE.set(&mut page, 0xffff);
+        assert!(page_type(&page).is_none());
+        set_page_type(&mut page, crate::PageType::DataPage);
+        assert!(page_type(&page) == Some(crate::PageType::DataPage));
+        Checksum::Crc32.stamp(&mut page);
+        assert!(Checksum::Crc32.verify(&page));
+    }
+
+    #[test]
+    fn checksums() {
+        let mut page = Buffer::with_value(3);
+        let algorithms = [Checksum::Crc32, Checksum::Crc32c, Checksum::Xxh3];
+        for &checksum in &algorithms {
+            checksum.stamp(&mut page);
+            assert!(checksum.verify(&page));
+            assert!(!algorithms.iter().any(|&other| other != checksum && other.verify(&page)));
+            assert_eq!(Checksum::try_from(checksum as u32), Ok(checksum));
+        }
+        // Only the rest of the page is checksummed.
+        assert_eq!(Checksum::Crc32.compute(&page), crc32::checksum_ieee(&page[4..]));
+        assert_eq!(Checksum::try_from(3), Err(3));
+    }
+}
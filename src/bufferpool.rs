@@ -1,23 +1,126 @@
 //! Buffer pool to cache pages from the page file in memory.
 
-// TODO:
-// 1.  Write pages to the buffer pool before persisting to the PagedFile
-// 2.  Implement delayed persistence.  Writes update buffer pool, and mark
-//     entries as dirty.  When a dirty page is marked for eviction, it needs
-//     to be persisted before it is written (and any adjacent dirty pages
-//     can be written in the same operation).
-
-use crate::{PAGESIZE, aligned, storage::PagedFile};
-use std::collections::{
-    HashMap,
+// Updates are written back lazily: `update_page` only changes the frame and
+// marks it dirty, and the page is persisted when its frame is evicted, when
+// it is flushed with `flush_page` or `flush_all`, or when the pool is
+// dropped.  When a dirty page is evicted or the whole pool is flushed, any
+// dirty pages with adjacent page ids are written with it, in the same
+// operation.
+//
+// A failed sync poisons the pool.  The pages written since the last sync
+// that succeeded are marked dirty again, as there is no telling whether
+// they reached the disk, and every later write and sync fails with a
+// `storage::Poisoned` error.  Retrying is never safe, so the caller has to
+// give up and recover from what is on disk.
+//
+// Freed pages are kept on a list threaded through the pages themselves, and
+// reused by `append_page` before the file is extended.  The head of the list
+// is kept in the master record, which must be page 0.  Files without one
+// can't free pages.
+//
+// Master record:
+//
+//   0x0000  CRC32 (4 bytes)  // CRC of bytes 4-end
+//   0x0004  Page type (2 bytes) (0x0000)
+//   0x0008  Magic (8 bytes) ("potpot\0\0")
+//   0x0010  First free page (8 bytes)  // 0 if there are none
+//   0x0018  Page size (4 bytes)  // 0 in files from before it was recorded
+//   0x001c  Checksum algorithm (4 bytes)  // aligned::Checksum, 0 is CRC32
+//
+// The master record is always checksummed with CRC32, so it can be read
+// before the algorithm it records for the other pages is known.
+//
+// Free page:
+//
+//   0x0000  Checksum (4 bytes)  // of bytes 4-end
+//   0x0004  Page type (2 bytes) (0x0001)
+//   0x0008  Next free page (8 bytes)  // 0 at the end of the list
+
+use crate::{
+    aligned::{self, page_type, set_page_type, Checksum, Field, FromAligned, IntoAligned},
+    storage::{PagedFile, Poisoned, SharedStorage, StorageBackend},
+    PageType, PAGESIZE,
+};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet, VecDeque},
+    convert::TryFrom,
+    fs::{self, File},
+    hash::{Hash, Hasher},
+    io::{self, BufReader, BufWriter, Write},
+    ops::Range,
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Condvar, Mutex, MutexGuard,
+    },
+    thread::JoinHandle,
+    time::Duration,
 };
 
+/// A replacement policy for the buffer pool, which decides which page to
+/// evict when a frame is needed.  Slots are the pool's frames, numbered from
+/// zero, and entries are the page ids held in them.
+///
+/// A pool with a custom policy is created with `BufferPool::with_manager`.
 pub trait CacheManager<T> {
-    // Mark the entry at the given slot as updated
+    /// The number of slots.
+    fn size(&self) -> usize;
+
+    /// Marks the entry at the given slot as used.
     fn update(&mut self, idx: usize);
 
-    // Find an available slot, and return the currently resident value, replacing it with the new value
-    fn sweep(&mut self, entry: T) -> (usize, Option<T>);
+    /// Marks the entry at the given slot as one that can be replaced right
+    /// away, as it is not expected to be used again soon.
+    fn demote(&mut self, idx: usize);
+
+    /// Reports the state of the replacement policy.
+    fn stats(&self) -> PolicyStats {
+        PolicyStats::Custom(Vec::new())
+    }
+
+    /// Puts an entry in a slot known to be empty, without looking for a
+    /// victim.
+    fn insert(&mut self, idx: usize, entry: T);
+
+    /// Empties a slot, returning the entry it held.
+    fn remove(&mut self, idx: usize) -> Option<T>;
+
+    /// Changes the number of slots.  Slots beyond the new size are empty.
+    fn resize(&mut self, size: usize);
+
+    /// Finds a slot for a new entry, and returns it with the entry it held,
+    /// if any, replacing that with the new one.  Slots for which `pinned`
+    /// returns true must not be chosen.  Returns None if every slot is
+    /// pinned.
+    fn sweep(&mut self, entry: T, pinned: &dyn Fn(usize) -> bool) -> Option<(usize, Option<T>)>;
+}
+
+/// The state of a buffer pool's replacement policy.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PolicyStats {
+    Clock {
+        /// Entries with their reference bit set
+        referenced: usize,
+    },
+    LruK {
+        k: usize,
+        /// Entries accessed fewer than k times, which are evicted first
+        under_k: usize,
+    },
+    Arc {
+        /// Target size of T1
+        p: usize,
+        t1: usize,
+        t2: usize,
+        b1: usize,
+        b2: usize,
+    },
+    TinyLfu {
+        window: usize,
+        main: usize,
+    },
+    /// Named values reported by a policy from outside this crate
+    Custom(Vec<(&'static str, usize)>),
 }
 
 pub struct ClockManager<T> {
@@ -37,29 +140,54 @@ impl<T: Copy + Eq> ClockManager<T> {
 }
 
 impl<T> CacheManager<T> for ClockManager<T> {
+    fn size(&self) -> usize {
+        self.entries.len()
+    }
+
     fn update(&mut self, idx: usize) {
         self.clock[idx] = true;
     }
 
+    fn demote(&mut self, idx: usize) {
+        self.clock[idx] = false;
+    }
+
+    fn stats(&self) -> PolicyStats {
+        PolicyStats::Clock {
+            referenced: self.clock.iter().filter(|&&bit| bit).count(),
+        }
+    }
+
+    fn insert(&mut self, idx: usize, entry: T) {
+        self.clock[idx] = true;
+        self.entries[idx] = Some(entry);
+    }
+
+    fn remove(&mut self, idx: usize) -> Option<T> {
+        self.clock[idx] = false;
+        self.entries[idx].take()
+    }
+
+    fn resize(&mut self, size: usize) {
+        self.clock.resize(size, false);
+        self.entries.resize_with(size, || None);
+        if self.idx >= size {
+            self.idx = 0;
+        }
+    }
+
     // Find an available slot for the cache
-    fn sweep(&mut self, entry: T) -> (usize, Option<T>) {
+    fn sweep(&mut self, entry: T, pinned: &dyn Fn(usize) -> bool) -> Option<(usize, Option<T>)> {
         let size = self.clock.len();
-        let (clock_from_start, clock_to_end) = self.clock.split_at_mut(self.idx);
-        let clock_cycle = clock_to_end.iter_mut().chain(clock_from_start);
-        let idx = {
-            let mut found = None;
-            for (i, clockbit) in clock_cycle.enumerate() {
-                if *clockbit {
-                    *clockbit = false;
-                } else {
-                    let idx = (self.idx + i) % size;
-                    found = Some(idx);
-                    break;
-                }
-            }
-            // if nothing was found, return the starting index.
-            found.unwrap_or(self.idx)
-        };
+        let start = self.idx;
+        let clock = &mut self.clock;
+
+        // The first time around the clock clears the reference bits, so if
+        // there is an unpinned slot, it is found by the end of the second.
+        let idx = (0..2 * size)
+            .map(|i| (start + i) % size)
+            .filter(|&idx| !pinned(idx))
+            .find(|&idx| !std::mem::replace(&mut clock[idx], false))?;
 
         // update the clock pointer to the selected slot.
         self.idx = idx;
@@ -68,229 +196,3413 @@ impl<T> CacheManager<T> for ClockManager<T> {
         self.clock[idx] = true;
 
         // return the selected index and the replaced entry, if any.
-        (idx, self.entries[idx].replace(entry))
+        Some((idx, self.entries[idx].replace(entry)))
     }
 }
 
-pub struct BufferPool<CM = ClockManager<u64>>
-where
-    CM: CacheManager<u64>,
-{
-    // map page IDs to their location in the buffer pool
-    page_table: HashMap<u64, usize>,
-
-    // manager to determine which frames to evict
-    manager: CM,
-
-    // cached pages
-    frames: Vec<[u8; PAGESIZE]>,
-
-    // the managed PagedFile
-    storage: PagedFile,
+/// Evicts the entry whose K-th most recent access is furthest in the past.
+/// Entries accessed fewer than K times are evicted first, least recently used
+/// first, so pages touched once by a scan don't push out pages in regular use.
+pub struct LruKManager<T> {
+    k: usize,
+    // logical clock, advanced on every access
+    time: u64,
+    // up to k most recent access times of each slot, oldest first
+    history: Vec<VecDeque<u64>>,
+    entries: Vec<Option<T>>,
 }
 
-impl BufferPool {
-    pub fn new(storage: PagedFile, size: usize) -> BufferPool {
-        let frames = std::iter::repeat([0; PAGESIZE]).take(size).collect();
-        BufferPool {
-            page_table: HashMap::with_capacity(size),
-            manager: ClockManager::new(size),
-            frames,
-            storage,
+impl<T: Copy + Eq> LruKManager<T> {
+    /// # Panic
+    ///
+    /// Panics if `k` is zero.
+    pub fn new(size: usize, k: usize) -> LruKManager<T> {
+        assert!(k > 0, "k must be at least 1");
+        LruKManager {
+            k,
+            time: 0,
+            history: vec![VecDeque::with_capacity(k); size],
+            entries: vec![None; size],
         }
     }
+}
 
-    pub fn read_page(&mut self, page_id: u64, buf: &mut aligned::Buffer) -> std::io::Result<()> {
+impl<T> LruKManager<T> {
+    fn access(&mut self, idx: usize) {
+        self.time += 1;
+        let history = &mut self.history[idx];
+        if history.len() == self.k {
+            history.pop_front();
+        }
+        history.push_back(self.time);
+    }
+}
 
-        let entry = self
-            .page_table
-            .get(&page_id)
-            .copied() // Release the borrow of self
-            .and_then(|frame_idx| {
-                self.manager.update(frame_idx);
-                self.frames.get_mut(frame_idx)
-            });
+impl<T> CacheManager<T> for LruKManager<T> {
+    fn size(&self) -> usize {
+        self.entries.len()
+    }
 
-        if let Some(val) = entry {
-            println!("Got some entry");
-            buf.copy_from_slice(val.as_ref());
-        } else {
-            println!("No entry");
-            self.storage.read_page(page_id, buf)?;
+    fn update(&mut self, idx: usize) {
+        self.access(idx);
+    }
 
-            let frame_idx = self.add_to_buffer_pool(page_id, buf);
+    fn demote(&mut self, idx: usize) {
+        // With no accesses on record, it sorts ahead of every other entry.
+        self.history[idx].clear();
+    }
 
-            self.frames[frame_idx][..].copy_from_slice(&buf);
+    fn stats(&self) -> PolicyStats {
+        PolicyStats::LruK {
+            k: self.k,
+            under_k: (self.entries.iter().zip(&self.history))
+                .filter(|(entry, history)| entry.is_some() && history.len() < self.k)
+                .count(),
         }
-        Ok(())
     }
 
-    // Write a page and get back a page id.
-    pub fn append_page(&mut self, aligned_data: &aligned::Buffer) -> std::io::Result<u64> {
-        // TBD: Figure out how to manage page_ids of new pages written to the buffer pool
-        // without persisting to disk first. Decouple page_ids from disk order?  Track
-        // unwritten page_ids?
-        let page_id = self.storage.append_page(aligned_data)?;
-        self.add_to_buffer_pool(page_id, aligned_data);
-        Ok(page_id)
+    fn insert(&mut self, idx: usize, entry: T) {
+        self.history[idx].clear();
+        self.access(idx);
+        self.entries[idx] = Some(entry);
     }
 
-    // Update an existing page
-    pub fn update_page(&mut self, page_id: u64, data: &aligned::Buffer) -> std::io::Result<()> {
-        self.add_to_buffer_pool(page_id, data);
-        self.storage.write_page(page_id, data)
+    fn remove(&mut self, idx: usize) -> Option<T> {
+        self.history[idx].clear();
+        self.entries[idx].take()
     }
 
-    fn add_to_buffer_pool(&mut self, page_id: u64, data: &[u8]) -> usize {
-        let frame_idx = self.page_table.get(&page_id);
-        let frame_idx = match frame_idx {
-            Some(&frame_idx) => {
-                self.manager.update(frame_idx);
-                frame_idx
-            }
-            None => {
-                let (idx, evicted_page) = self.manager.sweep(page_id);
+    fn resize(&mut self, size: usize) {
+        let k = self.k;
+        self.history.resize_with(size, || VecDeque::with_capacity(k));
+        self.entries.resize_with(size, || None);
+    }
 
-                // If there is a page to evict, remove it now.
-                if let Some(page_id) = evicted_page {
-                    self.page_table.remove(&page_id);
-                }
-                self.page_table.insert(page_id, idx);
-                idx
+    fn sweep(&mut self, entry: T, pinned: &dyn Fn(usize) -> bool) -> Option<(usize, Option<T>)> {
+        let k = self.k;
+        let entries = &self.entries;
+        let history = &self.history;
+        let idx = (0..entries.len()).filter(|&idx| !pinned(idx)).min_by_key(|&idx| {
+            match (&entries[idx], history[idx].len() == k) {
+                // use empty slots first,
+                (None, _) => (0, 0),
+                // then entries with fewer than k accesses, by last access,
+                (Some(_), false) => (1, history[idx].back().copied().unwrap_or(0)),
+                // then entries by their k-th most recent access.
+                (Some(_), true) => (2, history[idx][0]),
             }
-        };
+        })?;
 
-        // Review: Is this sometimes not necessary?
-        self.frames[frame_idx].copy_from_slice(data);
-        frame_idx
+        self.history[idx].clear();
+        self.access(idx);
+        Some((idx, self.entries[idx].replace(entry)))
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::{aligned, storage::PagedFile, testutils::create_test_path};
-    use std::fmt;
+/// Adaptive replacement cache.  Entries seen once (T1) and entries seen more
+/// than once (T2) are kept in separate LRU lists, along with ghost lists of
+/// recently evicted entries from each (B1 and B2).  A miss on a ghost entry
+/// shows that its list was too short, and moves the target size of T1 (`p`)
+/// towards whichever list would have kept it.
+pub struct ArcManager<T> {
+    // target number of slots for T1
+    p: usize,
+    // resident slots, least recently used first
+    t1: VecDeque<usize>,
+    t2: VecDeque<usize>,
+    // recently evicted entries, least recently evicted first
+    b1: VecDeque<T>,
+    b2: VecDeque<T>,
+    entries: Vec<Option<T>>,
+}
 
-    struct CMDebug<'a, T>(&'a ClockManager<T>);
+impl<T: Copy + Eq> ArcManager<T> {
+    pub fn new(size: usize) -> ArcManager<T> {
+        ArcManager {
+            p: 0,
+            t1: VecDeque::with_capacity(size),
+            t2: VecDeque::with_capacity(size),
+            b1: VecDeque::with_capacity(size),
+            b2: VecDeque::with_capacity(size),
+            entries: vec![None; size],
+        }
+    }
 
-    impl<'a, T: fmt::Debug> fmt::Debug for CMDebug<'a, T> {
-        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-            f.debug_struct("ClockManager")
-                .field("idx", &self.0.idx)
-                .field("clock", &self.0.clock)
-                .field("entries", &self.0.entries)
-                .finish()
+    // Evict an entry to make room, from T1 or T2 depending on the target size
+    // of T1, moving it to the matching ghost list.
+    fn replace(&mut self, ghost_in_b2: bool, pinned: &dyn Fn(usize) -> bool) -> Option<(usize, Option<T>)> {
+        let from_t1 = !self.t1.is_empty() && (self.t1.len() > self.p || (ghost_in_b2 && self.t1.len() == self.p));
+        let unpinned = |list: &VecDeque<usize>| list.iter().position(|&idx| !pinned(idx));
+
+        // If everything in the preferred list is pinned, take from the other.
+        let (idx, ghosts) = match (from_t1, unpinned(&self.t1), unpinned(&self.t2)) {
+            (true, Some(pos), _) | (false, Some(pos), None) => (self.t1.remove(pos)?, &mut self.b1),
+            (_, _, Some(pos)) => (self.t2.remove(pos)?, &mut self.b2),
+            (_, None, None) => return None,
+        };
+        let evicted = self.entries[idx].take();
+        ghosts.extend(evicted);
+        Some((idx, evicted))
+    }
+
+    // Adapt to misses on recently evicted entries, which are brought back
+    // into T2 since they have now been seen twice.  Returns whether the entry
+    // was a ghost, and whether it was in B2.
+    fn adapt(&mut self, entry: &T) -> (bool, bool) {
+        let size = self.entries.len();
+        if let Some(pos) = self.b1.iter().position(|e| e == entry) {
+            let delta = (self.b2.len() / self.b1.len()).max(1);
+            self.p = (self.p + delta).min(size);
+            self.b1.remove(pos);
+            (true, false)
+        } else if let Some(pos) = self.b2.iter().position(|e| e == entry) {
+            let delta = (self.b1.len() / self.b2.len()).max(1);
+            self.p = self.p.saturating_sub(delta);
+            self.b2.remove(pos);
+            (true, true)
+        } else {
+            (false, false)
         }
     }
 
-    #[test]
-    fn clock_manager() {
-        let mut cm = ClockManager::new(4);
+    // Put an entry in an empty slot, at the MRU end of T2 if it has been seen
+    // before, or of T1 if not.
+    fn admit(&mut self, idx: usize, entry: T, frequent: bool) {
+        let size = self.entries.len();
+        if frequent {
+            self.t2.push_back(idx);
+        } else {
+            // Keep the ghost lists from remembering more than the cache
+            // could hold: |T1| + |B1| <= size, and all four lists <= 2 * size.
+            while self.t1.len() + self.b1.len() >= size && !self.b1.is_empty() {
+                self.b1.pop_front();
+            }
+            while self.t1.len() + self.t2.len() + self.b1.len() + self.b2.len() >= 2 * size && !self.b2.is_empty() {
+                self.b2.pop_front();
+            }
+            self.t1.push_back(idx);
+        }
+        self.entries[idx] = Some(entry);
+    }
+}
 
-        // Fill the buffer pool
-        for (i, val) in (100..104).enumerate() {
-            let (idx, replaced) = dbg!(cm.sweep(val));
-            assert_eq!(idx, i);
-            assert!(replaced.is_none());
-            dbg!(CMDebug(&cm));
+impl<T: Copy + Eq> CacheManager<T> for ArcManager<T> {
+    fn size(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn update(&mut self, idx: usize) {
+        if let Some(pos) = self.t1.iter().position(|&i| i == idx) {
+            self.t1.remove(pos);
+        } else if let Some(pos) = self.t2.iter().position(|&i| i == idx) {
+            self.t2.remove(pos);
         }
+        self.t2.push_back(idx);
+    }
 
-        // Now we evict the first entry
-        let result = cm.sweep(104);
-        assert_eq!(result, (0, Some(100)));
+    fn demote(&mut self, idx: usize) {
+        for list in [&mut self.t1, &mut self.t2] {
+            if let Some(pos) = list.iter().position(|&i| i == idx) {
+                list.remove(pos);
+                list.push_front(idx);
+            }
+        }
+    }
 
-        // Some entries get accessed
-        cm.update(1);
-        cm.update(2);
-        dbg!(CMDebug(&cm));
+    fn stats(&self) -> PolicyStats {
+        PolicyStats::Arc {
+            p: self.p,
+            t1: self.t1.len(),
+            t2: self.t2.len(),
+            b1: self.b1.len(),
+            b2: self.b2.len(),
+        }
+    }
 
-        // The next one after those gets evicted
-        let result = cm.sweep(105);
-        assert_eq!(result, (3, Some(103)));
-        dbg!(CMDebug(&cm));
+    fn insert(&mut self, idx: usize, entry: T) {
+        let (frequent, _) = self.adapt(&entry);
+        self.admit(idx, entry, frequent);
+    }
 
-        // Final state of the ClockManager
-        assert_eq!(cm.entries, &[Some(104), Some(101), Some(102), Some(105)]);
+    fn remove(&mut self, idx: usize) -> Option<T> {
+        self.t1.retain(|&i| i != idx);
+        self.t2.retain(|&i| i != idx);
+        self.entries[idx].take()
     }
 
-    #[test]
-    fn append_and_update_pages() -> anyhow::Result<()> {
-        let path = create_test_path("test-potpotdb::buffer::append_pages.data");
-        let storage = PagedFile::from_path(&path)?;
-        let mut pool = BufferPool::new(storage, 3);
+    fn resize(&mut self, size: usize) {
+        self.t1.retain(|&idx| idx < size);
+        self.t2.retain(|&idx| idx < size);
+        self.entries.resize_with(size, || None);
+        self.p = self.p.min(size);
+        // Forget the oldest ghosts that a smaller cache wouldn't remember.
+        while self.b1.len() + self.b2.len() > size {
+            if self.b1.len() >= self.b2.len() {
+                self.b1.pop_front();
+            } else {
+                self.b2.pop_front();
+            }
+        }
+    }
 
-        let aligned = aligned::Buffer::with_value(0xff);
+    fn sweep(&mut self, entry: T, pinned: &dyn Fn(usize) -> bool) -> Option<(usize, Option<T>)> {
+        let size = self.entries.len();
+        if (0..size).all(pinned) {
+            return None;
+        }
 
-        for expected_page in [0, 1, 2, 3, 4].iter() {
-            let page_id = pool.append_page(&aligned)?;
-            dbg!(page_id);
-            assert_eq!(page_id, *expected_page);
+        let (frequent, ghost_in_b2) = self.adapt(&entry);
+        let free = (0..size).find(|&idx| self.entries[idx].is_none() && !pinned(idx));
+        let (idx, evicted) = match free {
+            Some(idx) => (idx, None),
+            None => self.replace(ghost_in_b2, pinned)?,
+        };
+        self.admit(idx, entry, frequent);
+        Some((idx, evicted))
+    }
+}
 
-            // Verify that the page is written into the buffer pool
-            //assert!(pool.page_table.contains_key(&page_id));
+/// W-TinyLFU.  New entries go into a small LRU window.  Once the window is
+/// full, the entry leaving it is only admitted to the main LRU region if a
+/// sketch of recent access frequencies shows it to be used more often than
+/// the entry it would displace there.  Otherwise it is the one evicted, so
+/// a page read once by a scan can't push out a frequently used page.
+pub struct TinyLfuManager<T> {
+    window_size: usize,
+    // resident slots, least recently used first
+    window: VecDeque<usize>,
+    main: VecDeque<usize>,
+    sketch: FrequencySketch,
+    entries: Vec<Option<T>>,
+}
+
+impl<T: Copy + Eq + Hash> TinyLfuManager<T> {
+    pub fn new(size: usize) -> TinyLfuManager<T> {
+        // The window gets 1% of the slots, as in the W-TinyLFU paper.
+        let window_size = (size / 100).max(1);
+        TinyLfuManager {
+            window_size,
+            window: VecDeque::with_capacity(window_size + 1),
+            main: VecDeque::with_capacity(size),
+            sketch: FrequencySketch::new(size),
+            entries: vec![None; size],
         }
+    }
 
-        let mut read_aligned = aligned::Buffer::new();
+    // Move entries leaving the window into the main region.
+    fn rebalance(&mut self) {
+        while self.window.len() > self.window_size {
+            if let Some(idx) = self.window.pop_front() {
+                self.main.push_back(idx);
+            }
+        }
+    }
+}
 
-        for page_id in [0, 1, 2, 3, 4].iter() {
-            // Assert that reading a page fills the buffer with the appropriate data
-            pool.read_page(*page_id, &mut read_aligned)?;
-            read_aligned.iter().for_each(|&byte| assert_eq!(byte, 255));
+impl<T: Copy + Eq + Hash> CacheManager<T> for TinyLfuManager<T> {
+    fn size(&self) -> usize {
+        self.entries.len()
+    }
 
-            // Reset to zeros.
-            read_aligned.iter_mut().for_each(|loc| *loc = 0)
+    fn update(&mut self, idx: usize) {
+        if let Some(entry) = self.entries[idx] {
+            self.sketch.increment(&entry);
         }
+        if let Some(pos) = self.window.iter().position(|&i| i == idx) {
+            self.window.remove(pos);
+            self.window.push_back(idx);
+        } else if let Some(pos) = self.main.iter().position(|&i| i == idx) {
+            self.main.remove(pos);
+            self.main.push_back(idx);
+        }
+    }
 
-        // Assert that trying to read a non-existent page results in an error
-        pool.read_page(5, &mut read_aligned)
-            .expect_err("reading a nonexistent page should error");
+    fn demote(&mut self, idx: usize) {
+        for list in [&mut self.window, &mut self.main] {
+            if let Some(pos) = list.iter().position(|&i| i == idx) {
+                list.remove(pos);
+                list.push_front(idx);
+            }
+        }
+    }
 
-        // Try updating a page that is in the buffer pool, and a page that is not in the buffer pool:
-        // Verify that the data can be read from the page, and that the page is still in the buffer pool.
+    fn stats(&self) -> PolicyStats {
+        PolicyStats::TinyLfu {
+            window: self.window.len(),
+            main: self.main.len(),
+        }
+    }
 
-        let aligned = aligned::Buffer::with_value(0x80);
+    fn insert(&mut self, idx: usize, entry: T) {
+        self.sketch.increment(&entry);
+        self.window.push_back(idx);
+        self.rebalance();
+        self.entries[idx] = Some(entry);
+    }
 
-        let in_pool = 4;
-        assert!(pool.page_table.contains_key(&in_pool));
+    fn remove(&mut self, idx: usize) -> Option<T> {
+        self.window.retain(|&i| i != idx);
+        self.main.retain(|&i| i != idx);
+        self.entries[idx].take()
+    }
 
-        let not_in_pool = 0;
-        assert!(!pool.page_table.contains_key(&not_in_pool));
+    fn resize(&mut self, size: usize) {
+        // The sketch is kept as it is, so the frequencies seen so far aren't
+        // lost.
+        self.window_size = (size / 100).max(1);
+        self.window.retain(|&idx| idx < size);
+        self.main.retain(|&idx| idx < size);
+        self.entries.resize_with(size, || None);
+        self.rebalance();
+    }
 
-        // Test in_pool first, because testing not_in_pool could evict in_pool
-        for &page_id in &[in_pool, not_in_pool] {
-            pool.update_page(page_id, &aligned)?;
-            assert!(pool.page_table.contains_key(&page_id));
+    fn sweep(&mut self, entry: T, pinned: &dyn Fn(usize) -> bool) -> Option<(usize, Option<T>)> {
+        self.sketch.increment(&entry);
 
-            pool.read_page(page_id, &mut read_aligned)?;
-            read_aligned.iter().for_each(|byte| assert_eq!(*byte, 128));
+        let free = (0..self.entries.len()).find(|&idx| self.entries[idx].is_none() && !pinned(idx));
+        let idx = match free {
+            Some(idx) => idx,
+            None => {
+                let candidate = self.window.iter().position(|&idx| !pinned(idx));
+                let victim = self.main.iter().position(|&idx| !pinned(idx));
+                match (candidate, victim) {
+                    (Some(c), Some(v)) => {
+                        let frequency = |list: &VecDeque<usize>, pos: usize| {
+                            self.entries[list[pos]].map_or(0, |e| self.sketch.frequency(&e))
+                        };
+                        if frequency(&self.window, c) > frequency(&self.main, v) {
+                            // Admit the candidate to the main region in place of the victim.
+                            let candidate = self.window.remove(c)?;
+                            self.main.push_back(candidate);
+                            self.main.remove(v)?
+                        } else {
+                            self.window.remove(c)?
+                        }
+                    }
+                    (Some(c), None) => self.window.remove(c)?,
+                    (None, Some(v)) => self.main.remove(v)?,
+                    (None, None) => return None,
+                }
+            }
+        };
+
+        self.window.push_back(idx);
+        self.rebalance();
+        Some((idx, self.entries[idx].replace(entry)))
+    }
+}
+
+/// A count-min sketch of how often entries have been seen, with four bit
+/// counters that are halved periodically so old popularity fades.
+struct FrequencySketch {
+    // DEPTH rows of `width` counters
+    counters: Vec<u8>,
+    width: usize,
+    additions: usize,
+    sample_size: usize,
+}
+
+impl FrequencySketch {
+    const DEPTH: usize = 4;
+    const MAX_COUNT: u8 = 15;
+
+    fn new(size: usize) -> FrequencySketch {
+        let width = (size * 4).next_power_of_two().max(16);
+        FrequencySketch {
+            counters: vec![0; Self::DEPTH * width],
+            width,
+            additions: 0,
+            sample_size: 10 * size.max(1),
+        }
+    }
+
+    fn counter(&self, row: usize, entry: &impl Hash) -> usize {
+        let mut hasher = DefaultHasher::new();
+        row.hash(&mut hasher);
+        entry.hash(&mut hasher);
+        row * self.width + (hasher.finish() as usize & (self.width - 1))
+    }
+
+    fn increment(&mut self, entry: &impl Hash) {
+        for row in 0..Self::DEPTH {
+            let counter = self.counter(row, entry);
+            self.counters[counter] = (self.counters[counter] + 1).min(Self::MAX_COUNT);
         }
+        self.additions += 1;
+        if self.additions >= self.sample_size {
+            self.counters.iter_mut().for_each(|count| *count /= 2);
+            self.additions /= 2;
+        }
+    }
+
+    fn frequency(&self, entry: &impl Hash) -> u8 {
+        (0..Self::DEPTH)
+            .map(|row| self.counters[self.counter(row, entry)])
+            .min()
+            .unwrap_or(0)
+    }
+}
+
+/// How a page being read is expected to be used.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReadHint {
+    /// The page may well be read again soon.
+    #[default]
+    Normal,
+    /// The page is read as part of a scan, and is unlikely to be read again
+    /// soon.  Its frame can be reused straight away, so the scan doesn't push
+    /// the pages in regular use out of the buffer pool.
+    Sequential,
+}
 
+/// Callbacks made by the buffer pool as pages are written to storage or
+/// evicted, so that higher layers can enforce write-ahead logging and
+/// invalidate their own caches of page contents.  One set of hooks may be
+/// shared by several pools, such as the shards of a `ShardedBufferPool`.
+pub trait BufferPoolHooks: Send + Sync {
+    /// Called before a dirty page is written to storage, with the contents
+    /// to be written.  Returning an error, say because the log records for
+    /// the page could not be made durable, stops the write, and the page
+    /// stays dirty.
+    fn before_flush(&self, _page_id: u64, _page: &[u8]) -> io::Result<()> {
         Ok(())
     }
 
-    #[test]
-    fn buffer_pool() -> anyhow::Result<()> {
-        let path = create_test_path("test-potpotdb::buffer::buffer_pool.data");
-        let storage = PagedFile::from_path(&path)?;
-        let mut pool = BufferPool::new(storage, 3);
-        let mut aligned = aligned::Buffer::new();
+    /// Called after a page has been written to storage.
+    fn after_flush(&self, _page_id: u64) {}
 
-        let pages: Vec<_> = [101, 102, 103, 104]
-            .iter()
-            // Insert enough pages to exceed the capacity of the buffer pool.
-            // Pages should get written to disk / evicted from buffer pool.
-            .map(|&value| {
-                aligned[..].iter_mut().for_each(|loc| *loc = value);
-                let page_id = pool.append_page(&aligned).unwrap();
-                (page_id, value)
-            })
-            .collect();
+    /// Called when a page is about to leave the buffer pool, after any
+    /// updates to it have been written.
+    fn before_evict(&self, _page_id: u64) {}
+}
 
-        for (page_id, value) in pages {
-            pool.read_page(page_id, &mut aligned)?;
-            aligned.iter().for_each(|&byte| assert_eq!(byte, value));
+/// The kind of page held in a buffer pool frame, from the page type stored in
+/// the page.  Frames can be reserved for a class of pages with
+/// `BufferPool::reserve`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PageClass {
+    /// Hash table pages
+    Index,
+    /// Data pages
+    Heap,
+    /// Any other page
+    Other,
+}
+
+impl PageClass {
+    const INDEX_PAGE_TYPES: [PageType; 9] = [
+        PageType::SinglePageHashTable,
+        PageType::HashTableHeader,
+        PageType::ExtendibleHashDirectory,
+        PageType::LinearHashHeader,
+        PageType::SinglePageVarHashTable,
+        PageType::HashTableFixedWidthSlot,
+        PageType::ExtendibleHashBucket,
+        PageType::LinearHashBucket,
+        PageType::HashTableFilter,
+    ];
+
+    /// Classifies a page by its page type.
+    pub fn of(page: &[u8]) -> PageClass {
+        let page_type = page_type(page);
+        if page_type == Some(PageType::DataPage) {
+            PageClass::Heap
+        } else if Self::INDEX_PAGE_TYPES.iter().any(|&index| Some(index) == page_type) {
+            PageClass::Index
+        } else {
+            PageClass::Other
         }
-        Ok(())
+    }
+}
+
+/// Counters describing how well the buffer pool is working, from
+/// `BufferPool::stats`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BufferPoolStats {
+    /// Reads served from a frame
+    pub hits: u64,
+    /// Reads that had to go to storage
+    pub misses: u64,
+    /// Pages removed from the pool to make room for another
+    pub evictions: u64,
+    /// Pages read in ahead of use by `prefetch`
+    pub prefetched: u64,
+    /// Writes to storage, each of a run of pages with adjacent page ids
+    pub writes: u64,
+    /// Pages updated in the pool since they were last written to storage
+    pub dirty_pages: usize,
+    /// Pages currently held in the pool
+    pub resident_pages: usize,
+    pub policy: PolicyStats,
+}
+
+pub struct BufferPool<CM = ClockManager<u64>, S = PagedFile>
+where
+    CM: CacheManager<u64>,
+    S: StorageBackend,
+{
+    // map page IDs to their location in the buffer pool
+    page_table: HashMap<u64, usize>,
+
+    // manager to determine which frames to evict
+    manager: CM,
+
+    // cached pages.  Frames are allocated the first time they are used, so
+    // there may be fewer than the pool's capacity.
+    frames: Vec<Box<aligned::Buffer>>,
+
+    // frames that have been updated since they were last written to storage
+    dirty: Vec<bool>,
+
+    // the number of live PinnedPage guards for each frame
+    pins: Vec<Arc<AtomicUsize>>,
+
+    // frames holding no page, taken from the end
+    free_frames: Vec<usize>,
+
+    // the fraction of frames reserved for each class of pages
+    reservations: HashMap<PageClass, f64>,
+
+    hooks: Option<Arc<dyn BufferPoolHooks>>,
+
+    // whether page 0 is a master record, once that is known
+    master_record: Option<bool>,
+    checksum: Checksum,
+
+    // resident pages written since the storage was last synced, to be marked
+    // dirty again if a sync fails
+    unsynced: HashSet<u64>,
+
+    // the error from the sync that failed, if one has
+    poisoned: Option<String>,
+
+    // buffers to lend out, for reading pages into
+    spare_buffers: aligned::BufferAllocator,
+
+    // the storage the pages are read from and written to
+    storage: S,
+
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+    prefetched: u64,
+    writes: u64,
+}
+
+impl BufferPool {
+    /// An upper bound on the memory used to keep track of each frame, on top
+    /// of the page itself: its entries in the page table, the replacement
+    /// policy, the pin count and the dirty and free lists.
+    pub const FRAME_OVERHEAD: usize = 256;
+
+    /// The most buffers kept to lend out with `lend_buffer`, on top of the
+    /// pool's frames.
+    pub const SPARE_BUFFERS: usize = 8;
+
+    /// The number of frames that fit in `bytes` of memory, for creating a
+    /// pool with another replacement policy.
+    pub fn frames_for_budget(bytes: usize) -> usize {
+        (bytes / (PAGESIZE + BufferPool::FRAME_OVERHEAD)).max(1)
+    }
+}
+
+impl<S: StorageBackend> BufferPool<ClockManager<u64>, S> {
+    pub fn new(storage: S, size: usize) -> BufferPool<ClockManager<u64>, S> {
+        BufferPool::with_manager(storage, ClockManager::new(size))
+    }
+
+    /// Creates a buffer pool using at most `bytes` of memory, or one frame
+    /// if that is less.  `capacity` gives the number of pages it holds.
+    pub fn with_memory_budget(storage: S, bytes: usize) -> BufferPool<ClockManager<u64>, S> {
+        BufferPool::new(storage, BufferPool::frames_for_budget(bytes))
+    }
+}
+
+impl<S: StorageBackend> BufferPool<LruKManager<u64>, S> {
+    /// Creates a buffer pool that evicts pages with the LRU-K policy, which
+    /// holds on to pages that have been used at least `k` times over pages
+    /// read once by a scan.
+    pub fn with_lru_k(storage: S, size: usize, k: usize) -> BufferPool<LruKManager<u64>, S> {
+        BufferPool::with_manager(storage, LruKManager::new(size, k))
+    }
+}
+
+impl<S: StorageBackend> BufferPool<ArcManager<u64>, S> {
+    /// Creates a buffer pool that evicts pages with the adaptive replacement
+    /// cache policy, which balances recently and frequently used pages to
+    /// suit the workload.
+    pub fn with_arc(storage: S, size: usize) -> BufferPool<ArcManager<u64>, S> {
+        BufferPool::with_manager(storage, ArcManager::new(size))
+    }
+}
+
+impl<S: StorageBackend> BufferPool<TinyLfuManager<u64>, S> {
+    /// Creates a buffer pool that only lets a page displace another if it has
+    /// been used more often recently, using the W-TinyLFU policy.
+    pub fn with_tiny_lfu(storage: S, size: usize) -> BufferPool<TinyLfuManager<u64>, S> {
+        BufferPool::with_manager(storage, TinyLfuManager::new(size))
+    }
+}
+
+impl<CM, S> BufferPool<CM, S>
+where
+    CM: CacheManager<u64>,
+    S: StorageBackend,
+{
+    /// Creates a buffer pool that evicts pages with the given replacement
+    /// policy.  It holds as many pages as the policy has slots.
+    pub fn with_manager(storage: S, manager: CM) -> BufferPool<CM, S> {
+        let size = manager.size();
+        BufferPool {
+            page_table: HashMap::with_capacity(size),
+            manager,
+            frames: Vec::with_capacity(size),
+            dirty: vec![false; size],
+            pins: (0..size).map(|_| Arc::new(AtomicUsize::new(0))).collect(),
+            free_frames: (0..size).rev().collect(),
+            reservations: HashMap::new(),
+            hooks: None,
+            master_record: None,
+            checksum: Checksum::Crc32,
+            unsynced: HashSet::new(),
+            poisoned: None,
+            spare_buffers: aligned::BufferAllocator::new(BufferPool::SPARE_BUFFERS),
+            storage,
+            hits: 0,
+            misses: 0,
+            evictions: 0,
+            prefetched: 0,
+            writes: 0,
+        }
+    }
+
+    /// The number of pages the buffer pool can hold.
+    pub fn capacity(&self) -> usize {
+        self.pins.len()
+    }
+
+    /// The size of the pages in the pool's storage.  Each page is held in an
+    /// `aligned::Buffer`, and only this much of it is written back.
+    pub fn page_size(&self) -> usize {
+        self.storage.page_size()
+    }
+
+    /// The algorithm the database's pages are checksummed with, from its
+    /// master record.  Files without one use CRC32.
+    pub fn checksum(&mut self) -> std::io::Result<Checksum> {
+        self.has_master_record()?;
+        Ok(self.checksum)
+    }
+
+    /// Lends a buffer to read a page into, reusing one given back with
+    /// `return_buffer` where there is one.  Its contents are left over from
+    /// its last use.
+    pub fn lend_buffer(&mut self) -> Box<aligned::Buffer> {
+        self.spare_buffers.allocate()
+    }
+
+    /// Gives a buffer back, to be lent out again.
+    pub fn return_buffer(&mut self, buffer: Box<aligned::Buffer>) {
+        self.spare_buffers.release(buffer)
+    }
+
+    /// Sets the callbacks to make as pages are written and evicted, replacing
+    /// any set before.
+    pub fn set_hooks(&mut self, hooks: Arc<dyn BufferPoolHooks>) {
+        self.hooks = Some(hooks);
+    }
+
+    /// Reserves a fraction of the buffer pool's frames for a class of pages.
+    /// While no more pages of the class are resident than fit in its
+    /// reservation, they are only evicted to make room if every other frame
+    /// is pinned, so that, say, a scan over data pages can't evict every
+    /// index page.  A fraction of zero removes the reservation.
+    pub fn reserve(&mut self, class: PageClass, fraction: f64) {
+        if fraction > 0.0 {
+            self.reservations.insert(class, fraction);
+        } else {
+            self.reservations.remove(&class);
+        }
+    }
+
+    // The classes of pages that fit within their reservations.
+    fn protected_classes(&self) -> Vec<PageClass> {
+        (self.reservations.iter())
+            .filter(|&(&class, &fraction)| {
+                let resident = (self.page_table.values())
+                    .filter(|&&frame_idx| PageClass::of(&self.frames[frame_idx]) == class)
+                    .count();
+                resident as f64 <= fraction * self.capacity() as f64
+            })
+            .map(|(&class, _)| class)
+            .collect()
+    }
+
+    /// Changes the number of pages the buffer pool can hold.  Growing the
+    /// pool only allocates frames as they are needed.  Shrinking it writes
+    /// back and evicts the pages held in the frames removed, and fails,
+    /// leaving the pool as it was, if any of them are pinned.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip(self)))]
+    pub fn resize(&mut self, size: usize) -> std::io::Result<()> {
+        let capacity = self.capacity();
+        if size < capacity {
+            if self.pins[size..].iter().any(|pins| pins.load(Ordering::Acquire) > 0) {
+                return Err(io::Error::other("cannot evict a pinned page"));
+            }
+            let mut evicted: Vec<_> = (self.page_table.iter())
+                .filter(|&(_, &frame_idx)| frame_idx >= size)
+                .map(|(&page_id, _)| page_id)
+                .collect();
+            evicted.sort_unstable();
+            for page_id in evicted {
+                self.evict_page(page_id)?;
+            }
+            self.free_frames.retain(|&frame_idx| frame_idx < size);
+            self.frames.truncate(size);
+            self.dirty.truncate(size);
+            self.pins.truncate(size);
+        } else {
+            // New frames go to the bottom of the free list, so they are
+            // taken, and allocated, in order after the existing ones.
+            self.free_frames.splice(0..0, (capacity..size).rev());
+            self.dirty.resize(size, false);
+            self.pins.resize_with(size, || Arc::new(AtomicUsize::new(0)));
+        }
+        self.manager.resize(size);
+        Ok(())
+    }
+
+    pub fn stats(&self) -> BufferPoolStats {
+        BufferPoolStats {
+            hits: self.hits,
+            misses: self.misses,
+            evictions: self.evictions,
+            prefetched: self.prefetched,
+            writes: self.writes,
+            dirty_pages: self.dirty.iter().filter(|&&dirty| dirty).count(),
+            resident_pages: self.page_table.len(),
+            policy: self.manager.stats(),
+        }
+    }
+
+    /// Pins a page in the buffer pool, reading it in if it is not resident.
+    /// The page will not be evicted until the returned guard (and any other
+    /// guards for the same page) are dropped.
+    ///
+    /// While every frame is pinned, reading or appending a page that is not
+    /// resident fails.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip(self)))]
+    pub fn pin(&mut self, page_id: u64) -> io::Result<PinnedPage> {
+        if !self.page_table.contains_key(&page_id) {
+            let mut buf = aligned::Buffer::new();
+            self.read_page(page_id, &mut buf)?;
+        }
+        let frame_idx = self.page_table[&page_id];
+        self.pins[frame_idx].fetch_add(1, Ordering::AcqRel);
+        Ok(PinnedPage {
+            page_id,
+            pins: Arc::clone(&self.pins[frame_idx]),
+        })
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip(self, buf)))]
+    pub fn read_page(&mut self, page_id: u64, buf: &mut aligned::Buffer) -> std::io::Result<()> {
+        self.read_page_with_hint(page_id, buf, ReadHint::Normal)
+    }
+
+    /// Reads a page like `read_page`.  With `ReadHint::Sequential`, a hit
+    /// doesn't count as a use of the page, and a page read in on a miss is
+    /// the first candidate for eviction.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip(self, buf)))]
+    pub fn read_page_with_hint(
+        &mut self,
+        page_id: u64,
+        buf: &mut aligned::Buffer,
+        hint: ReadHint,
+    ) -> std::io::Result<()> {
+        let entry = self
+            .page_table
+            .get(&page_id)
+            .copied() // Release the borrow of self
+            .and_then(|frame_idx| {
+                if hint == ReadHint::Normal {
+                    self.manager.update(frame_idx);
+                }
+                self.frames.get_mut(frame_idx)
+            });
+
+        if let Some(val) = entry {
+            #[cfg(feature = "tracing")]
+            tracing::trace!("buffer pool hit");
+            buf.copy_from_slice(val.as_ref());
+            self.hits += 1;
+        } else {
+            self.misses += 1;
+            #[cfg(feature = "tracing")]
+            tracing::trace!("buffer pool miss");
+            let frame_idx = self.load_page(page_id)?;
+            buf.copy_from_slice(&self.frames[frame_idx]);
+            if hint == ReadHint::Sequential {
+                self.manager.demote(frame_idx);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns a view of a page straight out of its frame, reading it in if
+    /// it is not resident.  Unlike `read_page`, a hit doesn't copy the page.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip(self)))]
+    pub fn get_page(&mut self, page_id: u64) -> std::io::Result<PageRef<'_>> {
+        let frame_idx = match self.page_table.get(&page_id) {
+            Some(&frame_idx) => {
+                self.hits += 1;
+                #[cfg(feature = "tracing")]
+                tracing::trace!("buffer pool hit");
+                self.manager.update(frame_idx);
+                frame_idx
+            }
+            None => {
+                self.misses += 1;
+                #[cfg(feature = "tracing")]
+                tracing::trace!("buffer pool miss");
+                self.load_page(page_id)?
+            }
+        };
+        Ok(PageRef {
+            page_id,
+            data: &self.frames[frame_idx],
+        })
+    }
+
+    /// Reads the given pages into the buffer pool ahead of their use, so a
+    /// scan can stage the next pages while it works on the current one.
+    /// Pages already resident are left alone, and the rest are read in page
+    /// order.  No more pages are read than there are frames, taking them in
+    /// the order given.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip(self)))]
+    pub fn prefetch(&mut self, page_ids: &[u64]) -> std::io::Result<()> {
+        let mut seen = HashSet::new();
+        let mut missing: Vec<_> = (page_ids.iter().copied())
+            .filter(|page_id| !self.page_table.contains_key(page_id) && seen.insert(*page_id))
+            .take(self.capacity())
+            .collect();
+        missing.sort_unstable();
+
+        // Runs of adjacent pages are read together.
+        let mut start = 0;
+        while start < missing.len() {
+            let first = missing[start];
+            let count = (missing[start..].iter().zip(first..))
+                .take_while(|&(&page_id, expected)| page_id == expected)
+                .count();
+            self.load_run(first, count)?;
+            self.prefetched += count as u64;
+            start += count;
+        }
+        Ok(())
+    }
+
+    // Read `count` adjacent pages that are not resident into frames, with a
+    // single read from storage.
+    fn load_run(&mut self, first: u64, count: usize) -> std::io::Result<()> {
+        let mut frame_idxs = Vec::with_capacity(count);
+        let mut result = Ok(());
+        for page_id in first..first + count as u64 {
+            match self.frame_for(page_id) {
+                Ok(frame_idx) => {
+                    // Keep the frames of the run from being taken for the
+                    // rest of it.
+                    self.pins[frame_idx].fetch_add(1, Ordering::AcqRel);
+                    frame_idxs.push(frame_idx);
+                }
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                }
+            }
+        }
+        if result.is_ok() {
+            let mut frames: Vec<_> = self.frames.iter_mut().map(Some).collect();
+            let mut bufs: Vec<&mut aligned::Buffer> = (frame_idxs.iter())
+                .map(|&frame_idx| &mut **frames[frame_idx].take().expect("frames of a run are distinct"))
+                .collect();
+            result = self.storage.read_pages(first, &mut bufs);
+        }
+        for (page_id, &frame_idx) in (first..).zip(&frame_idxs) {
+            self.pins[frame_idx].fetch_sub(1, Ordering::AcqRel);
+            if result.is_err() {
+                // Don't leave the frames claiming to hold the pages.
+                self.page_table.remove(&page_id);
+                self.manager.remove(frame_idx);
+                self.free_frames.push(frame_idx);
+            }
+        }
+        result
+    }
+
+    /// Warms up the buffer pool with the given pages, such as those from
+    /// `cached_page_ids` when the pool was last in use.  Like `prefetch`, no
+    /// more pages are read than the pool holds.
+    pub fn preload(&mut self, page_ids: impl IntoIterator<Item = u64>) -> std::io::Result<()> {
+        let page_ids: Vec<_> = page_ids.into_iter().collect();
+        self.prefetch(&page_ids)
+    }
+
+    /// Returns the ids of the pages held in the buffer pool, in no particular
+    /// order.
+    pub fn cached_page_ids(&self) -> impl Iterator<Item = u64> + '_ {
+        self.page_table.keys().copied()
+    }
+
+    /// Records the ids of the pages held in the buffer pool in a file, to be
+    /// read back in with `load_state` after a restart.
+    pub fn save_state(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        let mut page_ids: Vec<_> = self.cached_page_ids().collect();
+        page_ids.sort_unstable();
+        save_page_ids(path.as_ref(), &page_ids)
+    }
+
+    /// Reads the pages recorded with `save_state` back into the buffer pool.
+    /// If there is no such file, there is nothing to do.
+    pub fn load_state(&mut self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        self.preload(load_page_ids(path.as_ref())?)
+    }
+
+    // Write a page and get back a page id.  A freed page is reused if there
+    // is one, and is only written to storage when it is evicted, like an
+    // updated page.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip(self, aligned_data)))]
+    pub fn append_page(&mut self, aligned_data: &aligned::Buffer) -> std::io::Result<u64> {
+        if let Some(page_id) = self.take_free_page()? {
+            self.update_page(page_id, aligned_data)?;
+            return Ok(page_id);
+        }
+        // TBD: Figure out how to manage page_ids of new pages written to the buffer pool
+        // without persisting to disk first. Decouple page_ids from disk order?  Track
+        // unwritten page_ids?
+        self.check_poisoned()?;
+        let page_id = self.storage.append_page(aligned_data).map_err(|err| self.storage_error(err))?;
+        self.add_to_buffer_pool(page_id, aligned_data)?;
+        self.unsynced.insert(page_id);
+        Ok(page_id)
+    }
+
+    /// Writes pages after the last one in storage, with one sync for all of
+    /// them, and returns their page ids.  Unlike `append_page`, this doesn't
+    /// reuse freed pages, so the page ids are always adjacent.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip(self, pages)))]
+    pub fn append_pages(&mut self, pages: &[&aligned::Buffer]) -> std::io::Result<Range<u64>> {
+        self.check_poisoned()?;
+        let bufs: Vec<&[u8]> = pages.iter().map(|page| &page[..]).collect();
+        let page_ids = self.storage.append_pages(&bufs).map_err(|err| self.storage_error(err))?;
+        for (page_id, page) in page_ids.clone().zip(pages) {
+            self.add_to_buffer_pool(page_id, page)?;
+            self.unsynced.insert(page_id);
+        }
+        Ok(page_ids)
+    }
+
+    // Update an existing page.  The page is only written to storage when it
+    // is evicted from the buffer pool.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip(self, data)))]
+    pub fn update_page(&mut self, page_id: u64, data: &aligned::Buffer) -> std::io::Result<()> {
+        let frame_idx = self.add_to_buffer_pool(page_id, data)?;
+        self.dirty[frame_idx] = true;
+        Ok(())
+    }
+
+    /// Writes a master record to page 0 of empty storage, so pages can be
+    /// freed.  Does nothing if there is one already, and fails if page 0 is
+    /// some other page.
+    pub fn create_master_record(&mut self) -> std::io::Result<()> {
+        self.create_master_record_with(Checksum::Crc32)
+    }
+
+    /// Like `create_master_record`, but the pages of the database are to be
+    /// checksummed with `checksum`.  If there is a master record already, the
+    /// algorithm it records is kept.
+    pub fn create_master_record_with(&mut self, checksum: Checksum) -> std::io::Result<()> {
+        if self.has_master_record()? {
+            return Ok(());
+        }
+        if self.master_record.is_some() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "page 0 is not a master record"));
+        }
+        let master = MasterRecord::new(self.storage.page_size(), checksum).into_aligned(Checksum::Crc32);
+        self.check_poisoned()?;
+        let page_id = self.storage.append_page(&master).map_err(|err| self.storage_error(err))?;
+        debug_assert_eq!(page_id, 0);
+        self.add_to_buffer_pool(page_id, &master)?;
+        self.unsynced.insert(page_id);
+        self.master_record = Some(true);
+        self.checksum = checksum;
+        Ok(())
+    }
+
+    /// Puts a page on the free list, for `append_page` to reuse.  The page
+    /// must not be used again until then.  Fails if there is no master
+    /// record, or if the page is the master record or is already free.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip(self)))]
+    pub fn free_page(&mut self, page_id: u64) -> std::io::Result<()> {
+        if !self.has_master_record()? {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "freeing pages needs a master record"));
+        }
+        if page_id == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "the master record can't be freed"));
+        }
+        if page_type(&self.get_page(page_id)?) == Some(PageType::FreePage) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "page is already free"));
+        }
+        let mut master = self.read_master_record()?;
+        self.update_page(page_id, &free_page(master.free_head(), self.checksum))?;
+        master.set_free_head(page_id);
+        self.write_master_record(master)
+    }
+
+    /// Gives the space held by free pages back to the filesystem.  Free pages
+    /// at the end of storage are truncated away, and if `punch_holes` is
+    /// set, holes are punched in the rest past their first block, which
+    /// keeps their place on the free list.  Does nothing if there is no
+    /// master record.
+    ///
+    /// The free list is left in page order, so pages nearer the start of
+    /// storage are reused first.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip(self)))]
+    pub fn shrink(&mut self, punch_holes: bool) -> std::io::Result<()> {
+        if !self.has_master_record()? {
+            return Ok(());
+        }
+        let mut free = self.free_page_ids()?;
+        free.sort_unstable();
+        let old_count = self.storage.page_count()?;
+        let mut page_count = old_count;
+        while free.last() == Some(&(page_count - 1)) {
+            free.pop();
+            page_count -= 1;
+        }
+        for page_id in page_count..old_count {
+            self.forget_page(page_id)?;
+        }
+
+        let nexts = free.iter().skip(1).copied().chain(Some(0));
+        for (&page_id, next) in free.iter().zip(nexts) {
+            self.update_page(page_id, &free_page(next, self.checksum))?;
+        }
+        let mut master = self.read_master_record()?;
+        master.set_free_head(free.first().copied().unwrap_or(0));
+        self.write_master_record(master)?;
+        self.flush_all()?;
+
+        self.storage.truncate(page_count).map_err(|err| self.storage_error(err))?;
+        if punch_holes {
+            for &page_id in &free {
+                self.storage.discard(page_id, FREE_PAGE_KEPT)?;
+            }
+        }
+        self.sync()
+    }
+
+    // The pages on the free list, in list order.
+    fn free_page_ids(&mut self) -> std::io::Result<Vec<u64>> {
+        let mut page_ids = Vec::new();
+        let mut seen = HashSet::new();
+        let mut page_id = self.read_master_record()?.free_head();
+        while page_id != 0 {
+            if !seen.insert(page_id) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "free list loops back on itself"));
+            }
+            let page = self.get_page(page_id)?;
+            if page_type(&page) != Some(PageType::FreePage) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "free list points at a page in use"));
+            }
+            let next = FREE_NEXT.get(&page);
+            page_ids.push(page_id);
+            page_id = next;
+        }
+        Ok(page_ids)
+    }
+
+    // Drop a page that is gone from storage, without writing it.
+    fn forget_page(&mut self, page_id: u64) -> std::io::Result<()> {
+        if let Some(&frame_idx) = self.page_table.get(&page_id) {
+            if self.pins[frame_idx].load(Ordering::Acquire) > 0 {
+                return Err(io::Error::other("cannot drop a pinned page"));
+            }
+            self.page_table.remove(&page_id);
+            self.unsynced.remove(&page_id);
+            self.manager.remove(frame_idx);
+            self.dirty[frame_idx] = false;
+            self.free_frames.push(frame_idx);
+        }
+        Ok(())
+    }
+
+    // Unlink the first page of the free list, if there is a master record
+    // and the list isn't empty.
+    fn take_free_page(&mut self) -> std::io::Result<Option<u64>> {
+        if !self.has_master_record()? {
+            return Ok(None);
+        }
+        let mut master = self.read_master_record()?;
+        let page_id = master.free_head();
+        if page_id == 0 {
+            return Ok(None);
+        }
+        let free = self.get_page(page_id)?;
+        if page_type(&free) != Some(PageType::FreePage) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "free list points at a page in use"));
+        }
+        master.set_free_head(FREE_NEXT.get(&free));
+        self.write_master_record(master)?;
+        Ok(Some(page_id))
+    }
+
+    // The master record is always checksummed with CRC32.
+    fn read_master_record(&mut self) -> std::io::Result<MasterRecord> {
+        let page = self.copy_page(0)?;
+        MasterRecord::from_aligned(page, Checksum::Crc32).map_err(bad_master_record)
+    }
+
+    fn write_master_record(&mut self, master: MasterRecord) -> std::io::Result<()> {
+        self.update_page(0, &master.into_aligned(Checksum::Crc32))
+    }
+
+    // Page 0 is checked once, straight from storage, so the pages the pool
+    // holds aren't disturbed.  Empty storage is checked again next time, as
+    // page 0 is yet to be written.  A master record for pages of another size
+    // than the storage's is an error.
+    fn has_master_record(&mut self) -> std::io::Result<bool> {
+        if let Some(master_record) = self.master_record {
+            return Ok(master_record);
+        }
+        let mut page = aligned::Buffer::new();
+        match self.storage.read_page(0, &mut page) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+            Err(err) => return Err(err),
+        }
+        let master_record = MasterRecord::is_master_record(&page);
+        if master_record {
+            let master = MasterRecord::from_aligned(page, Checksum::Crc32).map_err(bad_master_record)?;
+            if master.page_size() != self.storage.page_size() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("file has {} byte pages, not {}", master.page_size(), self.storage.page_size()),
+                ));
+            }
+            self.checksum = master.checksum().map_err(|algorithm| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown checksum algorithm {}", algorithm),
+                )
+            })?;
+        }
+        self.master_record = Some(master_record);
+        Ok(master_record)
+    }
+
+    fn copy_page(&mut self, page_id: u64) -> std::io::Result<Box<aligned::Buffer>> {
+        let mut buf = aligned::Buffer::new();
+        self.read_page(page_id, &mut buf)?;
+        Ok(buf)
+    }
+
+    fn add_to_buffer_pool(&mut self, page_id: u64, data: &[u8]) -> std::io::Result<usize> {
+        let frame_idx = self.frame_for(page_id)?;
+        self.frames[frame_idx].copy_from_slice(data);
+        Ok(frame_idx)
+    }
+
+    // Put a page read from storage without the pool's help into a frame, as
+    // `read_page_with_hint` would have on a miss.
+    fn add_read_page(&mut self, page_id: u64, page: &aligned::Buffer, hint: ReadHint) -> std::io::Result<()> {
+        self.misses += 1;
+        let frame_idx = self.add_to_buffer_pool(page_id, page)?;
+        if hint == ReadHint::Sequential {
+            self.manager.demote(frame_idx);
+        }
+        Ok(())
+    }
+
+    // Read a page that is not resident from storage straight into a frame.
+    fn load_page(&mut self, page_id: u64) -> std::io::Result<usize> {
+        let frame_idx = self.frame_for(page_id)?;
+        if let Err(err) = self.storage.read_page(page_id, &mut self.frames[frame_idx]) {
+            // Don't leave the frame claiming to hold the page.
+            self.page_table.remove(&page_id);
+            self.manager.remove(frame_idx);
+            self.free_frames.push(frame_idx);
+            return Err(err);
+        }
+        Ok(frame_idx)
+    }
+
+    // Find the frame for a page: its own if it is resident, or else a free
+    // one or one freed by evicting another page.  The frame's contents are
+    // left for the caller to fill.
+    fn frame_for(&mut self, page_id: u64) -> std::io::Result<usize> {
+        let frame_idx = self.page_table.get(&page_id);
+        let frame_idx = match frame_idx {
+            Some(&frame_idx) => {
+                self.manager.update(frame_idx);
+                frame_idx
+            }
+            None if !self.free_frames.is_empty() => {
+                // The replacement policy is only needed once the pool is full.
+                let idx = self.free_frames.pop().unwrap();
+                if idx == self.frames.len() {
+                    // Frames are aligned, so pages are read into and written
+                    // out of them directly with direct I/O.
+                    self.frames.push(aligned::Buffer::new());
+                }
+                self.manager.insert(idx, page_id);
+                self.page_table.insert(page_id, idx);
+                idx
+            }
+            None => {
+                let protected = self.protected_classes();
+                let pins = &self.pins;
+                let frames = &self.frames;
+                let pinned = |idx: usize| pins[idx].load(Ordering::Acquire) > 0;
+                let reserved = |idx: usize| {
+                    (frames.get(idx)).is_some_and(|frame| protected.contains(&PageClass::of(frame)))
+                };
+                // Reserved frames are still better than none at all.
+                let manager = &mut self.manager;
+                let (idx, evicted_page) = manager
+                    .sweep(page_id, &|idx| pinned(idx) || reserved(idx))
+                    .or_else(|| manager.sweep(page_id, &pinned))
+                    .ok_or_else(|| io::Error::other("every buffer pool frame is pinned"))?;
+
+                // If there is a page to evict, persist it if needed, and
+                // remove it now.
+                if let Some(page_id) = evicted_page {
+                    self.evictions += 1;
+                    #[cfg(feature = "tracing")]
+                    tracing::trace!(evicted = page_id, frame = idx, "evicting page");
+                    if self.dirty[idx] {
+                        if let Err(err) = self.write_run(page_id, idx) {
+                            // The sweep gave the frame to the new page, but it
+                            // still holds the victim, which stays resident.
+                            self.manager.remove(idx);
+                            self.manager.insert(idx, page_id);
+                            return Err(err);
+                        }
+                    }
+                    if let Some(hooks) = &self.hooks {
+                        hooks.before_evict(page_id);
+                    }
+                    self.page_table.remove(&page_id);
+                    self.unsynced.remove(&page_id);
+                }
+                self.page_table.insert(page_id, idx);
+                idx
+            }
+        };
+        Ok(frame_idx)
+    }
+
+    /// Removes a page from the buffer pool, writing it to storage first if
+    /// it is dirty, and frees its frame for the next page read in.  Fails if
+    /// the page is pinned.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip(self)))]
+    pub fn evict_page(&mut self, page_id: u64) -> std::io::Result<()> {
+        let frame_idx = match self.page_table.get(&page_id) {
+            Some(&frame_idx) => frame_idx,
+            None => return Ok(()),
+        };
+        if self.pins[frame_idx].load(Ordering::Acquire) > 0 {
+            return Err(io::Error::other("cannot evict a pinned page"));
+        }
+        if self.dirty[frame_idx] {
+            self.write_run(page_id, frame_idx)?;
+        }
+        if let Some(hooks) = &self.hooks {
+            hooks.before_evict(page_id);
+        }
+        self.page_table.remove(&page_id);
+        self.unsynced.remove(&page_id);
+        self.manager.remove(frame_idx);
+        self.free_frames.push(frame_idx);
+        self.evictions += 1;
+        Ok(())
+    }
+
+    /// Writes the page to storage if it has been updated in the buffer pool
+    /// since it was last written.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip(self)))]
+    pub fn flush_page(&mut self, page_id: u64) -> std::io::Result<()> {
+        match self.page_table.get(&page_id) {
+            Some(&frame_idx) if self.dirty[frame_idx] => {
+                self.write_frame(page_id, frame_idx)?;
+                self.sync()
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Writes every page that has been updated in the buffer pool to storage,
+    /// so that all updates so far are durable.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip(self)))]
+    pub fn flush_all(&mut self) -> std::io::Result<()> {
+        if self.write_dirty()? {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    // Write every dirty page, in runs of consecutive pages, without syncing.
+    // Returns whether there were any.
+    fn write_dirty(&mut self) -> std::io::Result<bool> {
+        let mut dirty: Vec<_> = self
+            .page_table
+            .iter()
+            .filter(|&(_, &frame_idx)| self.dirty[frame_idx])
+            .map(|(&page_id, &frame_idx)| (page_id, frame_idx))
+            .collect();
+        dirty.sort_unstable();
+        for &(page_id, frame_idx) in &dirty {
+            // It may have been written with the run of an earlier page.
+            if self.dirty[frame_idx] {
+                self.write_run(page_id, frame_idx)?;
+            }
+        }
+        Ok(!dirty.is_empty())
+    }
+
+    /// Returns true if a sync has failed.  The pool then refuses to write
+    /// anything more; see the `Poisoned` error.
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned.is_some()
+    }
+
+    // Sync the storage, poisoning the pool if that fails.
+    fn sync(&mut self) -> std::io::Result<()> {
+        self.check_poisoned()?;
+        let result = self.storage.sync();
+        self.synced(result.as_ref().map(|_| ()))
+    }
+
+    // Take note of the outcome of a sync of the storage, which may have been
+    // made through another handle to it.
+    fn synced(&mut self, result: Result<(), &io::Error>) -> std::io::Result<()> {
+        match result {
+            Ok(()) => {
+                self.unsynced.clear();
+                Ok(())
+            }
+            Err(err) => Err(self.poison(err)),
+        }
+    }
+
+    fn check_poisoned(&self) -> std::io::Result<()> {
+        match &self.poisoned {
+            Some(cause) => Err(Poisoned::error(cause)),
+            None => Ok(()),
+        }
+    }
+
+    // Storage that syncs as it writes may fail a write with a `Poisoned`
+    // error, which poisons the pool too.
+    fn storage_error(&mut self, err: io::Error) -> io::Error {
+        if Poisoned::of(&err).is_some() {
+            self.poison(&err)
+        } else {
+            err
+        }
+    }
+
+    // Mark the pages written since the last sync dirty again, and refuse
+    // further writes.
+    fn poison(&mut self, err: &io::Error) -> io::Error {
+        let cause = match Poisoned::of(err) {
+            Some(poisoned) => poisoned.cause.clone(),
+            None => err.to_string(),
+        };
+        #[cfg(feature = "tracing")]
+        tracing::error!(cause = %cause, "sync failed, poisoning the buffer pool");
+        for page_id in self.unsynced.drain() {
+            if let Some(&frame_idx) = self.page_table.get(&page_id) {
+                self.dirty[frame_idx] = true;
+            }
+        }
+        let err = Poisoned::error(&cause);
+        self.poisoned = Some(cause);
+        err
+    }
+
+    // The fraction of frames holding updates not yet written to storage.
+    fn dirty_ratio(&self) -> f64 {
+        self.dirty.iter().filter(|&&dirty| dirty).count() as f64 / self.capacity().max(1) as f64
+    }
+
+    // Write a frame back to storage, and mark it clean.
+    fn write_frame(&mut self, page_id: u64, frame_idx: usize) -> std::io::Result<()> {
+        self.write_frames(page_id, &[frame_idx])
+    }
+
+    // Write a frame back to storage, along with the frames of any dirty
+    // pages adjacent to it, and mark them clean.
+    fn write_run(&mut self, page_id: u64, frame_idx: usize) -> std::io::Result<()> {
+        let dirty_frame = |page_id: u64| {
+            (self.page_table.get(&page_id).copied()).filter(|&frame_idx| self.dirty[frame_idx])
+        };
+        let mut first = page_id;
+        while let Some(prev) = first.checked_sub(1).filter(|&prev| dirty_frame(prev).is_some()) {
+            first = prev;
+        }
+        let run: Vec<_> = (first..)
+            .map_while(|id| if id == page_id { Some(frame_idx) } else { dirty_frame(id) })
+            .collect();
+        self.write_frames(first, &run)
+    }
+
+    // Write the frames of consecutive pages, starting at `first`, in a single
+    // write, and mark them clean.
+    fn write_frames(&mut self, first: u64, run: &[usize]) -> std::io::Result<()> {
+        self.check_poisoned()?;
+        let frames = &self.frames;
+        let bufs: Vec<&[u8]> = run.iter().map(|&frame_idx| &frames[frame_idx][..]).collect();
+        if let Some(hooks) = &self.hooks {
+            for (page_id, buf) in (first..).zip(&bufs) {
+                hooks.before_flush(page_id, buf)?;
+            }
+        }
+        if let Err(err) = self.storage.write_pages(first, &bufs) {
+            return Err(self.storage_error(err));
+        }
+        for &frame_idx in run {
+            self.dirty[frame_idx] = false;
+        }
+        self.unsynced.extend(first..first + run.len() as u64);
+        if let Some(hooks) = &self.hooks {
+            (first..first + run.len() as u64).for_each(|page_id| hooks.after_flush(page_id));
+        }
+        self.writes += 1;
+        Ok(())
+    }
+}
+
+// Write page ids to a file, replacing it in one step so that a crash can't
+// leave it half written.
+fn save_page_ids(path: &Path, page_ids: &[u64]) -> io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut file = BufWriter::new(File::create(&tmp_path)?);
+    bincode::serialize_into(&mut file, page_ids).map_err(io::Error::other)?;
+    file.flush()?;
+    file.get_ref().sync_all()?;
+    fs::rename(tmp_path, path)
+}
+
+fn load_page_ids(path: &Path) -> io::Result<Vec<u64>> {
+    match File::open(path) {
+        Ok(file) => bincode::deserialize_from(BufReader::new(file)).map_err(io::Error::other),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(err) => Err(err),
+    }
+}
+
+const MAGIC: &[u8; 8] = b"potpot\0\0";
+const MASTER_MAGIC: std::ops::Range<usize> = 8..16;
+const MASTER_FREE_HEAD: Field<u64> = Field::at(0x10);
+const MASTER_PAGE_SIZE: Field<u32> = Field::at(0x18);
+const MASTER_CHECKSUM: Field<u32> = Field::at(0x1c);
+const FREE_NEXT: Field<u64> = Field::at(0x8);
+// The part of a free page kept when a hole is punched in the rest of it
+const FREE_PAGE_KEPT: usize = 4096;
+
+/// Page 0, when the pool keeps a free list.
+struct MasterRecord {
+    buffer: Box<aligned::Buffer>,
+}
+
+impl MasterRecord {
+    fn new(page_size: usize, checksum: Checksum) -> MasterRecord {
+        let mut buffer = aligned::Buffer::new();
+        buffer[MASTER_MAGIC].copy_from_slice(MAGIC);
+        MASTER_PAGE_SIZE.set(&mut buffer, page_size as u32);
+        MASTER_CHECKSUM.set(&mut buffer, checksum as u32);
+        MasterRecord { buffer }
+    }
+
+    /// Whether a page 0 was written as a master record, leaving whether it
+    /// is intact to `from_aligned`.
+    fn is_master_record(page: &[u8]) -> bool {
+        page_type(page) == Some(PageType::MasterRecord) && &page[MASTER_MAGIC] == MAGIC
+    }
+
+    fn free_head(&self) -> u64 {
+        MASTER_FREE_HEAD.get(&self.buffer)
+    }
+
+    fn set_free_head(&mut self, page_id: u64) {
+        MASTER_FREE_HEAD.set(&mut self.buffer, page_id)
+    }
+
+    fn page_size(&self) -> usize {
+        match MASTER_PAGE_SIZE.get(&self.buffer) {
+            0 => PAGESIZE,
+            page_size => page_size as usize,
+        }
+    }
+
+    /// The algorithm the other pages are checksummed with, or the unknown
+    /// value stored.
+    fn checksum(&self) -> Result<Checksum, u32> {
+        Checksum::try_from(MASTER_CHECKSUM.get(&self.buffer))
+    }
+}
+
+impl aligned::PageHeader for MasterRecord {
+    const PAGE_TYPE: PageType = PageType::MasterRecord;
+}
+
+impl aligned::IntoAligned for MasterRecord {
+    fn as_buffer(&self) -> &aligned::Buffer {
+        &self.buffer
+    }
+
+    fn into_buffer(self) -> Box<aligned::Buffer> {
+        self.buffer
+    }
+}
+
+impl aligned::FromAligned for MasterRecord {
+    fn extra_constraints(buffer: &aligned::Buffer) -> Result<(), aligned::Error> {
+        if MasterRecord::is_master_record(buffer) {
+            Ok(())
+        } else {
+            Err(aligned::Error::PageType)
+        }
+    }
+
+    fn transform(buffer: Box<aligned::Buffer>) -> MasterRecord {
+        MasterRecord { buffer }
+    }
+}
+
+fn bad_master_record(err: aligned::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("bad master record: {}", err))
+}
+
+fn free_page(next: u64, checksum: Checksum) -> Box<aligned::Buffer> {
+    let mut page = aligned::Buffer::new();
+    set_page_type(&mut page, PageType::FreePage);
+    FREE_NEXT.set(&mut page, next);
+    checksum.stamp(&mut page);
+    page
+}
+
+/// A page borrowed from its buffer pool frame, from `BufferPool::get_page`.
+/// Derefs to the page's bytes.
+pub struct PageRef<'a> {
+    page_id: u64,
+    data: &'a aligned::Buffer,
+}
+
+impl<'a> PageRef<'a> {
+    pub fn page_id(&self) -> u64 {
+        self.page_id
+    }
+}
+
+impl<'a> std::ops::Deref for PageRef<'a> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.data
+    }
+}
+
+/// A guard that keeps a page resident in the buffer pool, from
+/// `BufferPool::pin`.  The page is unpinned when the guard is dropped.
+#[must_use = "the page is unpinned as soon as the guard is dropped"]
+pub struct PinnedPage {
+    page_id: u64,
+    pins: Arc<AtomicUsize>,
+}
+
+impl PinnedPage {
+    pub fn page_id(&self) -> u64 {
+        self.page_id
+    }
+}
+
+impl Drop for PinnedPage {
+    fn drop(&mut self) {
+        self.pins.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl<CM, S> Drop for BufferPool<CM, S>
+where
+    CM: CacheManager<u64>,
+    S: StorageBackend,
+{
+    fn drop(&mut self) {
+        // There is no one left to report an error to.  Call `flush_all`
+        // first to find out whether the writes succeeded.
+        let _ = self.flush_all();
+    }
+}
+
+/// When the background flusher started by
+/// `ShardedBufferPool::start_flusher` writes dirty pages.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FlusherConfig {
+    /// How long the flusher waits between flushes.
+    pub interval: Duration,
+    /// The fraction of a shard's frames that may be dirty before the flusher
+    /// is woken early.
+    pub dirty_ratio: f64,
+}
+
+impl Default for FlusherConfig {
+    fn default() -> FlusherConfig {
+        FlusherConfig {
+            interval: Duration::from_secs(1),
+            dirty_ratio: 0.5,
+        }
+    }
+}
+
+#[derive(Default)]
+struct FlushSignal {
+    state: Mutex<FlushState>,
+    condvar: Condvar,
+}
+
+#[derive(Default)]
+struct FlushState {
+    // the dirty ratio that wakes a running flusher
+    dirty_ratio: Option<f64>,
+    wake: bool,
+    stop: bool,
+}
+
+impl FlushSignal {
+    fn lock(&self) -> MutexGuard<'_, FlushState> {
+        self.state.lock().expect("buffer pool flusher poisoned")
+    }
+
+    // Wake the flusher if a shard has become too dirty.
+    fn dirtied(&self, dirty_ratio: f64) {
+        let mut state = self.lock();
+        if state.dirty_ratio.is_some_and(|threshold| dirty_ratio > threshold) {
+            state.wake = true;
+            self.condvar.notify_one();
+        }
+    }
+}
+
+/// The handle of a background flusher.  The flusher is stopped when the
+/// handle is dropped.
+pub struct Flusher<CM = ClockManager<u64>, S = PagedFile>
+where
+    CM: CacheManager<u64>,
+    S: SharedStorage,
+{
+    pool: Arc<ShardedBufferPool<CM, S>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl<CM, S> Drop for Flusher<CM, S>
+where
+    CM: CacheManager<u64>,
+    S: SharedStorage,
+{
+    fn drop(&mut self) {
+        let signal = &self.pool.flush_signal;
+        let mut state = signal.lock();
+        state.stop = true;
+        state.dirty_ratio = None;
+        signal.condvar.notify_all();
+        drop(state);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// A buffer pool that can be shared between threads.  Pages are divided
+/// between shards by a hash of their page id.  Each shard is a `BufferPool`
+/// of its own behind a latch, so threads working on pages in different
+/// shards don't wait for each other.  `read_page` reads a page that isn't
+/// resident with its shard unlatched, so a miss doesn't hold up the rest of
+/// the shard.
+pub struct ShardedBufferPool<CM = ClockManager<u64>, S = PagedFile>
+where
+    CM: CacheManager<u64>,
+    S: SharedStorage,
+{
+    shards: Vec<Mutex<BufferPool<CM, S>>>,
+
+    // Appends go through this handle, so that concurrent appends get
+    // different page ids.
+    storage: Mutex<S>,
+
+    // Pages that aren't resident are read through these handles, without
+    // their shard latched.  A read takes one, or clones another if they are
+    // all in use, and puts it back afterwards.
+    readers: Mutex<Vec<S>>,
+
+    flush_signal: FlushSignal,
+}
+
+impl<S: SharedStorage> ShardedBufferPool<ClockManager<u64>, S> {
+    /// Creates a pool of `shard_count` shards, each with `size` frames.
+    pub fn new(storage: S, shard_count: usize, size: usize) -> io::Result<Self> {
+        ShardedBufferPool::with_manager(storage, shard_count, || ClockManager::new(size))
+    }
+
+    /// Creates a pool of `shard_count` shards sharing at most `bytes` of
+    /// memory between them, or one frame each if that is less.
+    pub fn with_memory_budget(storage: S, shard_count: usize, bytes: usize) -> io::Result<Self> {
+        let size = BufferPool::frames_for_budget(bytes / shard_count.max(1));
+        ShardedBufferPool::new(storage, shard_count, size)
+    }
+}
+
+impl<CM, S> ShardedBufferPool<CM, S>
+where
+    CM: CacheManager<u64> + Send + 'static,
+    S: SharedStorage + 'static,
+{
+    /// Starts a thread that writes dirty pages to storage, in runs of
+    /// consecutive pages held by the same shard, every `config.interval` or
+    /// as soon as a shard has more than `config.dirty_ratio` of its frames
+    /// dirty.  This leaves clean frames for evictions, so reads don't wait
+    /// for a write to make room.
+    ///
+    /// Only one flusher should run at a time.  Errors are not reported by
+    /// the flusher: the pages stay dirty, and `flush_all` will return the
+    /// error if it persists.
+    pub fn start_flusher(self: &Arc<Self>, config: FlusherConfig) -> io::Result<Flusher<CM, S>> {
+        let mut state = self.flush_signal.lock();
+        *state = FlushState {
+            dirty_ratio: Some(config.dirty_ratio),
+            ..FlushState::default()
+        };
+        drop(state);
+
+        let pool = Arc::clone(self);
+        let thread = std::thread::Builder::new()
+            .name("buffer-pool-flusher".into())
+            .spawn(move || loop {
+                let signal = &pool.flush_signal;
+                let mut state = signal.lock();
+                if !state.wake && !state.stop {
+                    state = signal
+                        .condvar
+                        .wait_timeout(state, config.interval)
+                        .expect("buffer pool flusher poisoned")
+                        .0;
+                }
+                if state.stop {
+                    break;
+                }
+                state.wake = false;
+                drop(state);
+                let _ = pool.flush_shards();
+            })?;
+        Ok(Flusher {
+            pool: Arc::clone(self),
+            thread: Some(thread),
+        })
+    }
+}
+
+impl<CM, S> ShardedBufferPool<CM, S>
+where
+    CM: CacheManager<u64>,
+    S: SharedStorage,
+{
+    /// Creates a pool of `shard_count` shards, each evicting pages with a
+    /// replacement policy from `make_manager`.
+    pub fn with_manager(
+        storage: S,
+        shard_count: usize,
+        mut make_manager: impl FnMut() -> CM,
+    ) -> io::Result<ShardedBufferPool<CM, S>> {
+        let shards = (0..shard_count)
+            .map(|_| Ok(Mutex::new(BufferPool::with_manager(storage.try_clone()?, make_manager()))))
+            .collect::<io::Result<_>>()?;
+        Ok(ShardedBufferPool {
+            shards,
+            readers: Mutex::new(vec![storage.try_clone()?]),
+            storage: Mutex::new(storage),
+            flush_signal: FlushSignal::default(),
+        })
+    }
+
+    // The index of the shard holding the given page.
+    fn shard_index(&self, page_id: u64) -> usize {
+        let mut hasher = DefaultHasher::new();
+        page_id.hash(&mut hasher);
+        hasher.finish() as usize % self.shards.len()
+    }
+
+    // Latch the shard holding the given page.
+    fn shard(&self, page_id: u64) -> MutexGuard<'_, BufferPool<CM, S>> {
+        self.shards[self.shard_index(page_id)].lock().expect("buffer pool shard poisoned")
+    }
+
+    // Read a page straight from storage, through a reader of its own.
+    fn read_unlatched(&self, page_id: u64, buf: &mut aligned::Buffer) -> io::Result<()> {
+        let reader = self.readers.lock().expect("buffer pool readers poisoned").pop();
+        let mut reader = match reader {
+            Some(reader) => reader,
+            None => self.storage.lock().expect("buffer pool storage poisoned").try_clone()?,
+        };
+        let result = reader.read_page(page_id, buf);
+        self.readers.lock().expect("buffer pool readers poisoned").push(reader);
+        result
+    }
+
+    pub fn read_page(&self, page_id: u64, buf: &mut aligned::Buffer) -> io::Result<()> {
+        self.read_page_with_hint(page_id, buf, ReadHint::Normal)
+    }
+
+    pub fn read_page_with_hint(&self, page_id: u64, buf: &mut aligned::Buffer, hint: ReadHint) -> io::Result<()> {
+        let writes = {
+            let mut shard = self.shard(page_id);
+            if shard.page_table.contains_key(&page_id) {
+                return shard.read_page_with_hint(page_id, buf, hint);
+            }
+            shard.writes
+        };
+        self.read_unlatched(page_id, buf)?;
+        let mut shard = self.shard(page_id);
+        if shard.page_table.contains_key(&page_id) || shard.writes != writes {
+            // Another thread read it in meanwhile, or may have written a
+            // newer copy back and evicted it, so this copy can't be trusted.
+            return shard.read_page_with_hint(page_id, buf, hint);
+        }
+        shard.add_read_page(page_id, buf, hint)
+    }
+
+    /// Calls `f` with the page straight out of its frame, reading it in if it
+    /// is not resident.  The page's shard stays latched until `f` returns.
+    pub fn with_page<R>(&self, page_id: u64, f: impl FnOnce(&[u8]) -> R) -> io::Result<R> {
+        let mut shard = self.shard(page_id);
+        let page = shard.get_page(page_id)?;
+        Ok(f(&page))
+    }
+
+    /// Pins a page in its shard.  See `BufferPool::pin`.
+    pub fn pin(&self, page_id: u64) -> io::Result<PinnedPage> {
+        self.shard(page_id).pin(page_id)
+    }
+
+    pub fn append_page(&self, aligned_data: &aligned::Buffer) -> io::Result<u64> {
+        let page_id = self
+            .storage
+            .lock()
+            .expect("buffer pool storage poisoned")
+            .append_page(aligned_data)?;
+        self.shard(page_id).add_to_buffer_pool(page_id, aligned_data)?;
+        Ok(page_id)
+    }
+
+    /// Appends pages with one sync for all of them.  See
+    /// `BufferPool::append_pages`.
+    pub fn append_pages(&self, pages: &[&aligned::Buffer]) -> io::Result<Range<u64>> {
+        let bufs: Vec<&[u8]> = pages.iter().map(|page| &page[..]).collect();
+        let page_ids = self
+            .storage
+            .lock()
+            .expect("buffer pool storage poisoned")
+            .append_pages(&bufs)?;
+        for (page_id, page) in page_ids.clone().zip(pages) {
+            self.shard(page_id).add_to_buffer_pool(page_id, page)?;
+        }
+        Ok(page_ids)
+    }
+
+    pub fn update_page(&self, page_id: u64, data: &aligned::Buffer) -> io::Result<()> {
+        let dirty_ratio = {
+            let mut shard = self.shard(page_id);
+            shard.update_page(page_id, data)?;
+            shard.dirty_ratio()
+        };
+        self.flush_signal.dirtied(dirty_ratio);
+        Ok(())
+    }
+
+    pub fn flush_page(&self, page_id: u64) -> io::Result<()> {
+        self.shard(page_id).flush_page(page_id)
+    }
+
+    pub fn evict_page(&self, page_id: u64) -> io::Result<()> {
+        self.shard(page_id).evict_page(page_id)
+    }
+
+    /// Warms up the shards with the given pages.  See `BufferPool::preload`.
+    pub fn preload(&self, page_ids: impl IntoIterator<Item = u64>) -> io::Result<()> {
+        let page_ids: Vec<_> = page_ids.into_iter().collect();
+        self.prefetch(&page_ids)
+    }
+
+    /// Returns the ids of the pages held in every shard, in page order.
+    pub fn cached_page_ids(&self) -> Vec<u64> {
+        let mut page_ids = Vec::new();
+        for shard in &self.shards {
+            page_ids.extend(shard.lock().expect("buffer pool shard poisoned").cached_page_ids());
+        }
+        page_ids.sort_unstable();
+        page_ids
+    }
+
+    /// Sets the callbacks every shard makes as pages are written and evicted.
+    pub fn set_hooks(&self, hooks: Arc<dyn BufferPoolHooks>) {
+        for shard in &self.shards {
+            shard.lock().expect("buffer pool shard poisoned").set_hooks(Arc::clone(&hooks));
+        }
+    }
+
+    /// Reserves a fraction of each shard's frames for a class of pages.  See
+    /// `BufferPool::reserve`.
+    pub fn reserve(&self, class: PageClass, fraction: f64) {
+        for shard in &self.shards {
+            shard.lock().expect("buffer pool shard poisoned").reserve(class, fraction);
+        }
+    }
+
+    /// Records the ids of the pages held in every shard in a file.  See
+    /// `BufferPool::save_state`.
+    pub fn save_state(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        save_page_ids(path.as_ref(), &self.cached_page_ids())
+    }
+
+    /// Reads the pages recorded with `save_state` back into their shards.
+    pub fn load_state(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        self.preload(load_page_ids(path.as_ref())?)
+    }
+
+    /// Changes the number of frames in each shard.  See `BufferPool::resize`.
+    pub fn resize(&self, size: usize) -> io::Result<()> {
+        for shard in &self.shards {
+            shard.lock().expect("buffer pool shard poisoned").resize(size)?;
+        }
+        Ok(())
+    }
+
+    /// Reads the given pages into their shards ahead of their use.  Each
+    /// shard is handed its own pages in page order, so it can read runs of
+    /// them together.  See `BufferPool::prefetch`.
+    pub fn prefetch(&self, page_ids: &[u64]) -> io::Result<()> {
+        let mut by_shard = vec![Vec::new(); self.shards.len()];
+        for &page_id in page_ids {
+            by_shard[self.shard_index(page_id)].push(page_id);
+        }
+        for (shard, mut page_ids) in self.shards.iter().zip(by_shard) {
+            if page_ids.is_empty() {
+                continue;
+            }
+            page_ids.sort_unstable();
+            page_ids.dedup();
+            shard.lock().expect("buffer pool shard poisoned").prefetch(&page_ids)?;
+        }
+        Ok(())
+    }
+
+    pub fn flush_all(&self) -> io::Result<()> {
+        for shard in &self.shards {
+            shard.lock().expect("buffer pool shard poisoned").flush_all()?;
+        }
+        Ok(())
+    }
+
+    /// Writes every dirty page to storage, latching one shard at a time, and
+    /// then syncs once for all of them.
+    fn flush_shards(&self) -> io::Result<()> {
+        let mut written = Vec::new();
+        for shard in &self.shards {
+            if shard.lock().expect("buffer pool shard poisoned").write_dirty()? {
+                written.push(shard);
+            }
+        }
+        if written.is_empty() {
+            return Ok(());
+        }
+        let result = self.storage.lock().expect("buffer pool storage poisoned").sync();
+        // Every shard that wrote pages is poisoned if the sync failed.
+        for shard in written {
+            shard.lock().expect("buffer pool shard poisoned").synced(result.as_ref().map(|_| ()))?;
+        }
+        Ok(())
+    }
+
+    /// Returns the statistics of each shard.
+    pub fn stats(&self) -> Vec<BufferPoolStats> {
+        (self.shards.iter())
+            .map(|shard| shard.lock().expect("buffer pool shard poisoned").stats())
+            .collect()
+    }
+}
+
+/// A `ShardedBufferPool` for use from async code.  Pages are read and
+/// written on tokio's blocking thread pool, so a miss doesn't hold up the
+/// executor.  Clones share the pool.
+#[cfg(feature = "tokio")]
+pub struct AsyncBufferPool<CM = ClockManager<u64>, S = PagedFile>
+where
+    CM: CacheManager<u64>,
+    S: SharedStorage,
+{
+    pool: Arc<ShardedBufferPool<CM, S>>,
+}
+
+#[cfg(feature = "tokio")]
+impl<CM, S> Clone for AsyncBufferPool<CM, S>
+where
+    CM: CacheManager<u64>,
+    S: SharedStorage,
+{
+    fn clone(&self) -> Self {
+        AsyncBufferPool {
+            pool: Arc::clone(&self.pool),
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<CM, S> AsyncBufferPool<CM, S>
+where
+    CM: CacheManager<u64> + Send + 'static,
+    S: SharedStorage + 'static,
+{
+    pub fn new(pool: Arc<ShardedBufferPool<CM, S>>) -> AsyncBufferPool<CM, S> {
+        AsyncBufferPool { pool }
+    }
+
+    /// The pool, for the rest of the synchronous API.
+    pub fn pool(&self) -> &Arc<ShardedBufferPool<CM, S>> {
+        &self.pool
+    }
+
+    /// Reads a page into a new buffer.
+    pub async fn read_page(&self, page_id: u64) -> io::Result<Box<aligned::Buffer>> {
+        let pool = Arc::clone(&self.pool);
+        crate::storage::blocking(move || {
+            let mut buf = aligned::Buffer::new();
+            pool.read_page(page_id, &mut buf)?;
+            Ok(buf)
+        })
+        .await
+    }
+
+    /// Updates a page.  As with `BufferPool::update_page`, it is only
+    /// written to storage when it is flushed or evicted.
+    pub async fn write_page(&self, page_id: u64, buf: Box<aligned::Buffer>) -> io::Result<()> {
+        let pool = Arc::clone(&self.pool);
+        crate::storage::blocking(move || pool.update_page(page_id, &buf)).await
+    }
+
+    pub async fn append_page(&self, buf: Box<aligned::Buffer>) -> io::Result<u64> {
+        let pool = Arc::clone(&self.pool);
+        crate::storage::blocking(move || pool.append_page(&buf)).await
+    }
+
+    pub async fn flush_all(&self) -> io::Result<()> {
+        let pool = Arc::clone(&self.pool);
+        crate::storage::blocking(move || pool.flush_all()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        aligned,
+        storage::{self, FaultyStorage, Faults, MemStorage, PageSize, PagedFile},
+        testutils::create_test_path,
+    };
+    use std::fmt;
+
+    struct CMDebug<'a, T>(&'a ClockManager<T>);
+
+    impl<'a, T: fmt::Debug> fmt::Debug for CMDebug<'a, T> {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.debug_struct("ClockManager")
+                .field("idx", &self.0.idx)
+                .field("clock", &self.0.clock)
+                .field("entries", &self.0.entries)
+                .finish()
+        }
+    }
+
+    #[test]
+    fn clock_manager() {
+        let mut cm = ClockManager::new(4);
+
+        // Fill the buffer pool
+        for (i, val) in (100..104).enumerate() {
+            let (idx, replaced) = dbg!(cm.sweep(val, &|_| false)).unwrap();
+            assert_eq!(idx, i);
+            assert!(replaced.is_none());
+            dbg!(CMDebug(&cm));
+        }
+
+        // Now we evict the first entry
+        let result = cm.sweep(104, &|_| false);
+        assert_eq!(result, Some((0, Some(100))));
+
+        // Some entries get accessed
+        cm.update(1);
+        cm.update(2);
+        dbg!(CMDebug(&cm));
+
+        // The next one after those gets evicted
+        let result = cm.sweep(105, &|_| false);
+        assert_eq!(result, Some((3, Some(103))));
+        dbg!(CMDebug(&cm));
+
+        // Final state of the ClockManager
+        assert_eq!(cm.entries, &[Some(104), Some(101), Some(102), Some(105)]);
+
+        // Pinned entries are skipped, even when their reference bit is clear
+        let result = cm.sweep(106, &|idx| idx == 0 || idx == 1);
+        assert_eq!(result, Some((2, Some(102))));
+        assert_eq!(cm.sweep(107, &|_| true), None);
+    }
+
+    #[test]
+    fn lru_k_manager() {
+        let mut cm = LruKManager::new(3, 2);
+        let not_pinned = |_| false;
+
+        // Empty slots are used first
+        for (i, val) in (100..103).enumerate() {
+            assert_eq!(cm.sweep(val, &not_pinned), Some((i, None)));
+        }
+
+        // 100 and 102 are used a second time, so 101, used once, goes first
+        cm.update(0);
+        cm.update(2);
+        assert_eq!(cm.sweep(103, &not_pinned), Some((1, Some(101))));
+
+        // 103 has been used once, but 100 and 102 twice, so 103 goes again
+        assert_eq!(cm.sweep(104, &not_pinned), Some((1, Some(103))));
+
+        // Among entries used k times, the one with the oldest second most
+        // recent access goes, even if it was used most recently
+        cm.update(1);
+        cm.update(0);
+        assert_eq!(cm.sweep(105, &not_pinned), Some((2, Some(102))));
+
+        // Pinned entries are skipped
+        cm.update(2);
+        assert_eq!(cm.sweep(106, &|idx| idx == 1), Some((0, Some(100))));
+        assert_eq!(cm.sweep(107, &|_| true), None);
+    }
+
+    #[test]
+    fn arc_manager() {
+        let mut cm = ArcManager::new(3);
+        let not_pinned = |_| false;
+
+        // Empty slots are used first
+        for (i, val) in (100..103).enumerate() {
+            assert_eq!(cm.sweep(val, &not_pinned), Some((i, None)));
+        }
+
+        // 100 moves to T2, so the least recent entry of T1 goes first
+        cm.update(0);
+        assert_eq!(cm.sweep(103, &not_pinned), Some((1, Some(101))));
+        assert_eq!(cm.b1, &[101]);
+
+        // Missing on 101 again grows the target size of T1, and brings it
+        // back into T2, but T1 is still over its target
+        assert_eq!(cm.sweep(101, &not_pinned), Some((2, Some(102))));
+        assert_eq!(cm.p, 1);
+        assert_eq!(cm.t2, &[0, 2]);
+
+        // T1 is now at its target size, so T2 gives up an entry, skipping
+        // pinned ones
+        assert_eq!(cm.sweep(104, &|idx| idx == 0), Some((2, Some(101))));
+        assert_eq!(cm.b2, &[101]);
+        assert_eq!(cm.sweep(105, &|_| true), None);
+    }
+
+    #[test]
+    fn tiny_lfu_manager() {
+        let mut cm = TinyLfuManager::new(3);
+        let not_pinned = |_| false;
+
+        // Empty slots are used first, and entries pass through the one slot
+        // window into the main region
+        for (i, val) in (100..103).enumerate() {
+            assert_eq!(cm.sweep(val, &not_pinned), Some((i, None)));
+        }
+        assert_eq!((&cm.window, &cm.main), (&[2].into(), &[0, 1].into()));
+
+        // 100 is used often, so 102, leaving the window, is only admitted in
+        // place of 101
+        for _ in 0..3 {
+            cm.update(0);
+        }
+        cm.update(2);
+        assert_eq!(cm.sweep(103, &not_pinned), Some((1, Some(101))));
+        assert_eq!((&cm.window, &cm.main), (&[1].into(), &[0, 2].into()));
+
+        // 103 has been seen once, so it is the one to go
+        assert_eq!(cm.sweep(104, &not_pinned), Some((1, Some(103))));
+
+        // Pinned entries are skipped
+        assert_eq!(cm.sweep(105, &|idx| idx == 1), Some((0, Some(100))));
+        assert_eq!(cm.sweep(106, &|_| true), None);
+    }
+
+    #[test]
+    fn frequency_sketch() {
+        let mut sketch = FrequencySketch::new(8);
+        for _ in 0..5 {
+            sketch.increment(&1u64);
+        }
+        sketch.increment(&2u64);
+        assert!(sketch.frequency(&1u64) >= 5);
+        assert!(sketch.frequency(&2u64) >= 1);
+        assert!(sketch.frequency(&1u64) > sketch.frequency(&2u64));
+
+        // Counters saturate, and are halved once enough have been added
+        for _ in 0..80 {
+            sketch.increment(&3u64);
+        }
+        assert!(sketch.frequency(&3u64) <= FrequencySketch::MAX_COUNT);
+        assert!(sketch.frequency(&1u64) < 5);
+    }
+
+    #[test]
+    fn demote() {
+        fn check(mut cm: impl CacheManager<u64>) {
+            for val in 100..103 {
+                cm.sweep(val, &|_| false);
+            }
+            cm.demote(2);
+            assert_eq!(cm.sweep(103, &|_| false), Some((2, Some(102))));
+        }
+        check(ClockManager::new(3));
+        check(LruKManager::new(3, 2));
+        check(ArcManager::new(3));
+        check(TinyLfuManager::new(3));
+    }
+
+    #[test]
+    fn insert_and_remove() {
+        fn check(mut cm: impl CacheManager<u64>) {
+            for (idx, val) in (100..103).enumerate() {
+                cm.insert(idx, val);
+            }
+            assert_eq!(cm.remove(1), Some(101));
+            assert_eq!(cm.remove(1), None);
+            // The emptied slot is used before anything is evicted
+            assert_eq!(cm.sweep(103, &|_| false), Some((1, None)));
+        }
+        check(ClockManager::new(3));
+        check(LruKManager::new(3, 2));
+        check(ArcManager::new(3));
+        check(TinyLfuManager::new(3));
+    }
+
+    #[test]
+    fn resize_manager() {
+        fn check(mut cm: impl CacheManager<u64>) {
+            for val in 100..103 {
+                cm.sweep(val, &|_| false);
+            }
+            cm.remove(2);
+            cm.resize(2);
+            assert!(matches!(cm.sweep(103, &|_| false), Some((0 | 1, Some(100 | 101)))));
+            cm.resize(3);
+            cm.insert(2, 104);
+            assert_eq!(cm.remove(2), Some(104));
+        }
+        check(ClockManager::new(3));
+        check(LruKManager::new(3, 2));
+        check(ArcManager::new(3));
+        check(TinyLfuManager::new(3));
+    }
+
+    #[test]
+    fn append_and_update_pages() -> anyhow::Result<()> {
+        let path = create_test_path("test-potpotdb::buffer::append_pages.data");
+        let storage = PagedFile::from_path(&path)?;
+        let mut pool = BufferPool::new(storage, 3);
+
+        let aligned = aligned::Buffer::with_value(0xff);
+
+        for expected_page in [0, 1, 2, 3, 4].iter() {
+            let page_id = pool.append_page(&aligned)?;
+            dbg!(page_id);
+            assert_eq!(page_id, *expected_page);
+
+            // Verify that the page is written into the buffer pool
+            //assert!(pool.page_table.contains_key(&page_id));
+        }
+
+        let mut read_aligned = aligned::Buffer::new();
+
+        for page_id in [0, 1, 2, 3, 4].iter() {
+            // Assert that reading a page fills the buffer with the appropriate data
+            pool.read_page(*page_id, &mut read_aligned)?;
+            read_aligned.iter().for_each(|&byte| assert_eq!(byte, 255));
+
+            // Reset to zeros.
+            read_aligned.iter_mut().for_each(|loc| *loc = 0)
+        }
+
+        // Assert that trying to read a non-existent page results in an error
+        pool.read_page(5, &mut read_aligned)
+            .expect_err("reading a nonexistent page should error");
+
+        // Try updating a page that is in the buffer pool, and a page that is not in the buffer pool:
+        // Verify that the data can be read from the page, and that the page is still in the buffer pool.
+
+        let aligned = aligned::Buffer::with_value(0x80);
+
+        let in_pool = 4;
+        assert!(pool.page_table.contains_key(&in_pool));
+
+        let not_in_pool = 0;
+        assert!(!pool.page_table.contains_key(&not_in_pool));
+
+        // Test in_pool first, because testing not_in_pool could evict in_pool
+        for &page_id in &[in_pool, not_in_pool] {
+            pool.update_page(page_id, &aligned)?;
+            assert!(pool.page_table.contains_key(&page_id));
+
+            pool.read_page(page_id, &mut read_aligned)?;
+            read_aligned.iter().for_each(|byte| assert_eq!(*byte, 128));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn buffer_pool() -> anyhow::Result<()> {
+        let path = create_test_path("test-potpotdb::buffer::buffer_pool.data");
+        let storage = PagedFile::from_path(&path)?;
+        let mut pool = BufferPool::new(storage, 3);
+        let mut aligned = aligned::Buffer::new();
+
+        let pages: Vec<_> = [101, 102, 103, 104]
+            .iter()
+            // Insert enough pages to exceed the capacity of the buffer pool.
+            // Pages should get written to disk / evicted from buffer pool.
+            .map(|&value| {
+                aligned[..].iter_mut().for_each(|loc| *loc = value);
+                let page_id = pool.append_page(&aligned).unwrap();
+                (page_id, value)
+            })
+            .collect();
+
+        for (page_id, value) in pages {
+            pool.read_page(page_id, &mut aligned)?;
+            aligned.iter().for_each(|&byte| assert_eq!(byte, value));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn write_back() -> anyhow::Result<()> {
+        let path = create_test_path("test-potpotdb::buffer::write_back.data");
+        let mut pool = BufferPool::new(PagedFile::from_path(&path)?, 2);
+        let disk = PagedFile::from_path(&path)?;
+        let mut read_aligned = aligned::Buffer::new();
+
+        let first = pool.append_page(&aligned::Buffer::with_value(1))?;
+        let second = pool.append_page(&aligned::Buffer::with_value(2))?;
+
+        // Updates stay in the pool...
+        for value in 10..20 {
+            pool.update_page(first, &aligned::Buffer::with_value(value))?;
+        }
+        disk.read_page(first, &mut read_aligned)?;
+        assert!(read_aligned.iter().all(|&byte| byte == 1));
+        pool.read_page(first, &mut read_aligned)?;
+        assert!(read_aligned.iter().all(|&byte| byte == 19));
+
+        // ...until the page is evicted.
+        pool.append_page(&aligned::Buffer::with_value(3))?;
+        pool.append_page(&aligned::Buffer::with_value(4))?;
+        disk.read_page(first, &mut read_aligned)?;
+        assert!(read_aligned.iter().all(|&byte| byte == 19));
+
+        // Dropping the pool writes back what is still dirty.
+        pool.update_page(second, &aligned::Buffer::with_value(20))?;
+        disk.read_page(second, &mut read_aligned)?;
+        assert!(read_aligned.iter().all(|&byte| byte == 2));
+        drop(pool);
+        disk.read_page(second, &mut read_aligned)?;
+        assert!(read_aligned.iter().all(|&byte| byte == 20));
+        Ok(())
+    }
+
+    #[test]
+    fn flush() -> anyhow::Result<()> {
+        let path = create_test_path("test-potpotdb::buffer::flush.data");
+        let mut pool = BufferPool::new(PagedFile::from_path(&path)?, 4);
+        let disk = PagedFile::from_path(&path)?;
+        let mut read_aligned = aligned::Buffer::new();
+
+        let pages: Vec<_> = (0..3)
+            .map(|value| pool.append_page(&aligned::Buffer::with_value(value)))
+            .collect::<Result<_, _>>()?;
+        for &page_id in &pages {
+            pool.update_page(page_id, &aligned::Buffer::with_value(0x80 + page_id as u8))?;
+        }
+
+        pool.flush_page(pages[0])?;
+        disk.read_page(pages[0], &mut read_aligned)?;
+        assert!(read_aligned.iter().all(|&byte| byte == 0x80));
+        disk.read_page(pages[1], &mut read_aligned)?;
+        assert!(read_aligned.iter().all(|&byte| byte == 1));
+
+        // Flushing clean or non-resident pages does nothing.
+        pool.flush_page(pages[0])?;
+        pool.flush_page(100)?;
+
+        pool.flush_all()?;
+        for &page_id in &pages {
+            disk.read_page(page_id, &mut read_aligned)?;
+            assert!(read_aligned.iter().all(|&byte| byte == 0x80 + page_id as u8));
+        }
+        assert!(pool.dirty.iter().all(|&dirty| !dirty));
+        Ok(())
+    }
+
+    #[test]
+    fn pin() -> anyhow::Result<()> {
+        let path = create_test_path("test-potpotdb::buffer::pin.data");
+        let mut pool = BufferPool::new(PagedFile::from_path(&path)?, 2);
+        let mut read_aligned = aligned::Buffer::new();
+
+        let pages: Vec<_> = (0..4)
+            .map(|value| pool.append_page(&aligned::Buffer::with_value(value)))
+            .collect::<Result<_, _>>()?;
+
+        // Pinning reads the page in, and keeps it there however many other
+        // pages pass through the pool.
+        let pinned = pool.pin(pages[0])?;
+        let also_pinned = pool.pin(pages[0])?;
+        assert_eq!(pinned.page_id(), pages[0]);
+        for &page_id in pages.iter().skip(1).cycle().take(9) {
+            pool.read_page(page_id, &mut read_aligned)?;
+            assert!(pool.page_table.contains_key(&pages[0]));
+        }
+
+        // With every frame pinned, nothing else can be read in.
+        let other = pool.pin(pages[1])?;
+        pool.read_page(pages[2], &mut read_aligned)
+            .expect_err("every frame is pinned");
+        drop(other);
+        pool.read_page(pages[2], &mut read_aligned)?;
+
+        // The page stays pinned until the last guard is gone.
+        drop(pinned);
+        pool.read_page(pages[3], &mut read_aligned)?;
+        assert!(pool.page_table.contains_key(&pages[0]));
+        drop(also_pinned);
+        pool.read_page(pages[1], &mut read_aligned)?;
+        pool.read_page(pages[2], &mut read_aligned)?;
+        assert!(!pool.page_table.contains_key(&pages[0]));
+        Ok(())
+    }
+
+    #[test]
+    fn get_page() -> anyhow::Result<()> {
+        let path = create_test_path("test-potpotdb::buffer::get_page.data");
+        let mut pool = BufferPool::new(PagedFile::from_path(&path)?, 2);
+
+        let pages: Vec<_> = (0..3)
+            .map(|value| pool.append_page(&aligned::Buffer::with_value(value)))
+            .collect::<Result<_, _>>()?;
+        pool.update_page(pages[1], &aligned::Buffer::with_value(0x11))?;
+
+        // Page 0 has been evicted, so this reads it in.
+        assert!(!pool.page_table.contains_key(&pages[0]));
+        let page = pool.get_page(pages[0])?;
+        assert_eq!(page.page_id(), pages[0]);
+        assert_eq!(page.len(), PAGESIZE);
+        // Frames can be handed straight to direct I/O.
+        assert_eq!(page.as_ptr() as usize % 4096, 0);
+        assert!(page.iter().all(|&byte| byte == 0));
+
+        // Resident pages, including updated ones, are read from their frame.
+        let frame_idx = pool.page_table[&pages[0]];
+        let page = pool.get_page(pages[0])?;
+        assert!(std::ptr::eq(page.as_ptr(), pool.frames[frame_idx].as_ptr()));
+        let page = pool.get_page(pages[1])?;
+        assert!(page.iter().all(|&byte| byte == 0x11));
+
+        pool.get_page(100).err().expect("page 100 does not exist");
+        Ok(())
+    }
+
+    #[test]
+    fn lru_k_buffer_pool() -> anyhow::Result<()> {
+        let path = create_test_path("test-potpotdb::buffer::lru_k_buffer_pool.data");
+        let mut pool = BufferPool::with_lru_k(PagedFile::from_path(&path)?, 3, 2);
+        let mut read_aligned = aligned::Buffer::new();
+
+        let pages: Vec<_> = (0..10)
+            .map(|value| pool.append_page(&aligned::Buffer::with_value(value)))
+            .collect::<Result<_, _>>()?;
+
+        // A page in regular use survives a scan over the rest.
+        for _ in 0..2 {
+            pool.read_page(pages[0], &mut read_aligned)?;
+        }
+        for &page_id in &pages[1..] {
+            pool.read_page(page_id, &mut read_aligned)?;
+            assert!(read_aligned.iter().all(|&byte| byte == page_id as u8));
+        }
+        assert!(pool.page_table.contains_key(&pages[0]));
+        Ok(())
+    }
+
+    #[test]
+    fn arc_buffer_pool() -> anyhow::Result<()> {
+        let path = create_test_path("test-potpotdb::buffer::arc_buffer_pool.data");
+        let mut pool = BufferPool::with_arc(PagedFile::from_path(&path)?, 3);
+        let mut read_aligned = aligned::Buffer::new();
+
+        let pages: Vec<_> = (0..10)
+            .map(|value| pool.append_page(&aligned::Buffer::with_value(value)))
+            .collect::<Result<_, _>>()?;
+
+        // A page in regular use survives a scan over the rest.
+        for _ in 0..2 {
+            pool.read_page(pages[0], &mut read_aligned)?;
+        }
+        for &page_id in &pages[1..] {
+            pool.read_page(page_id, &mut read_aligned)?;
+            assert!(read_aligned.iter().all(|&byte| byte == page_id as u8));
+        }
+        assert!(pool.page_table.contains_key(&pages[0]));
+        Ok(())
+    }
+
+    #[test]
+    fn tiny_lfu_buffer_pool() -> anyhow::Result<()> {
+        let path = create_test_path("test-potpotdb::buffer::tiny_lfu_buffer_pool.data");
+        let mut pool = BufferPool::with_tiny_lfu(PagedFile::from_path(&path)?, 3);
+        let mut read_aligned = aligned::Buffer::new();
+
+        let pages: Vec<_> = (0..10)
+            .map(|value| pool.append_page(&aligned::Buffer::with_value(value)))
+            .collect::<Result<_, _>>()?;
+
+        // A page in regular use survives a scan over the rest.
+        for _ in 0..3 {
+            pool.read_page(pages[0], &mut read_aligned)?;
+        }
+        for &page_id in &pages[1..] {
+            pool.read_page(page_id, &mut read_aligned)?;
+            assert!(read_aligned.iter().all(|&byte| byte == page_id as u8));
+        }
+        assert!(pool.page_table.contains_key(&pages[0]));
+        Ok(())
+    }
+
+    #[test]
+    fn sequential_scan() -> anyhow::Result<()> {
+        let path = create_test_path("test-potpotdb::buffer::sequential_scan.data");
+        let mut pool = BufferPool::new(PagedFile::from_path(&path)?, 3);
+        let mut read_aligned = aligned::Buffer::new();
+
+        let pages: Vec<_> = (0..10)
+            .map(|value| pool.append_page(&aligned::Buffer::with_value(value)))
+            .collect::<Result<_, _>>()?;
+
+        for _ in 0..2 {
+            pool.read_page(pages[0], &mut read_aligned)?;
+            pool.read_page(pages[1], &mut read_aligned)?;
+        }
+
+        // Scanning the rest reuses a single frame.
+        for &page_id in &pages[2..] {
+            pool.read_page_with_hint(page_id, &mut read_aligned, ReadHint::Sequential)?;
+            assert!(read_aligned.iter().all(|&byte| byte == page_id as u8));
+        }
+        assert!(pool.page_table.contains_key(&pages[0]));
+        assert!(pool.page_table.contains_key(&pages[1]));
+        Ok(())
+    }
+
+    #[test]
+    fn stats() -> anyhow::Result<()> {
+        let path = create_test_path("test-potpotdb::buffer::stats.data");
+        let mut pool = BufferPool::new(PagedFile::from_path(&path)?, 2);
+        let mut read_aligned = aligned::Buffer::new();
+
+        let pages: Vec<_> = (0..3)
+            .map(|value| pool.append_page(&aligned::Buffer::with_value(value)))
+            .collect::<Result<_, _>>()?;
+        pool.read_page(pages[2], &mut read_aligned)?;
+        pool.read_page(pages[0], &mut read_aligned)?;
+        pool.update_page(pages[0], &aligned::Buffer::with_value(10))?;
+        pool.get_page(pages[0])?;
+
+        let stats = pool.stats();
+        assert_eq!((stats.hits, stats.misses, stats.evictions), (2, 1, 2));
+        assert_eq!((stats.dirty_pages, stats.resident_pages), (1, 2));
+        assert!(matches!(stats.policy, PolicyStats::Clock { .. }));
+
+        pool.flush_all()?;
+        assert_eq!(pool.stats().dirty_pages, 0);
+        assert!(matches!(
+            BufferPool::with_arc(PagedFile::from_path(&path)?, 2).stats().policy,
+            PolicyStats::Arc { t1: 0, .. }
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn sharded_prefetch() -> anyhow::Result<()> {
+        let path = create_test_path("test-potpotdb::buffer::sharded_prefetch.data");
+        let _ = fs::remove_file(&path);
+        let pool = ShardedBufferPool::new(PagedFile::from_path(&path)?, 3, 8)?;
+        for value in 0..8 {
+            pool.append_page(&aligned::Buffer::with_value(value))?;
+        }
+        pool.flush_all()?;
+        for page_id in 0..8 {
+            pool.evict_page(page_id)?;
+        }
+
+        // Each shard reads its own pages, once each.
+        pool.prefetch(&[7, 0, 3, 3, 5, 1])?;
+        assert_eq!(pool.stats().iter().map(|stats| stats.prefetched).sum::<u64>(), 5);
+        assert_eq!(pool.cached_page_ids(), [0, 1, 3, 5, 7]);
+        let mut read_aligned = aligned::Buffer::new();
+        for page_id in 0..8 {
+            pool.read_page(page_id, &mut read_aligned)?;
+            assert!(read_aligned.iter().all(|&byte| byte == page_id as u8));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn sharded_buffer_pool() -> anyhow::Result<()> {
+        fn assert_send_sync<T: Send + Sync>(_: &T) {}
+
+        let path = create_test_path("test-potpotdb::buffer::sharded_buffer_pool.data");
+        let pool = ShardedBufferPool::new(PagedFile::from_path(&path)?, 4, 2)?;
+        assert_send_sync(&pool);
+
+        let pages: Vec<Vec<(u64, u8)>> = std::thread::scope(|scope| {
+            let threads: Vec<_> = (0..4u8)
+                .map(|thread| {
+                    let pool = &pool;
+                    scope.spawn(move || -> io::Result<_> {
+                        let mut read_aligned = aligned::Buffer::new();
+                        let mut pages = Vec::new();
+                        for value in (0..5).map(|i| thread * 10 + i) {
+                            let page_id = pool.append_page(&aligned::Buffer::with_value(value))?;
+                            pages.push((page_id, value));
+                        }
+                        for &(page_id, value) in &pages {
+                            pool.read_page(page_id, &mut read_aligned)?;
+                            assert!(read_aligned.iter().all(|&byte| byte == value));
+                        }
+                        Ok(pages)
+                    })
+                })
+                .collect();
+            threads.into_iter().map(|thread| thread.join().unwrap()).collect::<io::Result<_>>()
+        })?;
+
+        let mut page_ids: Vec<_> = pages.iter().flatten().map(|&(page_id, _)| page_id).collect();
+        page_ids.sort_unstable();
+        page_ids.dedup();
+        assert_eq!(page_ids.len(), 20);
+
+        for &(page_id, value) in pages.iter().flatten() {
+            pool.update_page(page_id, &aligned::Buffer::with_value(value + 100))?;
+        }
+        for &(page_id, value) in pages.iter().flatten() {
+            assert!(pool.with_page(page_id, |page| page.iter().all(|&byte| byte == value + 100))?);
+        }
+        pool.flush_all()?;
+        assert!(pool.stats().iter().all(|stats| stats.dirty_pages == 0));
+        Ok(())
+    }
+
+    #[test]
+    fn background_flusher() -> anyhow::Result<()> {
+        let path = create_test_path("test-potpotdb::buffer::background_flusher.data");
+        let pool = Arc::new(ShardedBufferPool::new(PagedFile::from_path(&path)?, 1, 4)?);
+        let page_id = pool.append_page(&aligned::Buffer::with_value(1))?;
+        let all_clean = |pool: &ShardedBufferPool| {
+            (0..500).any(|_| {
+                std::thread::sleep(Duration::from_millis(10));
+                pool.stats()[0].dirty_pages == 0
+            })
+        };
+
+        // Flushed after the interval
+        let flusher = pool.start_flusher(FlusherConfig {
+            interval: Duration::from_millis(10),
+            dirty_ratio: 1.0,
+        })?;
+        pool.update_page(page_id, &aligned::Buffer::with_value(2))?;
+        assert!(all_clean(&pool));
+        drop(flusher);
+
+        // Flushed once too many frames are dirty
+        let _flusher = pool.start_flusher(FlusherConfig {
+            interval: Duration::from_secs(3600),
+            dirty_ratio: 0.5,
+        })?;
+        let page_ids: Vec<_> = (0..2)
+            .map(|value| pool.append_page(&aligned::Buffer::with_value(value)))
+            .collect::<Result<_, _>>()?;
+        pool.update_page(page_ids[0], &aligned::Buffer::with_value(3))?;
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(pool.stats()[0].dirty_pages, 1);
+        pool.update_page(page_ids[1], &aligned::Buffer::with_value(3))?;
+        pool.update_page(page_id, &aligned::Buffer::with_value(3))?;
+        assert!(all_clean(&pool));
+        Ok(())
+    }
+
+    #[test]
+    fn sharded_read_miss() -> anyhow::Result<()> {
+        let path = create_test_path("test-potpotdb::buffer::sharded_read_miss.data");
+        let pool = ShardedBufferPool::new(PagedFile::from_path(&path)?, 1, 2)?;
+        for value in 0..4 {
+            pool.append_page(&aligned::Buffer::with_value(value))?;
+        }
+        assert_eq!(pool.cached_page_ids(), [2, 3]);
+
+        // Read from storage, then from its frame
+        let mut read_aligned = aligned::Buffer::new();
+        for _ in 0..2 {
+            pool.read_page(0, &mut read_aligned)?;
+            assert!(read_aligned.iter().all(|&byte| byte == 0));
+        }
+        let stats = &pool.stats()[0];
+        assert_eq!((stats.misses, stats.hits), (1, 1));
+        assert!(pool.cached_page_ids().contains(&0));
+
+        // A page written back as it was evicted reads back as written.
+        pool.update_page(1, &aligned::Buffer::with_value(0x81))?;
+        pool.evict_page(1)?;
+        pool.read_page(1, &mut read_aligned)?;
+        assert!(read_aligned.iter().all(|&byte| byte == 0x81));
+        Ok(())
+    }
+
+    #[test]
+    fn flush_shards() -> anyhow::Result<()> {
+        let path = create_test_path("test-potpotdb::buffer::flush_shards.data");
+        let pool = ShardedBufferPool::new(PagedFile::from_path(&path)?, 2, 8)?;
+        let page_ids: Vec<_> = (0..8)
+            .map(|value| pool.append_page(&aligned::Buffer::with_value(value)))
+            .collect::<Result<_, _>>()?;
+        for &page_id in &page_ids {
+            pool.update_page(page_id, &aligned::Buffer::with_value(0x80 + page_id as u8))?;
+        }
+        let writes: Vec<_> = pool.stats().iter().map(|stats| stats.writes).collect();
+
+        // Each shard writes its dirty pages in runs, not one at a time, and
+        // the storage is synced once for all of them.
+        pool.flush_shards()?;
+        for (shard, (stats, before)) in pool.shards.iter().zip(pool.stats().iter().zip(writes)) {
+            let shard = shard.lock().unwrap();
+            assert_eq!(stats.dirty_pages, 0);
+            let runs = (shard.page_table.keys())
+                .filter(|&&page_id| page_id == 0 || !shard.page_table.contains_key(&(page_id - 1)))
+                .count();
+            assert_eq!(stats.writes - before, runs as u64);
+            assert!(shard.unsynced.is_empty());
+        }
+        let storage = PagedFile::from_path(&path)?;
+        let mut read_aligned = aligned::Buffer::new();
+        for &page_id in &page_ids {
+            storage.read_page(page_id, &mut read_aligned)?;
+            assert!(read_aligned.iter().all(|&byte| byte == 0x80 + page_id as u8));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn free_frames() -> anyhow::Result<()> {
+        let path = create_test_path("test-potpotdb::buffer::free_frames.data");
+        let mut pool = BufferPool::new(PagedFile::from_path(&path)?, 3);
+        let mut read_aligned = aligned::Buffer::new();
+
+        let pages: Vec<_> = (0..3)
+            .map(|value| pool.append_page(&aligned::Buffer::with_value(value)))
+            .collect::<Result<_, _>>()?;
+        assert_eq!((0..3).map(|i| pool.page_table[&pages[i]]).collect::<Vec<_>>(), [0, 1, 2]);
+        assert!(pool.free_frames.is_empty());
+        assert_eq!(pool.stats().evictions, 0);
+
+        // An evicted page's frame is reused without evicting anything else.
+        pool.update_page(pages[1], &aligned::Buffer::with_value(10))?;
+        pool.evict_page(pages[1])?;
+        assert_eq!(pool.free_frames, [1]);
+        let page_id = pool.append_page(&aligned::Buffer::with_value(3))?;
+        assert_eq!(pool.page_table[&page_id], 1);
+        assert_eq!(pool.stats().evictions, 1);
+
+        // The evicted page was written back.
+        pool.read_page(pages[1], &mut read_aligned)?;
+        assert!(read_aligned.iter().all(|&byte| byte == 10));
+
+        let _pinned = pool.pin(pages[0])?;
+        assert!(pool.evict_page(pages[0]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn prefetch() -> anyhow::Result<()> {
+        let path = create_test_path("test-potpotdb::buffer::prefetch.data");
+        let mut pool = BufferPool::new(PagedFile::from_path(&path)?, 4);
+        let mut read_aligned = aligned::Buffer::new();
+
+        let pages: Vec<_> = (0..8)
+            .map(|value| pool.append_page(&aligned::Buffer::with_value(value)))
+            .collect::<Result<_, _>>()?;
+
+        pool.prefetch(&[pages[2], pages[0], pages[0], pages[7], pages[1]])?;
+        assert_eq!(pool.stats().prefetched, 3);
+        for &page_id in &pages[..3] {
+            pool.read_page(page_id, &mut read_aligned)?;
+            assert!(read_aligned.iter().all(|&byte| byte == page_id as u8));
+        }
+        let stats = pool.stats();
+        assert_eq!((stats.hits, stats.misses), (3, 0));
+
+        // No more pages than the pool holds are read.
+        pool.prefetch(&pages)?;
+        assert_eq!(pool.stats().prefetched, 7);
+        Ok(())
+    }
+
+    #[test]
+    fn resize() -> anyhow::Result<()> {
+        let path = create_test_path("test-potpotdb::buffer::resize.data");
+        let mut pool = BufferPool::new(PagedFile::from_path(&path)?, 2);
+        let mut read_aligned = aligned::Buffer::new();
+        assert!(pool.frames.is_empty());
+
+        let mut pages: Vec<_> = (0..2)
+            .map(|value| pool.append_page(&aligned::Buffer::with_value(value)))
+            .collect::<Result<_, _>>()?;
+
+        // Frames are only allocated as they are used
+        pool.resize(4)?;
+        assert_eq!((pool.capacity(), pool.frames.len()), (4, 2));
+        for value in 2..4 {
+            pages.push(pool.append_page(&aligned::Buffer::with_value(value))?);
+        }
+        assert_eq!((pool.frames.len(), pool.stats().evictions), (4, 0));
+
+        // Pages in the frames removed are written back and evicted
+        pool.update_page(pages[3], &aligned::Buffer::with_value(10))?;
+        pool.resize(1)?;
+        assert_eq!((pool.capacity(), pool.frames.len()), (1, 1));
+        assert_eq!(pool.stats().resident_pages, 1);
+        pool.read_page(pages[3], &mut read_aligned)?;
+        assert!(read_aligned.iter().all(|&byte| byte == 10));
+
+        let _pinned = pool.pin(pages[3])?;
+        assert!(pool.resize(0).is_err());
+        assert_eq!(pool.capacity(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn custom_manager() -> anyhow::Result<()> {
+        // Evicts entries in the order they came in.
+        struct Fifo(VecDeque<(usize, u64)>, usize);
+
+        impl CacheManager<u64> for Fifo {
+            fn size(&self) -> usize {
+                self.1
+            }
+            fn update(&mut self, _idx: usize) {}
+            fn demote(&mut self, _idx: usize) {}
+            fn insert(&mut self, idx: usize, entry: u64) {
+                self.0.push_back((idx, entry));
+            }
+            fn remove(&mut self, idx: usize) -> Option<u64> {
+                let pos = self.0.iter().position(|&(i, _)| i == idx)?;
+                self.0.remove(pos).map(|(_, entry)| entry)
+            }
+            fn resize(&mut self, size: usize) {
+                self.0.retain(|&(idx, _)| idx < size);
+                self.1 = size;
+            }
+            fn sweep(&mut self, entry: u64, pinned: &dyn Fn(usize) -> bool) -> Option<(usize, Option<u64>)> {
+                let pos = self.0.iter().position(|&(idx, _)| !pinned(idx))?;
+                let (idx, evicted) = self.0.remove(pos)?;
+                self.0.push_back((idx, entry));
+                Some((idx, Some(evicted)))
+            }
+        }
+
+        let path = create_test_path("test-potpotdb::buffer::custom_manager.data");
+        let mut pool = BufferPool::with_manager(PagedFile::from_path(&path)?, Fifo(VecDeque::new(), 2));
+        let mut read_aligned = aligned::Buffer::new();
+
+        let pages: Vec<_> = (0..3)
+            .map(|value| pool.append_page(&aligned::Buffer::with_value(value)))
+            .collect::<Result<_, _>>()?;
+        assert!(!pool.page_table.contains_key(&pages[0]));
+        for &page_id in &pages {
+            pool.read_page(page_id, &mut read_aligned)?;
+            assert!(read_aligned.iter().all(|&byte| byte == page_id as u8));
+        }
+        assert_eq!(pool.stats().policy, PolicyStats::Custom(Vec::new()));
+        Ok(())
+    }
+
+    #[test]
+    fn preload() -> anyhow::Result<()> {
+        let path = create_test_path("test-potpotdb::buffer::preload.data");
+        let mut pool = BufferPool::new(PagedFile::from_path(&path)?, 3);
+        let mut read_aligned = aligned::Buffer::new();
+
+        let pages: Vec<_> = (0..6)
+            .map(|value| pool.append_page(&aligned::Buffer::with_value(value)))
+            .collect::<Result<_, _>>()?;
+        for &page_id in &[pages[1], pages[4], pages[2]] {
+            pool.read_page(page_id, &mut read_aligned)?;
+        }
+        let mut hot: Vec<_> = pool.cached_page_ids().collect();
+        hot.sort_unstable();
+        assert_eq!(hot, [pages[1], pages[2], pages[4]]);
+        drop(pool);
+
+        // A new pool starts with the hot set resident
+        let mut pool = BufferPool::new(PagedFile::from_path(&path)?, 3);
+        pool.preload(hot.iter().copied())?;
+        for &page_id in &hot {
+            pool.read_page(page_id, &mut read_aligned)?;
+            assert!(read_aligned.iter().all(|&byte| byte == page_id as u8));
+        }
+        assert_eq!((pool.stats().hits, pool.stats().misses), (3, 0));
+        Ok(())
+    }
+
+    #[test]
+    fn reserve() -> anyhow::Result<()> {
+        let path = create_test_path("test-potpotdb::buffer::reserve.data");
+        let mut pool = BufferPool::new(PagedFile::from_path(&path)?, 4);
+        pool.reserve(PageClass::Index, 0.5);
+        let mut read_aligned = aligned::Buffer::new();
+
+        let page = |page_type: PageType| {
+            let mut page = aligned::Buffer::new();
+            page[4..6].copy_from_slice(&(page_type as u16).to_le_bytes());
+            page
+        };
+        let index_pages: Vec<_> = (0..2)
+            .map(|_| pool.append_page(&page(PageType::HashTableHeader)))
+            .collect::<Result<_, _>>()?;
+        let heap_pages: Vec<_> = (0..10)
+            .map(|_| pool.append_page(&page(PageType::DataPage)))
+            .collect::<Result<_, _>>()?;
+        assert_eq!(PageClass::of(&page(PageType::LinearHashBucket)), PageClass::Index);
+        assert_eq!(PageClass::of(&page(PageType::MasterRecord)), PageClass::Other);
+
+        // A scan over the heap pages leaves the index pages alone
+        for &page_id in &heap_pages {
+            pool.read_page(page_id, &mut read_aligned)?;
+            assert_eq!(PageClass::of(&read_aligned), PageClass::Heap);
+        }
+        assert!(index_pages.iter().all(|page_id| pool.page_table.contains_key(page_id)));
+
+        // Unless nothing else can be evicted
+        let _pinned: Vec<_> = (heap_pages[8..].iter())
+            .map(|&page_id| pool.pin(page_id))
+            .collect::<Result<_, _>>()?;
+        pool.read_page(heap_pages[0], &mut read_aligned)?;
+        assert!(!index_pages.iter().all(|page_id| pool.page_table.contains_key(page_id)));
+        Ok(())
+    }
+
+    #[test]
+    fn memory_budget() -> anyhow::Result<()> {
+        let path = create_test_path("test-potpotdb::buffer::memory_budget.data");
+        let frame = PAGESIZE + BufferPool::FRAME_OVERHEAD;
+
+        let budget = 256 << 20;
+        let pool = BufferPool::with_memory_budget(PagedFile::from_path(&path)?, budget);
+        assert!(pool.capacity() * frame <= budget);
+        assert!((pool.capacity() + 1) * frame > budget);
+        // Nothing is allocated up front
+        assert!(pool.frames.is_empty());
+
+        assert_eq!(BufferPool::frames_for_budget(10 * frame - 1), 9);
+        assert_eq!(BufferPool::frames_for_budget(0), 1);
+
+        let pool = ShardedBufferPool::with_memory_budget(PagedFile::from_path(&path)?, 4, 40 * frame)?;
+        assert!(pool.shards.iter().all(|shard| shard.lock().unwrap().capacity() == 10));
+        Ok(())
+    }
+
+    #[test]
+    fn write_clustering() -> anyhow::Result<()> {
+        let path = create_test_path("test-potpotdb::buffer::write_clustering.data");
+        let mut pool = BufferPool::new(PagedFile::from_path(&path)?, 8);
+        let mut read_aligned = aligned::Buffer::new();
+
+        let pages: Vec<_> = (0..8)
+            .map(|value| pool.append_page(&aligned::Buffer::with_value(value)))
+            .collect::<Result<_, _>>()?;
+        for &i in &[0, 1, 2, 3, 5, 6] {
+            pool.update_page(pages[i], &aligned::Buffer::with_value(10 + i as u8))?;
+        }
+
+        // Two runs of adjacent pages, in two writes.
+        pool.flush_all()?;
+        assert_eq!((pool.stats().writes, pool.stats().dirty_pages), (2, 0));
+
+        // Evicting a page writes its dirty neighbours too.
+        pool.update_page(pages[6], &aligned::Buffer::with_value(20))?;
+        pool.update_page(pages[7], &aligned::Buffer::with_value(21))?;
+        pool.evict_page(pages[6])?;
+        assert_eq!((pool.stats().writes, pool.stats().dirty_pages), (3, 0));
+        drop(pool);
+
+        let mut pool = BufferPool::new(PagedFile::from_path(&path)?, 8);
+        for (i, &page_id) in pages.iter().enumerate() {
+            pool.read_page(page_id, &mut read_aligned)?;
+            let expected = match i {
+                6 => 20,
+                7 => 21,
+                4 => 4,
+                i => 10 + i as u8,
+            };
+            assert!(read_aligned.iter().all(|&byte| byte == expected));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn hooks() -> anyhow::Result<()> {
+        #[derive(Default)]
+        struct Recorder {
+            events: Mutex<Vec<(&'static str, u64)>>,
+            // pages whose log records are "not yet durable"
+            unlogged: Mutex<HashSet<u64>>,
+        }
+
+        impl BufferPoolHooks for Recorder {
+            fn before_flush(&self, page_id: u64, page: &[u8]) -> io::Result<()> {
+                assert!(page.iter().all(|&byte| byte == 0x80 + page_id as u8));
+                if self.unlogged.lock().unwrap().contains(&page_id) {
+                    return Err(io::Error::other("log not flushed"));
+                }
+                self.events.lock().unwrap().push(("before_flush", page_id));
+                Ok(())
+            }
+
+            fn after_flush(&self, page_id: u64) {
+                self.events.lock().unwrap().push(("after_flush", page_id));
+            }
+
+            fn before_evict(&self, page_id: u64) {
+                self.events.lock().unwrap().push(("before_evict", page_id));
+            }
+        }
+
+        let path = create_test_path("test-potpotdb::buffer::hooks.data");
+        let mut pool = BufferPool::new(PagedFile::from_path(&path)?, 2);
+        let recorder = Arc::new(Recorder::default());
+        pool.set_hooks(recorder.clone());
+
+        let pages: Vec<_> = (0..2)
+            .map(|value| pool.append_page(&aligned::Buffer::with_value(value)))
+            .collect::<Result<_, _>>()?;
+        for &page_id in &pages {
+            pool.update_page(page_id, &aligned::Buffer::with_value(0x80 + page_id as u8))?;
+        }
+
+        // A page can't be written before its log records, nor can the rest
+        // of its run
+        recorder.unlogged.lock().unwrap().insert(pages[1]);
+        assert!(pool.flush_all().is_err());
+        assert_eq!(pool.stats().dirty_pages, 2);
+        recorder.unlogged.lock().unwrap().clear();
+        recorder.events.lock().unwrap().clear();
+
+        pool.flush_page(pages[1])?;
+        pool.append_page(&aligned::Buffer::with_value(2))?;
+        assert_eq!(
+            *recorder.events.lock().unwrap(),
+            [
+                ("before_flush", pages[1]),
+                ("after_flush", pages[1]),
+                ("before_flush", pages[0]),
+                ("after_flush", pages[0]),
+                ("before_evict", pages[0]),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn failed_eviction() -> anyhow::Result<()> {
+        struct FailOnce(std::sync::atomic::AtomicBool);
+
+        impl BufferPoolHooks for FailOnce {
+            fn before_flush(&self, _page_id: u64, _page: &[u8]) -> io::Result<()> {
+                if self.0.swap(false, Ordering::SeqCst) {
+                    Err(io::Error::other("log not flushed"))
+                } else {
+                    Ok(())
+                }
+            }
+        }
+
+        let path = create_test_path("test-potpotdb::buffer::failed_eviction.data");
+        let mut pool = BufferPool::new(PagedFile::from_path(&path)?, 1);
+        for value in 0..3 {
+            pool.append_page(&aligned::Buffer::with_value(value))?;
+        }
+        pool.flush_all()?;
+        pool.update_page(2, &aligned::Buffer::with_value(0x82))?;
+
+        // The dirty page can't be written, so it keeps its frame...
+        pool.set_hooks(Arc::new(FailOnce(std::sync::atomic::AtomicBool::new(true))));
+        let mut read_aligned = aligned::Buffer::new();
+        assert!(pool.read_page(1, &mut read_aligned).is_err());
+        assert_eq!(pool.stats().dirty_pages, 1);
+
+        // ...and is written under its own page id once it can be.
+        pool.read_page(1, &mut read_aligned)?;
+        assert!(read_aligned.iter().all(|&byte| byte == 1));
+        pool.flush_all()?;
+        let storage = PagedFile::from_path(&path)?;
+        for (page_id, expected) in [(0, 0), (1, 1), (2, 0x82)] {
+            storage.read_page(page_id, &mut read_aligned)?;
+            assert!(read_aligned.iter().all(|&byte| byte == expected), "page {}", page_id);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn save_and_load_state() -> anyhow::Result<()> {
+        let path = create_test_path("test-potpotdb::buffer::save_and_load_state.data");
+        let state_path = create_test_path("test-potpotdb::buffer::save_and_load_state.state");
+        let _ = fs::remove_file(&state_path);
+        let mut pool = BufferPool::new(PagedFile::from_path(&path)?, 3);
+        let mut read_aligned = aligned::Buffer::new();
+
+        // Nothing saved yet
+        pool.load_state(&state_path)?;
+
+        let pages: Vec<_> = (0..6)
+            .map(|value| pool.append_page(&aligned::Buffer::with_value(value)))
+            .collect::<Result<_, _>>()?;
+        for &page_id in &[pages[0], pages[2], pages[5]] {
+            pool.read_page(page_id, &mut read_aligned)?;
+        }
+        pool.save_state(&state_path)?;
+        drop(pool);
+
+        let mut pool = BufferPool::new(PagedFile::from_path(&path)?, 3);
+        pool.load_state(&state_path)?;
+        let mut resident: Vec<_> = pool.cached_page_ids().collect();
+        resident.sort_unstable();
+        assert_eq!(resident, [pages[0], pages[2], pages[5]]);
+
+        let pool = ShardedBufferPool::new(PagedFile::from_path(&path)?, 2, 3)?;
+        pool.load_state(&state_path)?;
+        assert_eq!(pool.cached_page_ids(), [pages[0], pages[2], pages[5]]);
+        Ok(())
+    }
+
+    #[test]
+    fn mem_storage() -> anyhow::Result<()> {
+        let mut pool = BufferPool::new(MemStorage::new(), 2);
+        let mut read_aligned = aligned::Buffer::new();
+
+        let pages: Vec<_> = (0..4)
+            .map(|value| pool.append_page(&aligned::Buffer::with_value(value)))
+            .collect::<Result<_, _>>()?;
+        pool.update_page(pages[0], &aligned::Buffer::with_value(10))?;
+        for (&page_id, value) in pages.iter().zip([10, 1, 2, 3]) {
+            pool.read_page(page_id, &mut read_aligned)?;
+            assert!(read_aligned.iter().all(|&byte| byte == value));
+        }
+        assert!(pool.read_page(4, &mut read_aligned).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn append_pages_in_one_batch() -> anyhow::Result<()> {
+        let path = create_test_path("test-potpotdb::buffer::append_pages_in_one_batch.data");
+        let pool = ShardedBufferPool::new(PagedFile::from_path(&path)?, 2, 2)?;
+        let pages: Vec<_> = (0..6).map(aligned::Buffer::with_value).collect();
+        let page_refs: Vec<&aligned::Buffer> = pages.iter().map(|page| &**page).collect();
+
+        assert_eq!(pool.append_pages(&page_refs[..1])?, 0..1);
+        assert_eq!(pool.append_pages(&page_refs[1..])?, 1..6);
+        let mut read_aligned = aligned::Buffer::new();
+        for value in 0..6 {
+            pool.read_page(value as u64, &mut read_aligned)?;
+            assert!(read_aligned.iter().all(|&byte| byte == value));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn free_pages() -> anyhow::Result<()> {
+        let path = create_test_path("test-potpotdb::buffer::free_pages.data");
+        let mut pool = BufferPool::new(PagedFile::from_path(&path)?, 3);
+        assert!(pool.free_page(0).is_err());
+        pool.create_master_record()?;
+        let pages: Vec<_> = (1..=3)
+            .map(|value| pool.append_page(&aligned::Buffer::with_value(value)))
+            .collect::<Result<_, _>>()?;
+        assert_eq!(pages, [1, 2, 3]);
+
+        pool.free_page(2)?;
+        pool.free_page(1)?;
+        assert!(pool.free_page(1).is_err());
+        assert!(pool.free_page(0).is_err());
+
+        // The free list survives the pool
+        pool.flush_all()?;
+        drop(pool);
+        let mut pool = BufferPool::new(PagedFile::from_path(&path)?, 3);
+        pool.create_master_record()?;
+
+        // Freed pages are reused, most recently freed first, before the file
+        // grows
+        let mut read_aligned = aligned::Buffer::new();
+        for (expected, value) in [(1, 4), (2, 5), (4, 6)] {
+            assert_eq!(pool.append_page(&aligned::Buffer::with_value(value))?, expected);
+            pool.read_page(expected, &mut read_aligned)?;
+            assert!(read_aligned.iter().all(|&byte| byte == value));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn corrupt_master_record() -> anyhow::Result<()> {
+        let path = create_test_path("test-potpotdb::buffer::corrupt_master_record.data");
+        let mut pool = BufferPool::new(PagedFile::from_path(&path)?, 3);
+        pool.create_master_record_with(Checksum::Xxh3)?;
+        pool.append_page(&aligned::Buffer::with_value(1))?;
+        drop(pool);
+
+        let storage = PagedFile::from_path(&path)?;
+        let mut page = aligned::Buffer::new();
+        storage.read_page(0, &mut page)?;
+        page[0x100] = 1;
+        storage.write_page(0, &page)?;
+
+        let mut pool = BufferPool::new(storage, 3);
+        let err = pool.free_page(1).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("master record"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn shrink() -> anyhow::Result<()> {
+        let path = create_test_path("test-potpotdb::buffer::shrink.data");
+        let mut pool = BufferPool::new(PagedFile::from_path(&path)?, 3);
+        pool.create_master_record()?;
+        for value in 1..=5 {
+            pool.append_page(&aligned::Buffer::with_value(value))?;
+        }
+        for page_id in [2, 5, 4] {
+            pool.free_page(page_id)?;
+        }
+
+        // Pages 4 and 5 are cut off, and page 2 keeps its place
+        pool.shrink(true)?;
+        assert_eq!(fs::metadata(&path)?.len(), 4 * PAGESIZE as u64);
+        assert!(!pool.cached_page_ids().any(|page_id| page_id > 3));
+        assert_eq!(pool.append_page(&aligned::Buffer::with_value(6))?, 2);
+        assert_eq!(pool.append_page(&aligned::Buffer::with_value(7))?, 4);
+
+        let mut read_aligned = aligned::Buffer::new();
+        for (page_id, value) in [(1, 1), (2, 6), (3, 3), (4, 7)] {
+            pool.read_page(page_id, &mut read_aligned)?;
+            assert!(read_aligned.iter().all(|&byte| byte == value));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn free_page_needs_master_record() -> anyhow::Result<()> {
+        let mut pool = BufferPool::new(MemStorage::new(), 2);
+        pool.append_page(&aligned::Buffer::with_value(1))?;
+        pool.append_page(&aligned::Buffer::with_value(2))?;
+        assert_eq!(pool.free_page(1).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(pool.create_master_record().unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(pool.append_page(&aligned::Buffer::with_value(3))?, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn master_record_page_size() -> anyhow::Result<()> {
+        // Files record their page size themselves, but storage in memory
+        // leaves it to the master record.
+        let mut storage = MemStorage::with_page_size(PageSize::K4);
+        storage.append_page(&MasterRecord::new(PAGESIZE, Checksum::Crc32).into_aligned(Checksum::Crc32))?;
+        storage.append_page(&aligned::Buffer::with_value(1))?;
+        let mut pool = BufferPool::new(storage, 2);
+        assert_eq!(pool.free_page(1).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        Ok(())
+    }
+
+    #[test]
+    fn failed_sync_poisons() -> anyhow::Result<()> {
+        let faults = Faults {
+            fail_sync: Some(0),
+            ..Faults::default()
+        };
+        let mut pool = BufferPool::new(FaultyStorage::new(MemStorage::new(), faults), 4);
+        pool.append_page(&aligned::Buffer::with_value(1))?;
+        pool.update_page(0, &aligned::Buffer::with_value(2))?;
+
+        let err = pool.flush_all().unwrap_err();
+        assert!(storage::is_poisoned(&err));
+        assert!(pool.is_poisoned());
+        // The page may not have reached the disk, so it is dirty again.
+        assert_eq!(pool.stats().dirty_pages, 1);
+        // Syncs would succeed now, but the pool won't trust them.
+        assert!(storage::is_poisoned(&pool.flush_all().unwrap_err()));
+        assert!(storage::is_poisoned(&pool.append_page(&aligned::Buffer::with_value(3)).unwrap_err()));
+
+        let mut read_aligned = aligned::Buffer::new();
+        pool.read_page(0, &mut read_aligned)?;
+        assert!(read_aligned.iter().all(|&byte| byte == 2));
+        Ok(())
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn async_buffer_pool() -> anyhow::Result<()> {
+        let path = create_test_path("test-potpotdb::buffer::async_buffer_pool.data");
+        let pool = AsyncBufferPool::new(Arc::new(ShardedBufferPool::new(PagedFile::from_path(&path)?, 2, 2)?));
+        let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+
+        runtime.block_on(async {
+            let mut pages = Vec::new();
+            for value in 0..6 {
+                pages.push(pool.append_page(aligned::Buffer::with_value(value)).await?);
+            }
+            pool.write_page(pages[0], aligned::Buffer::with_value(10)).await?;
+            for (&page_id, value) in pages.iter().zip([10, 1, 2, 3, 4, 5]) {
+                let page = pool.read_page(page_id).await?;
+                assert!(page.iter().all(|&byte| byte == value));
+            }
+            pool.flush_all().await?;
+            assert!(pool.pool().stats().iter().all(|stats| stats.dirty_pages == 0));
+            Ok(())
+        })
     }
 }
@@ -3,7 +3,8 @@
 //! A hash table that uses spillover to
 //! Page layouts:
 //!
-//! Single Page Hash Table - Hashes u64 to a fixed-size byte value.
+//! Single Page Hash Table - Hashes a fixed-width key (a u64 unless another
+//! `Key` type is chosen) to a fixed-size byte value.
 //!
 //! Emits a CapacityError when trying to insert more elements than we have room for in the page.
 //!
@@ -11,12 +12,56 @@
 //!   0x0004  Page type (2 bytes) (0x2000)
 //!   0x0006  Value size (2 bytes)
 //!   0x0008  Hash algorithm (2 bytes)
-//!   0x000a  Padding (6 bytes)
+//!   0x000a  Key size (2 bytes)
+//!   0x000c  Entry count (4 bytes)  // 0 in tables written before it was kept; count the slots instead
 //!   0x0010  Hash Seed (8 bytes) (maybe depends on hash algorithm?)
-//!   0x0018  Data ((8 byte key + N byte value)* up to (PAGE_SIZE - 0x18) / 8)
+//!   0x0018  Slot states (2 bits per slot, capacity / 4 bytes rounded up)
+//!   0x....  Slots ((K byte key + N byte value) * capacity)
 //!
-//!   Capacity: (PAGESIZE - 0x18) / (value size + 8) == 16360 / (8 + valuesize)
-//!       So for valuesize=24, capacity == 16360 / 32 == 511
+//!   Slot state bits are xy, x: HasValue, y: ContinueFallthrough.
+//!       00 - Empty, 11 - Full, 01 - Deleted.
+//!
+//!   Capacity: largest n where n / 4 (rounded up) + n * (keysize + valuesize) <= page size - 0x18
+//!       So for u64 keys and valuesize=24 in 16K pages, capacity == 507
+//!
+//!   Keys are stored whole, in `Key::SIZE` bytes: 8 for u64 keys, and a 2
+//!   byte length plus N bytes for `ByteKey<N>`.
+//!
+//!   The value size and key size fields of a table's first page (here, or
+//!   the header or directory page of the other layouts) are checked against
+//!   the types a table is reopened with.  Tables written before the key size
+//!   was recorded have 0 there, which means u64 keys.
+//!
+//!   The hash algorithm field of a table's first page picks the hasher used
+//!   for every page of the table: 0 xxHash64, 1 SipHash-1-3 (keyed with the
+//!   seed and its complement), 2 wyhash, 3 XXH3.  Other values are rejected
+//!   when the table is opened.
+//!
+//! Variable-length Single Page Hash Table - Like the single page table, but
+//! slots hold a reference to the value instead of the value itself.  Values
+//! are packed downwards from the end of the page, so values of different
+//! sizes don't have to be padded to a common width.  Space freed by removed
+//! or shrunk values is reclaimed by compacting the values when the free
+//! space in the middle runs out.
+//!
+//!   0x0000  CRC32 (4 bytes)  // CRC of bytes 4-end
+//!   0x0004  Page type (2 bytes) (0x2010)
+//!   0x0006  Value size (2 bytes)  // Always 4, the size of a value reference
+//!   0x0008  Hash algorithm (2 bytes)
+//!   0x000a  Slot count (2 bytes)
+//!   0x000c  Start of values (2 bytes)
+//!   0x000e  Key size (2 bytes)
+//!   0x0010  Hash Seed (8 bytes)
+//!   0x0018  Slot states (2 bits per slot, as in the single page table)
+//!   0x....  Slots ((K byte key + 2 byte value offset + 2 byte value length) * slot count)
+//!   0x....  Free space
+//!   0x....  Values, up to the end of the page
+//!
+//! Multi-page Hash Table - A header page pointing at a fixed number of slot
+//! pages.  A key hashes to one of the page pointers, and slot pages are only
+//! allocated once a key lands on them.  When a slot page is full, inserts
+//! spill over to the next page pointer (wrapping around), and lookups keep
+//! going past pages that have no empty slots left.
 //!
 //! Header page:
 //!
@@ -24,351 +69,3520 @@
 //!   0x0004  Page type (2 bytes) (0x2001)
 //!   0x0006  Value size (2 bytes)
 //!   0x0008  Hash algorithm (4 bytes)
-//!   0x000c  Padding (4 bytes)
+//!   0x000c  Key size (2 bytes)
+//!   0x000e  Bloom filter size (2 bytes)  // Bytes per slot page; 0 if there are no filters
 //!   0x0010  Page count (8 bytes)  // Future optimization: if 0, use page pointers as single-page storage.
 //!   0x0018  Hash Seed (8 bytes) (maybe depends on hash algorithm?)
-//!   0x0020  Page pointers (8 bytes x page count) (up to (PAGE_SIZE - 0x20) / 8)
+//!   0x0020  Entry count (8 bytes)
+//!   0x0028  Page pointers (8 bytes x page count) (up to (PAGE_SIZE - 0x28) / 8)
+//!   0x....  Filter page pointers (8 bytes x filter page count)
 //!   0x     End
 //!
+//!   A page pointer of 0 means the slot page has not been allocated yet.
+//!   (Slot pages are always allocated after their header page, so they can
+//!   never be page 0.)  Filter page pointers work the same way.
+//!
+//!   Each slot page has a bloom filter over the hashes of the keys stored
+//!   there, of 10 bits per slot, so lookups of missing keys can skip reading
+//!   slot pages.  Removing a key leaves its bits set.  The filters are packed
+//!   into filter pages, as many to a page as fit, and the header points to
+//!   those after its page pointers.  Tables written before the filters were
+//!   added, and tables with too many slot pages for their filter page
+//!   pointers to fit as well, have a filter size of 0 and always read their
+//!   slot pages.
+//!
+//! Bloom filter page
+//!
+//!   0x0   CRC32 (4 bytes)  // CRC of bytes 4-end
+//!   0x4   Page type (2 bytes) (0x2024)
+//!   0x8   Bloom filters (filter size x filters per page)
+//!
 //! Fixed width slot page
 //!
 //!   0x0   CRC32 (4 bytes)  // CRC of bytes 4-end
 //!   0x4   Page type (2 bytes) (0x2021)
 //!   0x6  Value size (2 bytes)
-//!   0x8   First slot number (8 bytes)
-//!   0x10  Slots (2 byte size + value)
+//!   0x8   First slot number (8 bytes)  // page pointer index * slots per page
+//!   0x10  Slot states (2 bits per slot, as in the single page table)
+//!   0x..  Slots ((K byte key + N byte value) * capacity)
+//!
+//! Extendible Hash Table - A directory page of 2^global depth bucket
+//! pointers, indexed by the low bits of the hash.  Several directory entries
+//! may share a bucket.  When a bucket fills up it splits in two on the next
+//! hash bit, doubling the directory first if the bucket already uses all of
+//! the directory's bits.
+//!
+//! Directory page:
+//!
+//!   0x0000  CRC32 (4 bytes)  // CRC of bytes 4-end
+//!   0x0004  Page type (2 bytes) (0x2002)
+//!   0x0006  Value size (2 bytes)
+//!   0x0008  Hash algorithm (4 bytes)
+//!   0x000c  Key size (2 bytes)
+//!   0x000e  Padding (2 bytes)
+//!   0x0010  Global depth (8 bytes)
+//!   0x0018  Hash Seed (8 bytes)
+//!   0x0020  Entry count (8 bytes)
+//!   0x0028  Bucket pointers (8 bytes x 2^global depth) (global depth <= 10)
+//!
+//! Bucket page
+//!
+//!   0x0   CRC32 (4 bytes)  // CRC of bytes 4-end
+//!   0x4   Page type (2 bytes) (0x2022)
+//!   0x6   Value size (2 bytes)
+//!   0x8   Local depth (8 bytes)
+//!   0x10  Slot states (2 bits per slot, as in the single page table)
+//!   0x..  Slots ((K byte key + N byte value) * capacity)
+//!
+//! Linear Hash Table - A header page holding bucket pointers, a level and a
+//! split pointer.  There are 2^level + split pointer buckets.  A key goes to
+//! bucket `hash mod 2^level`, or `hash mod 2^(level + 1)` if that bucket has
+//! already been split this round.  Whenever the table gets more than 3/4 full
+//! the bucket under the split pointer (and only that one) is split, so each
+//! insert does a bounded amount of work.  Buckets that fill up before their
+//! turn to split grow a chain of overflow pages.
+//!
+//! Header page:
+//!
+//!   0x0000  CRC32 (4 bytes)  // CRC of bytes 4-end
+//!   0x0004  Page type (2 bytes) (0x2003)
+//!   0x0006  Value size (2 bytes)
+//!   0x0008  Hash algorithm (4 bytes)
+//!   0x000c  Key size (2 bytes)
+//!   0x000e  Padding (2 bytes)
+//!   0x0010  Level (8 bytes)
+//!   0x0018  Split pointer (8 bytes)
+//!   0x0020  Hash Seed (8 bytes)
+//!   0x0028  Entry count (8 bytes)
+//!   0x0030  Bucket pointers (8 bytes x bucket count) (up to (PAGE_SIZE - 0x30) / 8)
+//!
+//! Linear hash bucket page
+//!
+//!   0x0   CRC32 (4 bytes)  // CRC of bytes 4-end
+//!   0x4   Page type (2 bytes) (0x2023)
+//!   0x6   Value size (2 bytes)
+//!   0x8   Next overflow page (8 bytes)  // 0 if this is the end of the chain
+//!   0x10  Slot states (2 bits per slot, as in the single page table)
+//!   0x..  Slots ((K byte key + N byte value) * capacity)
+//!
+//!   Only the first bucket page is allocated before the header page, so an
+//!   overflow pointer can never legitimately be page 0.
 //!
+//! Each layout fills the whole page, whatever size the storage's pages are,
+//! so slot capacities, and the most page pointers, buckets and directory
+//! entries a page holds, scale with the page size.
+//!
+//! The CRC32 field of each layout holds whichever checksum the buffer
+//! pool's master record chooses (see `aligned::Checksum`).  It is CRC32 in
+//! pools without a master record, or whose master record predates the choice.
 //!
 
 #[test]
 fn capacity() {
-    assert_eq!(0, 16360 / 32)
+    assert_eq!(507, page::capacity(PAGESIZE, 8, 24));
+    assert_eq!(1335, page::capacity(PAGESIZE, 8, 4));
+    assert_eq!(126, page::capacity(4096, 8, 24));
 }
 use std::{
+    cell::RefCell,
+    convert::{TryFrom, TryInto},
     hash::{BuildHasher, Hash, Hasher},
     marker::PhantomData,
 };
 
-use twox_hash::XxHash64;
+use serde::{de::DeserializeOwned, Serialize};
+use thiserror::Error;
+use siphasher::sip::SipHasher13;
+use twox_hash::{xxh3, XxHash64};
+use wyhash::WyHash;
 
 use crate::{
-    aligned::{self, FromAligned},
+    aligned::{self, IntoAligned},
     bufferpool::BufferPool,
+    record::PageId,
+    PAGESIZE,
 };
 
+use page::{SlotState, SlotStorage};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("hash table is full ({capacity} entries)")]
+    Capacity { capacity: usize },
+    #[error("value does not fit in a {value_size} byte slot")]
+    ValueSize { value_size: usize },
+    #[error("value needs {needed} bytes, but only {available} are free in the page")]
+    Space { needed: usize, available: usize },
+    #[error("unknown hash algorithm {0:#x}")]
+    HashAlgorithm(u32),
+    #[error("table stores {found} byte values, but the value type is {expected} bytes")]
+    ValueType { expected: usize, found: usize },
+    #[error("table stores {found} byte keys, but the key type is {expected} bytes")]
+    KeyType { expected: usize, found: usize },
+    #[error("key is {len} bytes long, but at most {max} bytes fit in a slot")]
+    KeyLength { len: usize, max: usize },
+}
+
+/// A key type that can be stored in fixed-width hash table slots.
+///
+/// The full key is stored in each slot, so lookups compare whole keys rather
+/// than trusting the hash.
+pub trait Key: Hash + Eq {
+    /// The number of bytes the key takes up in a slot.
+    const SIZE: usize;
+
+    /// Writes the key to `bytes`, which is exactly `SIZE` bytes long.
+    fn write_to(&self, bytes: &mut [u8]);
+
+    /// Reads a key written by `write_to`.
+    fn read_from(bytes: &[u8]) -> Self;
+}
+
+impl Key for u64 {
+    const SIZE: usize = 8;
+
+    fn write_to(&self, bytes: &mut [u8]) {
+        bytes.copy_from_slice(&self.to_le_bytes())
+    }
+
+    fn read_from(bytes: &[u8]) -> Self {
+        u64::from_le_bytes(bytes.try_into().expect("u64 keys are eight bytes"))
+    }
+}
+
+/// A byte string key of up to `N` bytes, such as a string identifier.
+///
+/// Slots store a two byte length followed by `N` bytes, so keep `N` close to
+/// the longest key you expect.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ByteKey<const N: usize> {
+    len: u16,
+    bytes: [u8; N],
+}
+
+impl<const N: usize> ByteKey<N> {
+    /// Copies `key` into a new `ByteKey`.
+    ///
+    /// Returns `Error::KeyLength` if `key` is longer than `N` bytes.
+    pub fn new(key: &[u8]) -> Result<Self, Error> {
+        if key.len() > N || key.len() > u16::MAX as usize {
+            return Err(Error::KeyLength {
+                len: key.len(),
+                max: N.min(u16::MAX as usize),
+            });
+        }
+        let mut bytes = [0; N];
+        bytes[..key.len()].copy_from_slice(key);
+        Ok(ByteKey {
+            len: key.len() as u16,
+            bytes,
+        })
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+}
+
+impl<const N: usize> TryFrom<&[u8]> for ByteKey<N> {
+    type Error = Error;
+
+    fn try_from(key: &[u8]) -> Result<Self, Error> {
+        ByteKey::new(key)
+    }
+}
+
+impl<const N: usize> TryFrom<&str> for ByteKey<N> {
+    type Error = Error;
+
+    fn try_from(key: &str) -> Result<Self, Error> {
+        ByteKey::new(key.as_bytes())
+    }
+}
+
+// Hashes decide where keys are stored, so they must not change with the
+// platform.  Hashing the slice would prefix it with a usize length.
+impl<const N: usize> Hash for ByteKey<N> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write(&self.len.to_le_bytes());
+        state.write(self.as_bytes());
+    }
+}
+
+impl<const N: usize> Key for ByteKey<N> {
+    const SIZE: usize = 2 + N;
+
+    fn write_to(&self, bytes: &mut [u8]) {
+        bytes[..2].copy_from_slice(&self.len.to_le_bytes());
+        bytes[2..].copy_from_slice(&self.bytes);
+    }
+
+    fn read_from(bytes: &[u8]) -> Self {
+        // A damaged slot may claim more bytes than the key holds.
+        let len = u16::from_le_bytes([bytes[0], bytes[1]]).min(N as u16);
+        let mut key = [0; N];
+        key.copy_from_slice(&bytes[2..]);
+        ByteKey { len, bytes: key }
+    }
+}
+
+/// The hash function a table uses, recorded in its first page so it is
+/// reopened with the same one.
 #[repr(u32)]
-#[derive(Copy, Clone, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum HashAlgorithm {
+    /// 64 bit xxHash.  The default.
     XxHash = 0x0000_0000,
+    /// SipHash-1-3, keyed with the table's seed, for tables whose keys may be
+    /// chosen by an attacker.
+    SipHash13 = 0x0000_0001,
+    /// wyhash.
+    WyHash = 0x0000_0002,
+    /// 64 bit XXH3.
+    Xxh3 = 0x0000_0003,
+}
+
+impl TryFrom<u32> for HashAlgorithm {
+    type Error = Error;
+
+    fn try_from(value: u32) -> Result<Self, Error> {
+        match value {
+            0x0000_0000 => Ok(HashAlgorithm::XxHash),
+            0x0000_0001 => Ok(HashAlgorithm::SipHash13),
+            0x0000_0002 => Ok(HashAlgorithm::WyHash),
+            0x0000_0003 => Ok(HashAlgorithm::Xxh3),
+            _ => Err(Error::HashAlgorithm(value)),
+        }
+    }
+}
+
+/// Checks a table's stored value size against the type it is opened with.
+fn check_value_type<V>(found: usize) -> Result<(), Error> {
+    let expected = std::mem::size_of::<V>();
+    if found == expected {
+        Ok(())
+    } else {
+        Err(Error::ValueType { expected, found })
+    }
+}
+
+/// Reads a page into a buffer lent by the pool.
+fn read_buffer(buffer_pool: &RefCell<BufferPool>, page_id: PageId) -> std::io::Result<Box<aligned::Buffer>> {
+    let mut buffer_pool = buffer_pool.borrow_mut();
+    let mut buffer = buffer_pool.lend_buffer();
+    match buffer_pool.read_page(page_id, &mut buffer) {
+        Ok(()) => Ok(buffer),
+        Err(err) => {
+            buffer_pool.return_buffer(buffer);
+            Err(err)
+        }
+    }
+}
+
+/// Reads a table page, checking it against the pool's checksum algorithm.
+fn load_page<P: page::TablePage>(buffer_pool: &RefCell<BufferPool>, page_id: PageId) -> anyhow::Result<P> {
+    let buffer = read_buffer(buffer_pool, page_id)?;
+    let checksum = buffer_pool.borrow_mut().checksum()?;
+    let mut page = P::from_aligned(buffer, checksum)?;
+    page.set_page_size(page_size(buffer_pool));
+    Ok(page)
+}
+
+/// Writes a table page over `page_id`, stamped with the pool's checksum
+/// algorithm, and gives its buffer to the pool to lend out again.
+fn write_page<P: IntoAligned>(buffer_pool: &RefCell<BufferPool>, page_id: PageId, page: P) -> std::io::Result<()> {
+    let mut buffer_pool = buffer_pool.borrow_mut();
+    let buffer = page.into_aligned(buffer_pool.checksum()?);
+    let result = buffer_pool.update_page(page_id, &buffer);
+    buffer_pool.return_buffer(buffer);
+    result
+}
+
+/// Appends a table page, stamped with the pool's checksum algorithm, and
+/// gives its buffer to the pool to lend out again.
+fn append_page<P: IntoAligned>(buffer_pool: &RefCell<BufferPool>, page: P) -> std::io::Result<PageId> {
+    let mut buffer_pool = buffer_pool.borrow_mut();
+    let buffer = page.into_aligned(buffer_pool.checksum()?);
+    let result = buffer_pool.append_page(&buffer);
+    buffer_pool.return_buffer(buffer);
+    result
+}
+
+/// The size of the pool's pages, which table pages are laid out to fill.
+fn page_size(buffer_pool: &RefCell<BufferPool>) -> usize {
+    buffer_pool.borrow().page_size()
+}
+
+/// Checks a table's stored key size against the type it is opened with.
+fn check_key_type<K: Key>(found: usize) -> Result<(), Error> {
+    if found == K::SIZE {
+        Ok(())
+    } else {
+        Err(Error::KeyType {
+            expected: K::SIZE,
+            found,
+        })
+    }
 }
 
-struct SeededXxHashBuilder {
+struct SeededHashBuilder {
+    algorithm: HashAlgorithm,
     seed: u64,
 }
-impl SeededXxHashBuilder {
-    fn new(seed: u64) -> SeededXxHashBuilder {
-        SeededXxHashBuilder { seed }
+impl SeededHashBuilder {
+    fn new(algorithm: HashAlgorithm, seed: u64) -> SeededHashBuilder {
+        SeededHashBuilder { algorithm, seed }
     }
 }
 
-impl BuildHasher for SeededXxHashBuilder {
-    type Hasher = XxHash64;
+impl BuildHasher for SeededHashBuilder {
+    type Hasher = SeededHasher;
 
     fn build_hasher(&self) -> Self::Hasher {
-        XxHash64::with_seed(self.seed)
+        match self.algorithm {
+            HashAlgorithm::XxHash => SeededHasher::XxHash(XxHash64::with_seed(self.seed)),
+            // SipHash takes a 128 bit key, but tables only store a 64 bit seed.
+            HashAlgorithm::SipHash13 => SeededHasher::SipHash13(SipHasher13::new_with_keys(self.seed, !self.seed)),
+            HashAlgorithm::WyHash => SeededHasher::WyHash(WyHash::with_seed(self.seed)),
+            HashAlgorithm::Xxh3 => SeededHasher::Xxh3(xxh3::Hash64::with_seed(self.seed)),
+        }
+    }
+}
+
+/// A hasher for any of the supported `HashAlgorithm`s.
+// XXH3 keeps a large buffer, but hashers only live for one key, on the stack.
+#[allow(clippy::large_enum_variant)]
+enum SeededHasher {
+    XxHash(XxHash64),
+    SipHash13(SipHasher13),
+    WyHash(WyHash),
+    Xxh3(xxh3::Hash64),
+}
+
+impl SeededHasher {
+    fn inner(&mut self) -> &mut dyn Hasher {
+        match self {
+            SeededHasher::XxHash(hasher) => hasher,
+            SeededHasher::SipHash13(hasher) => hasher,
+            SeededHasher::WyHash(hasher) => hasher,
+            SeededHasher::Xxh3(hasher) => hasher,
+        }
+    }
+}
+
+impl Hasher for SeededHasher {
+    fn finish(&self) -> u64 {
+        match self {
+            SeededHasher::XxHash(hasher) => hasher.finish(),
+            SeededHasher::SipHash13(hasher) => hasher.finish(),
+            SeededHasher::WyHash(hasher) => hasher.finish(),
+            SeededHasher::Xxh3(hasher) => hasher.finish(),
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.inner().write(bytes)
+    }
+
+    // Forward the integer writes too, in case a hasher treats them specially.
+    fn write_u16(&mut self, i: u16) {
+        self.inner().write_u16(i)
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.inner().write_u64(i)
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.inner().write_usize(i)
+    }
+}
+
+/// Occupancy and probe length figures for a table, from `stats`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Stats {
+    /// The number of stored entries.
+    pub entries: usize,
+    /// The number of slots holding a removed entry, which lookups have to
+    /// probe past until an insert reuses them.
+    pub tombstones: usize,
+    /// The most slots a lookup of any stored key examines.
+    pub max_probe_length: usize,
+    /// The mean number of slots a lookup of a stored key examines.
+    pub mean_probe_length: f64,
+    /// The fraction of each page's slots holding an entry, in page pointer
+    /// order.  Pages that have not been allocated count as empty.
+    pub page_fill: Vec<f64>,
+}
+
+impl Stats {
+    fn new() -> Stats {
+        Stats {
+            entries: 0,
+            tombstones: 0,
+            max_probe_length: 0,
+            mean_probe_length: 0.0,
+            page_fill: Vec::new(),
+        }
+    }
+
+    /// Tallies the slots of `page`, where `probe_length` gives the number
+    /// of slots a lookup examines to find the key stored in a slot.  Returns
+    /// the sum of the page's probe lengths.
+    fn add_page<P, F>(&mut self, page: &P, probe_length: F) -> usize
+    where
+        P: SlotStorage,
+        F: Fn(usize, &P::Key) -> usize,
+    {
+        let mut full = 0;
+        let mut total_probe_length = 0;
+        for slot in 0..page.capacity() {
+            match page.slot_state(slot) {
+                SlotState::Full => {
+                    let length = probe_length(slot, &page.key(slot));
+                    self.max_probe_length = self.max_probe_length.max(length);
+                    total_probe_length += length;
+                    full += 1;
+                }
+                SlotState::Deleted => self.tombstones += 1,
+                SlotState::Empty => {}
+            }
+        }
+        self.entries += full;
+        self.page_fill.push(full as f64 / page.capacity() as f64);
+        total_probe_length
+    }
+
+    fn finish(mut self, total_probe_length: usize) -> Stats {
+        if self.entries > 0 {
+            self.mean_probe_length = total_probe_length as f64 / self.entries as f64;
+        }
+        self
     }
 }
 
-pub struct SinglePageHashTable<'bp, V> {
-    hash_builder: SeededXxHashBuilder,
-    buffer_pool: &'bp mut BufferPool, // TODO: Change to shared reference
-    page_id: crate::record::PageId,
+/// A hash table stored in a single page.
+///
+/// Like the other tables here, it borrows its buffer pool through a
+/// `RefCell`, so several tables and other structures can share one pool.
+pub struct SinglePageHashTable<'bp, V, K = u64> {
+    hash_builder: SeededHashBuilder,
+    buffer_pool: &'bp RefCell<BufferPool>,
+    page_id: PageId,
     _value_type: PhantomData<V>,
+    _key_type: PhantomData<K>,
 }
 
-impl<'bp, V> SinglePageHashTable<'bp, V> {
-    pub fn new(buffer_pool: &'bp mut BufferPool) -> Self {
+impl<'bp, V, K: Key> SinglePageHashTable<'bp, V, K> {
+    pub fn new(buffer_pool: &'bp RefCell<BufferPool>) -> Self {
         let rng = rand::thread_rng();
         SinglePageHashTable::new_with_rng(buffer_pool, rng)
     }
 
-    pub fn new_with_rng<R: rand::Rng>(buffer_pool: &'bp mut BufferPool, mut rng: R) -> Self {
+    pub fn new_with_rng<R: rand::Rng>(buffer_pool: &'bp RefCell<BufferPool>, rng: R) -> Self {
+        SinglePageHashTable::create(buffer_pool, HashAlgorithm::XxHash, rng)
+    }
+
+    pub fn with_hash_algorithm(buffer_pool: &'bp RefCell<BufferPool>, algorithm: HashAlgorithm) -> Self {
+        let rng = rand::thread_rng();
+        SinglePageHashTable::create(buffer_pool, algorithm, rng)
+    }
+
+    fn create<R: rand::Rng>(buffer_pool: &'bp RefCell<BufferPool>, algorithm: HashAlgorithm, mut rng: R) -> Self {
         let hash_seed = rng.gen();
-        let header_page: page::Page<V> = page::Page::new(hash_seed);
+        let header_page: page::Page<V, K> = page::Page::new(page_size(buffer_pool), hash_seed, algorithm);
 
-        // TODO: Allow shared access to the buffer pool.
-        let page_buffer = header_page.into_aligned();
-        let page_id = buffer_pool
-            .append_page(&page_buffer)
-            .expect("cannot write page");
+        let page_id = append_page(buffer_pool, header_page).expect("cannot write page");
 
         SinglePageHashTable {
-            hash_builder: SeededXxHashBuilder::new(hash_seed),
+            hash_builder: SeededHashBuilder::new(algorithm, hash_seed),
             buffer_pool,
             page_id,
             _value_type: PhantomData,
+            _key_type: PhantomData,
         }
     }
 
     pub fn from_page(
-        buffer_pool: &'bp mut BufferPool,
-        page_id: crate::record::PageId,
+        buffer_pool: &'bp RefCell<BufferPool>,
+        page_id: PageId,
     ) -> anyhow::Result<Self> {
-        let page_buffer = {
-            let mut page_buffer = aligned::Buffer::new();
-            buffer_pool.read_page(page_id, &mut page_buffer)?;
-            page_buffer
-        };
-
-        let page = page::Page::<V>::from_aligned(page_buffer)?;
+        let page: page::Page<V, K> = load_page(buffer_pool, page_id)?;
+        check_value_type::<V>(page.value_size())?;
+        check_key_type::<K>(page.key_size())?;
 
-        // TODO: Validate _value_type
         let ht = SinglePageHashTable {
-            hash_builder: SeededXxHashBuilder::new(page.hash_seed()),
+            hash_builder: SeededHashBuilder::new(page.hash_algorithm()?, page.hash_seed()),
             buffer_pool,
             page_id,
             _value_type: PhantomData,
+            _key_type: PhantomData,
         };
         Ok(ht)
     }
 
-    pub fn page_id(&self) -> crate::record::PageId {
+    /// Creates a table holding `entries`, filling the page in memory and
+    /// writing it once.  If a key appears more than once, the last value
+    /// wins.
+    ///
+    /// Returns `Error::Capacity`, without writing anything, if there are more
+    /// distinct keys than the page has slots.
+    pub fn from_iter<I>(buffer_pool: &'bp RefCell<BufferPool>, entries: I) -> anyhow::Result<Self>
+    where
+        V: Serialize,
+        I: IntoIterator<Item = (K, V)>,
+    {
+        let hash_seed = rand::random();
+        let hash_builder = SeededHashBuilder::new(HashAlgorithm::XxHash, hash_seed);
+        let mut page = page::Page::<V, K>::new(page_size(buffer_pool), hash_seed, HashAlgorithm::XxHash);
+
+        // Filling slots in order keeps probe runs short and the writes
+        // sequential within the page.
+        let capacity = page.capacity() as u64;
+        let mut entries: Vec<_> = entries
+            .into_iter()
+            .map(|(key, value)| (hash_builder.hash_one(&key), key, value))
+            .collect();
+        entries.sort_by_key(|&(hash, _, _)| hash % capacity);
+        for (hash, key, value) in &entries {
+            Self::store(&mut page, *hash, key, value)?;
+        }
+
+        let page_id = append_page(buffer_pool, page)?;
+        Ok(SinglePageHashTable {
+            hash_builder,
+            buffer_pool,
+            page_id,
+            _value_type: PhantomData,
+            _key_type: PhantomData,
+        })
+    }
+
+    pub fn page_id(&self) -> PageId {
         self.page_id
     }
 
     pub fn capacity(&self) -> usize {
-        (crate::PAGESIZE - 0x18) / (8 + std::mem::size_of::<V>())
+        page::capacity(page_size(self.buffer_pool), K::SIZE, std::mem::size_of::<V>())
+    }
+
+    /// The number of entries in the table.
+    pub fn len(&self) -> anyhow::Result<usize> {
+        Ok(self.read_page()?.len())
+    }
+
+    pub fn is_empty(&self) -> anyhow::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// The fraction of the table's capacity in use, from 0.0 (empty) to 1.0
+    /// (full).
+    pub fn load_factor(&self) -> anyhow::Result<f64> {
+        Ok(self.len()? as f64 / self.capacity() as f64)
+    }
+
+    /// Counts entries and tombstones and measures probe lengths, reading
+    /// every slot in the table.
+    pub fn stats(&self) -> anyhow::Result<Stats> {
+        let page = self.read_page()?;
+        let capacity = page.capacity();
+        let mut stats = Stats::new();
+        let total = stats.add_page(&page, |slot, key| {
+            let start = (self.hash(key) % capacity as u64) as usize;
+            (slot + capacity - start) % capacity + 1
+        });
+        Ok(stats.finish(total))
     }
 
-    pub fn insert(&mut self, key: u64, value: V) -> anyhow::Result<()>
+    pub fn insert(&mut self, key: K, value: V) -> anyhow::Result<()>
     where
-        V: serde::Serialize + serde::Deserialize<'static>,
+        V: Serialize,
     {
-        let mut page_buffer = aligned::Buffer::new();
-        self.buffer_pool
-            .read_page(self.page_id, &mut page_buffer)
-            .expect("cannot read page");
-        let mut page: page::Page<V> = page::Page::from_aligned(page_buffer)?;
-        let mut hasher = self.hash_builder.build_hasher();
-        key.hash(&mut hasher);
-        let hash = hasher.finish();
-        let slot = hash % self.capacity() as u64;
+        let mut page = self.read_page()?;
+        Self::store(&mut page, self.hash(&key), &key, &value)?;
+        write_page(self.buffer_pool, self.page_id, page)?;
+        Ok(())
+    }
 
-        // TODO: Need ability to get a slot by ID, check if it's empty, and step through following
-        // slots until an empty one is found.  Empty slot bitarray before data slots or interleaved?
-        // If before, pre-check next empty slot, then iterate from current to next-empty - 1.  If key
-        // not found, insert at next-empty.  Bitarray: 2 bits per slot: 00 - empty, 11 - Full, 01 - Deleted.
-        // (bit value xy, x: HasValue, y: ContinueFallthrough)
+    /// Stores `value` if `key` is absent, or else applies `update` to the
+    /// stored value, reading and writing the page once.
+    pub fn insert_or_update<F>(&mut self, key: K, value: V, update: F) -> anyhow::Result<()>
+    where
+        V: Serialize + DeserializeOwned,
+        F: FnOnce(&mut V),
+    {
+        let mut page = self.read_page()?;
+        let capacity = page.capacity();
+        let (slot, value) = match probe(&page, self.hash(&key), &key) {
+            Probe::Found(slot) => {
+                let mut stored = page.read_value(slot)?;
+                update(&mut stored);
+                (slot, stored)
+            }
+            Probe::Absent(slot) => {
+                page.set_len(page.len() + 1);
+                (slot, value)
+            }
+            Probe::Exhausted(tombstone) => {
+                let slot = tombstone.ok_or(Error::Capacity { capacity })?;
+                page.set_len(page.len() + 1);
+                (slot, value)
+            }
+        };
 
+        page.write_entry(slot, &key, &value)?;
+        write_page(self.buffer_pool, self.page_id, page)?;
         Ok(())
     }
 
-    pub fn get(&self, key: u64) -> Option<&V> {
-        None
+    pub fn get(&self, key: K) -> anyhow::Result<Option<V>>
+    where
+        V: DeserializeOwned,
+    {
+        let page = self.read_page()?;
+        match probe(&page, self.hash(&key), &key) {
+            Probe::Found(slot) => Ok(Some(page.read_value(slot)?)),
+            Probe::Absent(_) | Probe::Exhausted(_) => Ok(None),
+        }
     }
-}
 
-mod page {
+    /// Whether `key` is in the table.  The value is not deserialized.
+    pub fn contains_key(&self, key: K) -> anyhow::Result<bool> {
+        let page = self.read_page()?;
+        Ok(matches!(probe(&page, self.hash(&key), &key), Probe::Found(_)))
+    }
 
-    struct FieldSpec {
-        offset: usize,
-        len: usize,
-    }
-    impl FieldSpec {
-        const fn new(offset: usize, len: usize) -> FieldSpec {
-            FieldSpec {
-                offset, len
-            }
-        }
-    }
-
-    #[repr(usize)]
-    enum FieldIndex {
-        Crc32 = 0,
-        PageType = 1,
-        ValueSize = 2,
-        HashAlgorithm = 3,
-        HashSeed = 4,
-        Data = 5,
-    }
-
-    static FIELDS: &[FieldSpec] = &[
-        FieldSpec { // FieldIndex::Crc32
-            offset: 0x0,
-            len: 4,
-        },
-        FieldSpec { // FieldIndex::PageType
-            offset: 0x4,
-            len: 2,
-        },
-        FieldSpec { // FieldIndex::ValueSize
-            offset: 0x6,
-            len: 2,
-        },
-
-        FieldSpec { // FieldIndex::HashAlgorithm
-            offset: 0x8,
-            len: 2,
-        },
-        FieldSpec { // FieldIndex::HashSeed
-            offset : 0x10,
-            len: 8,
-        },
-        FieldSpec { // FieldIndex::Data
-            offset: 0x18,
-            len: crate::PAGESIZE - 0x18,
-        },
-    ];
+    /// Returns the value stored under `key`, or else stores and returns the
+    /// result of `f`.  The page is only written if `f` is called.
+    pub fn get_or_insert_with<F>(&mut self, key: K, f: F) -> anyhow::Result<V>
+    where
+        V: Serialize + DeserializeOwned,
+        F: FnOnce() -> V,
+    {
+        let mut page = self.read_page()?;
+        let hash = self.hash(&key);
+        if let Probe::Found(slot) = probe(&page, hash, &key) {
+            return page.read_value(slot);
+        }
+        let value = f();
+        Self::store(&mut page, hash, &key, &value)?;
+        write_page(self.buffer_pool, self.page_id, page)?;
+        Ok(value)
+    }
 
-    use std::{
-        convert::{TryFrom, TryInto},
-        marker::PhantomData,
-        mem::size_of,
-    };
+    /// Removes `key` from the table, returning its value if it was present.
+    ///
+    /// The slot is left as a tombstone so that probes for keys stored past
+    /// it still find them.  Later inserts may reuse it.
+    pub fn remove(&mut self, key: K) -> anyhow::Result<Option<V>>
+    where
+        V: DeserializeOwned,
+    {
+        let mut page = self.read_page()?;
+        let slot = match probe(&page, self.hash(&key), &key) {
+            Probe::Found(slot) => slot,
+            Probe::Absent(_) | Probe::Exhausted(_) => return Ok(None),
+        };
+        let value = page.read_value(slot)?;
+        page.set_len(page.len() - 1);
+        page.set_slot_state(slot, SlotState::Deleted);
+        write_page(self.buffer_pool, self.page_id, page)?;
+        Ok(Some(value))
+    }
 
-    use crc::crc32;
+    fn hash(&self, key: &K) -> u64 {
+        self.hash_builder.hash_one(key)
+    }
 
-    use super::HashAlgorithm;
-    use crate::{aligned, PageType, PAGESIZE};
+    /// Writes an entry into `page`, without writing the page back.
+    fn store(page: &mut page::Page<V, K>, hash: u64, key: &K, value: &V) -> anyhow::Result<()>
+    where
+        V: Serialize,
+    {
+        let capacity = page.capacity();
+        let slot = match probe(page, hash, key) {
+            Probe::Found(slot) => slot,
+            Probe::Absent(slot) => {
+                page.set_len(page.len() + 1);
+                slot
+            }
+            Probe::Exhausted(tombstone) => {
+                let slot = tombstone.ok_or(Error::Capacity { capacity })?;
+                page.set_len(page.len() + 1);
+                slot
+            }
+        };
+        page.write_entry(slot, key, value)
+    }
 
-    // crc32: u32,
-    // page_type: PageType,
-    // value_size: u16,
-    // hash_algorithm: HashAlgorithm,
-    // padding: [u8; 4],
-    // page_count: usize, // Assume 8-byte usize
-    // hash_seed: u64,
-    // data: [u8; PAGESIZE - 0x20],
-    pub(super) struct Page<V> {
-        buffer: Box<aligned::Buffer>,
-        _value_type: PhantomData<V>,
+    fn read_page(&self) -> anyhow::Result<page::Page<V, K>> {
+        load_page(self.buffer_pool, self.page_id)
     }
+}
 
-    /// Reads a slice of two u8s as a u16 using little endian encoding.
-    ///
-    /// # Panic
-    ///
-    /// Panics if given a slice of the wrong size.
-    fn read_u16(s: &[u8]) -> u16 {
-        u16::from_le_bytes(s.try_into().expect("to_u16 expects a slice of two u8s."))
+/// A single page hash table for values that serialize to different sizes.
+///
+/// The number of slots is fixed when the table is created.  Inserts fail
+/// with `Error::Capacity` once every slot is in use, or with `Error::Space`
+/// once the values themselves don't fit in the page.
+pub struct SinglePageVarHashTable<'bp, V, K = u64> {
+    hash_builder: SeededHashBuilder,
+    buffer_pool: &'bp RefCell<BufferPool>,
+    page_id: PageId,
+    _value_type: PhantomData<V>,
+    _key_type: PhantomData<K>,
+}
+
+impl<'bp, V, K: Key> SinglePageVarHashTable<'bp, V, K> {
+    /// The number of slots used by `SinglePageVarHashTable::new`.
+    pub const DEFAULT_SLOT_COUNT: usize = 256;
+
+    pub fn new(buffer_pool: &'bp RefCell<BufferPool>) -> anyhow::Result<Self> {
+        SinglePageVarHashTable::with_slot_count(buffer_pool, Self::DEFAULT_SLOT_COUNT)
     }
 
-    /// Reads a slice of four u8s as a u32 using little endian encoding.
+    /// Creates a table with room for `slot_count` entries.  The rest of the
+    /// page is left for values.
     ///
     /// # Panic
     ///
-    /// Panics if given a slice of the wrong size.
-    fn read_u32(s: &[u8]) -> u32 {
-        u32::from_le_bytes(s.try_into().expect("to_u32 expects a slice of four u8s."))
+    /// Panics if `slot_count` is zero, or so large that the slots would leave
+    /// no room for values.
+    pub fn with_slot_count(buffer_pool: &'bp RefCell<BufferPool>, slot_count: usize) -> anyhow::Result<Self> {
+        let rng = rand::thread_rng();
+        SinglePageVarHashTable::new_with_rng(buffer_pool, slot_count, rng)
     }
 
-        /// Reads a slice of eight u8s as a u64 using little endian encoding.
-    ///
-    /// # Panic
-    ///
-    /// Panics if given a slice of the wrong size.
-    fn read_u64(s: &[u8]) -> u64 {
-        u64::from_le_bytes(s.try_into().expect("to_u32 expects a slice of four u8s."))
+    pub fn new_with_rng<R: rand::Rng>(
+        buffer_pool: &'bp RefCell<BufferPool>,
+        slot_count: usize,
+        rng: R,
+    ) -> anyhow::Result<Self> {
+        SinglePageVarHashTable::create(buffer_pool, slot_count, HashAlgorithm::XxHash, rng)
     }
 
+    pub fn with_hash_algorithm(
+        buffer_pool: &'bp RefCell<BufferPool>,
+        slot_count: usize,
+        algorithm: HashAlgorithm,
+    ) -> anyhow::Result<Self> {
+        let rng = rand::thread_rng();
+        SinglePageVarHashTable::create(buffer_pool, slot_count, algorithm, rng)
+    }
 
-    impl<V> Page<V> {
-        pub(super) fn page_type(&self) -> PageType {
-            PageType::SinglePageHashTable
-        }
+    fn create<R: rand::Rng>(
+        buffer_pool: &'bp RefCell<BufferPool>,
+        slot_count: usize,
+        algorithm: HashAlgorithm,
+        mut rng: R,
+    ) -> anyhow::Result<Self> {
+        let hash_seed = rng.gen();
+        let page = page::VarPage::<K>::new(page_size(buffer_pool), hash_seed, slot_count, algorithm);
+        let page_id = append_page(buffer_pool, page)?;
 
-        fn set_page_type(&mut self) {
-            let page_type = &(self.page_type() as u32).to_le_bytes();
-            self.buffer[4..8].copy_from_slice(page_type);
-        }
+        Ok(SinglePageVarHashTable {
+            hash_builder: SeededHashBuilder::new(algorithm, hash_seed),
+            buffer_pool,
+            page_id,
+            _value_type: PhantomData,
+            _key_type: PhantomData,
+        })
+    }
 
-        fn set_crc(&mut self) {
-            let crc = crc32::checksum_ieee(&self.buffer[4..]);
-            self.buffer[..4].copy_from_slice(&crc.to_le_bytes())
-        }
+    pub fn from_page(buffer_pool: &'bp RefCell<BufferPool>, page_id: PageId) -> anyhow::Result<Self> {
+        let page: page::VarPage<K> = load_page(buffer_pool, page_id)?;
+        check_key_type::<K>(page.key_size())?;
 
-        pub(super) fn value_size(&self) -> usize {
-            let size = u16::from_le_bytes(self.buffer[8..10].try_into().unwrap());
-            size as usize
-        }
+        Ok(SinglePageVarHashTable {
+            hash_builder: SeededHashBuilder::new(page.hash_algorithm()?, page.hash_seed()),
+            buffer_pool,
+            page_id,
+            _value_type: PhantomData,
+            _key_type: PhantomData,
+        })
+    }
 
-        /// # Panic
-        ///
-        /// This method panics if value_size is greater than 4096 bytes;
-        fn set_value_size(&mut self, size: usize) {
-            assert!(size <= 4096, "value size cannot be greater than 4096 bytes");
-            self.buffer[8..10].copy_from_slice(&(size as u16).to_le_bytes())
-        }
+    pub fn page_id(&self) -> PageId {
+        self.page_id
     }
 
-    impl<V> Page<V> {
-        pub(super) fn new(hash_seed: u64) -> Page<V> {
-            let buffer = aligned::Buffer::new();
-            let mut p = Page {
-                buffer,
-                _value_type: PhantomData,
-            };
-            p.set_page_type();
-            p.set_value_size(size_of::<V>().try_into().unwrap());
-            p.set_hash_algorithm(HashAlgorithm::XxHash);
-            p.set_hash_seed(hash_seed);
-            p
-        }
+    /// The number of slots, which is the most entries the table can hold.
+    pub fn capacity(&self) -> anyhow::Result<usize> {
+        Ok(self.read_page()?.capacity())
+    }
 
-        pub(super) fn into_aligned(self) -> Box<aligned::Buffer> {
-            debug_assert_eq!(size_of::<Self>(), PAGESIZE);
-            debug_assert_eq!(
-                std::mem::align_of::<Self>(),
-                std::mem::align_of::<aligned::Buffer>()
-            );
+    /// The number of bytes left for values, counting space that would be
+    /// reclaimed by compaction.
+    pub fn free_space(&self) -> anyhow::Result<usize> {
+        Ok(self.read_page()?.reclaimable_space(None))
+    }
 
-            // Safety: HeaderPage's size and alignment is the same as PAGESIZE
-            let mut buffer: Box<aligned::Buffer> = unsafe { std::mem::transmute(self) };
+    pub fn insert(&mut self, key: K, value: V) -> anyhow::Result<()>
+    where
+        V: Serialize,
+    {
+        let value = bincode::serialize(&value)?;
+        let mut page = self.read_page()?;
+        let capacity = page.capacity();
+        let slot = match probe(&page, self.hash(&key), &key) {
+            Probe::Found(slot) | Probe::Absent(slot) => slot,
+            Probe::Exhausted(tombstone) => tombstone.ok_or(Error::Capacity { capacity })?,
+        };
 
-            // Calculate and write the checksum for this page
-            let checksum = crc32::checksum_ieee(&buffer[4..]);
-            buffer[0..4].clone_from_slice(&checksum.to_le_bytes());
-            buffer
+        page.write_var_entry(slot, &key, &value)?;
+        write_page(self.buffer_pool, self.page_id, page)?;
+        Ok(())
+    }
+
+    /// Stores `value` if `key` is absent, or else applies `update` to the
+    /// stored value, reading and writing the page once.
+    pub fn insert_or_update<F>(&mut self, key: K, value: V, update: F) -> anyhow::Result<()>
+    where
+        V: Serialize + DeserializeOwned,
+        F: FnOnce(&mut V),
+    {
+        let mut page = self.read_page()?;
+        let capacity = page.capacity();
+        let (slot, value) = match probe(&page, self.hash(&key), &key) {
+            Probe::Found(slot) => {
+                let mut stored = page.read_var_value(slot)?;
+                update(&mut stored);
+                (slot, stored)
+            }
+            Probe::Absent(slot) => (slot, value),
+            Probe::Exhausted(tombstone) => (tombstone.ok_or(Error::Capacity { capacity })?, value),
+        };
+
+        page.write_var_entry(slot, &key, &bincode::serialize(&value)?)?;
+        write_page(self.buffer_pool, self.page_id, page)?;
+        Ok(())
+    }
+
+    pub fn get(&self, key: K) -> anyhow::Result<Option<V>>
+    where
+        V: DeserializeOwned,
+    {
+        let page = self.read_page()?;
+        match probe(&page, self.hash(&key), &key) {
+            Probe::Found(slot) => Ok(Some(page.read_var_value(slot)?)),
+            Probe::Absent(_) | Probe::Exhausted(_) => Ok(None),
+        }
+    }
+
+    /// Removes `key` from the table, returning its value if it was present.
+    ///
+    /// The value's space is reclaimed the next time the page is compacted.
+    pub fn remove(&mut self, key: K) -> anyhow::Result<Option<V>>
+    where
+        V: DeserializeOwned,
+    {
+        let mut page = self.read_page()?;
+        let slot = match probe(&page, self.hash(&key), &key) {
+            Probe::Found(slot) => slot,
+            Probe::Absent(_) | Probe::Exhausted(_) => return Ok(None),
+        };
+        let value = page.read_var_value(slot)?;
+        page.set_slot_state(slot, SlotState::Deleted);
+        write_page(self.buffer_pool, self.page_id, page)?;
+        Ok(Some(value))
+    }
+
+    fn hash(&self, key: &K) -> u64 {
+        self.hash_builder.hash_one(key)
+    }
+
+    fn read_page(&self) -> anyhow::Result<page::VarPage<K>> {
+        load_page(self.buffer_pool, self.page_id)
+    }
+}
+
+/// A hash table spread over a header page and up to `page_count` slot pages.
+pub struct HashTable<'bp, V, K = u64> {
+    hash_builder: SeededHashBuilder,
+    buffer_pool: &'bp RefCell<BufferPool>,
+    page_id: PageId,
+    _value_type: PhantomData<V>,
+    _key_type: PhantomData<K>,
+}
+
+impl<'bp, V, K: Key> HashTable<'bp, V, K> {
+    /// The number of slot pages used by `HashTable::new`.
+    pub const DEFAULT_PAGE_COUNT: usize = 64;
+
+    pub fn new(buffer_pool: &'bp RefCell<BufferPool>) -> anyhow::Result<Self> {
+        HashTable::with_page_count(buffer_pool, Self::DEFAULT_PAGE_COUNT)
+    }
+
+    /// Creates a hash table that can spread its entries over `page_count`
+    /// slot pages.
+    ///
+    /// # Panic
+    ///
+    /// Panics if `page_count` is zero or more than a header page can point to.
+    pub fn with_page_count(buffer_pool: &'bp RefCell<BufferPool>, page_count: usize) -> anyhow::Result<Self> {
+        let rng = rand::thread_rng();
+        HashTable::new_with_rng(buffer_pool, page_count, rng)
+    }
+
+    pub fn new_with_rng<R: rand::Rng>(
+        buffer_pool: &'bp RefCell<BufferPool>,
+        page_count: usize,
+        rng: R,
+    ) -> anyhow::Result<Self> {
+        HashTable::create(buffer_pool, page_count, HashAlgorithm::XxHash, rng)
+    }
+
+    pub fn with_hash_algorithm(
+        buffer_pool: &'bp RefCell<BufferPool>,
+        page_count: usize,
+        algorithm: HashAlgorithm,
+    ) -> anyhow::Result<Self> {
+        let rng = rand::thread_rng();
+        HashTable::create(buffer_pool, page_count, algorithm, rng)
+    }
+
+    fn create<R: rand::Rng>(
+        buffer_pool: &'bp RefCell<BufferPool>,
+        page_count: usize,
+        algorithm: HashAlgorithm,
+        mut rng: R,
+    ) -> anyhow::Result<Self> {
+        let hash_seed = rng.gen();
+        let header_page: page::HeaderPage<V> =
+            page::HeaderPage::new(page_size(buffer_pool), hash_seed, page_count, K::SIZE, algorithm);
+        let page_id = append_page(buffer_pool, header_page)?;
+
+        Ok(HashTable {
+            hash_builder: SeededHashBuilder::new(algorithm, hash_seed),
+            buffer_pool,
+            page_id,
+            _value_type: PhantomData,
+            _key_type: PhantomData,
+        })
+    }
+
+    pub fn from_page(buffer_pool: &'bp RefCell<BufferPool>, page_id: PageId) -> anyhow::Result<Self> {
+        let header: page::HeaderPage<V> = load_page(buffer_pool, page_id)?;
+        check_value_type::<V>(header.value_size())?;
+        check_key_type::<K>(header.key_size())?;
+
+        Ok(HashTable {
+            hash_builder: SeededHashBuilder::new(header.hash_algorithm()?, header.hash_seed()),
+            buffer_pool,
+            page_id,
+            _value_type: PhantomData,
+            _key_type: PhantomData,
+        })
+    }
+
+    /// Creates a table over `page_count` slot pages holding `entries`.
+    ///
+    /// Entries are sorted by the page and slot they hash to and placed in
+    /// memory, so each slot page is written once.  The header page is
+    /// written before the slot pages, so that none of them can be page 0,
+    /// and again once they have all been placed.  If a key appears more
+    /// than once, the last value wins.
+    ///
+    /// Returns `Error::Capacity` if the entries don't fit, before any slot
+    /// page is written.
+    ///
+    /// # Panic
+    ///
+    /// Panics if `page_count` is zero or more than a header page can point to.
+    pub fn bulk_load<I>(buffer_pool: &'bp RefCell<BufferPool>, page_count: usize, entries: I) -> anyhow::Result<Self>
+    where
+        V: Serialize,
+        I: IntoIterator<Item = (K, V)>,
+    {
+        let hash_seed = rand::random();
+        let hash_builder = SeededHashBuilder::new(HashAlgorithm::XxHash, hash_seed);
+        let page_size = page_size(buffer_pool);
+        let mut header: page::HeaderPage<V> =
+            page::HeaderPage::new(page_size, hash_seed, page_count, K::SIZE, HashAlgorithm::XxHash);
+        let capacity = page_count * header.slot_page_capacity(K::SIZE);
+
+        let mut entries: Vec<_> = entries
+            .into_iter()
+            .map(|(key, value)| {
+                let hash = hash_builder.hash_one(&key);
+                let start = slot_sequence_start::<V, K>(hash, &header);
+                (hash % page_count as u64, start, hash, key, value)
+            })
+            .collect();
+        entries.sort_by_key(|&(index, start, _, _, _)| (index, start));
+
+        let mut pages: Vec<Option<page::SlotPage<V, K>>> = (0..page_count).map(|_| None).collect();
+        let mut filters: Vec<Option<page::FilterPage>> = (0..header.filter_page_count()).map(|_| None).collect();
+        let mut len = 0;
+        'entries: for (_, start, hash, key, value) in &entries {
+            for index in page_sequence(*hash, page_count) {
+                let page = pages[index].get_or_insert_with(|| page::SlotPage::new(page_size, index));
+                match probe_from(page, *start, key) {
+                    Probe::Found(slot) | Probe::Absent(slot) => {
+                        if page.slot_state(slot) != SlotState::Full {
+                            len += 1;
+                        }
+                        page.write_entry(slot, key, value)?;
+                        if let Some((filter_index, range)) = header.filter_location(index) {
+                            filters[filter_index].get_or_insert_with(page::FilterPage::new).add(range, *hash);
+                        }
+                        continue 'entries;
+                    }
+                    // Nothing has been removed, so there are no tombstones.
+                    Probe::Exhausted(_) => {}
+                }
+            }
+            return Err(Error::Capacity { capacity }.into());
+        }
+
+        let checksum = buffer_pool.borrow_mut().checksum()?;
+        let page_id = buffer_pool.borrow_mut().append_page(&header.to_aligned(checksum))?;
+        for (index, page) in pages.into_iter().enumerate() {
+            if let Some(page) = page {
+                header.set_page_pointer(index, append_page(buffer_pool, page)?);
+            }
+        }
+        for (filter_index, filter) in filters.into_iter().enumerate() {
+            if let Some(filter) = filter {
+                header.set_filter_page_pointer(filter_index, append_page(buffer_pool, filter)?);
+            }
+        }
+        header.set_len(len);
+        write_page(buffer_pool, page_id, header)?;
+
+        Ok(HashTable {
+            hash_builder,
+            buffer_pool,
+            page_id,
+            _value_type: PhantomData,
+            _key_type: PhantomData,
+        })
+    }
+
+    /// The page id of the header page, used to reopen the table.
+    pub fn page_id(&self) -> PageId {
+        self.page_id
+    }
+
+    pub fn capacity(&self) -> anyhow::Result<usize> {
+        let header = self.read_header()?;
+        Ok(header.page_count() * header.slot_page_capacity(K::SIZE))
+    }
+
+    /// The number of entries in the table.
+    pub fn len(&self) -> anyhow::Result<usize> {
+        Ok(self.read_header()?.len() as usize)
+    }
+
+    pub fn is_empty(&self) -> anyhow::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// The fraction of the table's capacity in use, from 0.0 (empty) to 1.0
+    /// (full).
+    pub fn load_factor(&self) -> anyhow::Result<f64> {
+        let header = self.read_header()?;
+        let capacity = header.page_count() * header.slot_page_capacity(K::SIZE);
+        Ok(header.len() as f64 / capacity as f64)
+    }
+
+    /// Counts entries and tombstones and measures probe lengths, reading
+    /// every slot page in the table.
+    ///
+    /// The probe length of a key that spilled over includes every slot of
+    /// the pages it spilled past.
+    pub fn stats(&self) -> anyhow::Result<Stats> {
+        let header = self.read_header()?;
+        let page_count = header.page_count();
+        let capacity = header.slot_page_capacity(K::SIZE);
+        let mut stats = Stats::new();
+        let mut total = 0;
+        for index in 0..page_count {
+            let slot_page_id = header.page_pointer(index);
+            if slot_page_id == 0 {
+                stats.page_fill.push(0.0);
+                continue;
+            }
+            let page = self.read_slot_page(slot_page_id)?;
+            total += stats.add_page(&page, |slot, key| {
+                let hash = self.hash(key);
+                let start = slot_sequence_start::<V, K>(hash, &header);
+                let pages_passed = (index + page_count - (hash % page_count as u64) as usize) % page_count;
+                pages_passed * capacity + (slot + capacity - start) % capacity + 1
+            });
+        }
+        Ok(stats.finish(total))
+    }
+
+    pub fn insert(&mut self, key: K, value: V) -> anyhow::Result<()>
+    where
+        V: Serialize,
+    {
+        match self.entry(key)? {
+            Entry::Occupied(entry) => entry.insert(value),
+            Entry::Vacant(entry) => entry.insert(value),
+        }
+    }
+
+    /// Stores `value` if `key` is absent, or else applies `update` to the
+    /// stored value, with a single lookup.
+    pub fn insert_or_update<F>(&mut self, key: K, value: V, update: F) -> anyhow::Result<()>
+    where
+        V: Serialize + DeserializeOwned,
+        F: FnOnce(&mut V),
+    {
+        self.entry(key)?.and_modify(update)?.or_insert(value)
+    }
+
+    pub fn get(&self, key: K) -> anyhow::Result<Option<V>>
+    where
+        V: DeserializeOwned,
+    {
+        Ok(match self.find(&key)? {
+            Some((page, slot, _)) => Some(page.read_value(slot)?),
+            None => None,
+        })
+    }
+
+    /// Whether `key` is in the table.  The value is not deserialized.
+    pub fn contains_key(&self, key: K) -> anyhow::Result<bool> {
+        Ok(self.find(&key)?.is_some())
+    }
+
+    /// Returns the value stored under `key`, or else stores and returns the
+    /// result of `f`.  Nothing is written if the key is present.
+    pub fn get_or_insert_with<F>(&mut self, key: K, f: F) -> anyhow::Result<V>
+    where
+        V: Serialize + DeserializeOwned,
+        F: FnOnce() -> V,
+    {
+        match self.entry(key)? {
+            Entry::Occupied(entry) => entry.get(),
+            Entry::Vacant(entry) => {
+                let value = f();
+                entry.store(&value)?;
+                Ok(value)
+            }
+        }
+    }
+
+    /// Removes `key` from the table, returning its value if it was present.
+    pub fn remove(&mut self, key: K) -> anyhow::Result<Option<V>>
+    where
+        V: DeserializeOwned,
+    {
+        match self.entry(key)? {
+            Entry::Occupied(entry) => Ok(Some(entry.remove()?)),
+            Entry::Vacant(_) => Ok(None),
+        }
+    }
+
+    /// Looks up `key`, returning an entry that can read, replace or remove
+    /// its value, or store one if it is absent, without looking it up again.
+    pub fn entry(&mut self, key: K) -> anyhow::Result<Entry<'_, 'bp, V, K>> {
+        let header = self.read_header()?;
+        let hash = self.hash(&key);
+        let start = slot_sequence_start::<V, K>(hash, &header);
+
+        // Where the entry will go if the key isn't already present: the
+        // first tombstone on the probe path, or else the first empty slot.
+        let mut vacancy: Option<(usize, usize)> = None;
+        for index in page_sequence(hash, header.page_count()) {
+            let slot_page_id = header.page_pointer(index);
+            if slot_page_id == 0 {
+                // Nothing has ever been stored past here.
+                vacancy = vacancy.or(Some((index, start)));
+                break;
+            }
+            let page = self.read_slot_page(slot_page_id)?;
+            match probe_from(&page, start, &key) {
+                Probe::Found(slot) => {
+                    return Ok(Entry::Occupied(OccupiedEntry {
+                        table: self,
+                        key,
+                        page,
+                        page_id: slot_page_id,
+                        slot,
+                    }))
+                }
+                Probe::Absent(slot) => {
+                    vacancy = vacancy.or(Some((index, slot)));
+                    break;
+                }
+                Probe::Exhausted(tombstone) => {
+                    vacancy = vacancy.or(tombstone.map(|slot| (index, slot)));
+                }
+            }
+        }
+        Ok(Entry::Vacant(VacantEntry {
+            table: self,
+            key,
+            header,
+            vacancy,
+        }))
+    }
+
+    /// Finds the slot page and slot holding `key`, without the bookkeeping
+    /// `entry` does to find a vacancy.  Slot pages whose bloom filter rules
+    /// the key out are not read.
+    fn find(&self, key: &K) -> anyhow::Result<Found<page::SlotPage<V, K>>> {
+        let header = self.read_header()?;
+        let hash = self.hash(key);
+        let start = slot_sequence_start::<V, K>(hash, &header);
+        let mut filter = None;
+        for index in page_sequence(hash, header.page_count()) {
+            let slot_page_id = header.page_pointer(index);
+            if slot_page_id == 0 {
+                break;
+            }
+            if !self.may_contain(&header, index, hash, &mut filter)? {
+                continue;
+            }
+            let page = self.read_slot_page(slot_page_id)?;
+            match probe_from(&page, start, key) {
+                Probe::Found(slot) => return Ok(Some((page, slot, slot_page_id))),
+                Probe::Absent(_) => break,
+                Probe::Exhausted(_) => {}
+            }
+        }
+        Ok(None)
+    }
+
+    fn hash(&self, key: &K) -> u64 {
+        self.hash_builder.hash_one(key)
+    }
+
+    // Whether the slot page at `index` may hold a key with `hash`, by its
+    // bloom filter.  `filter` keeps the last filter page read, as
+    // neighbouring slot pages share one.
+    fn may_contain(
+        &self,
+        header: &page::HeaderPage<V>,
+        index: usize,
+        hash: u64,
+        filter: &mut Option<(PageId, page::FilterPage)>,
+    ) -> anyhow::Result<bool> {
+        let (filter_index, range) = match header.filter_location(index) {
+            Some(location) => location,
+            None => return Ok(true),
+        };
+        let filter_page_id = header.filter_page_pointer(filter_index);
+        if filter_page_id == 0 {
+            // Nothing has been added to the filters of this filter page.
+            return Ok(false);
+        }
+        match filter {
+            Some((page_id, page)) if *page_id == filter_page_id => Ok(page.may_contain(range, hash)),
+            _ => {
+                let page: page::FilterPage = load_page(self.buffer_pool, filter_page_id)?;
+                let may_contain = page.may_contain(range, hash);
+                *filter = Some((filter_page_id, page));
+                Ok(may_contain)
+            }
+        }
+    }
+
+    // Adds a key with `hash` to the bloom filter of the slot page at
+    // `index`, allocating its filter page in `header` if need be.
+    fn add_to_filter(&self, header: &mut page::HeaderPage<V>, index: usize, hash: u64) -> anyhow::Result<()> {
+        let (filter_index, range) = match header.filter_location(index) {
+            Some(location) => location,
+            None => return Ok(()),
+        };
+        let filter_page_id = header.filter_page_pointer(filter_index);
+        if filter_page_id == 0 {
+            let mut filter = page::FilterPage::new();
+            filter.add(range, hash);
+            header.set_filter_page_pointer(filter_index, append_page(self.buffer_pool, filter)?);
+            return Ok(());
+        }
+        let mut filter: page::FilterPage = load_page(self.buffer_pool, filter_page_id)?;
+        if filter.add(range, hash) {
+            write_page(self.buffer_pool, filter_page_id, filter)?;
+        }
+        Ok(())
+    }
+
+    fn read_header(&self) -> anyhow::Result<page::HeaderPage<V>> {
+        load_page(self.buffer_pool, self.page_id)
+    }
+
+    fn read_slot_page(&self, page_id: PageId) -> anyhow::Result<page::SlotPage<V, K>> {
+        load_page(self.buffer_pool, page_id)
+    }
+}
+
+/// A view into a single key of a `HashTable`, from `HashTable::entry`.
+pub enum Entry<'a, 'bp, V, K = u64> {
+    Occupied(OccupiedEntry<'a, 'bp, V, K>),
+    Vacant(VacantEntry<'a, 'bp, V, K>),
+}
+
+impl<'a, 'bp, V, K: Key> Entry<'a, 'bp, V, K> {
+    pub fn key(&self) -> &K {
+        match self {
+            Entry::Occupied(entry) => entry.key(),
+            Entry::Vacant(entry) => entry.key(),
+        }
+    }
+
+    /// Applies `f` to the stored value, if there is one, and writes it back.
+    pub fn and_modify<F>(self, f: F) -> anyhow::Result<Self>
+    where
+        V: Serialize + DeserializeOwned,
+        F: FnOnce(&mut V),
+    {
+        Ok(match self {
+            Entry::Occupied(mut entry) => {
+                let mut value = entry.get()?;
+                f(&mut value);
+                entry.write(&value)?;
+                Entry::Occupied(entry)
+            }
+            vacant => vacant,
+        })
+    }
+
+    /// Stores `value` if the key is absent.
+    pub fn or_insert(self, value: V) -> anyhow::Result<()>
+    where
+        V: Serialize,
+    {
+        self.or_insert_with(|| value)
+    }
+
+    /// Stores the result of `f` if the key is absent.
+    pub fn or_insert_with<F>(self, f: F) -> anyhow::Result<()>
+    where
+        V: Serialize,
+        F: FnOnce() -> V,
+    {
+        match self {
+            Entry::Occupied(_) => Ok(()),
+            Entry::Vacant(entry) => entry.insert(f()),
+        }
+    }
+}
+
+/// An entry for a key that is stored in the table.
+pub struct OccupiedEntry<'a, 'bp, V, K> {
+    table: &'a mut HashTable<'bp, V, K>,
+    key: K,
+    page: page::SlotPage<V, K>,
+    page_id: PageId,
+    slot: usize,
+}
+
+impl<'a, 'bp, V, K: Key> OccupiedEntry<'a, 'bp, V, K> {
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    pub fn get(&self) -> anyhow::Result<V>
+    where
+        V: DeserializeOwned,
+    {
+        self.page.read_value(self.slot)
+    }
+
+    /// Replaces the stored value.
+    pub fn insert(mut self, value: V) -> anyhow::Result<()>
+    where
+        V: Serialize,
+    {
+        self.write(&value)
+    }
+
+    /// Removes the key from the table, returning its value.
+    pub fn remove(mut self) -> anyhow::Result<V>
+    where
+        V: DeserializeOwned,
+    {
+        let value = self.page.read_value(self.slot)?;
+        self.page.set_slot_state(self.slot, SlotState::Deleted);
+        write_page(self.table.buffer_pool, self.page_id, self.page)?;
+        let mut header = self.table.read_header()?;
+        header.set_len(header.len() - 1);
+        write_page(self.table.buffer_pool, self.table.page_id, header)?;
+        Ok(value)
+    }
+
+    fn write(&mut self, value: &V) -> anyhow::Result<()>
+    where
+        V: Serialize,
+    {
+        self.page.write_entry(self.slot, &self.key, value)?;
+        let checksum = self.table.buffer_pool.borrow_mut().checksum()?;
+        let buffer = self.page.to_aligned(checksum);
+        self.table.buffer_pool.borrow_mut().update_page(self.page_id, &buffer)?;
+        Ok(())
+    }
+}
+
+/// An entry for a key that is not in the table.
+pub struct VacantEntry<'a, 'bp, V, K> {
+    table: &'a mut HashTable<'bp, V, K>,
+    key: K,
+    header: page::HeaderPage<V>,
+    /// The page pointer index and slot to store the key in, if the table
+    /// has room for it.
+    vacancy: Option<(usize, usize)>,
+}
+
+impl<'a, 'bp, V, K: Key> VacantEntry<'a, 'bp, V, K> {
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// Stores `value` under the entry's key.
+    ///
+    /// Returns `Error::Capacity` if every slot in the table is in use.
+    pub fn insert(self, value: V) -> anyhow::Result<()>
+    where
+        V: Serialize,
+    {
+        self.store(&value)
+    }
+
+    fn store(self, value: &V) -> anyhow::Result<()>
+    where
+        V: Serialize,
+    {
+        let VacantEntry {
+            table,
+            key,
+            mut header,
+            vacancy,
+        } = self;
+        let capacity = header.page_count() * header.slot_page_capacity(K::SIZE);
+        let (index, slot) = vacancy.ok_or(Error::Capacity { capacity })?;
+        table.add_to_filter(&mut header, index, table.hash(&key))?;
+        let slot_page_id = header.page_pointer(index);
+        if slot_page_id == 0 {
+            let mut page = page::SlotPage::<V, K>::new(header.page_size(), index);
+            page.write_entry(slot, &key, value)?;
+            let slot_page_id = append_page(table.buffer_pool, page)?;
+            header.set_page_pointer(index, slot_page_id);
+        } else {
+            let mut page = table.read_slot_page(slot_page_id)?;
+            page.write_entry(slot, &key, value)?;
+            write_page(table.buffer_pool, slot_page_id, page)?;
+        }
+        header.set_len(header.len() + 1);
+        write_page(table.buffer_pool, table.page_id, header)?;
+        Ok(())
+    }
+}
+
+/// A hash table that grows by splitting buckets, using extendible hashing.
+///
+/// Inserts only fail with `Error::Capacity` once the directory has reached
+/// the deepest that fits in a page and the target bucket is full.
+pub struct ExtendibleHashTable<'bp, V, K = u64> {
+    hash_builder: SeededHashBuilder,
+    buffer_pool: &'bp RefCell<BufferPool>,
+    page_id: PageId,
+    _value_type: PhantomData<V>,
+    _key_type: PhantomData<K>,
+}
+
+impl<'bp, V, K: Key> ExtendibleHashTable<'bp, V, K> {
+    /// The deepest the directory can get while still fitting in one 16K
+    /// page.  Smaller pages hold shallower directories.
+    pub const MAX_GLOBAL_DEPTH: u32 = page::max_global_depth(PAGESIZE);
+
+    pub fn new(buffer_pool: &'bp RefCell<BufferPool>) -> anyhow::Result<Self> {
+        let rng = rand::thread_rng();
+        ExtendibleHashTable::new_with_rng(buffer_pool, rng)
+    }
+
+    pub fn new_with_rng<R: rand::Rng>(buffer_pool: &'bp RefCell<BufferPool>, rng: R) -> anyhow::Result<Self> {
+        ExtendibleHashTable::create(buffer_pool, HashAlgorithm::XxHash, rng)
+    }
+
+    pub fn with_hash_algorithm(buffer_pool: &'bp RefCell<BufferPool>, algorithm: HashAlgorithm) -> anyhow::Result<Self> {
+        let rng = rand::thread_rng();
+        ExtendibleHashTable::create(buffer_pool, algorithm, rng)
+    }
+
+    fn create<R: rand::Rng>(
+        buffer_pool: &'bp RefCell<BufferPool>,
+        algorithm: HashAlgorithm,
+        mut rng: R,
+    ) -> anyhow::Result<Self> {
+        let hash_seed = rng.gen();
+        let page_size = page_size(buffer_pool);
+        let bucket = page::BucketPage::<V, K>::new(page_size, 0);
+        let bucket_id = append_page(buffer_pool, bucket)?;
+        let mut directory = page::DirectoryPage::<V>::new(page_size, hash_seed, K::SIZE, algorithm);
+        directory.set_bucket(0, bucket_id);
+        let page_id = append_page(buffer_pool, directory)?;
+
+        Ok(ExtendibleHashTable {
+            hash_builder: SeededHashBuilder::new(algorithm, hash_seed),
+            buffer_pool,
+            page_id,
+            _value_type: PhantomData,
+            _key_type: PhantomData,
+        })
+    }
+
+    pub fn from_page(buffer_pool: &'bp RefCell<BufferPool>, page_id: PageId) -> anyhow::Result<Self> {
+        let directory: page::DirectoryPage<V> = load_page(buffer_pool, page_id)?;
+        check_value_type::<V>(directory.value_size())?;
+        check_key_type::<K>(directory.key_size())?;
+
+        Ok(ExtendibleHashTable {
+            hash_builder: SeededHashBuilder::new(directory.hash_algorithm()?, directory.hash_seed()),
+            buffer_pool,
+            page_id,
+            _value_type: PhantomData,
+            _key_type: PhantomData,
+        })
+    }
+
+    /// The page id of the directory page, used to reopen the table.
+    pub fn page_id(&self) -> PageId {
+        self.page_id
+    }
+
+    /// The number of hash bits currently used to index the directory.
+    pub fn global_depth(&self) -> anyhow::Result<u32> {
+        Ok(self.read_directory()?.global_depth())
+    }
+
+    /// The number of entries in the table.
+    pub fn len(&self) -> anyhow::Result<usize> {
+        Ok(self.read_directory()?.entry_count() as usize)
+    }
+
+    pub fn is_empty(&self) -> anyhow::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Entries per bucket slot, from 0.0 (empty) to 1.0 (every bucket
+    /// full).
+    pub fn load_factor(&self) -> anyhow::Result<f64> {
+        let directory = self.read_directory()?;
+        let slots = directory.bucket_count()
+            * page::slot_page_capacity(directory.page_size(), K::SIZE, directory.value_size());
+        Ok(directory.entry_count() as f64 / slots as f64)
+    }
+
+    pub fn insert(&mut self, key: K, value: V) -> anyhow::Result<()>
+    where
+        V: Serialize,
+    {
+        let hash = self.hash(&key);
+        loop {
+            let mut directory = self.read_directory()?;
+            let bucket_id = directory.bucket(bucket_index(hash, directory.global_depth()));
+            let mut bucket = self.read_bucket(bucket_id)?;
+            let (slot, added) = match probe_from(&bucket, bucket_slot_start(hash, &bucket), &key) {
+                Probe::Found(slot) => (Some(slot), false),
+                Probe::Absent(slot) => (Some(slot), true),
+                Probe::Exhausted(tombstone) => (tombstone, true),
+            };
+            if let Some(slot) = slot {
+                bucket.write_entry(slot, &key, &value)?;
+                write_page(self.buffer_pool, bucket_id, bucket)?;
+                if added {
+                    directory.set_entry_count(directory.entry_count() + 1);
+                    write_page(self.buffer_pool, self.page_id, directory)?;
+                }
+                return Ok(());
+            }
+            self.split(directory, bucket_id, bucket)?;
+        }
+    }
+
+    /// Stores `value` if `key` is absent, or else applies `update` to the
+    /// stored value.
+    pub fn insert_or_update<F>(&mut self, key: K, value: V, update: F) -> anyhow::Result<()>
+    where
+        V: Serialize + DeserializeOwned,
+        F: FnOnce(&mut V),
+    {
+        if let Some((mut bucket, slot, bucket_id)) = self.find(&key)? {
+            let mut stored = bucket.read_value(slot)?;
+            update(&mut stored);
+            bucket.write_entry(slot, &key, &stored)?;
+            write_page(self.buffer_pool, bucket_id, bucket)?;
+            return Ok(());
+        }
+        self.insert(key, value)
+    }
+
+    pub fn get(&self, key: K) -> anyhow::Result<Option<V>>
+    where
+        V: DeserializeOwned,
+    {
+        Ok(match self.find(&key)? {
+            Some((bucket, slot, _)) => Some(bucket.read_value(slot)?),
+            None => None,
+        })
+    }
+
+    /// Removes `key` from the table, returning its value if it was present.
+    ///
+    /// Buckets are never merged, so the table does not shrink.
+    pub fn remove(&mut self, key: K) -> anyhow::Result<Option<V>>
+    where
+        V: DeserializeOwned,
+    {
+        let (mut bucket, slot, bucket_id) = match self.find(&key)? {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        let value = bucket.read_value(slot)?;
+        bucket.set_slot_state(slot, SlotState::Deleted);
+        write_page(self.buffer_pool, bucket_id, bucket)?;
+        let mut directory = self.read_directory()?;
+        directory.set_entry_count(directory.entry_count() - 1);
+        write_page(self.buffer_pool, self.page_id, directory)?;
+        Ok(Some(value))
+    }
+
+    fn hash(&self, key: &K) -> u64 {
+        self.hash_builder.hash_one(key)
+    }
+
+    /// Returns the bucket, slot and bucket page id holding `key`, if any.
+    fn find(&self, key: &K) -> anyhow::Result<Found<page::BucketPage<V, K>>> {
+        let hash = self.hash(key);
+        let directory = self.read_directory()?;
+        let bucket_id = directory.bucket(bucket_index(hash, directory.global_depth()));
+        let bucket = self.read_bucket(bucket_id)?;
+        Ok(match probe_from(&bucket, bucket_slot_start(hash, &bucket), key) {
+            Probe::Found(slot) => Some((bucket, slot, bucket_id)),
+            Probe::Absent(_) | Probe::Exhausted(_) => None,
+        })
+    }
+
+    /// Splits a full bucket on its next hash bit, doubling the directory
+    /// first if needed.  Tombstones are dropped along the way.
+    fn split(
+        &mut self,
+        mut directory: page::DirectoryPage<V>,
+        bucket_id: PageId,
+        bucket: page::BucketPage<V, K>,
+    ) -> anyhow::Result<()> {
+        let local_depth = bucket.local_depth();
+        if local_depth == directory.global_depth() {
+            if local_depth == directory.max_global_depth() {
+                let capacity = (1 << local_depth) * bucket.capacity();
+                return Err(Error::Capacity { capacity }.into());
+            }
+            directory.double();
+        }
+
+        // Entries whose hash has the new bit set move to the new bucket.
+        let split_bit = 1 << local_depth;
+        let mut low = page::BucketPage::<V, K>::new(bucket.page_size(), local_depth + 1);
+        let mut high = page::BucketPage::<V, K>::new(bucket.page_size(), local_depth + 1);
+        for slot in (0..bucket.capacity()).filter(|&slot| bucket.slot_state(slot) == SlotState::Full) {
+            let key = bucket.key(slot);
+            let hash = self.hash(&key);
+            let target = if hash & split_bit == 0 { &mut low } else { &mut high };
+            match probe_from(&*target, bucket_slot_start(hash, &*target), &key) {
+                Probe::Absent(new_slot) => target.copy_entry(new_slot, &bucket, slot),
+                Probe::Found(_) | Probe::Exhausted(_) => unreachable!("split bucket cannot overflow"),
+            }
+        }
+
+        let high_id = append_page(self.buffer_pool, high)?;
+        write_page(self.buffer_pool, bucket_id, low)?;
+        for index in 0..directory.len() {
+            if directory.bucket(index) == bucket_id && index as u64 & split_bit != 0 {
+                directory.set_bucket(index, high_id);
+            }
+        }
+        write_page(self.buffer_pool, self.page_id, directory)?;
+        Ok(())
+    }
+
+    fn read_directory(&self) -> anyhow::Result<page::DirectoryPage<V>> {
+        load_page(self.buffer_pool, self.page_id)
+    }
+
+    fn read_bucket(&self, page_id: PageId) -> anyhow::Result<page::BucketPage<V, K>> {
+        load_page(self.buffer_pool, page_id)
+    }
+}
+
+/// The directory entry for a hash: its low `global_depth` bits.
+fn bucket_index(hash: u64, global_depth: u32) -> usize {
+    (hash & ((1 << global_depth) - 1)) as usize
+}
+
+/// Where to start probing within a bucket.  Uses the high half of the hash,
+/// since the low bits are shared by everything in the bucket.
+fn bucket_slot_start<P: SlotStorage>(hash: u64, bucket: &P) -> usize {
+    ((hash >> 32) % bucket.capacity() as u64) as usize
+}
+
+/// A hash table that grows one bucket at a time, using linear hashing.
+///
+/// Unlike `ExtendibleHashTable`, an insert splits at most one bucket, so
+/// growth never stalls on rewriting a large part of the table.
+pub struct LinearHashTable<'bp, V, K = u64> {
+    hash_builder: SeededHashBuilder,
+    buffer_pool: &'bp RefCell<BufferPool>,
+    page_id: PageId,
+    _value_type: PhantomData<V>,
+    _key_type: PhantomData<K>,
+}
+
+impl<'bp, V, K: Key> LinearHashTable<'bp, V, K> {
+    /// The most buckets a 16K header page can point to, and fewer in smaller
+    /// pages.  Past this, buckets stop splitting and their overflow chains
+    /// grow instead.
+    pub const MAX_BUCKETS: usize = page::LinearHeaderPage::<()>::max_buckets(PAGESIZE);
+
+    pub fn new(buffer_pool: &'bp RefCell<BufferPool>) -> anyhow::Result<Self> {
+        let rng = rand::thread_rng();
+        LinearHashTable::new_with_rng(buffer_pool, rng)
+    }
+
+    pub fn new_with_rng<R: rand::Rng>(buffer_pool: &'bp RefCell<BufferPool>, rng: R) -> anyhow::Result<Self> {
+        LinearHashTable::create(buffer_pool, HashAlgorithm::XxHash, rng)
+    }
+
+    pub fn with_hash_algorithm(buffer_pool: &'bp RefCell<BufferPool>, algorithm: HashAlgorithm) -> anyhow::Result<Self> {
+        let rng = rand::thread_rng();
+        LinearHashTable::create(buffer_pool, algorithm, rng)
+    }
+
+    fn create<R: rand::Rng>(
+        buffer_pool: &'bp RefCell<BufferPool>,
+        algorithm: HashAlgorithm,
+        mut rng: R,
+    ) -> anyhow::Result<Self> {
+        let hash_seed = rng.gen();
+        let page_size = page_size(buffer_pool);
+        let bucket_id = append_page(buffer_pool, page::LinearBucketPage::<V, K>::new(page_size))?;
+        let mut header = page::LinearHeaderPage::<V>::new(page_size, hash_seed, K::SIZE, algorithm);
+        header.set_bucket(0, bucket_id);
+        let page_id = append_page(buffer_pool, header)?;
+
+        Ok(LinearHashTable {
+            hash_builder: SeededHashBuilder::new(algorithm, hash_seed),
+            buffer_pool,
+            page_id,
+            _value_type: PhantomData,
+            _key_type: PhantomData,
+        })
+    }
+
+    pub fn from_page(buffer_pool: &'bp RefCell<BufferPool>, page_id: PageId) -> anyhow::Result<Self> {
+        let header: page::LinearHeaderPage<V> = load_page(buffer_pool, page_id)?;
+        check_value_type::<V>(header.value_size())?;
+        check_key_type::<K>(header.key_size())?;
+
+        Ok(LinearHashTable {
+            hash_builder: SeededHashBuilder::new(header.hash_algorithm()?, header.hash_seed()),
+            buffer_pool,
+            page_id,
+            _value_type: PhantomData,
+            _key_type: PhantomData,
+        })
+    }
+
+    /// The page id of the header page, used to reopen the table.
+    pub fn page_id(&self) -> PageId {
+        self.page_id
+    }
+
+    /// The number of buckets, not counting overflow pages.
+    pub fn bucket_count(&self) -> anyhow::Result<usize> {
+        Ok(self.read_header()?.bucket_count())
+    }
+
+    /// The number of entries in the table.
+    pub fn len(&self) -> anyhow::Result<usize> {
+        Ok(self.read_header()?.len() as usize)
+    }
+
+    pub fn is_empty(&self) -> anyhow::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Entries per bucket slot, not counting overflow pages.  The table
+    /// splits a bucket whenever this goes over 0.75, but it can go past 1.0
+    /// once the table has as many buckets as its header can hold.
+    pub fn load_factor(&self) -> anyhow::Result<f64> {
+        let header = self.read_header()?;
+        let slots = header.bucket_count() * page::slot_page_capacity(header.page_size(), K::SIZE, header.value_size());
+        Ok(header.len() as f64 / slots as f64)
+    }
+
+    pub fn insert(&mut self, key: K, value: V) -> anyhow::Result<()>
+    where
+        V: Serialize,
+    {
+        let hash = self.hash(&key);
+        let mut header = self.read_header()?;
+        let mut page_id = header.bucket(header.bucket_index(hash));
+        let mut vacancy = None;
+        let last = loop {
+            let mut bucket = self.read_bucket(page_id)?;
+            match probe_from(&bucket, bucket_slot_start(hash, &bucket), &key) {
+                Probe::Found(slot) => {
+                    bucket.write_entry(slot, &key, &value)?;
+                    write_page(self.buffer_pool, page_id, bucket)?;
+                    return Ok(());
+                }
+                Probe::Absent(slot) => {
+                    vacancy.get_or_insert((page_id, slot));
+                    break None;
+                }
+                Probe::Exhausted(tombstone) => {
+                    if vacancy.is_none() {
+                        vacancy = tombstone.map(|slot| (page_id, slot));
+                    }
+                }
+            }
+            match bucket.next_page() {
+                Some(next) => page_id = next,
+                None => break Some(bucket),
+            }
+        };
+
+        if let Some((vacant_id, slot)) = vacancy {
+            let mut bucket = self.read_bucket(vacant_id)?;
+            bucket.write_entry(slot, &key, &value)?;
+            write_page(self.buffer_pool, vacant_id, bucket)?;
+        } else {
+            let mut last = last.expect("chain without a vacancy ends on a full page");
+            let mut overflow = page::LinearBucketPage::<V, K>::new(header.page_size());
+            overflow.write_entry(bucket_slot_start(hash, &overflow), &key, &value)?;
+            let overflow_id = append_page(self.buffer_pool, overflow)?;
+            last.set_next_page(Some(overflow_id));
+            write_page(self.buffer_pool, page_id, last)?;
+        }
+
+        header.set_len(header.len() + 1);
+        if header.needs_split(K::SIZE) {
+            self.split(&mut header)?;
+        }
+        write_page(self.buffer_pool, self.page_id, header)?;
+        Ok(())
+    }
+
+    /// Stores `value` if `key` is absent, or else applies `update` to the
+    /// stored value.
+    pub fn insert_or_update<F>(&mut self, key: K, value: V, update: F) -> anyhow::Result<()>
+    where
+        V: Serialize + DeserializeOwned,
+        F: FnOnce(&mut V),
+    {
+        if let Some((mut bucket, slot, bucket_id)) = self.find(&key)? {
+            let mut stored = bucket.read_value(slot)?;
+            update(&mut stored);
+            bucket.write_entry(slot, &key, &stored)?;
+            write_page(self.buffer_pool, bucket_id, bucket)?;
+            return Ok(());
+        }
+        self.insert(key, value)
+    }
+
+    pub fn get(&self, key: K) -> anyhow::Result<Option<V>>
+    where
+        V: DeserializeOwned,
+    {
+        Ok(match self.find(&key)? {
+            Some((bucket, slot, _)) => Some(bucket.read_value(slot)?),
+            None => None,
+        })
+    }
+
+    /// Removes `key` from the table, returning its value if it was present.
+    ///
+    /// Buckets are never merged, so the table does not shrink.
+    pub fn remove(&mut self, key: K) -> anyhow::Result<Option<V>>
+    where
+        V: DeserializeOwned,
+    {
+        let (mut bucket, slot, bucket_id) = match self.find(&key)? {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        let value = bucket.read_value(slot)?;
+        bucket.set_slot_state(slot, SlotState::Deleted);
+        write_page(self.buffer_pool, bucket_id, bucket)?;
+
+        let mut header = self.read_header()?;
+        header.set_len(header.len() - 1);
+        write_page(self.buffer_pool, self.page_id, header)?;
+        Ok(Some(value))
+    }
+
+    fn hash(&self, key: &K) -> u64 {
+        self.hash_builder.hash_one(key)
+    }
+
+    /// Returns the page, slot and page id holding `key`, if any.
+    fn find(&self, key: &K) -> anyhow::Result<Found<page::LinearBucketPage<V, K>>> {
+        let hash = self.hash(key);
+        let header = self.read_header()?;
+        let mut page_id = header.bucket(header.bucket_index(hash));
+        loop {
+            let bucket = self.read_bucket(page_id)?;
+            match probe_from(&bucket, bucket_slot_start(hash, &bucket), key) {
+                Probe::Found(slot) => return Ok(Some((bucket, slot, page_id))),
+                // Keys only go to overflow pages once every earlier page in
+                // the chain is full, so an empty slot ends the search.
+                Probe::Absent(_) => return Ok(None),
+                Probe::Exhausted(_) => {}
+            }
+            match bucket.next_page() {
+                Some(next) => page_id = next,
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// Splits the bucket under the split pointer in two and advances the
+    /// split pointer.  Entries are rehashed on one more bit of the hash, and
+    /// tombstones are dropped.
+    fn split(&mut self, header: &mut page::LinearHeaderPage<V>) -> anyhow::Result<()> {
+        let level = header.level();
+        let low_index = header.split_pointer();
+        let high_index = low_index + (1 << level);
+
+        let page_size = header.page_size();
+        let mut old_ids = Vec::new();
+        let mut next = Some(header.bucket(low_index));
+        let mut low = vec![page::LinearBucketPage::<V, K>::new(page_size)];
+        let mut high = vec![page::LinearBucketPage::<V, K>::new(page_size)];
+        while let Some(page_id) = next {
+            let bucket = self.read_bucket(page_id)?;
+            for slot in (0..bucket.capacity()).filter(|&slot| bucket.slot_state(slot) == SlotState::Full) {
+                let hash = self.hash(&bucket.key(slot));
+                let chain = if hash & (1 << level) == 0 { &mut low } else { &mut high };
+                place_in_chain(chain, hash, &bucket, slot);
+            }
+            next = bucket.next_page();
+            old_ids.push(page_id);
+        }
+
+        // The low half never needs more pages than the bucket had, so it can
+        // reuse them.  Any left over stay on the chain, empty.
+        low.resize_with(old_ids.len(), || page::LinearBucketPage::new(page_size));
+        for (i, mut bucket) in low.into_iter().enumerate() {
+            bucket.set_next_page(old_ids.get(i + 1).copied());
+            write_page(self.buffer_pool, old_ids[i], bucket)?;
+        }
+        let mut high_id = None;
+        for mut bucket in high.into_iter().rev() {
+            bucket.set_next_page(high_id);
+            high_id = Some(append_page(self.buffer_pool, bucket)?);
+        }
+
+        header.set_bucket(high_index, high_id.expect("split produces a high bucket"));
+        header.advance_split_pointer();
+        Ok(())
+    }
+
+    fn read_header(&self) -> anyhow::Result<page::LinearHeaderPage<V>> {
+        load_page(self.buffer_pool, self.page_id)
+    }
+
+    fn read_bucket(&self, page_id: PageId) -> anyhow::Result<page::LinearBucketPage<V, K>> {
+        load_page(self.buffer_pool, page_id)
+    }
+}
+
+/// Copies an entry into the first page of an in-memory chain with room for
+/// it, adding a page to the end of the chain if they are all full.
+fn place_in_chain<V, K: Key, P: SlotStorage<Key = K>>(
+    chain: &mut Vec<page::LinearBucketPage<V, K>>,
+    hash: u64,
+    from: &P,
+    slot: usize,
+) {
+    let key = from.key(slot);
+    for bucket in chain.iter_mut() {
+        if let Probe::Absent(new_slot) = probe_from(&*bucket, bucket_slot_start(hash, &*bucket), &key) {
+            bucket.copy_entry(new_slot, from, slot);
+            return;
+        }
+    }
+    let mut bucket = page::LinearBucketPage::new(from.page_size());
+    bucket.copy_entry(bucket_slot_start(hash, &bucket), from, slot);
+    chain.push(bucket);
+}
+
+/// A page holding a key, the key's slot, and the page's id.
+type Found<P> = Option<(P, usize, PageId)>;
+
+/// The result of probing a page of slots for a key.
+enum Probe {
+    /// The key is stored in this slot.
+    Found(usize),
+
+    /// The key is not stored, and not stored anywhere further along the probe
+    /// sequence either.  The slot is where it should be inserted: the first
+    /// tombstone seen, or else the empty slot that ended the probe.
+    Absent(usize),
+
+    /// Every slot was visited without finding the key or an empty slot,
+    /// so it may have spilled over further.  Holds the first tombstone seen.
+    Exhausted(Option<usize>),
+}
+
+fn probe<P: SlotStorage>(page: &P, hash: u64, key: &P::Key) -> Probe {
+    probe_from(page, (hash % page.capacity() as u64) as usize, key)
+}
+
+/// Linearly probe every slot in `page`, starting at `start`.
+fn probe_from<P: SlotStorage>(page: &P, start: usize, key: &P::Key) -> Probe {
+    let capacity = page.capacity();
+    let mut tombstone = None;
+    for slot in (0..capacity).map(|i| (start + i) % capacity) {
+        match page.slot_state(slot) {
+            SlotState::Empty => return Probe::Absent(tombstone.unwrap_or(slot)),
+            SlotState::Full if page.key(slot) == *key => return Probe::Found(slot),
+            SlotState::Deleted if tombstone.is_none() => tombstone = Some(slot),
+            SlotState::Full | SlotState::Deleted => {}
+        }
+    }
+    Probe::Exhausted(tombstone)
+}
+
+/// Page pointer indexes to visit for a hash: all of them, starting at the
+/// hashed one.
+fn page_sequence(hash: u64, page_count: usize) -> impl Iterator<Item = usize> {
+    let start = (hash % page_count as u64) as usize;
+    (0..page_count).map(move |i| (start + i) % page_count)
+}
+
+/// Where to start probing within a slot page.  Uses the bits of the hash
+/// left over after choosing the page, so keys sharing a page don't all
+/// start on the same slot.
+fn slot_sequence_start<V, K: Key>(hash: u64, header: &page::HeaderPage<V>) -> usize {
+    let capacity = header.slot_page_capacity(K::SIZE) as u64;
+    ((hash / header.page_count() as u64) % capacity) as usize
+}
+
+mod page {
+
+    use std::{
+        collections::HashSet,
+        convert::{TryFrom, TryInto},
+        marker::PhantomData,
+        mem::size_of,
+        ops::Range,
+    };
+
+    use bitvec::prelude::*;
+    use serde::{de::DeserializeOwned, Serialize};
+
+    use super::{Error, HashAlgorithm, Key};
+    use crate::{
+        aligned::{self, Field},
+        record::PageId,
+        PageType, PAGESIZE,
+    };
+
+    const DATA_OFFSET: usize = 0x18;
+    const SLOT_PAGE_DATA_OFFSET: usize = 0x10;
+    const PAGE_POINTERS_OFFSET: usize = 0x28;
+
+    /// The number of slots that fit in a single page hash table of
+    /// `page_size` bytes for keys of `key_size` bytes and values of
+    /// `value_size` bytes.
+    pub(super) fn capacity(page_size: usize, key_size: usize, value_size: usize) -> usize {
+        capacity_from(page_size, DATA_OFFSET, key_size, value_size)
+    }
+
+    /// The number of slots that fit in a slot page of `page_size` bytes for
+    /// keys of `key_size` bytes and values of `value_size` bytes.
+    pub(super) fn slot_page_capacity(page_size: usize, key_size: usize, value_size: usize) -> usize {
+        capacity_from(page_size, SLOT_PAGE_DATA_OFFSET, key_size, value_size)
+    }
+
+    fn capacity_from(page_size: usize, offset: usize, key_size: usize, value_size: usize) -> usize {
+        let slot_size = key_size + value_size;
+        let available = page_size - offset;
+        // Each slot costs its own size plus a quarter byte of state bits.
+        let mut capacity = available * 4 / (slot_size * 4 + 1);
+        while capacity * slot_size + capacity.div_ceil(4) > available {
+            capacity -= 1;
+        }
+        capacity
+    }
+
+    #[derive(Clone, Copy, Debug, Eq, PartialEq)]
+    pub(super) enum SlotState {
+        Empty,
+        Full,
+        Deleted,
+    }
+
+    /// Reads a slice of two u8s as a u16 using little endian encoding.
+    ///
+    /// # Panic
+    ///
+    /// Panics if given a slice of the wrong size.
+    fn read_u16(s: &[u8]) -> u16 {
+        u16::from_le_bytes(s.try_into().expect("to_u16 expects a slice of two u8s."))
+    }
+
+    /// Reads a slice of eight u8s as a u64 using little endian encoding.
+    ///
+    /// # Panic
+    ///
+    /// Panics if given a slice of the wrong size.
+    fn read_u64(s: &[u8]) -> u64 {
+        u64::from_le_bytes(s.try_into().expect("to_u64 expects a slice of eight u8s."))
+    }
+
+    // Every hash table page stores its value size after the page type.
+    const VALUE_SIZE: Field<u16> = Field::at(0x6);
+
+    fn value_size(buffer: &aligned::Buffer) -> usize {
+        VALUE_SIZE.get(buffer) as usize
+    }
+
+    /// Reads the key size stored in `field`.  Pages written before key sizes
+    /// were recorded have 0 there, and could only hold u64 keys.
+    fn key_size(buffer: &aligned::Buffer, field: Field<u16>) -> usize {
+        match field.get(buffer) {
+            0 => 8,
+            size => size as usize,
+        }
+    }
+
+    /// # Panic
+    ///
+    /// Panics if `size` does not fit in two bytes.
+    fn set_key_size(buffer: &mut aligned::Buffer, field: Field<u16>, size: usize) {
+        assert!(size <= u16::MAX as usize, "key size cannot be greater than {} bytes", u16::MAX);
+        field.set(buffer, size as u16)
+    }
+
+    /// # Panic
+    ///
+    /// This function panics if value_size is greater than 4096 bytes;
+    fn set_value_size(buffer: &mut aligned::Buffer, size: usize) {
+        assert!(size <= 4096, "value size cannot be greater than 4096 bytes");
+        VALUE_SIZE.set(buffer, size as u16)
+    }
+
+    /// A table page, laid out to fill a page of the storage.  Pages are read
+    /// into buffers of the largest page size, so a page read back is told
+    /// the size of the pages it came from.
+    pub(super) trait TablePage: aligned::FromAligned {
+        fn set_page_size(&mut self, page_size: usize);
+    }
+
+    /// A page holding a two-bit state array followed by fixed-width
+    /// (key, value) slots.
+    pub(super) trait SlotStorage {
+        /// Offset of the slot state array.
+        const DATA_OFFSET: usize;
+
+        /// The type of key stored in the slots.
+        type Key: Key;
+
+        fn buffer(&self) -> &aligned::Buffer;
+
+        fn buffer_mut(&mut self) -> &mut aligned::Buffer;
+
+        fn page_size(&self) -> usize;
+
+        fn value_size(&self) -> usize {
+            value_size(self.buffer())
+        }
+
+        fn capacity(&self) -> usize {
+            capacity_from(self.page_size(), Self::DATA_OFFSET, Self::Key::SIZE, self.value_size())
+        }
+
+        fn slot_size(&self) -> usize {
+            Self::Key::SIZE + self.value_size()
+        }
+
+        fn slots_offset(&self) -> usize {
+            Self::DATA_OFFSET + self.capacity().div_ceil(4)
+        }
+
+        fn slot_range(&self, slot: usize) -> std::ops::Range<usize> {
+            debug_assert!(slot < self.capacity());
+            let start = self.slots_offset() + slot * self.slot_size();
+            start..start + self.slot_size()
+        }
+
+        fn slot_state(&self, slot: usize) -> SlotState {
+            let bits = self.buffer()[Self::DATA_OFFSET..self.slots_offset()].bits::<Lsb0>();
+            match (bits[slot * 2], bits[slot * 2 + 1]) {
+                (true, _) => SlotState::Full,
+                (false, true) => SlotState::Deleted,
+                (false, false) => SlotState::Empty,
+            }
+        }
+
+        fn set_slot_state(&mut self, slot: usize, state: SlotState) {
+            let (has_value, continue_fallthrough) = match state {
+                SlotState::Empty => (false, false),
+                SlotState::Full => (true, true),
+                SlotState::Deleted => (false, true),
+            };
+            let range = Self::DATA_OFFSET..self.slots_offset();
+            let bits = self.buffer_mut()[range].bits_mut::<Lsb0>();
+            bits.set(slot * 2, has_value);
+            bits.set(slot * 2 + 1, continue_fallthrough);
+        }
+
+        fn key(&self, slot: usize) -> Self::Key {
+            let start = self.slot_range(slot).start;
+            Self::Key::read_from(&self.buffer()[start..start + Self::Key::SIZE])
+        }
+
+        fn read_value<V: DeserializeOwned>(&self, slot: usize) -> anyhow::Result<V> {
+            let range = self.slot_range(slot);
+            Ok(bincode::deserialize(&self.buffer()[range.start + Self::Key::SIZE..range.end])?)
+        }
+
+        /// Copies the entry in `from_slot` of another page into `slot` and
+        /// marks it full.
+        fn copy_entry<P: SlotStorage<Key = Self::Key>>(&mut self, slot: usize, from: &P, from_slot: usize) {
+            debug_assert_eq!(self.value_size(), from.value_size());
+            let range = self.slot_range(slot);
+            self.buffer_mut()[range].copy_from_slice(&from.buffer()[from.slot_range(from_slot)]);
+            self.set_slot_state(slot, SlotState::Full);
+        }
+
+        /// Stores `key` and `value` in `slot` and marks it full.
+        fn write_entry<V: Serialize>(&mut self, slot: usize, key: &Self::Key, value: &V) -> anyhow::Result<()> {
+            let value_size = self.value_size();
+            anyhow::ensure!(
+                bincode::serialized_size(value)? as usize <= value_size,
+                Error::ValueSize { value_size }
+            );
+            let range = self.slot_range(slot);
+            let slot_bytes = &mut self.buffer_mut()[range];
+            key.write_to(&mut slot_bytes[..Self::Key::SIZE]);
+            bincode::serialize_into(&mut slot_bytes[Self::Key::SIZE..], value)?;
+            self.set_slot_state(slot, SlotState::Full);
+            Ok(())
+        }
+    }
+
+    const PAGE_HASH_ALGORITHM: Field<u16> = Field::at(0x8);
+    const PAGE_KEY_SIZE: Field<u16> = Field::at(0xa);
+    const PAGE_LEN: Field<u32> = Field::at(0xc);
+    const PAGE_HASH_SEED: Field<u64> = Field::at(0x10);
+
+    // crc32: u32,
+    // page_type: PageType,
+    // value_size: u16,
+    // hash_algorithm: HashAlgorithm,
+    // key_size: u16,
+    // len: u32,
+    // hash_seed: u64,
+    // slot_states: [u8; (capacity + 3) / 4],
+    // slots: [(K, [u8; value_size]); capacity],
+    pub(super) struct Page<V, K> {
+        buffer: Box<aligned::Buffer>,
+        page_size: usize,
+        _value_type: PhantomData<V>,
+        _key_type: PhantomData<K>,
+    }
+
+    impl<V, K: Key> Page<V, K> {
+        pub(super) fn new(page_size: usize, hash_seed: u64, algorithm: HashAlgorithm) -> Page<V, K> {
+            let mut buffer = aligned::Buffer::new();
+            set_value_size(&mut buffer, size_of::<V>());
+            set_key_size(&mut buffer, PAGE_KEY_SIZE, K::SIZE);
+            let mut p = Page {
+                buffer,
+                page_size,
+                _value_type: PhantomData,
+                _key_type: PhantomData,
+            };
+            p.set_hash_algorithm(algorithm);
+            p.set_hash_seed(hash_seed);
+            p
+        }
+
+        pub(super) fn hash_seed(&self) -> u64 {
+            PAGE_HASH_SEED.get(&self.buffer)
+        }
+
+        pub(super) fn key_size(&self) -> usize {
+            key_size(&self.buffer, PAGE_KEY_SIZE)
+        }
+
+        pub(super) fn set_hash_seed(&mut self, hash_seed: u64) {
+            PAGE_HASH_SEED.set(&mut self.buffer, hash_seed)
+        }
+
+        pub(super) fn hash_algorithm(&self) -> Result<HashAlgorithm, Error> {
+            HashAlgorithm::try_from(PAGE_HASH_ALGORITHM.get(&self.buffer) as u32)
+        }
+
+        /// The number of full slots.  Pages written before the count was
+        /// kept have 0 there, so a 0 is checked against the slot states.
+        pub(super) fn len(&self) -> usize {
+            match PAGE_LEN.get(&self.buffer) {
+                0 => (0..self.capacity())
+                    .filter(|&slot| self.slot_state(slot) == SlotState::Full)
+                    .count(),
+                len => len as usize,
+            }
+        }
+
+        pub(super) fn set_len(&mut self, len: usize) {
+            PAGE_LEN.set(&mut self.buffer, len as u32)
+        }
+
+        fn set_hash_algorithm(&mut self, algorithm: HashAlgorithm) {
+            PAGE_HASH_ALGORITHM.set(&mut self.buffer, algorithm as u16)
+        }
+    }
+
+    impl<V, K: Key> SlotStorage for Page<V, K> {
+        const DATA_OFFSET: usize = DATA_OFFSET;
+        type Key = K;
+
+        fn buffer(&self) -> &aligned::Buffer {
+            &self.buffer
+        }
+
+        fn buffer_mut(&mut self) -> &mut aligned::Buffer {
+            &mut self.buffer
+        }
+
+        fn page_size(&self) -> usize {
+            self.page_size
+        }
+    }
+
+    impl<V, K> aligned::PageHeader for Page<V, K> {
+        const PAGE_TYPE: PageType = PageType::SinglePageHashTable;
+    }
+
+    impl<V, K> aligned::IntoAligned for Page<V, K> {
+        fn as_buffer(&self) -> &aligned::Buffer {
+            &self.buffer
+        }
+
+        fn into_buffer(self) -> Box<aligned::Buffer> {
+            self.buffer
+        }
+    }
+
+    impl<V, K> aligned::FromAligned for Page<V, K> {
+        fn transform(buffer: Box<aligned::Buffer>) -> Self {
+            Page {
+                buffer,
+                page_size: PAGESIZE,
+                _value_type: PhantomData,
+                _key_type: PhantomData,
+            }
+        }
+    }
+
+    impl<V, K> TablePage for Page<V, K> {
+        fn set_page_size(&mut self, page_size: usize) {
+            self.page_size = page_size;
+        }
+    }
+
+    /// The size of the (offset, length) pair a variable-length slot holds in
+    /// place of its value.
+    const VALUE_REF_SIZE: usize = 4;
+
+    const VAR_PAGE_HASH_ALGORITHM: Field<u16> = Field::at(0x8);
+    const VAR_PAGE_SLOT_COUNT: Field<u16> = Field::at(0xa);
+    const VAR_PAGE_VALUES_START: Field<u16> = Field::at(0xc);
+    const VAR_PAGE_KEY_SIZE: Field<u16> = Field::at(0xe);
+    const VAR_PAGE_HASH_SEED: Field<u64> = Field::at(0x10);
+
+    // crc32: u32,
+    // page_type: PageType,
+    // value_size: u16,  // VALUE_REF_SIZE
+    // hash_algorithm: HashAlgorithm,
+    // slot_count: u16,
+    // values_start: u16,
+    // key_size: u16,
+    // hash_seed: u64,
+    // slot_states: [u8; (slot_count + 3) / 4],
+    // slots: [(K, u16, u16); slot_count],
+    // free_space: [u8; values_start - end of slots],
+    // values: [u8; page_size - values_start],
+    pub(super) struct VarPage<K> {
+        buffer: Box<aligned::Buffer>,
+        page_size: usize,
+        _key_type: PhantomData<K>,
+    }
+
+    impl<K: Key> VarPage<K> {
+        /// # Panic
+        ///
+        /// Panics if `slot_count` is zero, or if that many slots would fill
+        /// the whole page.
+        pub(super) fn new(page_size: usize, hash_seed: u64, slot_count: usize, algorithm: HashAlgorithm) -> VarPage<K> {
+            let max_slot_count = capacity_from(page_size, DATA_OFFSET, K::SIZE, VALUE_REF_SIZE) - 1;
+            assert!(
+                slot_count > 0 && slot_count <= max_slot_count,
+                "slot count must be between 1 and {}",
+                max_slot_count
+            );
+            let mut buffer = aligned::Buffer::new();
+            set_value_size(&mut buffer, VALUE_REF_SIZE);
+            VAR_PAGE_HASH_ALGORITHM.set(&mut buffer, algorithm as u16);
+            VAR_PAGE_SLOT_COUNT.set(&mut buffer, slot_count as u16);
+            set_key_size(&mut buffer, VAR_PAGE_KEY_SIZE, K::SIZE);
+            VAR_PAGE_HASH_SEED.set(&mut buffer, hash_seed);
+            let mut page = VarPage {
+                buffer,
+                page_size,
+                _key_type: PhantomData,
+            };
+            page.set_values_start(page_size);
+            page
+        }
+
+        pub(super) fn hash_seed(&self) -> u64 {
+            VAR_PAGE_HASH_SEED.get(&self.buffer)
+        }
+
+        pub(super) fn key_size(&self) -> usize {
+            key_size(&self.buffer, VAR_PAGE_KEY_SIZE)
+        }
+
+        pub(super) fn hash_algorithm(&self) -> Result<HashAlgorithm, Error> {
+            HashAlgorithm::try_from(VAR_PAGE_HASH_ALGORITHM.get(&self.buffer) as u32)
+        }
+
+        fn values_start(&self) -> usize {
+            VAR_PAGE_VALUES_START.get(&self.buffer) as usize
+        }
+
+        fn set_values_start(&mut self, offset: usize) {
+            VAR_PAGE_VALUES_START.set(&mut self.buffer, offset as u16)
+        }
+
+        fn slots_end(&self) -> usize {
+            self.slots_offset() + self.capacity() * self.slot_size()
+        }
+
+        /// The offset and length of the value stored for `slot`.
+        fn value_ref(&self, slot: usize) -> (usize, usize) {
+            let start = self.slot_range(slot).start + K::SIZE;
+            let offset = read_u16(&self.buffer[start..start + 2]) as usize;
+            let len = read_u16(&self.buffer[start + 2..start + 4]) as usize;
+            (offset, len)
+        }
+
+        fn set_value_ref(&mut self, slot: usize, offset: usize, len: usize) {
+            let start = self.slot_range(slot).start + K::SIZE;
+            self.buffer[start..start + 2].copy_from_slice(&(offset as u16).to_le_bytes());
+            self.buffer[start + 2..start + 4].copy_from_slice(&(len as u16).to_le_bytes());
+        }
+
+        /// The space available for values once the page is compacted, not
+        /// counting the value in `replacing`, which is about to be freed.
+        pub(super) fn reclaimable_space(&self, replacing: Option<usize>) -> usize {
+            let live: usize = (0..self.capacity())
+                .filter(|&slot| Some(slot) != replacing && self.slot_state(slot) == SlotState::Full)
+                .map(|slot| self.value_ref(slot).1)
+                .sum();
+            self.page_size - self.slots_end() - live
+        }
+
+        pub(super) fn read_var_value<V: DeserializeOwned>(&self, slot: usize) -> anyhow::Result<V> {
+            let (offset, len) = self.value_ref(slot);
+            Ok(bincode::deserialize(&self.buffer[offset..offset + len])?)
+        }
+
+        /// Stores `key` and the serialized `value` in `slot` and marks it
+        /// full, compacting the values first if that is what it takes to make
+        /// room.
+        pub(super) fn write_var_entry(&mut self, slot: usize, key: &K, value: &[u8]) -> Result<(), Error> {
+            let replacing = self.slot_state(slot) == SlotState::Full;
+            if replacing {
+                let (offset, len) = self.value_ref(slot);
+                if value.len() <= len {
+                    self.buffer[offset..offset + value.len()].copy_from_slice(value);
+                    self.set_value_ref(slot, offset, value.len());
+                    return Ok(());
+                }
+            }
+
+            let available = self.reclaimable_space(Some(slot).filter(|_| replacing));
+            if value.len() > available {
+                return Err(Error::Space {
+                    needed: value.len(),
+                    available,
+                });
+            }
+            if replacing {
+                // Let compaction drop the old value.
+                self.set_slot_state(slot, SlotState::Deleted);
+            }
+            if self.values_start() - self.slots_end() < value.len() {
+                self.compact();
+            }
+
+            let offset = self.values_start() - value.len();
+            self.buffer[offset..offset + value.len()].copy_from_slice(value);
+            self.set_values_start(offset);
+            let start = self.slot_range(slot).start;
+            key.write_to(&mut self.buffer[start..start + K::SIZE]);
+            self.set_value_ref(slot, offset, value.len());
+            self.set_slot_state(slot, SlotState::Full);
+            Ok(())
+        }
+
+        /// Packs the values of all full slots against the end of the page.
+        fn compact(&mut self) {
+            let values: Vec<(usize, Vec<u8>)> = (0..self.capacity())
+                .filter(|&slot| self.slot_state(slot) == SlotState::Full)
+                .map(|slot| {
+                    let (offset, len) = self.value_ref(slot);
+                    (slot, self.buffer[offset..offset + len].to_vec())
+                })
+                .collect();
+            let mut values_start = self.page_size;
+            for (slot, value) in values {
+                values_start -= value.len();
+                self.buffer[values_start..values_start + value.len()].copy_from_slice(&value);
+                self.set_value_ref(slot, values_start, value.len());
+            }
+            self.set_values_start(values_start);
+        }
+    }
+
+    impl<K: Key> SlotStorage for VarPage<K> {
+        const DATA_OFFSET: usize = DATA_OFFSET;
+        type Key = K;
+
+        fn buffer(&self) -> &aligned::Buffer {
+            &self.buffer
+        }
+
+        fn buffer_mut(&mut self) -> &mut aligned::Buffer {
+            &mut self.buffer
+        }
+
+        fn page_size(&self) -> usize {
+            self.page_size
+        }
+
+        /// The slot count is chosen when the table is created, rather than
+        /// filling the page.
+        fn capacity(&self) -> usize {
+            VAR_PAGE_SLOT_COUNT.get(&self.buffer) as usize
+        }
+    }
+
+    impl<K> aligned::PageHeader for VarPage<K> {
+        const PAGE_TYPE: PageType = PageType::SinglePageVarHashTable;
+    }
+
+    impl<K> aligned::IntoAligned for VarPage<K> {
+        fn as_buffer(&self) -> &aligned::Buffer {
+            &self.buffer
+        }
+
+        fn into_buffer(self) -> Box<aligned::Buffer> {
+            self.buffer
+        }
+    }
+
+    impl<K> aligned::FromAligned for VarPage<K> {
+        fn extra_constraints(buffer: &aligned::Buffer) -> Result<(), aligned::Error> {
+            let slot_count = VAR_PAGE_SLOT_COUNT.get(buffer) as usize;
+            let values_start = VAR_PAGE_VALUES_START.get(buffer) as usize;
+            if value_size(buffer) != VALUE_REF_SIZE || slot_count == 0 || values_start > PAGESIZE {
+                Err(aligned::Error::SizeError)
+            } else {
+                Ok(())
+            }
+        }
+
+        fn transform(buffer: Box<aligned::Buffer>) -> Self {
+            VarPage {
+                buffer,
+                page_size: PAGESIZE,
+                _key_type: PhantomData,
+            }
+        }
+    }
+
+    impl<K> TablePage for VarPage<K> {
+        fn set_page_size(&mut self, page_size: usize) {
+            self.page_size = page_size;
+        }
+    }
+
+    const HEADER_HASH_ALGORITHM: Field<u32> = Field::at(0x8);
+    const HEADER_KEY_SIZE: Field<u16> = Field::at(0xc);
+    const HEADER_FILTER_SIZE: Field<u16> = Field::at(0xe);
+    const HEADER_PAGE_COUNT: Field<u64> = Field::at(0x10);
+    const HEADER_HASH_SEED: Field<u64> = Field::at(0x18);
+    const HEADER_LEN: Field<u64> = Field::at(0x20);
+
+    // crc32: u32,
+    // page_type: PageType,
+    // value_size: u16,
+    // hash_algorithm: HashAlgorithm,
+    // key_size: u16,
+    // filter_size: u16,
+    // page_count: u64,
+    // hash_seed: u64,
+    // len: u64,
+    // page_pointers: [PageId; page_count],
+    // filter_page_pointers: [PageId; filter_page_count],
+    pub(super) struct HeaderPage<V> {
+        buffer: Box<aligned::Buffer>,
+        page_size: usize,
+        _value_type: PhantomData<V>,
+    }
+
+    impl<V> HeaderPage<V> {
+        /// The most slot pages a header page of `page_size` bytes can point
+        /// to.
+        pub(super) const fn max_page_count(page_size: usize) -> usize {
+            (page_size - PAGE_POINTERS_OFFSET) / 8
+        }
+
+        /// # Panic
+        ///
+        /// Panics if `page_count` is zero or greater than `max_page_count`.
+        pub(super) fn new(
+            page_size: usize,
+            hash_seed: u64,
+            page_count: usize,
+            key_size: usize,
+            algorithm: HashAlgorithm,
+        ) -> HeaderPage<V> {
+            let max_page_count = Self::max_page_count(page_size);
+            assert!(
+                page_count > 0 && page_count <= max_page_count,
+                "page count must be between 1 and {}",
+                max_page_count
+            );
+            let mut buffer = aligned::Buffer::new();
+            set_value_size(&mut buffer, size_of::<V>());
+            HEADER_HASH_ALGORITHM.set(&mut buffer, algorithm as u32);
+            set_key_size(&mut buffer, HEADER_KEY_SIZE, key_size);
+            let slots = slot_page_capacity(page_size, key_size, size_of::<V>());
+            HEADER_FILTER_SIZE.set(&mut buffer, Self::filter_size_for(page_size, page_count, slots) as u16);
+            HEADER_PAGE_COUNT.set(&mut buffer, page_count as u64);
+            HEADER_HASH_SEED.set(&mut buffer, hash_seed);
+            HeaderPage {
+                buffer,
+                page_size,
+                _value_type: PhantomData,
+            }
+        }
+
+        pub(super) fn page_size(&self) -> usize {
+            self.page_size
+        }
+
+        pub(super) fn value_size(&self) -> usize {
+            value_size(&self.buffer)
+        }
+
+        /// The number of slots in each of the table's slot pages.
+        pub(super) fn slot_page_capacity(&self, key_size: usize) -> usize {
+            slot_page_capacity(self.page_size, key_size, self.value_size())
+        }
+
+        pub(super) fn key_size(&self) -> usize {
+            key_size(&self.buffer, HEADER_KEY_SIZE)
+        }
+
+        pub(super) fn hash_algorithm(&self) -> Result<HashAlgorithm, Error> {
+            HashAlgorithm::try_from(HEADER_HASH_ALGORITHM.get(&self.buffer))
+        }
+
+        pub(super) fn page_count(&self) -> usize {
+            HEADER_PAGE_COUNT.get(&self.buffer) as usize
+        }
+
+        pub(super) fn hash_seed(&self) -> u64 {
+            HEADER_HASH_SEED.get(&self.buffer)
+        }
+
+        pub(super) fn len(&self) -> u64 {
+            HEADER_LEN.get(&self.buffer)
+        }
+
+        pub(super) fn set_len(&mut self, len: u64) {
+            HEADER_LEN.set(&mut self.buffer, len);
+        }
+
+        pub(super) fn page_pointer(&self, index: usize) -> PageId {
+            debug_assert!(index < self.page_count());
+            let offset = PAGE_POINTERS_OFFSET + index * 8;
+            read_u64(&self.buffer[offset..offset + 8])
+        }
+
+        pub(super) fn set_page_pointer(&mut self, index: usize, page_id: PageId) {
+            debug_assert!(index < self.page_count());
+            let offset = PAGE_POINTERS_OFFSET + index * 8;
+            self.buffer[offset..offset + 8].copy_from_slice(&page_id.to_le_bytes())
+        }
+
+        // The bytes of bloom filter for each slot page of `slots` slots, or
+        // 0 if the filter page pointers don't fit after `page_count` page
+        // pointers.
+        fn filter_size_for(page_size: usize, page_count: usize, slots: usize) -> usize {
+            let size = (slots * FILTER_BITS_PER_SLOT).div_ceil(8).min(FilterPage::capacity(page_size));
+            if size == 0 || page_count + Self::filter_pages_for(page_size, page_count, size) > Self::max_page_count(page_size) {
+                0
+            } else {
+                size
+            }
+        }
+
+        // The number of filter pages holding `page_count` filters of `size`
+        // bytes.
+        fn filter_pages_for(page_size: usize, page_count: usize, size: usize) -> usize {
+            page_count.div_ceil(FilterPage::capacity(page_size) / size)
+        }
+
+        fn filter_size(&self) -> usize {
+            HEADER_FILTER_SIZE.get(&self.buffer) as usize
+        }
+
+        /// The number of filter pages the table's bloom filters are packed
+        /// into, which is 0 if the table has no filters.
+        pub(super) fn filter_page_count(&self) -> usize {
+            match self.filter_size() {
+                0 => 0,
+                size => Self::filter_pages_for(self.page_size, self.page_count(), size),
+            }
+        }
+
+        /// Where the bloom filter of the slot page at `index` is: the index
+        /// of its filter page pointer, and its bytes in that page.  `None`
+        /// if the table has no filters.
+        pub(super) fn filter_location(&self, index: usize) -> Option<(usize, Range<usize>)> {
+            debug_assert!(index < self.page_count());
+            let size = self.filter_size();
+            if size == 0 {
+                return None;
+            }
+            let per_page = FilterPage::capacity(self.page_size) / size;
+            let start = FILTER_PAGE_DATA_OFFSET + (index % per_page) * size;
+            Some((index / per_page, start..start + size))
+        }
+
+        pub(super) fn filter_page_pointer(&self, filter_index: usize) -> PageId {
+            debug_assert!(filter_index < self.filter_page_count());
+            let offset = PAGE_POINTERS_OFFSET + (self.page_count() + filter_index) * 8;
+            read_u64(&self.buffer[offset..offset + 8])
+        }
+
+        pub(super) fn set_filter_page_pointer(&mut self, filter_index: usize, page_id: PageId) {
+            debug_assert!(filter_index < self.filter_page_count());
+            let offset = PAGE_POINTERS_OFFSET + (self.page_count() + filter_index) * 8;
+            self.buffer[offset..offset + 8].copy_from_slice(&page_id.to_le_bytes())
+        }
+    }
+
+    /// The bits of bloom filter kept for each slot of a slot page, which
+    /// keeps false positives near 1% when the page is full.
+    const FILTER_BITS_PER_SLOT: usize = 10;
+
+    /// The number of bits each key sets in a bloom filter.
+    const FILTER_HASHES: u64 = 7;
+
+    /// The bits set for `hash` in a bloom filter of `size` bytes, by double
+    /// hashing.  The low bits of the hash also choose the slot page, so they
+    /// are mixed first to spread the keys of each page over its filter.
+    fn filter_bits(hash: u64, size: usize) -> impl Iterator<Item = usize> {
+        let mixed = (hash ^ (hash >> 31)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        let (h1, h2) = (mixed >> 32, (mixed & 0xffff_ffff) | 1);
+        let bits = size as u64 * 8;
+        (0..FILTER_HASHES).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % bits) as usize)
+    }
+
+    impl<V> aligned::PageHeader for HeaderPage<V> {
+        const PAGE_TYPE: PageType = PageType::HashTableHeader;
+    }
+
+    impl<V> aligned::IntoAligned for HeaderPage<V> {
+        fn as_buffer(&self) -> &aligned::Buffer {
+            &self.buffer
+        }
+
+        fn into_buffer(self) -> Box<aligned::Buffer> {
+            self.buffer
+        }
+    }
+
+    impl<V> aligned::FromAligned for HeaderPage<V> {
+        fn extra_constraints(buffer: &aligned::Buffer) -> Result<(), aligned::Error> {
+            let page_count = HEADER_PAGE_COUNT.get(buffer) as usize;
+            let filter_size = HEADER_FILTER_SIZE.get(buffer) as usize;
+            let filter_pages = match filter_size {
+                0 => 0,
+                size if size > FilterPage::capacity(PAGESIZE) => usize::MAX,
+                size => Self::filter_pages_for(PAGESIZE, page_count, size),
+            };
+            if page_count == 0 || page_count.saturating_add(filter_pages) > Self::max_page_count(PAGESIZE) {
+                Err(aligned::Error::SizeError)
+            } else {
+                Ok(())
+            }
+        }
+
+        fn transform(buffer: Box<aligned::Buffer>) -> Self {
+            HeaderPage {
+                buffer,
+                page_size: PAGESIZE,
+                _value_type: PhantomData,
+            }
+        }
+    }
+
+    impl<V> TablePage for HeaderPage<V> {
+        fn set_page_size(&mut self, page_size: usize) {
+            self.page_size = page_size;
+        }
+    }
+
+    const FILTER_PAGE_DATA_OFFSET: usize = 0x8;
+
+    // crc32: u32,
+    // page_type: PageType,
+    // filters: [[u8; filter_size]; filters per page],
+    pub(super) struct FilterPage {
+        buffer: Box<aligned::Buffer>,
+    }
+
+    impl FilterPage {
+        /// The bytes of filters a filter page of `page_size` bytes holds.
+        pub(super) fn capacity(page_size: usize) -> usize {
+            page_size - FILTER_PAGE_DATA_OFFSET
+        }
+
+        pub(super) fn new() -> FilterPage {
+            FilterPage {
+                buffer: aligned::Buffer::new(),
+            }
+        }
+
+        /// Whether a key with `hash` may have been added to the filter at
+        /// `range`.  False positives are possible, false negatives are not.
+        pub(super) fn may_contain(&self, range: Range<usize>, hash: u64) -> bool {
+            let filter = &self.buffer[range];
+            filter_bits(hash, filter.len()).all(|bit| filter[bit / 8] & (1 << (bit % 8)) != 0)
+        }
+
+        /// Adds a key with `hash` to the filter at `range`, returning whether
+        /// the filter changed.
+        pub(super) fn add(&mut self, range: Range<usize>, hash: u64) -> bool {
+            let filter = &mut self.buffer[range];
+            let mut changed = false;
+            for bit in filter_bits(hash, filter.len()) {
+                changed |= filter[bit / 8] & (1 << (bit % 8)) == 0;
+                filter[bit / 8] |= 1 << (bit % 8);
+            }
+            changed
+        }
+    }
+
+    impl aligned::PageHeader for FilterPage {
+        const PAGE_TYPE: PageType = PageType::HashTableFilter;
+    }
+
+    impl aligned::IntoAligned for FilterPage {
+        fn as_buffer(&self) -> &aligned::Buffer {
+            &self.buffer
+        }
+
+        fn into_buffer(self) -> Box<aligned::Buffer> {
+            self.buffer
+        }
+    }
+
+    impl aligned::FromAligned for FilterPage {
+        fn transform(buffer: Box<aligned::Buffer>) -> Self {
+            FilterPage { buffer }
+        }
+    }
+
+    // The header page knows where the filters are in the page, so the page
+    // itself doesn't need its size.
+    impl TablePage for FilterPage {
+        fn set_page_size(&mut self, _page_size: usize) {}
+    }
+
+    const SLOT_PAGE_FIRST_SLOT: Field<u64> = Field::at(0x8);
+
+    // crc32: u32,
+    // page_type: PageType,
+    // value_size: u16,
+    // first_slot: u64,
+    // slot_states: [u8; (capacity + 3) / 4],
+    // slots: [(K, [u8; value_size]); capacity],
+    pub(super) struct SlotPage<V, K> {
+        buffer: Box<aligned::Buffer>,
+        page_size: usize,
+        _value_type: PhantomData<V>,
+        _key_type: PhantomData<K>,
+    }
+
+    impl<V, K: Key> SlotPage<V, K> {
+        /// Creates an empty slot page for the given page pointer index.
+        pub(super) fn new(page_size: usize, index: usize) -> SlotPage<V, K> {
+            let mut buffer = aligned::Buffer::new();
+            set_value_size(&mut buffer, size_of::<V>());
+            let first_slot = (index * slot_page_capacity(page_size, K::SIZE, size_of::<V>())) as u64;
+            SLOT_PAGE_FIRST_SLOT.set(&mut buffer, first_slot);
+            SlotPage {
+                buffer,
+                page_size,
+                _value_type: PhantomData,
+                _key_type: PhantomData,
+            }
+        }
+    }
+
+    impl<V, K: Key> SlotStorage for SlotPage<V, K> {
+        const DATA_OFFSET: usize = SLOT_PAGE_DATA_OFFSET;
+        type Key = K;
+
+        fn buffer(&self) -> &aligned::Buffer {
+            &self.buffer
+        }
+
+        fn buffer_mut(&mut self) -> &mut aligned::Buffer {
+            &mut self.buffer
+        }
+
+        fn page_size(&self) -> usize {
+            self.page_size
+        }
+    }
+
+    impl<V, K> aligned::PageHeader for SlotPage<V, K> {
+        const PAGE_TYPE: PageType = PageType::HashTableFixedWidthSlot;
+    }
+
+    impl<V, K> aligned::IntoAligned for SlotPage<V, K> {
+        fn as_buffer(&self) -> &aligned::Buffer {
+            &self.buffer
+        }
+
+        fn into_buffer(self) -> Box<aligned::Buffer> {
+            self.buffer
+        }
+    }
+
+    impl<V, K> aligned::FromAligned for SlotPage<V, K> {
+        fn transform(buffer: Box<aligned::Buffer>) -> Self {
+            SlotPage {
+                buffer,
+                page_size: PAGESIZE,
+                _value_type: PhantomData,
+                _key_type: PhantomData,
+            }
+        }
+    }
+
+    impl<V, K> TablePage for SlotPage<V, K> {
+        fn set_page_size(&mut self, page_size: usize) {
+            self.page_size = page_size;
+        }
+    }
+    /// The largest global depth whose directory fits in a page of
+    /// `page_size` bytes.
+    pub(super) const fn max_global_depth(page_size: usize) -> u32 {
+        ((page_size - PAGE_POINTERS_OFFSET) / 8).ilog2()
+    }
+
+    const DIRECTORY_HASH_ALGORITHM: Field<u32> = Field::at(0x8);
+    const DIRECTORY_KEY_SIZE: Field<u16> = Field::at(0xc);
+    const DIRECTORY_GLOBAL_DEPTH: Field<u64> = Field::at(0x10);
+    const DIRECTORY_HASH_SEED: Field<u64> = Field::at(0x18);
+    const DIRECTORY_ENTRY_COUNT: Field<u64> = Field::at(0x20);
+
+    // crc32: u32,
+    // page_type: PageType,
+    // value_size: u16,
+    // hash_algorithm: HashAlgorithm,
+    // key_size: u16,
+    // padding: [u8; 2],
+    // global_depth: u64,
+    // hash_seed: u64,
+    // entry_count: u64,
+    // buckets: [PageId; 1 << global_depth],
+    pub(super) struct DirectoryPage<V> {
+        buffer: Box<aligned::Buffer>,
+        page_size: usize,
+        _value_type: PhantomData<V>,
+    }
+
+    impl<V> DirectoryPage<V> {
+        pub(super) fn new(page_size: usize, hash_seed: u64, key_size: usize, algorithm: HashAlgorithm) -> DirectoryPage<V> {
+            let mut buffer = aligned::Buffer::new();
+            set_value_size(&mut buffer, size_of::<V>());
+            DIRECTORY_HASH_ALGORITHM.set(&mut buffer, algorithm as u32);
+            set_key_size(&mut buffer, DIRECTORY_KEY_SIZE, key_size);
+            DIRECTORY_HASH_SEED.set(&mut buffer, hash_seed);
+            DirectoryPage {
+                buffer,
+                page_size,
+                _value_type: PhantomData,
+            }
+        }
+
+        pub(super) fn value_size(&self) -> usize {
+            value_size(&self.buffer)
+        }
+
+        pub(super) fn key_size(&self) -> usize {
+            key_size(&self.buffer, DIRECTORY_KEY_SIZE)
+        }
+
+        pub(super) fn hash_algorithm(&self) -> Result<HashAlgorithm, Error> {
+            HashAlgorithm::try_from(DIRECTORY_HASH_ALGORITHM.get(&self.buffer))
+        }
+
+        pub(super) fn global_depth(&self) -> u32 {
+            DIRECTORY_GLOBAL_DEPTH.get(&self.buffer) as u32
+        }
+
+        /// The deepest the directory can get in its page.
+        pub(super) fn max_global_depth(&self) -> u32 {
+            max_global_depth(self.page_size)
         }
 
         pub(super) fn hash_seed(&self) -> u64 {
-            read_u64(&self.buffer[0x10..0x18])
+            DIRECTORY_HASH_SEED.get(&self.buffer)
         }
 
-        pub(super) fn set_hash_seed(&mut self, hash_seed: u64) {
-            self.buffer[0x10..0x18].copy_from_slice(&hash_seed.to_le_bytes())
+        pub(super) fn page_size(&self) -> usize {
+            self.page_size
+        }
+
+        /// The number of entries in the table's buckets.
+        pub(super) fn entry_count(&self) -> u64 {
+            DIRECTORY_ENTRY_COUNT.get(&self.buffer)
+        }
+
+        pub(super) fn set_entry_count(&mut self, entry_count: u64) {
+            DIRECTORY_ENTRY_COUNT.set(&mut self.buffer, entry_count);
+        }
+
+        /// The number of directory entries.
+        pub(super) fn len(&self) -> usize {
+            1 << self.global_depth()
+        }
+
+        /// The number of distinct buckets the directory entries point to.
+        pub(super) fn bucket_count(&self) -> usize {
+            (0..self.len()).map(|index| self.bucket(index)).collect::<HashSet<_>>().len()
+        }
+
+        pub(super) fn bucket(&self, index: usize) -> PageId {
+            debug_assert!(index < self.len());
+            let offset = PAGE_POINTERS_OFFSET + index * 8;
+            read_u64(&self.buffer[offset..offset + 8])
+        }
+
+        pub(super) fn set_bucket(&mut self, index: usize, page_id: PageId) {
+            debug_assert!(index < self.len());
+            let offset = PAGE_POINTERS_OFFSET + index * 8;
+            self.buffer[offset..offset + 8].copy_from_slice(&page_id.to_le_bytes())
+        }
+
+        /// Doubles the directory.  Each new entry points to the same bucket as
+        /// the entry it mirrors, which differs only in the new top bit.
+        ///
+        /// # Panic
+        ///
+        /// Panics if the directory is already at its maximum depth.
+        pub(super) fn double(&mut self) {
+            let global_depth = self.global_depth();
+            assert!(global_depth < self.max_global_depth(), "directory is at its maximum depth");
+            let len = self.len() * 8;
+            self.buffer
+                .copy_within(PAGE_POINTERS_OFFSET..PAGE_POINTERS_OFFSET + len, PAGE_POINTERS_OFFSET + len);
+            DIRECTORY_GLOBAL_DEPTH.set(&mut self.buffer, global_depth as u64 + 1);
         }
+    }
+
+    impl<V> aligned::PageHeader for DirectoryPage<V> {
+        const PAGE_TYPE: PageType = PageType::ExtendibleHashDirectory;
+    }
+
+    impl<V> aligned::IntoAligned for DirectoryPage<V> {
+        fn as_buffer(&self) -> &aligned::Buffer {
+            &self.buffer
+        }
+
+        fn into_buffer(self) -> Box<aligned::Buffer> {
+            self.buffer
+        }
+    }
 
-        pub(super) fn hash_algorithm(&self) -> Result<HashAlgorithm, ()> {
-            let algo_val = read_u16(&self.buffer[8..10]);
-            match algo_val {
-                0x0000 => Ok(HashAlgorithm::XxHash),
-                _ => Err(())
+    impl<V> aligned::FromAligned for DirectoryPage<V> {
+        fn extra_constraints(buffer: &aligned::Buffer) -> Result<(), aligned::Error> {
+            if DIRECTORY_GLOBAL_DEPTH.get(buffer) > max_global_depth(PAGESIZE) as u64 {
+                Err(aligned::Error::SizeError)
+            } else {
+                Ok(())
             }
+        }
 
+        fn transform(buffer: Box<aligned::Buffer>) -> Self {
+            DirectoryPage {
+                buffer,
+                page_size: PAGESIZE,
+                _value_type: PhantomData,
+            }
         }
+    }
 
-        fn tmp() {
-            let x: Box<[u8]> = Box::new([1, 2, 3u8]);
-            let x: Box<str> = "abc".into();
+    impl<V> TablePage for DirectoryPage<V> {
+        fn set_page_size(&mut self, page_size: usize) {
+            self.page_size = page_size;
         }
-        fn set_hash_algorithm(&mut self, algorithm: HashAlgorithm) {
-            self.buffer[8..10].copy_from_slice(&(algorithm as u16).to_le_bytes())
+    }
+
+    const BUCKET_LOCAL_DEPTH: Field<u64> = Field::at(0x8);
+
+    // crc32: u32,
+    // page_type: PageType,
+    // value_size: u16,
+    // local_depth: u64,
+    // slot_states: [u8; (capacity + 3) / 4],
+    // slots: [(K, [u8; value_size]); capacity],
+    pub(super) struct BucketPage<V, K> {
+        buffer: Box<aligned::Buffer>,
+        page_size: usize,
+        _value_type: PhantomData<V>,
+        _key_type: PhantomData<K>,
+    }
+
+    impl<V, K> BucketPage<V, K> {
+        pub(super) fn new(page_size: usize, local_depth: u32) -> BucketPage<V, K> {
+            let mut buffer = aligned::Buffer::new();
+            set_value_size(&mut buffer, size_of::<V>());
+            BUCKET_LOCAL_DEPTH.set(&mut buffer, local_depth as u64);
+            BucketPage {
+                buffer,
+                page_size,
+                _value_type: PhantomData,
+                _key_type: PhantomData,
+            }
+        }
+
+        pub(super) fn local_depth(&self) -> u32 {
+            BUCKET_LOCAL_DEPTH.get(&self.buffer) as u32
+        }
+    }
+
+    impl<V, K: Key> SlotStorage for BucketPage<V, K> {
+        const DATA_OFFSET: usize = SLOT_PAGE_DATA_OFFSET;
+        type Key = K;
+
+        fn buffer(&self) -> &aligned::Buffer {
+            &self.buffer
+        }
+
+        fn buffer_mut(&mut self) -> &mut aligned::Buffer {
+            &mut self.buffer
         }
+
+        fn page_size(&self) -> usize {
+            self.page_size
+        }
+    }
+
+    impl<V, K> aligned::PageHeader for BucketPage<V, K> {
+        const PAGE_TYPE: PageType = PageType::ExtendibleHashBucket;
     }
 
-    impl<V> aligned::FromAligned for Page<V> {
-        fn expected_page_type() -> PageType {
-            PageType::SinglePageHashTable
+    impl<V, K> aligned::IntoAligned for BucketPage<V, K> {
+        fn as_buffer(&self) -> &aligned::Buffer {
+            &self.buffer
         }
 
+        fn into_buffer(self) -> Box<aligned::Buffer> {
+            self.buffer
+        }
+    }
+
+    impl<V, K> aligned::FromAligned for BucketPage<V, K> {
         fn transform(buffer: Box<aligned::Buffer>) -> Self {
-            Page {
+            BucketPage {
+                buffer,
+                page_size: PAGESIZE,
+                _value_type: PhantomData,
+                _key_type: PhantomData,
+            }
+        }
+    }
+
+    impl<V, K> TablePage for BucketPage<V, K> {
+        fn set_page_size(&mut self, page_size: usize) {
+            self.page_size = page_size;
+        }
+    }
+    const LINEAR_HASH_ALGORITHM: Field<u32> = Field::at(0x8);
+    const LINEAR_KEY_SIZE: Field<u16> = Field::at(0xc);
+    const LINEAR_LEVEL: Field<u64> = Field::at(0x10);
+    const LINEAR_SPLIT_POINTER: Field<u64> = Field::at(0x18);
+    const LINEAR_HASH_SEED: Field<u64> = Field::at(0x20);
+    const LINEAR_LEN: Field<u64> = Field::at(0x28);
+
+    // crc32: u32,
+    // page_type: PageType,
+    // value_size: u16,
+    // hash_algorithm: HashAlgorithm,
+    // key_size: u16,
+    // padding: [u8; 2],
+    // level: u64,
+    // split_pointer: u64,
+    // hash_seed: u64,
+    // len: u64,
+    // buckets: [PageId; (1 << level) + split_pointer],
+    pub(super) struct LinearHeaderPage<V> {
+        buffer: Box<aligned::Buffer>,
+        page_size: usize,
+        _value_type: PhantomData<V>,
+    }
+
+    impl<V> LinearHeaderPage<V> {
+        const BUCKETS_OFFSET: usize = 0x30;
+
+        /// The most buckets a header page of `page_size` bytes can point to.
+        pub(super) const fn max_buckets(page_size: usize) -> usize {
+            (page_size - Self::BUCKETS_OFFSET) / 8
+        }
+
+        pub(super) fn new(page_size: usize, hash_seed: u64, key_size: usize, algorithm: HashAlgorithm) -> LinearHeaderPage<V> {
+            let mut buffer = aligned::Buffer::new();
+            set_value_size(&mut buffer, size_of::<V>());
+            LINEAR_HASH_ALGORITHM.set(&mut buffer, algorithm as u32);
+            set_key_size(&mut buffer, LINEAR_KEY_SIZE, key_size);
+            LINEAR_HASH_SEED.set(&mut buffer, hash_seed);
+            LinearHeaderPage {
+                buffer,
+                page_size,
+                _value_type: PhantomData,
+            }
+        }
+
+        pub(super) fn page_size(&self) -> usize {
+            self.page_size
+        }
+
+        pub(super) fn value_size(&self) -> usize {
+            value_size(&self.buffer)
+        }
+
+        pub(super) fn key_size(&self) -> usize {
+            key_size(&self.buffer, LINEAR_KEY_SIZE)
+        }
+
+        pub(super) fn hash_algorithm(&self) -> Result<HashAlgorithm, Error> {
+            HashAlgorithm::try_from(LINEAR_HASH_ALGORITHM.get(&self.buffer))
+        }
+
+        pub(super) fn level(&self) -> u32 {
+            LINEAR_LEVEL.get(&self.buffer) as u32
+        }
+
+        pub(super) fn split_pointer(&self) -> usize {
+            LINEAR_SPLIT_POINTER.get(&self.buffer) as usize
+        }
+
+        pub(super) fn hash_seed(&self) -> u64 {
+            LINEAR_HASH_SEED.get(&self.buffer)
+        }
+
+        pub(super) fn len(&self) -> u64 {
+            LINEAR_LEN.get(&self.buffer)
+        }
+
+        pub(super) fn set_len(&mut self, len: u64) {
+            LINEAR_LEN.set(&mut self.buffer, len);
+        }
+
+        pub(super) fn bucket_count(&self) -> usize {
+            (1 << self.level()) + self.split_pointer()
+        }
+
+        /// The bucket a hash belongs to.
+        pub(super) fn bucket_index(&self, hash: u64) -> usize {
+            let index = (hash & ((1 << self.level()) - 1)) as usize;
+            if index < self.split_pointer() {
+                (hash & ((1 << (self.level() + 1)) - 1)) as usize
+            } else {
+                index
+            }
+        }
+
+        /// Whether a table with `key_size` byte keys is over 3/4 full and
+        /// still has room to add a bucket.
+        pub(super) fn needs_split(&self, key_size: usize) -> bool {
+            let slots = self.bucket_count() * slot_page_capacity(self.page_size, key_size, self.value_size());
+            self.bucket_count() < Self::max_buckets(self.page_size) && self.len() as usize * 4 > slots * 3
+        }
+
+        /// Moves the split pointer on to the next bucket, starting a new
+        /// level once every bucket of the current one has been split.
+        pub(super) fn advance_split_pointer(&mut self) {
+            let mut split_pointer = self.split_pointer() as u64 + 1;
+            if split_pointer == 1 << self.level() {
+                let level = self.level() as u64 + 1;
+                LINEAR_LEVEL.set(&mut self.buffer, level);
+                split_pointer = 0;
+            }
+            LINEAR_SPLIT_POINTER.set(&mut self.buffer, split_pointer);
+        }
+
+        pub(super) fn bucket(&self, index: usize) -> PageId {
+            let offset = Self::BUCKETS_OFFSET + index * 8;
+            read_u64(&self.buffer[offset..offset + 8])
+        }
+
+        pub(super) fn set_bucket(&mut self, index: usize, page_id: PageId) {
+            let offset = Self::BUCKETS_OFFSET + index * 8;
+            self.buffer[offset..offset + 8].copy_from_slice(&page_id.to_le_bytes())
+        }
+    }
+
+    impl<V> aligned::PageHeader for LinearHeaderPage<V> {
+        const PAGE_TYPE: PageType = PageType::LinearHashHeader;
+    }
+
+    impl<V> aligned::IntoAligned for LinearHeaderPage<V> {
+        fn as_buffer(&self) -> &aligned::Buffer {
+            &self.buffer
+        }
+
+        fn into_buffer(self) -> Box<aligned::Buffer> {
+            self.buffer
+        }
+    }
+
+    impl<V> aligned::FromAligned for LinearHeaderPage<V> {
+        fn extra_constraints(buffer: &aligned::Buffer) -> Result<(), aligned::Error> {
+            let level = LINEAR_LEVEL.get(buffer);
+            let split_pointer = LINEAR_SPLIT_POINTER.get(buffer);
+            if level >= 63 || (1 << level) + split_pointer > Self::max_buckets(PAGESIZE) as u64 {
+                Err(aligned::Error::SizeError)
+            } else {
+                Ok(())
+            }
+        }
+
+        fn transform(buffer: Box<aligned::Buffer>) -> Self {
+            LinearHeaderPage {
+                buffer,
+                page_size: PAGESIZE,
+                _value_type: PhantomData,
+            }
+        }
+    }
+
+    impl<V> TablePage for LinearHeaderPage<V> {
+        fn set_page_size(&mut self, page_size: usize) {
+            self.page_size = page_size;
+        }
+    }
+
+    const LINEAR_BUCKET_OVERFLOW: Field<u64> = Field::at(0x8);
+
+    // crc32: u32,
+    // page_type: PageType,
+    // value_size: u16,
+    // next_page: PageId,
+    // slot_states: [u8; (capacity + 3) / 4],
+    // slots: [(K, [u8; value_size]); capacity],
+    pub(super) struct LinearBucketPage<V, K> {
+        buffer: Box<aligned::Buffer>,
+        page_size: usize,
+        _value_type: PhantomData<V>,
+        _key_type: PhantomData<K>,
+    }
+
+    impl<V, K> LinearBucketPage<V, K> {
+        pub(super) fn new(page_size: usize) -> LinearBucketPage<V, K> {
+            let mut buffer = aligned::Buffer::new();
+            set_value_size(&mut buffer, size_of::<V>());
+            LinearBucketPage {
+                buffer,
+                page_size,
+                _value_type: PhantomData,
+                _key_type: PhantomData,
+            }
+        }
+
+        pub(super) fn next_page(&self) -> Option<PageId> {
+            match LINEAR_BUCKET_OVERFLOW.get(&self.buffer) {
+                0 => None,
+                page_id => Some(page_id),
+            }
+        }
+
+        pub(super) fn set_next_page(&mut self, page_id: Option<PageId>) {
+            LINEAR_BUCKET_OVERFLOW.set(&mut self.buffer, page_id.unwrap_or(0));
+        }
+    }
+
+    impl<V, K: Key> SlotStorage for LinearBucketPage<V, K> {
+        const DATA_OFFSET: usize = SLOT_PAGE_DATA_OFFSET;
+        type Key = K;
+
+        fn buffer(&self) -> &aligned::Buffer {
+            &self.buffer
+        }
+
+        fn buffer_mut(&mut self) -> &mut aligned::Buffer {
+            &mut self.buffer
+        }
+
+        fn page_size(&self) -> usize {
+            self.page_size
+        }
+    }
+
+    impl<V, K> aligned::PageHeader for LinearBucketPage<V, K> {
+        const PAGE_TYPE: PageType = PageType::LinearHashBucket;
+    }
+
+    impl<V, K> aligned::IntoAligned for LinearBucketPage<V, K> {
+        fn as_buffer(&self) -> &aligned::Buffer {
+            &self.buffer
+        }
+
+        fn into_buffer(self) -> Box<aligned::Buffer> {
+            self.buffer
+        }
+    }
+
+    impl<V, K> aligned::FromAligned for LinearBucketPage<V, K> {
+        fn transform(buffer: Box<aligned::Buffer>) -> Self {
+            LinearBucketPage {
                 buffer,
+                page_size: PAGESIZE,
                 _value_type: PhantomData,
+                _key_type: PhantomData,
             }
         }
     }
+
+    impl<V, K> TablePage for LinearBucketPage<V, K> {
+        fn set_page_size(&mut self, page_size: usize) {
+            self.page_size = page_size;
+        }
+    }
 }
 
 #[cfg(test)]
@@ -381,39 +3595,797 @@ mod tests {
 
     #[test]
     fn simple_access() -> anyhow::Result<()> {
-        let path = create_test_path("test-potpotdb::hashtable::new_hashtable.data");
+        let path = create_test_path("test-potpotdb::hashtable::simple_access.data");
         let storage = PagedFile::from_path(&path)?;
-        let mut pool = BufferPool::new(storage, 3);
+        let pool = RefCell::new(BufferPool::new(storage, 3));
 
-        let mut ht = SinglePageHashTable::new(&mut pool);
+        let mut ht = SinglePageHashTable::new(&pool);
         ht.insert(97, (4, 12))?;
-        assert_eq!(ht.get(97).map(ToOwned::to_owned), Some((4, 12)));
-        assert!(ht.get(25).is_none());
+        assert_eq!(ht.get(97)?, Some((4, 12)));
+        assert!(ht.get(25)?.is_none());
+
+        // Inserting an existing key replaces its value.
+        ht.insert(97, (5, 13))?;
+        assert_eq!(ht.get(97)?, Some((5, 13)));
         Ok(())
     }
 
     #[test]
     fn persistence() -> anyhow::Result<()> {
-        let path = create_test_path("test-potpotdb::hashtable::new_hashtable.data");
+        let path = create_test_path("test-potpotdb::hashtable::persistence.data");
         let page_id = {
             let storage = PagedFile::from_path(&path)?;
 
-            let mut pool = BufferPool::new(storage, 3);
+            let pool = RefCell::new(BufferPool::new(storage, 3));
 
-            let mut ht = SinglePageHashTable::new(&mut pool);
+            let mut ht = SinglePageHashTable::<(usize, usize)>::new(&pool);
             ht.insert(97, (4, 12))?;
             ht.page_id()
             // Old buffer pool is deleted.
         };
         {
             let storage = PagedFile::from_path(&path)?;
-            let mut pool = BufferPool::new(storage, 3);
-            let ht = SinglePageHashTable::<(usize, usize)>::from_page(&mut pool, page_id)
+            let pool = RefCell::new(BufferPool::new(storage, 3));
+            let ht = SinglePageHashTable::<(usize, usize)>::from_page(&pool, page_id)
                 .expect("No hashtable found at that page ID");
 
-            assert_eq!(ht.get(97).map(ToOwned::to_owned), Some((4, 12)));
-            assert!(ht.get(25).is_none());
+            assert_eq!(ht.get(97)?, Some((4, 12)));
+            assert!(ht.get(25)?.is_none());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn fill_to_capacity() -> anyhow::Result<()> {
+        let path = create_test_path("test-potpotdb::hashtable::fill_to_capacity.data");
+        let storage = PagedFile::from_path(&path)?;
+        let pool = RefCell::new(BufferPool::new(storage, 3));
+
+        let mut ht = SinglePageHashTable::<u64>::new(&pool);
+        let capacity = ht.capacity() as u64;
+        for key in 0..capacity {
+            ht.insert(key * 7, key)?;
+        }
+        let err = ht.insert(capacity * 7, 0).expect_err("table should be full");
+        assert!(matches!(err.downcast_ref(), Some(Error::Capacity { .. })));
+
+        for key in 0..capacity {
+            assert_eq!(ht.get(key * 7)?, Some(key));
+        }
+        assert_eq!(ht.get(capacity * 7)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn remove() -> anyhow::Result<()> {
+        let path = create_test_path("test-potpotdb::hashtable::remove.data");
+        let storage = PagedFile::from_path(&path)?;
+        let pool = RefCell::new(BufferPool::new(storage, 3));
+
+        let mut ht = SinglePageHashTable::<u64>::new(&pool);
+        let capacity = ht.capacity() as u64;
+        for key in 0..capacity {
+            ht.insert(key, key + 1)?;
+        }
+
+        // Every key has been displaced by something, so removing half of
+        // them leaves tombstones in the middle of probe chains.
+        for key in (0..capacity).step_by(2) {
+            assert_eq!(ht.remove(key)?, Some(key + 1));
+        }
+        assert_eq!(ht.remove(0)?, None);
+        for key in 0..capacity {
+            let expected = if key % 2 == 0 { None } else { Some(key + 1) };
+            assert_eq!(ht.get(key)?, expected);
+        }
+
+        // Tombstoned slots are reused, so the table can be filled again.
+        for key in (0..capacity).step_by(2) {
+            ht.insert(key + capacity, key)?;
+        }
+        ht.insert(2 * capacity, 0).expect_err("table should be full");
+        for key in (0..capacity).step_by(2) {
+            assert_eq!(ht.get(key + capacity)?, Some(key));
+            assert_eq!(ht.get(key + 1)?, Some(key + 2));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn multi_page() -> anyhow::Result<()> {
+        let path = create_test_path("test-potpotdb::hashtable::multi_page.data");
+        let storage = PagedFile::from_path(&path)?;
+        let pool = RefCell::new(BufferPool::new(storage, 8));
+
+        let mut ht = HashTable::<[u64; 2]>::with_page_count(&pool, 4)?;
+        let page_capacity = page::slot_page_capacity(PAGESIZE, 8, 16) as u64;
+        assert_eq!(ht.capacity()?, 4 * page_capacity as usize);
+
+        // More entries than a single page can hold.
+        let count = page_capacity * 2;
+        for key in 0..count {
+            ht.insert(key, [key, key * 2])?;
+        }
+        for key in 0..count {
+            assert_eq!(ht.get(key)?, Some([key, key * 2]));
+        }
+        assert_eq!(ht.get(count)?, None);
+
+        assert_eq!(ht.remove(3)?, Some([3, 6]));
+        assert_eq!(ht.get(3)?, None);
+        assert_eq!(ht.remove(3)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn multi_page_spillover() -> anyhow::Result<()> {
+        let path = create_test_path("test-potpotdb::hashtable::multi_page_spillover.data");
+        // Large values keep the slot pages small.
+        let value = |key: u64| [key; 32];
+        let page_id = {
+            let storage = PagedFile::from_path(&path)?;
+            let pool = RefCell::new(BufferPool::new(storage, 3));
+
+            // Fill every slot page completely, so many keys have to spill
+            // over from their home page.
+            let mut ht = HashTable::<[u64; 32]>::with_page_count(&pool, 4)?;
+            let capacity = ht.capacity()? as u64;
+            for key in 0..capacity {
+                ht.insert(key, value(key))?;
+            }
+            let err = ht.insert(capacity, value(0)).expect_err("table should be full");
+            assert!(matches!(err.downcast_ref(), Some(Error::Capacity { .. })));
+
+            // Freeing one slot anywhere makes room for one more key.
+            ht.remove(17)?;
+            ht.insert(capacity, value(0))?;
+            ht.page_id()
+        };
+
+        let storage = PagedFile::from_path(&path)?;
+        let pool = RefCell::new(BufferPool::new(storage, 3));
+        let ht = HashTable::<[u64; 32]>::from_page(&pool, page_id)?;
+        let capacity = ht.capacity()? as u64;
+        for key in (0..capacity).filter(|&key| key != 17) {
+            assert_eq!(ht.get(key)?, Some(value(key)));
+        }
+        assert_eq!(ht.get(17)?, None);
+        assert_eq!(ht.get(capacity)?, Some(value(0)));
+        Ok(())
+    }
+
+    #[test]
+    fn extendible_growth() -> anyhow::Result<()> {
+        let path = create_test_path("test-potpotdb::hashtable::extendible_growth.data");
+        let value = |key: u64| [key; 32];
+        let count = 2000;
+        let page_id = {
+            let storage = PagedFile::from_path(&path)?;
+            let pool = RefCell::new(BufferPool::new(storage, 8));
+
+            let mut ht = ExtendibleHashTable::<[u64; 32]>::new(&pool)?;
+            assert_eq!(ht.global_depth()?, 0);
+            for key in 0..count {
+                ht.insert(key, value(key))?;
+            }
+            // Only about 60 entries fit in a bucket, so the directory has grown.
+            assert!(ht.global_depth()? >= 5);
+            for key in 0..count {
+                assert_eq!(ht.get(key)?, Some(value(key)), "key {}", key);
+            }
+            for key in (0..count).step_by(3) {
+                assert_eq!(ht.remove(key)?, Some(value(key)));
+            }
+            ht.page_id()
+        };
+
+        let storage = PagedFile::from_path(&path)?;
+        let pool = RefCell::new(BufferPool::new(storage, 8));
+        let ht = ExtendibleHashTable::<[u64; 32]>::from_page(&pool, page_id)?;
+        for key in 0..count {
+            let expected = if key % 3 == 0 { None } else { Some(value(key)) };
+            assert_eq!(ht.get(key)?, expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn linear_growth() -> anyhow::Result<()> {
+        let path = create_test_path("test-potpotdb::hashtable::linear_growth.data");
+        let value = |key: u64| [key; 32];
+        let count = 2000;
+        let page_id = {
+            let storage = PagedFile::from_path(&path)?;
+            let pool = RefCell::new(BufferPool::new(storage, 8));
+
+            let mut ht = LinearHashTable::<[u64; 32]>::new(&pool)?;
+            assert_eq!(ht.bucket_count()?, 1);
+            for key in 0..count {
+                ht.insert(key, value(key))?;
+            }
+            // About 60 entries fit in a bucket, and buckets split at 3/4 full.
+            assert!(ht.bucket_count()? >= 2000 / 45);
+            for key in 0..count {
+                assert_eq!(ht.get(key)?, Some(value(key)), "key {}", key);
+            }
+            for key in (0..count).step_by(3) {
+                assert_eq!(ht.remove(key)?, Some(value(key)));
+            }
+            assert_eq!(ht.remove(0)?, None);
+            ht.page_id()
+        };
+
+        let storage = PagedFile::from_path(&path)?;
+        let pool = RefCell::new(BufferPool::new(storage, 8));
+        let ht = LinearHashTable::<[u64; 32]>::from_page(&pool, page_id)?;
+        for key in 0..count {
+            let expected = if key % 3 == 0 { None } else { Some(value(key)) };
+            assert_eq!(ht.get(key)?, expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn byte_keys() -> anyhow::Result<()> {
+        let path = create_test_path("test-potpotdb::hashtable::byte_keys.data");
+        let key = |i: u64| ByteKey::<16>::try_from(format!("user:{}", i).as_str());
+        let page_id = {
+            let storage = PagedFile::from_path(&path)?;
+            let pool = RefCell::new(BufferPool::new(storage, 8));
+
+            let mut ht = HashTable::<u64, ByteKey<16>>::with_page_count(&pool, 4)?;
+            for i in 0..1000 {
+                ht.insert(key(i)?, i)?;
+            }
+            assert_eq!(ht.get(key(7)?)?, Some(7));
+            assert_eq!(ht.remove(key(8)?)?, Some(8));
+            ht.page_id()
+        };
+
+        let storage = PagedFile::from_path(&path)?;
+        let pool = RefCell::new(BufferPool::new(storage, 8));
+        let ht = HashTable::<u64, ByteKey<16>>::from_page(&pool, page_id)?;
+        for i in (0..1000).filter(|&i| i != 8) {
+            assert_eq!(ht.get(key(i)?)?, Some(i));
+        }
+        assert_eq!(ht.get(key(8)?)?, None);
+        assert_eq!(ht.get(ByteKey::new(b"")?)?, None);
+
+        let err = ByteKey::<4>::new(b"too long").expect_err("key should not fit");
+        assert!(matches!(err, Error::KeyLength { len: 8, max: 4 }));
+
+        // A stored length past the end of the key is cut short.
+        let damaged = ByteKey::<4>::read_from(&[9, 0, b'a', b'b', b'c', b'd']);
+        assert_eq!(damaged.as_bytes(), b"abcd");
+
+        // Keys hash the same bytes whatever the width of usize.
+        let hash_builder = SeededHashBuilder::new(HashAlgorithm::XxHash, 0);
+        let mut hasher = hash_builder.build_hasher();
+        hasher.write(&[3, 0, b'a', b'b', b'c']);
+        assert_eq!(hash_builder.hash_one(ByteKey::<4>::new(b"abc")?), hasher.finish());
+        Ok(())
+    }
+
+    #[test]
+    fn variable_length_values() -> anyhow::Result<()> {
+        let path = create_test_path("test-potpotdb::hashtable::variable_length_values.data");
+        let value = |key: u64| "x".repeat(key as usize % 100);
+        let page_id = {
+            let storage = PagedFile::from_path(&path)?;
+            let pool = RefCell::new(BufferPool::new(storage, 3));
+
+            let mut ht = SinglePageVarHashTable::<String>::new(&pool)?;
+            for key in 0..200 {
+                ht.insert(key, value(key))?;
+            }
+            for key in 0..200 {
+                assert_eq!(ht.get(key)?, Some(value(key)));
+            }
+
+            // Growing and shrinking values in place.
+            ht.insert(5, "a much longer value than before".to_string())?;
+            ht.insert(99, "short".to_string())?;
+            assert_eq!(ht.get(5)?.as_deref(), Some("a much longer value than before"));
+            assert_eq!(ht.get(99)?.as_deref(), Some("short"));
+            assert_eq!(ht.remove(7)?, Some(value(7)));
+            assert_eq!(ht.get(7)?, None);
+            ht.page_id()
+        };
+
+        let storage = PagedFile::from_path(&path)?;
+        let pool = RefCell::new(BufferPool::new(storage, 3));
+        let ht = SinglePageVarHashTable::<String>::from_page(&pool, page_id)?;
+        assert_eq!(ht.get(5)?.as_deref(), Some("a much longer value than before"));
+        assert_eq!(ht.get(7)?, None);
+        assert_eq!(ht.get(150)?, Some(value(150)));
+        Ok(())
+    }
+
+    #[test]
+    fn variable_length_compaction() -> anyhow::Result<()> {
+        let path = create_test_path("test-potpotdb::hashtable::variable_length_compaction.data");
+        let storage = PagedFile::from_path(&path)?;
+        let pool = RefCell::new(BufferPool::new(storage, 3));
+
+        let mut ht = SinglePageVarHashTable::<Vec<u8>>::with_slot_count(&pool, 16)?;
+        let big = |byte: u8| vec![byte; 3000];
+        for key in 0..5 {
+            ht.insert(key, big(key as u8))?;
+        }
+        let err = ht.insert(5, big(5)).expect_err("page should be full");
+        assert!(matches!(err.downcast_ref(), Some(Error::Space { .. })));
+
+        // Space freed in the middle of the values is reused after compaction.
+        ht.remove(2)?;
+        ht.insert(5, big(5))?;
+        for key in (0..6).filter(|&key| key != 2) {
+            assert_eq!(ht.get(key)?, Some(big(key as u8)));
+        }
+
+        for key in 10..21 {
+            ht.insert(key, vec![])?;
+        }
+        let err = ht.insert(21, vec![]).expect_err("slots should be full");
+        assert!(matches!(err.downcast_ref(), Some(Error::Capacity { capacity: 16 })));
+        Ok(())
+    }
+
+    #[test]
+    fn entry() -> anyhow::Result<()> {
+        let path = create_test_path("test-potpotdb::hashtable::entry.data");
+        let storage = PagedFile::from_path(&path)?;
+        let pool = RefCell::new(BufferPool::new(storage, 8));
+
+        let mut ht = HashTable::<u64>::with_page_count(&pool, 4)?;
+        for key in 0..100 {
+            ht.insert_or_update(key % 10, 1, |count| *count += 1)?;
         }
+        for key in 0..10 {
+            assert_eq!(ht.get(key)?, Some(10));
+        }
+
+        match ht.entry(3)? {
+            Entry::Occupied(entry) => {
+                assert_eq!(*entry.key(), 3);
+                assert_eq!(entry.get()?, 10);
+                assert_eq!(entry.remove()?, 10);
+            }
+            Entry::Vacant(_) => panic!("key 3 should be present"),
+        }
+        assert!(matches!(ht.entry(3)?, Entry::Vacant(_)));
+        ht.entry(3)?.and_modify(|value| *value = 0)?.or_insert_with(|| 42)?;
+        assert_eq!(ht.get(3)?, Some(42));
+        ht.entry(3)?.and_modify(|value| *value += 1)?.or_insert(0)?;
+        assert_eq!(ht.get(3)?, Some(43));
+        Ok(())
+    }
+
+    #[test]
+    fn insert_or_update() -> anyhow::Result<()> {
+        let path = create_test_path("test-potpotdb::hashtable::insert_or_update.data");
+        let storage = PagedFile::from_path(&path)?;
+        let pool = RefCell::new(BufferPool::new(storage, 8));
+        {
+            let mut ht = SinglePageHashTable::<u64>::new(&pool);
+            for key in 0..30 {
+                ht.insert_or_update(key % 3, 1, |count| *count += 1)?;
+            }
+            assert_eq!(ht.get(2)?, Some(10));
+        }
+        {
+            let mut ht = SinglePageVarHashTable::<String>::new(&pool)?;
+            for key in 0..30 {
+                ht.insert_or_update(key % 3, "x".to_string(), |s| s.push('x'))?;
+            }
+            assert_eq!(ht.get(2)?, Some("x".repeat(10)));
+        }
+        {
+            let mut ht = ExtendibleHashTable::<u64>::new(&pool)?;
+            for key in 0..30 {
+                ht.insert_or_update(key % 3, 1, |count| *count += 1)?;
+            }
+            assert_eq!(ht.get(2)?, Some(10));
+        }
+        {
+            let mut ht = LinearHashTable::<u64>::new(&pool)?;
+            for key in 0..30 {
+                ht.insert_or_update(key % 3, 1, |count| *count += 1)?;
+            }
+            assert_eq!(ht.get(2)?, Some(10));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn reopen_with_wrong_types() -> anyhow::Result<()> {
+        let path = create_test_path("test-potpotdb::hashtable::reopen_with_wrong_types.data");
+        let storage = PagedFile::from_path(&path)?;
+        let pool = RefCell::new(BufferPool::new(storage, 8));
+
+        let single = SinglePageHashTable::<(u64, u64)>::new(&pool).page_id();
+        let multi = HashTable::<u32>::with_page_count(&pool, 2)?.page_id();
+        let extendible = ExtendibleHashTable::<u64, ByteKey<10>>::new(&pool)?.page_id();
+
+        let err = SinglePageHashTable::<u64>::from_page(&pool, single).err().expect("wrong value type");
+        assert!(matches!(err.downcast_ref(), Some(Error::ValueType { expected: 8, found: 16 })));
+        SinglePageHashTable::<(u64, u64)>::from_page(&pool, single)?;
+
+        let err = HashTable::<u32, ByteKey<4>>::from_page(&pool, multi).err().expect("wrong key type");
+        assert!(matches!(err.downcast_ref(), Some(Error::KeyType { expected: 6, found: 8 })));
+        HashTable::<u32>::from_page(&pool, multi)?;
+
+        let err = ExtendibleHashTable::<u64>::from_page(&pool, extendible).err().expect("wrong key type");
+        assert!(matches!(err.downcast_ref(), Some(Error::KeyType { expected: 8, found: 12 })));
+        ExtendibleHashTable::<u64, ByteKey<10>>::from_page(&pool, extendible)?;
+        Ok(())
+    }
+
+    #[test]
+    fn small_pages() -> anyhow::Result<()> {
+        use crate::storage::{PageSize, PagedFileConfig};
+
+        let path = create_test_path("test-potpotdb::hashtable::small_pages.data");
+        let config = PagedFileConfig {
+            page_size: PageSize::K4,
+            ..PagedFileConfig::default()
+        };
+        let ids = {
+            let pool = RefCell::new(BufferPool::new(PagedFile::open_with(&path, config)?, 8));
+            let mut single = SinglePageHashTable::<u64>::new(&pool);
+            assert_eq!(single.capacity(), page::capacity(4096, 8, 8));
+            for key in 0..100 {
+                single.insert(key, key * 2)?;
+            }
+            let mut var = SinglePageVarHashTable::<String>::new(&pool)?;
+            for key in 0..20 {
+                var.insert(key, key.to_string())?;
+            }
+            // Each of these outgrows several 4K pages.
+            let mut multi = HashTable::<u64>::new(&pool)?;
+            let mut extendible = ExtendibleHashTable::<u64>::new(&pool)?;
+            let mut linear = LinearHashTable::<u64>::new(&pool)?;
+            for key in 0..2000 {
+                multi.insert(key, key * 2)?;
+                extendible.insert(key, key * 2)?;
+                linear.insert(key, key * 2)?;
+            }
+            [single.page_id(), var.page_id(), multi.page_id(), extendible.page_id(), linear.page_id()]
+        };
+
+        let pool = RefCell::new(BufferPool::new(PagedFile::open_with(&path, config)?, 8));
+        let single = SinglePageHashTable::<u64>::from_page(&pool, ids[0])?;
+        assert_eq!(single.len()?, 100);
+        assert_eq!(single.get(99)?, Some(198));
+        let var = SinglePageVarHashTable::<String>::from_page(&pool, ids[1])?;
+        assert_eq!(var.get(19)?.as_deref(), Some("19"));
+        let multi = HashTable::<u64>::from_page(&pool, ids[2])?;
+        let extendible = ExtendibleHashTable::<u64>::from_page(&pool, ids[3])?;
+        let linear = LinearHashTable::<u64>::from_page(&pool, ids[4])?;
+        assert_eq!(linear.len()?, 2000);
+        for key in 0..2000 {
+            assert_eq!(multi.get(key)?, Some(key * 2));
+            assert_eq!(extendible.get(key)?, Some(key * 2));
+            assert_eq!(linear.get(key)?, Some(key * 2));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn hash_algorithms() -> anyhow::Result<()> {
+        let path = create_test_path("test-potpotdb::hashtable::hash_algorithms.data");
+        let algorithms = [
+            HashAlgorithm::XxHash,
+            HashAlgorithm::SipHash13,
+            HashAlgorithm::WyHash,
+            HashAlgorithm::Xxh3,
+        ];
+        let page_ids = {
+            let storage = PagedFile::from_path(&path)?;
+            let pool = RefCell::new(BufferPool::new(storage, 8));
+            let mut page_ids = Vec::new();
+            for &algorithm in &algorithms {
+                let mut ht = HashTable::<u64>::with_hash_algorithm(&pool, 4, algorithm)?;
+                for key in 0..500 {
+                    ht.insert(key, key * 3)?;
+                }
+                page_ids.push(ht.page_id());
+            }
+            page_ids
+        };
+
+        // Each table is reopened with the hasher it was written with.
+        let storage = PagedFile::from_path(&path)?;
+        let pool = RefCell::new(BufferPool::new(storage, 8));
+        for page_id in page_ids {
+            let ht = HashTable::<u64>::from_page(&pool, page_id)?;
+            for key in 0..500 {
+                assert_eq!(ht.get(key)?, Some(key * 3));
+            }
+        }
+        assert!(matches!(HashAlgorithm::try_from(0x99), Err(Error::HashAlgorithm(0x99))));
+        Ok(())
+    }
+
+    #[test]
+    fn checksum_algorithms() -> anyhow::Result<()> {
+        use crate::aligned::Checksum;
+
+        for checksum in [Checksum::Crc32c, Checksum::Xxh3] {
+            let path = create_test_path(format!("test-potpotdb::hashtable::checksum_{:?}.data", checksum));
+            let page_id = {
+                let pool = RefCell::new(BufferPool::new(PagedFile::from_path(&path)?, 8));
+                pool.borrow_mut().create_master_record_with(checksum)?;
+                let mut ht = HashTable::<u64>::new(&pool)?;
+                for key in 0..500 {
+                    ht.insert(key, key * 3)?;
+                }
+                ht.page_id()
+            };
+
+            // The algorithm is read back from the master record.
+            let pool = RefCell::new(BufferPool::new(PagedFile::from_path(&path)?, 8));
+            assert_eq!(pool.borrow_mut().checksum()?, checksum);
+            let ht = HashTable::<u64>::from_page(&pool, page_id)?;
+            for key in 0..500 {
+                assert_eq!(ht.get(key)?, Some(key * 3));
+            }
+            let mut buffer = aligned::Buffer::new();
+            pool.borrow_mut().read_page(page_id, &mut buffer)?;
+            assert!(checksum.verify(&buffer));
+            assert!(!Checksum::Crc32.verify(&buffer));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn len() -> anyhow::Result<()> {
+        let path = create_test_path("test-potpotdb::hashtable::len.data");
+        let (page_id, linear_id, multi_id, extendible_id) = {
+            let storage = PagedFile::from_path(&path)?;
+            let pool = RefCell::new(BufferPool::new(storage, 4));
+            let mut ht = SinglePageHashTable::<u64>::new(&pool);
+            assert!(ht.is_empty()?);
+            for key in 0..100 {
+                ht.insert(key, key)?;
+            }
+            // Overwriting or removing a missing key leaves the count alone.
+            ht.insert(7, 70)?;
+            ht.insert_or_update(8, 0, |value| *value += 1)?;
+            assert_eq!(ht.remove(1000)?, None);
+            for key in 0..10 {
+                ht.remove(key)?;
+            }
+            assert_eq!(ht.len()?, 90);
+            assert_eq!(ht.load_factor()?, 90.0 / ht.capacity() as f64);
+            let page_id = ht.page_id();
+
+            let mut linear = LinearHashTable::<u64>::new(&pool)?;
+            assert!(linear.is_empty()?);
+            for key in 0..2000 {
+                linear.insert(key, key)?;
+            }
+            assert_eq!(linear.len()?, 2000);
+            assert!(linear.load_factor()? <= 0.75);
+
+            let mut multi = HashTable::<u64>::with_page_count(&pool, 4)?;
+            let mut extendible = ExtendibleHashTable::<u64>::new(&pool)?;
+            assert!(multi.is_empty()? && extendible.is_empty()?);
+            for key in 0..2000 {
+                multi.insert(key, key)?;
+                extendible.insert(key, key)?;
+            }
+            multi.insert(7, 70)?;
+            extendible.insert_or_update(8, 0, |value| *value += 1)?;
+            assert_eq!(multi.remove(5000)?, None);
+            assert_eq!(extendible.remove(5000)?, None);
+            for key in 0..10 {
+                multi.remove(key)?;
+                extendible.remove(key)?;
+            }
+            // Reusing a tombstone counts as much as an empty slot.
+            multi.insert(3, 3)?;
+            extendible.insert(3, 3)?;
+            assert_eq!(multi.len()?, 1991);
+            assert_eq!(multi.load_factor()?, 1991.0 / multi.capacity()? as f64);
+            assert_eq!(extendible.len()?, 1991);
+            assert!(extendible.load_factor()? > 0.0 && extendible.load_factor()? <= 1.0);
+
+            let bulk = HashTable::<u64>::bulk_load(&pool, 4, (0..500).chain(0..10).map(|key| (key, key)))?;
+            assert_eq!(bulk.len()?, 500);
+            (page_id, linear.page_id(), multi.page_id(), extendible.page_id())
+        };
+
+        let storage = PagedFile::from_path(&path)?;
+        let pool = RefCell::new(BufferPool::new(storage, 4));
+        assert_eq!(SinglePageHashTable::<u64>::from_page(&pool, page_id)?.len()?, 90);
+        assert_eq!(LinearHashTable::<u64>::from_page(&pool, linear_id)?.len()?, 2000);
+        assert_eq!(HashTable::<u64>::from_page(&pool, multi_id)?.len()?, 1991);
+        assert_eq!(ExtendibleHashTable::<u64>::from_page(&pool, extendible_id)?.len()?, 1991);
+        Ok(())
+    }
+
+    #[test]
+    fn bulk_load() -> anyhow::Result<()> {
+        let path = create_test_path("test-potpotdb::hashtable::bulk_load.data");
+        let storage = PagedFile::from_path(&path)?;
+        let pool = RefCell::new(BufferPool::new(storage, 4));
+
+        // The duplicate of key 0 replaces the first one.
+        let entries = (0..1000).map(|key| (key, key * 2)).chain(Some((0, 7)));
+        let ht = SinglePageHashTable::<u64>::from_iter(&pool, entries)?;
+        assert_eq!(ht.len()?, 1000);
+        assert_eq!(ht.get(0)?, Some(7));
+        for key in 1..1000 {
+            assert_eq!(ht.get(key)?, Some(key * 2));
+        }
+        let capacity = ht.capacity() as u64;
+        SinglePageHashTable::<u64>::from_iter(&pool, (0..=capacity).map(|key| (key, key)))
+            .err()
+            .expect("table should be full");
+
+        let entries = (0..5000).map(|key| (key, [key, key + 1]));
+        let mut ht = HashTable::<[u64; 2]>::bulk_load(&pool, 8, entries)?;
+        for key in 0..5000 {
+            assert_eq!(ht.get(key)?, Some([key, key + 1]));
+        }
+        // A bulk loaded table takes further inserts like any other.
+        ht.insert(5000, [0, 0])?;
+        assert_eq!(ht.get(5000)?, Some([0, 0]));
+        let page_id = ht.page_id();
+        let ht = HashTable::<[u64; 2]>::from_page(&pool, page_id)?;
+        assert_eq!(ht.get(4999)?, Some([4999, 5000]));
+
+        let capacity = ht.capacity()? as u64;
+        HashTable::<[u64; 2]>::bulk_load(&pool, 8, (0..=capacity).map(|key| (key, [key, key])))
+            .err()
+            .expect("table should be full");
+        Ok(())
+    }
+
+    #[test]
+    fn shared_buffer_pool() -> anyhow::Result<()> {
+        let path = create_test_path("test-potpotdb::hashtable::shared_buffer_pool.data");
+        let storage = PagedFile::from_path(&path)?;
+        let pool = RefCell::new(BufferPool::new(storage, 4));
+
+        // Several tables can use the same pool at once.
+        let mut single = SinglePageHashTable::<u64>::new(&pool);
+        let mut multi = HashTable::<u64>::with_page_count(&pool, 4)?;
+        let mut linear = LinearHashTable::<u64>::new(&pool)?;
+        for key in 0..200 {
+            single.insert(key, key + 1)?;
+            multi.insert(key, key + 2)?;
+            linear.insert(key, key + 3)?;
+        }
+        let reopened = HashTable::<u64>::from_page(&pool, multi.page_id())?;
+        for key in 0..200 {
+            assert_eq!(single.get(key)?, Some(key + 1));
+            assert_eq!(reopened.get(key)?, Some(key + 2));
+            assert_eq!(linear.get(key)?, Some(key + 3));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn stats() -> anyhow::Result<()> {
+        let path = create_test_path("test-potpotdb::hashtable::stats.data");
+        let storage = PagedFile::from_path(&path)?;
+        let pool = RefCell::new(BufferPool::new(storage, 4));
+
+        let mut ht = SinglePageHashTable::<u64>::new(&pool);
+        let stats = ht.stats()?;
+        assert_eq!((stats.entries, stats.tombstones, stats.max_probe_length), (0, 0, 0));
+        assert_eq!(stats.page_fill, vec![0.0]);
+
+        let capacity = ht.capacity();
+        for key in 0..capacity as u64 {
+            ht.insert(key, key)?;
+        }
+        for key in 0..10 {
+            ht.remove(key)?;
+        }
+        let stats = ht.stats()?;
+        assert_eq!(stats.entries, capacity - 10);
+        assert_eq!(stats.tombstones, 10);
+        assert!(stats.mean_probe_length >= 1.0);
+        assert!(stats.max_probe_length as f64 >= stats.mean_probe_length);
+        assert!(stats.max_probe_length <= capacity);
+
+        // Two pages, so one is left unallocated while all keys land on the
+        // other, and then everything spills over onto it.
+        let mut ht = HashTable::<[u64; 32]>::with_page_count(&pool, 2)?;
+        let stats = ht.stats()?;
+        assert_eq!(stats.page_fill, vec![0.0, 0.0]);
+        let page_capacity = ht.capacity()? / 2;
+        for key in 0..(page_capacity * 3 / 2) as u64 {
+            ht.insert(key, [key; 32])?;
+        }
+        let stats = ht.stats()?;
+        assert_eq!(stats.entries, page_capacity * 3 / 2);
+        assert_eq!(stats.page_fill.len(), 2);
+        let fill: f64 = stats.page_fill.iter().sum();
+        assert_eq!((fill * page_capacity as f64).round() as usize, stats.entries);
+        assert!(stats.max_probe_length <= 2 * page_capacity);
+        Ok(())
+    }
+
+    #[test]
+    fn contains_key_and_get_or_insert_with() -> anyhow::Result<()> {
+        let path = create_test_path("test-potpotdb::hashtable::contains_key_and_get_or_insert_with.data");
+        let storage = PagedFile::from_path(&path)?;
+        let pool = RefCell::new(BufferPool::new(storage, 4));
+
+        let mut single = SinglePageHashTable::<u64>::new(&pool);
+        let mut multi = HashTable::<u64>::with_page_count(&pool, 4)?;
+        for key in 0..100 {
+            assert!(!single.contains_key(key)?);
+            assert!(!multi.contains_key(key)?);
+            assert_eq!(single.get_or_insert_with(key, || key * 2)?, key * 2);
+            assert_eq!(multi.get_or_insert_with(key, || key * 3)?, key * 3);
+        }
+        for key in 0..100 {
+            assert!(single.contains_key(key)?);
+            assert!(multi.contains_key(key)?);
+            assert_eq!(single.get_or_insert_with(key, || unreachable!())?, key * 2);
+            assert_eq!(multi.get_or_insert_with(key, || unreachable!())?, key * 3);
+        }
+        single.remove(7)?;
+        multi.remove(7)?;
+        assert!(!single.contains_key(7)?);
+        assert!(!multi.contains_key(7)?);
+        Ok(())
+    }
+
+    #[test]
+    fn bloom_filters() -> anyhow::Result<()> {
+        let path = create_test_path("test-potpotdb::hashtable::bloom_filters.data");
+        let storage = PagedFile::from_path(&path)?;
+        let pool = RefCell::new(BufferPool::new(storage, 16));
+
+        // A table at the default page count, 90% full.
+        let page_count = HashTable::<u64>::DEFAULT_PAGE_COUNT;
+        let full = (page_count * page::slot_page_capacity(PAGESIZE, 8, 8)) as u64 * 9 / 10;
+        let mut ht = HashTable::<u64>::bulk_load(&pool, page_count, (0..full - 100).map(|key| (key, key)))?;
+        for key in full - 100..full {
+            ht.insert(key, key)?;
+        }
+        ht.remove(5)?;
+        let header = ht.read_header()?;
+        let filters = (0..header.filter_page_count())
+            .map(|filter_index| load_page(&pool, header.filter_page_pointer(filter_index)))
+            .collect::<anyhow::Result<Vec<page::FilterPage>>>()?;
+        let may_contain = |index: usize, hash: u64| {
+            let (filter_index, range) = header.filter_location(index).expect("table has filters");
+            filters[filter_index].may_contain(range, hash)
+        };
+
+        // Stored keys are never filtered out.
+        for key in 0..full {
+            let hash = ht.hash(&key);
+            assert!(page_sequence(hash, page_count).any(|index| may_contain(index, hash)));
+        }
+        for key in (0..full).step_by(97) {
+            assert_eq!(ht.contains_key(key)?, key != 5);
+        }
+        // Nearly all missing keys are, so looking them up skips the slot
+        // pages.
+        let false_positives = (full..full + 10000)
+            .filter(|key| may_contain((ht.hash(key) % page_count as u64) as usize, ht.hash(key)))
+            .count();
+        assert!(false_positives < 200, "{} false positives", false_positives);
+        for key in full..full + 1000 {
+            assert_eq!(ht.get(key)?, None);
+        }
+
+        // Tables written without filters read every slot page.
+        let mut buffer = aligned::Buffer::new();
+        pool.borrow_mut().read_page(ht.page_id(), &mut buffer)?;
+        buffer[0xe..0x10].copy_from_slice(&[0, 0]);
+        aligned::Checksum::Crc32.stamp(&mut buffer);
+        pool.borrow_mut().update_page(ht.page_id(), &buffer)?;
+        assert_eq!(ht.read_header()?.filter_location(0), None);
+        assert_eq!(ht.get(full - 1)?, Some(full - 1));
+        assert_eq!(ht.get(full)?, None);
         Ok(())
     }
 }
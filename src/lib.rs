@@ -7,6 +7,7 @@ pub mod query;
 pub mod record;
 pub mod result;
 pub mod hashtable;
+pub mod lock;
 
 #[cfg(test)]
 mod testutils;
@@ -19,14 +20,46 @@ pub(crate) type Result<T> = std::result::Result<T, result::Error>;
 #[derive(Clone, Copy, Eq, PartialEq)]
 pub(crate) enum PageType {
     MasterRecord = 0x0000,
+    FreePage = 0x0001,
     DataPage = 0x1000,
+    OverflowPage = 0x1001,
     SinglePageHashTable = 0x2000,
-    HashTableFixedWidthSlot = 0x2001,
+    HashTableHeader = 0x2001,
+    ExtendibleHashDirectory = 0x2002,
+    LinearHashHeader = 0x2003,
+    SinglePageVarHashTable = 0x2010,
+    HashTableFixedWidthSlot = 0x2021,
+    ExtendibleHashBucket = 0x2022,
+    LinearHashBucket = 0x2023,
+    HashTableFilter = 0x2024,
 }
 
-impl From<u16> for PageType {
-    fn from(val: u16) -> PageType {
-        unsafe { std::mem::transmute(val) }
+impl PageType {
+    const ALL: [PageType; 13] = [
+        PageType::MasterRecord,
+        PageType::FreePage,
+        PageType::DataPage,
+        PageType::OverflowPage,
+        PageType::SinglePageHashTable,
+        PageType::HashTableHeader,
+        PageType::ExtendibleHashDirectory,
+        PageType::LinearHashHeader,
+        PageType::SinglePageVarHashTable,
+        PageType::HashTableFixedWidthSlot,
+        PageType::ExtendibleHashBucket,
+        PageType::LinearHashBucket,
+        PageType::HashTableFilter,
+    ];
+}
+
+/// Fails with the value itself if it isn't a known page type.
+impl std::convert::TryFrom<u16> for PageType {
+    type Error = u16;
+
+    fn try_from(val: u16) -> std::result::Result<PageType, u16> {
+        (PageType::ALL.iter().copied())
+            .find(|&page_type| page_type as u16 == val)
+            .ok_or(val)
     }
 }
 
@@ -0,0 +1,453 @@
+//! Hierarchical lock manager.
+//!
+//! Locks are taken on tables, pages and rows.  Locking a row or page
+//! implicitly takes the matching intention lock on each of its ancestors,
+//! so a table-level `S` or `X` request conflicts with anyone working below
+//! it.
+//!
+//! The manager never blocks.  A request that cannot be granted is queued
+//! and `lock` returns `LockStatus::Waiting`; once the conflicting locks are
+//! released the waiting transaction shows up in `drain_ready()`, and the
+//! caller retries the same `lock` call.  Every time a request has to wait,
+//! the waits-for graph is checked for a cycle.  If one is found, the
+//! youngest transaction in the cycle (the one with the highest id) is
+//! aborted: its locks are released, and its next call to `lock` returns
+//! `LockError::Deadlock`.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use thiserror::Error;
+
+use crate::{page::RecordId, record::PageId};
+
+pub type TxnId = u64;
+pub type TableId = u32;
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Resource {
+    Table(TableId),
+    Page(TableId, PageId),
+    Row(TableId, PageId, RecordId),
+}
+
+impl Resource {
+    fn parent(self) -> Option<Resource> {
+        match self {
+            Resource::Table(_) => None,
+            Resource::Page(table, _) => Some(Resource::Table(table)),
+            Resource::Row(table, page, _) => Some(Resource::Page(table, page)),
+        }
+    }
+
+    /// The resource and all of its ancestors, outermost first.
+    fn path(self) -> Vec<Resource> {
+        let mut path = vec![self];
+        while let Some(parent) = path.last().and_then(|res| res.parent()) {
+            path.push(parent);
+        }
+        path.reverse();
+        path
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LockMode {
+    IntentionShared,
+    IntentionExclusive,
+    Shared,
+    SharedIntentionExclusive,
+    Exclusive,
+}
+
+impl LockMode {
+    /// Whether two different transactions may hold these modes on the same
+    /// resource at the same time.
+    pub fn compatible(self, other: LockMode) -> bool {
+        use LockMode::*;
+        match (self, other) {
+            (Exclusive, _) | (_, Exclusive) => false,
+            (IntentionShared, _) | (_, IntentionShared) => true,
+            (IntentionExclusive, IntentionExclusive) | (Shared, Shared) => true,
+            _ => false,
+        }
+    }
+
+    /// Whether holding `self` grants everything `other` would.
+    pub fn covers(self, other: LockMode) -> bool {
+        use LockMode::*;
+        match (self, other) {
+            (Exclusive, _) | (_, IntentionShared) => true,
+            (SharedIntentionExclusive, mode) => mode != Exclusive,
+            (a, b) => a == b,
+        }
+    }
+
+    /// The weakest mode covering both `self` and `other`.
+    fn supremum(self, other: LockMode) -> LockMode {
+        if self.covers(other) {
+            self
+        } else if other.covers(self) {
+            other
+        } else {
+            // The only incomparable pair is Shared / IntentionExclusive.
+            LockMode::SharedIntentionExclusive
+        }
+    }
+
+    /// The mode that must be held on ancestors before taking `self`.
+    fn intention(self) -> LockMode {
+        match self {
+            LockMode::IntentionShared | LockMode::Shared => LockMode::IntentionShared,
+            _ => LockMode::IntentionExclusive,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LockStatus {
+    Granted,
+    Waiting,
+}
+
+#[derive(Debug, Error, Eq, PartialEq)]
+pub enum LockError {
+    #[error("transaction {0} was aborted to resolve a deadlock")]
+    Deadlock(TxnId),
+}
+
+#[derive(Default)]
+struct LockEntry {
+    granted: Vec<(TxnId, LockMode)>,
+    waiting: VecDeque<(TxnId, LockMode)>,
+}
+
+impl LockEntry {
+    fn held_by(&self, txn: TxnId) -> Option<LockMode> {
+        self.granted
+            .iter()
+            .find(|&&(holder, _)| holder == txn)
+            .map(|&(_, mode)| mode)
+    }
+
+    fn compatible_with_others(&self, txn: TxnId, mode: LockMode) -> bool {
+        self.granted
+            .iter()
+            .all(|&(holder, held)| holder == txn || held.compatible(mode))
+    }
+
+    fn grant(&mut self, txn: TxnId, mode: LockMode) {
+        match self.granted.iter_mut().find(|(holder, _)| *holder == txn) {
+            Some(entry) => entry.1 = mode,
+            None => self.granted.push((txn, mode)),
+        }
+    }
+
+    /// Grant queued requests in FIFO order until one has to keep waiting.
+    fn grant_waiters(&mut self) -> Vec<TxnId> {
+        let mut woken = Vec::new();
+        while let Some(&(txn, mode)) = self.waiting.front() {
+            if !self.compatible_with_others(txn, mode) {
+                break;
+            }
+            self.waiting.pop_front();
+            self.grant(txn, mode);
+            woken.push(txn);
+        }
+        woken
+    }
+
+    /// Transactions that a waiting `txn` is blocked behind.
+    fn blockers(&self, txn: TxnId) -> Vec<TxnId> {
+        let mode = match self.waiting.iter().find(|&&(waiter, _)| waiter == txn) {
+            Some(&(_, mode)) => mode,
+            None => return Vec::new(),
+        };
+        let granted = self
+            .granted
+            .iter()
+            .filter(|&&(holder, held)| holder != txn && !held.compatible(mode));
+        let queued_ahead = self
+            .waiting
+            .iter()
+            .take_while(|&&(waiter, _)| waiter != txn)
+            .filter(|&&(_, queued)| !queued.compatible(mode));
+        granted.chain(queued_ahead).map(|&(other, _)| other).collect()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.granted.is_empty() && self.waiting.is_empty()
+    }
+}
+
+#[derive(Default)]
+pub struct LockManager {
+    table: HashMap<Resource, LockEntry>,
+
+    // Resources on which each transaction holds or awaits a lock.
+    held: HashMap<TxnId, HashSet<Resource>>,
+
+    // The single resource each blocked transaction is queued on.
+    waiting_on: HashMap<TxnId, Resource>,
+
+    // Transactions chosen as deadlock victims that have not yet called
+    // `unlock_all`.
+    aborted: HashSet<TxnId>,
+
+    // Transactions that should retry their pending request.
+    ready: Vec<TxnId>,
+}
+
+impl LockManager {
+    pub fn new() -> LockManager {
+        LockManager::default()
+    }
+
+    /// Request `mode` on `resource`, taking intention locks on its ancestors.
+    ///
+    /// Returns `LockStatus::Waiting` if the request was queued.  The caller
+    /// should retry the same request once `txn` appears in `drain_ready()`.
+    pub fn lock(
+        &mut self,
+        txn: TxnId,
+        resource: Resource,
+        mode: LockMode,
+    ) -> Result<LockStatus, LockError> {
+        if self.aborted.contains(&txn) {
+            return Err(LockError::Deadlock(txn));
+        }
+        let path = resource.path();
+        let (target, ancestors) = path.split_last().expect("path is never empty");
+        for &ancestor in ancestors {
+            if self.lock_one(txn, ancestor, mode.intention())? == LockStatus::Waiting {
+                return Ok(LockStatus::Waiting);
+            }
+        }
+        self.lock_one(txn, *target, mode)
+    }
+
+    /// Whether `txn` currently holds a lock on `resource` at least as strong
+    /// as `mode`.
+    pub fn holds(&self, txn: TxnId, resource: Resource, mode: LockMode) -> bool {
+        self.table
+            .get(&resource)
+            .and_then(|entry| entry.held_by(txn))
+            .is_some_and(|held| held.covers(mode))
+    }
+
+    /// Release every lock held or awaited by `txn`, at commit or abort.
+    pub fn unlock_all(&mut self, txn: TxnId) {
+        self.aborted.remove(&txn);
+        self.release(txn);
+    }
+
+    /// Take the list of transactions whose queued requests were granted, or
+    /// which were aborted, since the last call.
+    pub fn drain_ready(&mut self) -> Vec<TxnId> {
+        std::mem::take(&mut self.ready)
+    }
+
+    fn lock_one(
+        &mut self,
+        txn: TxnId,
+        resource: Resource,
+        mode: LockMode,
+    ) -> Result<LockStatus, LockError> {
+        let entry = self.table.entry(resource).or_default();
+        let held = entry.held_by(txn);
+        if held.is_some_and(|held| held.covers(mode)) {
+            return Ok(LockStatus::Granted);
+        }
+        if self.waiting_on.get(&txn) == Some(&resource) {
+            return Ok(LockStatus::Waiting);
+        }
+
+        let wanted = held.map_or(mode, |held| held.supremum(mode));
+        // Upgrades jump the queue; new requests wait behind earlier ones.
+        let may_grant = entry.compatible_with_others(txn, wanted)
+            && (held.is_some() || entry.waiting.is_empty());
+        self.held.entry(txn).or_default().insert(resource);
+        if may_grant {
+            entry.grant(txn, wanted);
+            return Ok(LockStatus::Granted);
+        }
+        if held.is_some() {
+            entry.waiting.push_front((txn, wanted));
+        } else {
+            entry.waiting.push_back((txn, wanted));
+        }
+        self.waiting_on.insert(txn, resource);
+
+        while let Some(cycle) = self.find_cycle(txn) {
+            let victim = *cycle.iter().max().expect("cycle is never empty");
+            self.abort(victim);
+            if victim == txn {
+                return Err(LockError::Deadlock(txn));
+            }
+        }
+        if self.holds(txn, resource, wanted) {
+            // Aborting a victim released what we were waiting for.
+            self.ready.retain(|&ready| ready != txn);
+            Ok(LockStatus::Granted)
+        } else {
+            Ok(LockStatus::Waiting)
+        }
+    }
+
+    fn blockers(&self, txn: TxnId) -> Vec<TxnId> {
+        self.waiting_on
+            .get(&txn)
+            .and_then(|res| self.table.get(res))
+            .map(|entry| entry.blockers(txn))
+            .unwrap_or_default()
+    }
+
+    /// Depth-first search of the waits-for graph for a cycle through `start`.
+    fn find_cycle(&self, start: TxnId) -> Option<Vec<TxnId>> {
+        let mut stack = vec![(start, self.blockers(start))];
+        let mut visited = HashSet::new();
+        visited.insert(start);
+        while let Some((_, next)) = stack.last_mut() {
+            match next.pop() {
+                Some(blocker) if blocker == start => {
+                    return Some(stack.iter().map(|&(txn, _)| txn).collect());
+                }
+                Some(blocker) => {
+                    if visited.insert(blocker) {
+                        let blockers = self.blockers(blocker);
+                        stack.push((blocker, blockers));
+                    }
+                }
+                None => {
+                    stack.pop();
+                }
+            }
+        }
+        None
+    }
+
+    fn abort(&mut self, victim: TxnId) {
+        self.aborted.insert(victim);
+        self.release(victim);
+        self.ready.push(victim);
+    }
+
+    fn release(&mut self, txn: TxnId) {
+        self.waiting_on.remove(&txn);
+        for resource in self.held.remove(&txn).unwrap_or_default() {
+            if let Some(entry) = self.table.get_mut(&resource) {
+                entry.granted.retain(|&(holder, _)| holder != txn);
+                entry.waiting.retain(|&(waiter, _)| waiter != txn);
+                for woken in entry.grant_waiters() {
+                    self.waiting_on.remove(&woken);
+                    self.ready.push(woken);
+                }
+                if entry.is_empty() {
+                    self.table.remove(&resource);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use LockMode::*;
+
+    #[test]
+    fn compatibility_matrix() {
+        let modes = [
+            IntentionShared,
+            IntentionExclusive,
+            Shared,
+            SharedIntentionExclusive,
+            Exclusive,
+        ];
+        let expected = [
+            [true, true, true, true, false],
+            [true, true, false, false, false],
+            [true, false, true, false, false],
+            [true, false, false, false, false],
+            [false, false, false, false, false],
+        ];
+        for (i, a) in modes.iter().enumerate() {
+            for (j, b) in modes.iter().enumerate() {
+                assert_eq!(a.compatible(*b), expected[i][j], "{:?} / {:?}", a, b);
+            }
+        }
+        assert_eq!(Shared.supremum(IntentionExclusive), SharedIntentionExclusive);
+        assert_eq!(IntentionShared.supremum(Exclusive), Exclusive);
+    }
+
+    #[test]
+    fn row_locks_take_intention_locks() -> anyhow::Result<()> {
+        let mut lm = LockManager::new();
+        let row = Resource::Row(1, 10, 3);
+        assert_eq!(lm.lock(1, row, Exclusive)?, LockStatus::Granted);
+        assert!(lm.holds(1, Resource::Table(1), IntentionExclusive));
+        assert!(lm.holds(1, Resource::Page(1, 10), IntentionExclusive));
+
+        // A different row on the same page is free.
+        assert_eq!(lm.lock(2, Resource::Row(1, 10, 4), Exclusive)?, LockStatus::Granted);
+
+        // A table-level read conflicts with the writers below it.
+        assert_eq!(lm.lock(3, Resource::Table(1), Shared)?, LockStatus::Waiting);
+        lm.unlock_all(1);
+        assert!(lm.drain_ready().is_empty());
+        lm.unlock_all(2);
+        assert_eq!(lm.drain_ready(), vec![3]);
+        assert_eq!(lm.lock(3, Resource::Table(1), Shared)?, LockStatus::Granted);
+        Ok(())
+    }
+
+    #[test]
+    fn shared_locks_upgrade() -> anyhow::Result<()> {
+        let mut lm = LockManager::new();
+        let table = Resource::Table(7);
+        assert_eq!(lm.lock(1, table, Shared)?, LockStatus::Granted);
+        assert_eq!(lm.lock(2, table, Shared)?, LockStatus::Granted);
+        assert_eq!(lm.lock(1, table, Exclusive)?, LockStatus::Waiting);
+        lm.unlock_all(2);
+        assert_eq!(lm.drain_ready(), vec![1]);
+        assert!(lm.holds(1, table, Exclusive));
+        Ok(())
+    }
+
+    #[test]
+    fn deadlock_aborts_youngest() -> anyhow::Result<()> {
+        let mut lm = LockManager::new();
+        let a = Resource::Row(1, 0, 0);
+        let b = Resource::Row(1, 0, 1);
+        assert_eq!(lm.lock(1, a, Exclusive)?, LockStatus::Granted);
+        assert_eq!(lm.lock(2, b, Exclusive)?, LockStatus::Granted);
+        assert_eq!(lm.lock(1, b, Exclusive)?, LockStatus::Waiting);
+
+        // Transaction 2 closes the cycle and is the youngest, so it loses.
+        assert_eq!(lm.lock(2, a, Exclusive), Err(LockError::Deadlock(2)));
+        assert_eq!(lm.drain_ready(), vec![1, 2]);
+        assert!(lm.holds(1, b, Exclusive));
+        assert_eq!(lm.lock(2, a, Shared), Err(LockError::Deadlock(2)));
+
+        lm.unlock_all(2);
+        lm.unlock_all(1);
+        assert_eq!(lm.lock(2, a, Shared)?, LockStatus::Granted);
+        Ok(())
+    }
+
+    #[test]
+    fn deadlock_victim_other_than_requester() -> anyhow::Result<()> {
+        let mut lm = LockManager::new();
+        let a = Resource::Table(1);
+        let b = Resource::Table(2);
+        assert_eq!(lm.lock(5, a, Exclusive)?, LockStatus::Granted);
+        assert_eq!(lm.lock(3, b, Exclusive)?, LockStatus::Granted);
+        assert_eq!(lm.lock(5, b, Exclusive)?, LockStatus::Waiting);
+
+        // Transaction 3 closes the cycle, but 5 is younger and is aborted,
+        // so 3 gets its lock straight away.
+        assert_eq!(lm.lock(3, a, Exclusive)?, LockStatus::Granted);
+        assert_eq!(lm.drain_ready(), vec![5]);
+        assert_eq!(lm.lock(5, b, Exclusive), Err(LockError::Deadlock(5)));
+        Ok(())
+    }
+}
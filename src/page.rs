@@ -1,7 +1,12 @@
 #![allow(dead_code)]
 
-use crate::aligned;
+use crate::{
+    aligned::{self, Field},
+    record::PageId,
+    PageType,
+};
 use std::{convert::TryInto, num::TryFromIntError};
+use thiserror::Error;
 
 pub(crate) type RecordId = u16;
 
@@ -14,12 +19,71 @@ impl From<TryFromIntError> for TmpError {
     }
 }
 
+/// Why `SlottedPage::update_record` failed.  The page is left as it was.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub(crate) enum UpdateError {
+    #[error("there is no record {0} in the page")]
+    NoSuchRecord(RecordId),
+    /// The new record doesn't fit in the page, even in place of the old one,
+    /// so it has to move to another page.
+    #[error("a {0} byte record does not fit in the page")]
+    DoesNotFit(usize),
+    /// The record is held in overflow pages, which the page can't free.
+    #[error("record {0} is held in overflow pages")]
+    Overflow(RecordId),
+}
+
+/// The largest record that fits in an empty `page_size` byte page; bigger
+/// ones go in overflow pages.
+pub(crate) fn max_record_size(page_size: usize) -> usize {
+    page_size - RECORD_HEADERS_OFFSET as usize - 4
+}
+
+/// A record as a SlottedPage holds it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Record<'a> {
+    Inline(&'a [u8]),
+    Overflow(OverflowPointer),
+}
+
+/// Where to find a record that was too big for a page: a chain of overflow
+/// pages holding `len` bytes, starting at `first_page`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct OverflowPointer {
+    pub(crate) first_page: PageId,
+    pub(crate) len: u64,
+}
+
+impl OverflowPointer {
+    pub(crate) const SIZE: usize = 16;
+
+    fn to_bytes(self) -> [u8; OverflowPointer::SIZE] {
+        let mut bytes = [0; OverflowPointer::SIZE];
+        bytes[..8].copy_from_slice(&self.first_page.to_le_bytes());
+        bytes[8..].copy_from_slice(&self.len.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> OverflowPointer {
+        OverflowPointer {
+            first_page: u64::from_le_bytes(bytes[..8].try_into().unwrap()),
+            len: u64::from_le_bytes(bytes[8..].try_into().unwrap()),
+        }
+    }
+}
+
 /// SlottedPage represents a page that holds variable-sized tuples.
 /// It comprises a header, followed by free space, followed by data.
 /// The header format looks like:
+///     * u32: CRC, and u16: page type (DataPage) -- the header all pages share
 ///     * u16: End of free space -- where the most recently data starts
+///     * u64: LSN -- the log sequence number of the last change to the page
+///     * u64, u64: Next and previous pages in the heap -- 0 (the master record) for none
 ///     * u16: Number of records: [recno]
-///     * [(u16, u16); recno]: (offset, size) to records.  (u16::MAX, 0) indicates deleted records?
+///     * u16: Fragmented bytes -- space freed by deleted records that is not part of the free space
+///     * [(u16, u16); recno]: (offset, size) to records.  (u16::MAX, 0) indicates deleted records,
+///       whose slots are reused by later inserts.  The top bit of the size marks records too big
+///       for a page, whose bytes are an `OverflowPointer` to the overflow pages holding them.
 /// Overall, the file looks like:
 ///
 /// +--------+------------+---------+
@@ -32,14 +96,22 @@ pub(crate) struct SlottedPage {
 
 impl Default for SlottedPage {
     fn default() -> SlottedPage {
+        SlottedPage::with_page_size(crate::PAGESIZE)
+    }
+}
+
+impl SlottedPage {
+    /// Creates an empty page for storage with `page_size` byte pages.
+    /// Records are packed down from the end of the page, so they stay within
+    /// the part of the buffer that gets written.
+    pub(crate) fn with_page_size(page_size: usize) -> SlottedPage {
+        assert!(page_size <= crate::PAGESIZE, "{} byte pages don't fit in a buffer", page_size);
         let data = aligned::Buffer::new();
         let mut pg = SlottedPage { data };
-        pg.write_end_of_free_space(crate::PAGESIZE as u16);
+        pg.write_end_of_free_space(page_size as u16);
         pg
     }
-}
 
-impl SlottedPage {
     pub(crate) fn new(records: &[&[u8]]) -> Result<SlottedPage, TmpError> {
         let mut pg = SlottedPage::default();
         let total_size: usize = records.iter().map(|rec| rec.len() + 4).sum();
@@ -53,15 +125,26 @@ impl SlottedPage {
         }
     }
 
+    /// Inserts a record, reusing the slot of a deleted record if there is
+    /// one, so its record id is given out again.  If the record only fits
+    /// once the fragmented bytes are reclaimed, the page is compacted first.
     pub(crate) fn insert_record(&mut self, record: &[u8]) -> Result<RecordId, TmpError> {
-        let recno = self.record_count();
-        let reclen = record.len().try_into()?;
-
-
-        if dbg!(reclen +4) > dbg!(self.available_bytes()) {
+        let reclen: u16 = record.len().try_into()?;
+        let (recno, new_slot) = match self.deleted_slot() {
+            Some(recno) => (recno, false),
+            None => (self.record_count(), true),
+        };
+
+        let needed = reclen as usize + if new_slot { 4 } else { 0 };
+        if needed > self.available_bytes() as usize && needed <= (self.available_bytes() + self.fragmented_bytes()) as usize {
+            self.compact();
+        }
+        if needed > self.available_bytes() as usize {
             Err(TmpError)
         } else {
-            self.write_record_count(recno + 1);
+            if new_slot {
+                self.write_record_count(recno + 1);
+            }
             let offset = self.end_of_free_space() - reclen;
             let size = reclen;
             self.write_record_header(recno, offset, size);
@@ -71,33 +154,219 @@ impl SlottedPage {
         }
     }
 
+    /// Inserts a pointer to a record stored in overflow pages, as
+    /// `insert_record` would insert the record itself.
+    pub(crate) fn insert_overflow_record(&mut self, pointer: OverflowPointer) -> Result<RecordId, TmpError> {
+        let recno = self.insert_record(&pointer.to_bytes())?;
+        let (offset, size) = self.record_header(recno).ok_or(TmpError)?;
+        self.write_record_header(recno, offset, size | OVERFLOW);
+        Ok(recno)
+    }
+
+    /// Deletes a record, leaving a tombstone in its slot.  If it was the
+    /// record nearest the free space, its bytes join the free space;
+    /// otherwise they are counted as fragmented.  Fails if there is no such
+    /// record, or if it is held in overflow pages, which would be left
+    /// behind; use `delete_overflow_record` for those.
+    pub(crate) fn delete_record(&mut self, recno: RecordId) -> Result<(), TmpError> {
+        if self.is_overflow(recno) {
+            return Err(TmpError);
+        }
+        self.remove_record(recno)
+    }
+
+    /// Deletes a record held in overflow pages, returning the pointer to
+    /// them so the caller can free them.  Fails if there is no such record,
+    /// or if it is held in the page itself.
+    pub(crate) fn delete_overflow_record(&mut self, recno: RecordId) -> Result<OverflowPointer, TmpError> {
+        match self.record(recno) {
+            Some(Record::Overflow(pointer)) => {
+                self.remove_record(recno)?;
+                Ok(pointer)
+            }
+            _ => Err(TmpError),
+        }
+    }
+
+    /// Replaces a record, keeping its record id.  A record no longer than the
+    /// old one is written in place, at the end of the old one's space; a
+    /// longer one is moved to the free space.  Either way, the space the old
+    /// one no longer needs is given back as `delete_record` would.  Records
+    /// held in overflow pages can't be updated, as their pages would be left
+    /// behind.
+    pub(crate) fn update_record(&mut self, recno: RecordId, record: &[u8]) -> Result<(), UpdateError> {
+        if self.is_overflow(recno) {
+            return Err(UpdateError::Overflow(recno));
+        }
+        let (offset, size) = self.live_record_header(recno).ok_or(UpdateError::NoSuchRecord(recno))?;
+        let borders_free_space = offset == self.end_of_free_space();
+        let new_offset = if record.len() <= size as usize {
+            offset + size - record.len() as u16
+        } else {
+            let reclaimable = if borders_free_space { size } else { 0 };
+            if record.len() > (self.available_bytes() + reclaimable) as usize {
+                return Err(UpdateError::DoesNotFit(record.len()));
+            }
+            (self.end_of_free_space() + reclaimable) - record.len() as u16
+        };
+
+        // The old record's bytes that the new one doesn't overwrite.
+        let freed = if new_offset >= offset { new_offset - offset } else { size };
+        if borders_free_space {
+            self.write_end_of_free_space(self.end_of_free_space() + freed);
+        } else {
+            self.write_fragmented_bytes(self.fragmented_bytes() + freed);
+        }
+        if new_offset < self.end_of_free_space() {
+            self.write_end_of_free_space(new_offset);
+        }
+        self.write_record_header(recno, new_offset, record.len() as u16);
+        self.write_record_at(new_offset, record);
+        Ok(())
+    }
+
+    /// Slides the live records up against the end of the page, so the
+    /// fragmented bytes join the free space.  Record ids don't change.
+    pub(crate) fn compact(&mut self) {
+        // Sizes keep their overflow flag, to be written back as they were.
+        let mut records: Vec<(RecordId, u16, u16)> = (0..self.record_count())
+            .filter_map(|recno| self.record_header(recno).filter(|&(offset, _)| offset != DELETED).map(|(offset, size)| (recno, offset, size)))
+            .collect();
+        let live_bytes: u16 = records.iter().map(|&(_, _, size)| size & !OVERFLOW).sum();
+        let mut end = self.end_of_free_space() + live_bytes + self.fragmented_bytes();
+
+        // Records only move towards the end of the page, so moving the last
+        // one first never overwrites one that hasn't moved yet.
+        records.sort_by_key(|&(_, offset, _)| std::cmp::Reverse(offset));
+        for (recno, offset, flagged_size) in records {
+            let size = flagged_size & !OVERFLOW;
+            end -= size;
+            self.data.copy_within(offset as usize..(offset + size) as usize, end as usize);
+            self.write_record_header(recno, end, flagged_size);
+        }
+        self.write_end_of_free_space(end);
+        self.write_fragmented_bytes(0);
+    }
+
+    /// The bytes of a record, if it is held in the page itself.  Use
+    /// `record` to find records stored in overflow pages as well.
     pub(crate) fn get_record(&self, recno: u16) -> Option<&[u8]> {
-        self.record_header(recno)
-            .map(|(offset, size)| (offset as usize, size as usize))
-            .map(|(offset, size)| &self.data[offset..offset + size])
+        match self.record(recno)? {
+            Record::Inline(record) => Some(record),
+            Record::Overflow(_) => None,
+        }
     }
 
-    pub(crate) fn data(&self) -> &aligned::Buffer {
-        &self.data
+    pub(crate) fn record(&self, recno: RecordId) -> Option<Record<'_>> {
+        let (offset, size) = self.live_record_header(recno)?;
+        let bytes = &self.data[offset as usize..(offset + size) as usize];
+        if self.is_overflow(recno) {
+            Some(Record::Overflow(OverflowPointer::from_bytes(bytes)))
+        } else {
+            Some(Record::Inline(bytes))
+        }
     }
 
-    pub(crate) fn data_mut(&mut self) -> &mut aligned::Buffer {
-        &mut self.data
+    /// The live records in the page, in record id order, skipping deleted ones.
+    pub(crate) fn records(&self) -> impl Iterator<Item = (RecordId, Record<'_>)> + '_ {
+        (0..self.record_count()).filter_map(move |recno| self.record(recno).map(|record| (recno, record)))
     }
 
     pub fn free_space(&self) -> usize {
         self.available_bytes() as usize
     }
+
+    /// The log sequence number of the last change to the page.
+    pub(crate) fn lsn(&self) -> u64 {
+        LSN.get(&self.data)
+    }
+
+    pub(crate) fn set_lsn(&mut self, lsn: u64) {
+        LSN.set(&mut self.data, lsn)
+    }
+
+    /// The page after this one in its heap, if it isn't the last.
+    pub(crate) fn next_page(&self) -> Option<PageId> {
+        Some(NEXT_PAGE.get(&self.data)).filter(|&page_id| page_id != NO_PAGE)
+    }
+
+    pub(crate) fn set_next_page(&mut self, page_id: Option<PageId>) {
+        NEXT_PAGE.set(&mut self.data, page_id.unwrap_or(NO_PAGE))
+    }
+
+    /// The page before this one in its heap, if it isn't the first.
+    pub(crate) fn prev_page(&self) -> Option<PageId> {
+        Some(PREV_PAGE.get(&self.data)).filter(|&page_id| page_id != NO_PAGE)
+    }
+
+    pub(crate) fn set_prev_page(&mut self, page_id: Option<PageId>) {
+        PREV_PAGE.set(&mut self.data, page_id.unwrap_or(NO_PAGE))
+    }
 }
 
+impl aligned::PageHeader for SlottedPage {
+    const PAGE_TYPE: PageType = PageType::DataPage;
+}
+
+impl aligned::FromAligned for SlottedPage {
+    fn extra_constraints(buffer: &aligned::Buffer) -> Result<(), aligned::Error> {
+        let end_of_free_space = END_OF_FREE_SPACE.get(buffer) as usize;
+        let record_headers_end = RECORD_HEADERS_OFFSET as usize + 4 * RECORD_COUNT.get(buffer) as usize;
+        if record_headers_end > end_of_free_space || end_of_free_space > crate::PAGESIZE {
+            return Err(aligned::Error::SizeError);
+        }
+        let records_fit = buffer[RECORD_HEADERS_OFFSET as usize..record_headers_end]
+            .chunks(4)
+            .map(|header| (u16::from_le_bytes([header[0], header[1]]), u16::from_le_bytes([header[2], header[3]])))
+            .filter(|&(offset, _)| offset != DELETED)
+            .map(|(offset, size)| (offset as usize, (size & !OVERFLOW) as usize))
+            // An empty record takes up no space, so the free space may have
+            // grown past it, when the record before it was deleted.
+            .all(|(offset, size)| (size == 0 || offset >= end_of_free_space) && offset + size <= crate::PAGESIZE);
+        if records_fit {
+            Ok(())
+        } else {
+            Err(aligned::Error::SizeError)
+        }
+    }
+
+    fn transform(data: Box<aligned::Buffer>) -> Self {
+        SlottedPage { data }
+    }
+}
+
+impl aligned::IntoAligned for SlottedPage {
+    fn as_buffer(&self) -> &aligned::Buffer {
+        &self.data
+    }
+
+    fn into_buffer(self) -> Box<aligned::Buffer> {
+        self.data
+    }
+}
+
+const END_OF_FREE_SPACE: Field<u16> = Field::at(0x6);
+const LSN: Field<u64> = Field::at(0x8);
+const NEXT_PAGE: Field<u64> = Field::at(0x10);
+const PREV_PAGE: Field<u64> = Field::at(0x18);
+const RECORD_COUNT: Field<u16> = Field::at(0x20);
+const FRAGMENTED_BYTES: Field<u16> = Field::at(0x22);
+const RECORD_HEADERS_OFFSET: u16 = 0x24;
+// The page id of the master record, which is never a data page
+const NO_PAGE: PageId = 0;
+// The record header of a deleted record
+const DELETED: u16 = u16::MAX;
+// The size flag of a record held in overflow pages
+const OVERFLOW: u16 = 0x8000;
+
 /// Low-level private methods for properly manipulating the internals of the SlottedPage record
 impl SlottedPage {
     fn end_of_free_space(&self) -> u16 {
-        u16::from_le_bytes(self.data[0..2].try_into().unwrap())
+        END_OF_FREE_SPACE.get(&self.data)
     }
 
     fn record_count(&self) -> u16 {
-        u16::from_le_bytes(self.data[2..4].try_into().unwrap())
+        RECORD_COUNT.get(&self.data)
     }
 
     fn record_header(&self, recno: u16) -> Option<(u16, u16)> {
@@ -112,24 +381,60 @@ impl SlottedPage {
         }
     }
 
+    // The header of a record that hasn't been deleted, without the overflow flag.
+    fn live_record_header(&self, recno: u16) -> Option<(u16, u16)> {
+        self.record_header(recno)
+            .filter(|&(offset, _)| offset != DELETED)
+            .map(|(offset, size)| (offset, size & !OVERFLOW))
+    }
+
+    // Frees a record's slot and gives its bytes back.
+    fn remove_record(&mut self, recno: RecordId) -> Result<(), TmpError> {
+        let (offset, size) = self.live_record_header(recno).ok_or(TmpError)?;
+        self.write_record_header(recno, DELETED, 0);
+        if offset == self.end_of_free_space() {
+            self.write_end_of_free_space(offset + size);
+        } else {
+            self.write_fragmented_bytes(self.fragmented_bytes() + size);
+        }
+        Ok(())
+    }
+
+    fn is_overflow(&self, recno: u16) -> bool {
+        matches!(self.record_header(recno), Some((offset, size)) if offset != DELETED && size & OVERFLOW != 0)
+    }
+
+    // The first slot left by a deleted record.
+    fn deleted_slot(&self) -> Option<RecordId> {
+        (0..self.record_count()).find(|&recno| self.record_header(recno) == Some((DELETED, 0)))
+    }
+
+    fn fragmented_bytes(&self) -> u16 {
+        FRAGMENTED_BYTES.get(&self.data)
+    }
+
     fn available_bytes(&self) -> u16 {
         self.end_of_free_space() - self.header_size()
     }
 
     fn header_size(&self) -> u16 {
-        2 + 2 + 4 * self.record_count()
+        self.record_header_offset(self.record_count())
     }
 
     fn write_end_of_free_space(&mut self, offset: u16) {
-        self.data[0..2].copy_from_slice(&offset.to_le_bytes())
+        END_OF_FREE_SPACE.set(&mut self.data, offset)
     }
 
     fn write_record_count(&mut self, new_count: u16) {
-        self.data[2..4].copy_from_slice(&new_count.to_le_bytes())
+        RECORD_COUNT.set(&mut self.data, new_count)
+    }
+
+    fn write_fragmented_bytes(&mut self, bytes: u16) {
+        FRAGMENTED_BYTES.set(&mut self.data, bytes)
     }
 
     fn record_header_offset(&self, recno: u16) -> u16 {
-        4 + 4 * recno
+        RECORD_HEADERS_OFFSET + 4 * recno
     }
 
     fn write_record_header(&mut self, recno: u16, offset: u16, size: u16) {
@@ -143,19 +448,101 @@ impl SlottedPage {
     }
 }
 
+/// OverflowPage holds a piece of a record too big for a SlottedPage.  The
+/// pieces are chained together, first to last, from an `OverflowPointer`.
+/// The header format looks like:
+///     * u32: CRC, and u16: page type (OverflowPage) -- the header all pages share
+///     * u16: Size of the piece
+///     * u64: Next page in the chain -- 0 (the master record) for none
+/// with the piece following it.
+pub(crate) struct OverflowPage {
+    data: Box<aligned::Buffer>,
+}
+
+impl OverflowPage {
+    /// The most a `page_size` byte page can hold of a record.
+    pub(crate) fn capacity(page_size: usize) -> usize {
+        page_size - OVERFLOW_DATA_OFFSET
+    }
+
+    /// Creates a page holding `piece`, which must fit in the pages of the
+    /// storage it is written to.
+    pub(crate) fn new(piece: &[u8], next_page: Option<PageId>) -> OverflowPage {
+        let capacity = OverflowPage::capacity(crate::PAGESIZE);
+        assert!(piece.len() <= capacity, "a {} byte piece doesn't fit in an overflow page", piece.len());
+        let mut data = aligned::Buffer::new();
+        OVERFLOW_PIECE_SIZE.set(&mut data, piece.len() as u16);
+        OVERFLOW_NEXT_PAGE.set(&mut data, next_page.unwrap_or(NO_PAGE));
+        data[OVERFLOW_DATA_OFFSET..OVERFLOW_DATA_OFFSET + piece.len()].copy_from_slice(piece);
+        OverflowPage { data }
+    }
+
+    pub(crate) fn piece(&self) -> &[u8] {
+        &self.data[OVERFLOW_DATA_OFFSET..OVERFLOW_DATA_OFFSET + OVERFLOW_PIECE_SIZE.get(&self.data) as usize]
+    }
+
+    /// The page holding the next piece of the record, if this isn't the last.
+    pub(crate) fn next_page(&self) -> Option<PageId> {
+        Some(OVERFLOW_NEXT_PAGE.get(&self.data)).filter(|&page_id| page_id != NO_PAGE)
+    }
+}
+
+impl aligned::PageHeader for OverflowPage {
+    const PAGE_TYPE: PageType = PageType::OverflowPage;
+}
+
+impl aligned::FromAligned for OverflowPage {
+    fn extra_constraints(buffer: &aligned::Buffer) -> Result<(), aligned::Error> {
+        if OVERFLOW_PIECE_SIZE.get(buffer) as usize > OverflowPage::capacity(crate::PAGESIZE) {
+            Err(aligned::Error::SizeError)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn transform(data: Box<aligned::Buffer>) -> Self {
+        OverflowPage { data }
+    }
+}
+
+impl aligned::IntoAligned for OverflowPage {
+    fn as_buffer(&self) -> &aligned::Buffer {
+        &self.data
+    }
+
+    fn into_buffer(self) -> Box<aligned::Buffer> {
+        self.data
+    }
+}
+
+const OVERFLOW_PIECE_SIZE: Field<u16> = Field::at(0x6);
+const OVERFLOW_NEXT_PAGE: Field<u64> = Field::at(0x8);
+const OVERFLOW_DATA_OFFSET: usize = 0x10;
+
 #[cfg(test)]
 mod tests {
     use crate::PAGESIZE;
 
     use super::*;
 
+    const HEADER: usize = RECORD_HEADERS_OFFSET as usize;
+
     #[test]
     fn empty_slotted_page() {
         let pg = SlottedPage::default();
         assert_eq!(pg.end_of_free_space(), PAGESIZE as u16);
         assert_eq!(pg.record_count(), 0);
         assert_eq!(pg.record_header(0), None);
-        assert_eq!(pg.free_space(), PAGESIZE - 4);
+        assert_eq!(pg.free_space(), PAGESIZE - HEADER);
+    }
+
+    #[test]
+    fn small_slotted_page() {
+        let mut pg = SlottedPage::with_page_size(4096);
+        assert_eq!(pg.free_space(), 4096 - HEADER);
+        pg.insert_record(&[7; 4000]).expect("insert record");
+        assert_eq!(pg.record_header(0), Some((96, 4000)));
+        pg.insert_record(&[8; 100]).expect_err("overflow at 4096 bytes");
     }
 
     #[test]
@@ -165,7 +552,7 @@ mod tests {
         pg.insert_record(b"second record").expect("insert second record");
         assert_eq!(pg.end_of_free_space(), (PAGESIZE as u16 - 10 - 13)); // 4096 - 10 - 13
         assert_eq!(pg.record_count(), 2);
-        assert_eq!(pg.free_space(), PAGESIZE - 10 - 13 - 12);
+        assert_eq!(pg.free_space(), PAGESIZE - 10 - 13 - HEADER - 8);
 
         assert_eq!(pg.record_header(0), Some((PAGESIZE as u16 - 10, 10)));
         assert_eq!(pg.get_record(0), Some(b"new record".as_ref()));
@@ -184,7 +571,7 @@ mod tests {
             i += 1;
         }
         pg.insert_record(&[0xee; 1024]).expect_err(&format!("overflow at {} bytes", PAGESIZE));
-        assert_eq!(pg.free_space(), 1024 - (i + 1) * 4);
+        assert_eq!(pg.free_space(), 1024 - HEADER - i * 4);
         let available = pg.free_space() - 4;
         assert_eq!(pg.insert_record(&vec![0xff; available]).unwrap_or_else(|_| panic!("insert {} bytes", 1024 * i + available)), i as u16);
         assert_eq!(pg.free_space(), 0); // Full page at 4076 bytes written in four records
@@ -195,25 +582,249 @@ mod tests {
         assert_eq!(pg.get_record(1).expect("record 1 not found"), &[2;1024][..], "record 1 not as expected");
         assert_eq!(pg.record_header(14).unwrap(), (1024, 1024));
         assert_eq!(pg.get_record(14).expect("record 2 not found"), &[15;1024][..], "record 2 not as expected");
-        assert_eq!(pg.record_header(15).unwrap(), (8 + i as u16 * 4, available as u16));
+        assert_eq!(pg.record_header(15).unwrap(), ((HEADER + (i + 1) * 4) as u16, available as u16));
         assert_eq!(pg.get_record(15).expect("record 3 not found"), &vec![0xff;available][..], "record 3 not as expected");
     }
 
     #[test]
     fn empty_records() {
         let mut pg = SlottedPage::default();
-        assert_eq!(pg.free_space(), PAGESIZE - 4);
+        assert_eq!(pg.free_space(), PAGESIZE - HEADER);
         pg.insert_record(&[]).expect("insert empty record");
-        assert_eq!(pg.free_space(), PAGESIZE - 8);
+        assert_eq!(pg.free_space(), PAGESIZE - HEADER - 4);
         pg.insert_record(&[4,5,6,9]).expect("insert record");
-        assert_eq!(pg.free_space(), PAGESIZE - 16);
+        assert_eq!(pg.free_space(), PAGESIZE - HEADER - 12);
         pg.insert_record(&[]).expect("insert empty record");
-        assert_eq!(pg.free_space(), PAGESIZE - 20);
+        assert_eq!(pg.free_space(), PAGESIZE - HEADER - 16);
 
         assert_eq!(pg.get_record(0), Some([].as_ref()));
         assert_eq!(pg.get_record(1), Some([4u8, 5, 6, 9].as_ref()));
         assert_eq!(pg.get_record(2), Some([].as_ref()));
         assert!(pg.get_record(3).is_none());
 
+        // The free space grows past an empty record when the one written
+        // before it is deleted, and the page is still valid.
+        use aligned::{Checksum, FromAligned, IntoAligned};
+        pg.delete_record(1).expect("delete record");
+        assert_eq!(pg.free_space(), PAGESIZE - HEADER - 12);
+        let pg = SlottedPage::from_aligned(pg.into_aligned(Checksum::Crc32), Checksum::Crc32).expect("valid page");
+        assert_eq!(pg.get_record(2), Some([].as_ref()));
+    }
+
+    #[test]
+    fn delete_record() {
+        let mut pg = SlottedPage::default();
+        for record in [&b"first"[..], b"second", b"third"] {
+            pg.insert_record(record).expect("insert record");
+        }
+        let free = pg.free_space();
+
+        // A record in the middle leaves a hole.
+        pg.delete_record(1).expect("delete record");
+        assert_eq!(pg.get_record(1), None);
+        assert_eq!(pg.record_header(1), Some((u16::MAX, 0)));
+        assert_eq!((pg.free_space(), pg.fragmented_bytes()), (free, 6));
+        pg.delete_record(1).expect_err("already deleted");
+        pg.delete_record(3).expect_err("no such record");
+
+        // The last record written gives its bytes back to the free space.
+        pg.delete_record(2).expect("delete record");
+        assert_eq!((pg.free_space(), pg.fragmented_bytes()), (free + 5, 6));
+        assert_eq!(pg.get_record(0), Some(b"first".as_ref()));
+
+        // Slots are reused, first one first, before new ones are added.
+        assert_eq!(pg.insert_record(b"fourth").expect("insert record"), 1);
+        assert_eq!(pg.insert_record(b"fifth").expect("insert record"), 2);
+        assert_eq!(pg.insert_record(b"sixth").expect("insert record"), 3);
+        assert_eq!(pg.record_count(), 4);
+        assert_eq!(pg.get_record(1), Some(b"fourth".as_ref()));
+        assert_eq!(pg.free_space(), free + 5 - 6 - 5 - 5 - 4);
+    }
+
+    #[test]
+    fn update_record() {
+        let mut pg = SlottedPage::default();
+        for record in [&b"first"[..], b"second", b"third"] {
+            pg.insert_record(record).expect("insert record");
+        }
+        let free = pg.free_space();
+
+        // Shorter records stay where they were, and their spare bytes are
+        // freed as deleting them would.
+        pg.update_record(1, b"2nd").expect("update record");
+        assert_eq!(pg.get_record(1), Some(b"2nd".as_ref()));
+        assert_eq!((pg.free_space(), pg.fragmented_bytes()), (free, 3));
+        pg.update_record(2, b"3rd").expect("update record");
+        assert_eq!(pg.get_record(2), Some(b"3rd".as_ref()));
+        assert_eq!((pg.free_space(), pg.fragmented_bytes()), (free + 2, 3));
+
+        // Longer ones move to the free space.
+        pg.update_record(0, b"the first").expect("update record");
+        assert_eq!(pg.record_header(0), Some((pg.end_of_free_space(), 9)));
+        assert_eq!((pg.free_space(), pg.fragmented_bytes()), (free + 2 - 9, 8));
+        pg.update_record(0, b"the very first").expect("update record");
+        assert_eq!((pg.free_space(), pg.fragmented_bytes()), (free + 2 - 14, 8));
+        assert_eq!(pg.get_record(0), Some(b"the very first".as_ref()));
+        assert_eq!(pg.get_record(1), Some(b"2nd".as_ref()));
+        assert_eq!(pg.get_record(2), Some(b"3rd".as_ref()));
+
+        let too_big = vec![0; pg.free_space() + 15];
+        assert_eq!(pg.update_record(0, &too_big), Err(UpdateError::DoesNotFit(too_big.len())));
+        assert_eq!(pg.get_record(0), Some(b"the very first".as_ref()));
+        pg.update_record(0, &too_big[1..]).expect("update record");
+        assert_eq!(pg.free_space(), 0);
+
+        pg.delete_record(1).expect("delete record");
+        assert_eq!(pg.update_record(1, b"x"), Err(UpdateError::NoSuchRecord(1)));
+        assert_eq!(pg.update_record(3, b"x"), Err(UpdateError::NoSuchRecord(3)));
+    }
+
+    #[test]
+    fn compact() {
+        let mut pg = SlottedPage::with_page_size(4096);
+        for i in 0..4u8 {
+            pg.insert_record(&[i; 1000]).expect("insert record");
+        }
+        pg.delete_record(0).expect("delete record");
+        pg.delete_record(2).expect("delete record");
+        pg.update_record(3, &[3; 600]).expect("update record");
+        assert_eq!(pg.fragmented_bytes(), 2000);
+
+        pg.compact();
+        assert_eq!(pg.fragmented_bytes(), 0);
+        assert_eq!(pg.free_space(), 4096 - HEADER - 16 - 1600);
+        assert_eq!(pg.record_header(1), Some((4096 - 1000, 1000)));
+        assert_eq!(pg.get_record(1), Some(&[1; 1000][..]));
+        assert_eq!(pg.record_header(3), Some((4096 - 1600, 600)));
+        assert_eq!(pg.get_record(3), Some(&[3; 600][..]));
+        assert_eq!(pg.get_record(0), None);
+        assert_eq!(pg.get_record(2), None);
+    }
+
+    #[test]
+    fn insert_compacts() {
+        let mut pg = SlottedPage::with_page_size(4096);
+        for i in 0..4u8 {
+            pg.insert_record(&[i; 1000]).expect("insert record");
+        }
+        pg.delete_record(1).expect("delete record");
+        assert_eq!(pg.free_space(), 4096 - HEADER - 16 - 4000);
+
+        // Only fits in the hole left by record 1, once it's reclaimed.
+        assert_eq!(pg.insert_record(&[9; 1040]).expect("insert record"), 1);
+        assert_eq!(pg.fragmented_bytes(), 0);
+        for (recno, byte) in [(0, 0), (2, 2), (3, 3)] {
+            assert_eq!(pg.get_record(recno), Some(&[byte; 1000][..]));
+        }
+        assert_eq!(pg.get_record(1), Some(&[9; 1040][..]));
+        pg.insert_record(&[0; 100]).expect_err("no room left, even compacted");
+    }
+
+    #[test]
+    fn records() {
+        let mut pg = SlottedPage::default();
+        assert_eq!(pg.records().count(), 0);
+        for record in [&b"first"[..], b"", b"third", b"fourth"] {
+            pg.insert_record(record).expect("insert record");
+        }
+        pg.delete_record(2).expect("delete record");
+        let records: Vec<_> = pg.records().collect();
+        assert_eq!(records, vec![(0, Record::Inline(b"first")), (1, Record::Inline(b"")), (3, Record::Inline(b"fourth"))]);
+    }
+
+    #[test]
+    fn from_aligned() {
+        use aligned::{Checksum, FromAligned, IntoAligned};
+
+        let mut pg = SlottedPage::default();
+        pg.insert_record(b"record").expect("insert record");
+        pg.insert_record(b"deleted").expect("insert record");
+        pg.delete_record(1).expect("delete record");
+        pg.set_lsn(0x1234_5678_9abc);
+        pg.set_next_page(Some(7));
+        let pg = SlottedPage::from_aligned(pg.into_aligned(Checksum::Xxh3), Checksum::Xxh3).expect("valid page");
+        assert_eq!(pg.lsn(), 0x1234_5678_9abc);
+        assert_eq!((pg.prev_page(), pg.next_page()), (None, Some(7)));
+        assert_eq!(pg.records().collect::<Vec<_>>(), vec![(0, Record::Inline(b"record"))]);
+
+        let mut buffer = pg.to_aligned(Checksum::Xxh3);
+        buffer[PAGESIZE - 1] ^= 1;
+        assert_eq!(SlottedPage::from_aligned(buffer, Checksum::Xxh3).err(), Some(aligned::Error::CrcError));
+
+        // Record headers must point after the free space, within the page.
+        let mut broken = SlottedPage::default();
+        broken.insert_record(b"record").expect("insert record");
+        broken.write_record_header(0, PAGESIZE as u16 - 2, 6);
+        let buffer = broken.into_aligned(Checksum::Xxh3);
+        assert_eq!(SlottedPage::from_aligned(buffer, Checksum::Xxh3).err(), Some(aligned::Error::SizeError));
+
+        let mut broken = SlottedPage::default();
+        broken.write_record_count(PAGESIZE as u16);
+        let buffer = broken.into_aligned(Checksum::Xxh3);
+        assert_eq!(SlottedPage::from_aligned(buffer, Checksum::Xxh3).err(), Some(aligned::Error::SizeError));
+
+
+        let mut buffer = SlottedPage::default().into_aligned(Checksum::Xxh3);
+        aligned::set_page_type(&mut buffer, PageType::FreePage);
+        Checksum::Xxh3.stamp(&mut buffer);
+        assert_eq!(SlottedPage::from_aligned(buffer, Checksum::Xxh3).err(), Some(aligned::Error::PageType));
+    }
+
+    #[test]
+    fn overflow_records() {
+        let mut pg = SlottedPage::default();
+        pg.insert_record(b"first").expect("insert record");
+        let pointer = OverflowPointer { first_page: 12, len: 100_000 };
+        assert_eq!(pg.insert_overflow_record(pointer).expect("insert overflow record"), 1);
+        pg.insert_record(b"third").expect("insert record");
+        assert_eq!(pg.record(1), Some(Record::Overflow(pointer)));
+        assert_eq!(pg.get_record(1), None);
+
+        // Pointers are only deleted by handing them back, so the overflow
+        // pages aren't lost.
+        pg.delete_record(1).expect_err("delete overflow record");
+        assert!(matches!(pg.update_record(1, b"second"), Err(UpdateError::Overflow(1))));
+        pg.delete_overflow_record(0).expect_err("inline record");
+        assert_eq!(pg.record(1), Some(Record::Overflow(pointer)));
+
+        // Compaction keeps track of which records are pointers.
+        pg.delete_record(0).expect("delete record");
+        pg.compact();
+        assert_eq!(pg.records().collect::<Vec<_>>(), vec![(1, Record::Overflow(pointer)), (2, Record::Inline(b"third"))]);
+
+        // And so do pages read back in.
+        use aligned::{Checksum, FromAligned, IntoAligned};
+        let mut pg = SlottedPage::from_aligned(pg.into_aligned(Checksum::Crc32), Checksum::Crc32).expect("valid page");
+        assert_eq!(pg.record(1), Some(Record::Overflow(pointer)));
+        assert_eq!(pg.delete_overflow_record(1).expect("delete overflow record"), pointer);
+        assert_eq!(pg.record(1), None);
+    }
+
+    #[test]
+    fn overflow_page() {
+        use aligned::{Checksum, FromAligned, IntoAligned};
+
+        let piece = vec![0xab; OverflowPage::capacity(PAGESIZE)];
+        let page = OverflowPage::new(&piece, Some(5));
+        let page = OverflowPage::from_aligned(page.into_aligned(Checksum::Crc32), Checksum::Crc32).expect("valid page");
+        assert_eq!(page.piece(), &piece[..]);
+        assert_eq!(page.next_page(), Some(5));
+
+        let page = OverflowPage::new(b"last piece", None);
+        assert_eq!((page.piece(), page.next_page()), (&b"last piece"[..], None));
+    }
+
+    #[test]
+    fn into_aligned() {
+        use aligned::{Checksum, IntoAligned};
+
+        let mut pg = SlottedPage::default();
+        pg.insert_record(b"record").expect("insert record");
+        let copy = pg.to_aligned(Checksum::Crc32c);
+        assert_eq!(pg.get_record(0), Some(b"record".as_ref()));
+        let buffer = pg.into_aligned(Checksum::Crc32c);
+        assert!(buffer[..] == copy[..]);
+        assert!(aligned::page_type(&buffer) == Some(PageType::DataPage));
+        assert!(Checksum::Crc32c.verify(&buffer));
     }
 }
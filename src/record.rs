@@ -1,11 +1,21 @@
 #![allow(unused)]
 
-use crate::{bufferpool, result, page};
+use crate::{
+    aligned::{self, Checksum, FromAligned, IntoAligned},
+    bufferpool, page, result,
+};
 use std::collections::BTreeMap;
 pub(crate) type PageId = u64;
 
 /// Creating and accessing record
 pub struct RecordManager {
+    // The first page of the heap.  The rest are found by following the
+    // pages' next page links.
+    first_page: PageId,
+
+    // The size of the pool's pages, which records are split to fit in.
+    page_size: usize,
+
     // The ID of the page currently accepting record appends, until it fills up.
     current_page: (PageId, page::SlottedPage),
 
@@ -14,33 +24,289 @@ pub struct RecordManager {
 }
 
 impl RecordManager {
+    /// Starts a heap with a single empty page.
+    pub fn new(bufpool: &mut bufferpool::BufferPool) -> Result<RecordManager, result::Error> {
+        let checksum = bufpool.checksum().map_err(|_| result::Error::Other)?;
+        let page_size = bufpool.page_size();
+        let pg = page::SlottedPage::with_page_size(page_size);
+        let pid = bufpool.append_page(&pg.to_aligned(checksum)).map_err(|_| result::Error::Other)?;
+        Ok(RecordManager {
+            first_page: pid,
+            page_size,
+            current_page: (pid, pg),
+            free_space: BTreeMap::new(),
+        })
+    }
 
-    /// Write a record into the current
+    /// The first page of the heap.
+    pub fn first_page(&self) -> PageId {
+        self.first_page
+    }
+
+    /// Write a record into the current page.  Records too big for a page are
+    /// written to a chain of overflow pages, and a pointer to them goes in
+    /// the current page in their place.
     pub fn append_record(
         &mut self,
         record: &[u8],
         bufpool: &mut bufferpool::BufferPool,
     ) -> Result<(PageId, u16), result::Error> {
+        if record.len() > page::max_record_size(self.page_size) {
+            let pointer = write_overflow_pages(record, self.page_size, bufpool)?;
+            self.append_with(page::OverflowPointer::SIZE, bufpool, |pg| pg.insert_overflow_record(pointer))
+        } else {
+            self.append_with(record.len(), bufpool, |pg| pg.insert_record(record))
+        }
+    }
+
+    // Inserts `len` bytes with `insert`, into a new page if the current one is full.
+    fn append_with(
+        &mut self,
+        len: usize,
+        bufpool: &mut bufferpool::BufferPool,
+        insert: impl Fn(&mut page::SlottedPage) -> Result<u16, page::TmpError>,
+    ) -> Result<(PageId, u16), result::Error> {
 
+        let checksum = bufpool.checksum().map_err(|_| result::Error::Other)?;
         let &mut(pid, ref mut pg) = &mut self.current_page;
-        if pg.free_space() >= record.len() + 4 {
-            let rid = pg.insert_record(record).map_err(|_| result::Error::Other)?;
-            let res = bufpool.update_page(pid, pg.data()).map_err(|_| result::Error::Other)?;
+        if pg.free_space() >= len + 4 {
+            let rid = insert(pg).map_err(|_| result::Error::Other)?;
+            bufpool.update_page(pid, &pg.to_aligned(checksum)).map_err(|_| result::Error::Other)?;
             Ok((pid, rid))
         } else {
-            let mut newpg = page::SlottedPage::default();
-            match newpg.insert_record(&record) {
+            let mut newpg = page::SlottedPage::with_page_size(self.page_size);
+            newpg.set_prev_page(Some(pid));
+            match insert(&mut newpg) {
                 Ok(rid) => {
-                    let pid = bufpool.append_page(newpg.data()).map_err(|_| result::Error::Other)?;
-                    self.current_page = (pid, newpg);
-                    Ok((pid, rid))
+                    let newpid = bufpool.append_page(&newpg.to_aligned(checksum)).map_err(|_| result::Error::Other)?;
+                    pg.set_next_page(Some(newpid));
+                    bufpool.update_page(pid, &pg.to_aligned(checksum)).map_err(|_| result::Error::Other)?;
+                    self.current_page = (newpid, newpg);
+                    Ok((newpid, rid))
                 }
                 Err(_) => Err(result::Error::Other),
             }
         }
     }
 
-    pub fn get_record(&mut self, _page_id: PageId) -> Result<(), result::Error> {
+    /// Reads a record, putting it back together from its overflow pages if
+    /// it was too big for a page.
+    pub fn get_record(
+        &mut self,
+        page_id: PageId,
+        recno: u16,
+        bufpool: &mut bufferpool::BufferPool,
+    ) -> Result<Vec<u8>, result::Error> {
+        let checksum = bufpool.checksum().map_err(|_| result::Error::Other)?;
+        let loaded;
+        let pg = if page_id == self.current_page.0 {
+            &self.current_page.1
+        } else {
+            loaded = load_page::<page::SlottedPage>(page_id, checksum, bufpool)?;
+            &loaded
+        };
+        match pg.record(recno).ok_or(result::Error::Other)? {
+            page::Record::Inline(record) => Ok(record.to_vec()),
+            page::Record::Overflow(pointer) => {
+                let mut record = Vec::with_capacity(pointer.len as usize);
+                let mut next = Some(pointer.first_page);
+                while let Some(pid) = next {
+                    let overflow = load_page::<page::OverflowPage>(pid, checksum, bufpool)?;
+                    record.extend_from_slice(overflow.piece());
+                    // A chain longer than the record is corrupt, and may not end.
+                    if record.len() as u64 > pointer.len {
+                        return Err(result::Error::Other);
+                    }
+                    next = overflow.next_page();
+                }
+                if record.len() as u64 == pointer.len {
+                    Ok(record)
+                } else {
+                    Err(result::Error::Other)
+                }
+            }
+        }
+    }
+
+    /// Deletes a record.  The overflow pages of a record too big for a page
+    /// are put on the pool's free list, which needs a master record.
+    pub fn delete_record(
+        &mut self,
+        page_id: PageId,
+        recno: u16,
+        bufpool: &mut bufferpool::BufferPool,
+    ) -> Result<(), result::Error> {
+        let checksum = bufpool.checksum().map_err(|_| result::Error::Other)?;
+        let mut loaded;
+        let pg = if page_id == self.current_page.0 {
+            &mut self.current_page.1
+        } else {
+            loaded = load_page::<page::SlottedPage>(page_id, checksum, bufpool)?;
+            &mut loaded
+        };
+        let pointer = match pg.record(recno).ok_or(result::Error::Other)? {
+            page::Record::Inline(_) => {
+                pg.delete_record(recno).map_err(|_| result::Error::Other)?;
+                None
+            }
+            page::Record::Overflow(_) => Some(pg.delete_overflow_record(recno).map_err(|_| result::Error::Other)?),
+        };
+        bufpool.update_page(page_id, &pg.to_aligned(checksum)).map_err(|_| result::Error::Other)?;
+
+        // The pointer is gone before its pages are freed, so a failure part
+        // way through leaks pages rather than leaving a pointer to free ones.
+        if let Some(pointer) = pointer {
+            let mut next = Some(pointer.first_page);
+            while let Some(pid) = next {
+                next = load_page::<page::OverflowPage>(pid, checksum, bufpool)?.next_page();
+                bufpool.free_page(pid).map_err(|_| result::Error::Other)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Writes a record to a chain of overflow pages, last piece first, so each
+// page is written knowing the page after it.
+fn write_overflow_pages(
+    record: &[u8],
+    page_size: usize,
+    bufpool: &mut bufferpool::BufferPool,
+) -> Result<page::OverflowPointer, result::Error> {
+    let checksum = bufpool.checksum().map_err(|_| result::Error::Other)?;
+    let mut next = None;
+    for piece in record.chunks(page::OverflowPage::capacity(page_size)).rev() {
+        let overflow = page::OverflowPage::new(piece, next);
+        next = Some(bufpool.append_page(&overflow.into_aligned(checksum)).map_err(|_| result::Error::Other)?);
+    }
+    Ok(page::OverflowPointer {
+        first_page: next.ok_or(result::Error::Other)?,
+        len: record.len() as u64,
+    })
+}
+
+fn load_page<P: FromAligned>(
+    page_id: PageId,
+    checksum: Checksum,
+    bufpool: &mut bufferpool::BufferPool,
+) -> Result<P, result::Error> {
+    let mut buffer = aligned::Buffer::new();
+    bufpool.read_page(page_id, &mut buffer).map_err(|_| result::Error::Other)?;
+    P::from_aligned(buffer, checksum).map_err(|_| result::Error::Other)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        storage::{PageSize, PagedFile, PagedFileConfig},
+        testutils::create_test_path,
+    };
+
+    #[test]
+    fn linked_pages() -> anyhow::Result<()> {
+        let path = create_test_path("test-potpotdb::record::linked_pages.data");
+        let mut bufpool = bufferpool::BufferPool::new(PagedFile::from_path(&path)?, 4);
+        bufpool.create_master_record()?;
+        let mut records = RecordManager::new(&mut bufpool)?;
+        let mut page_ids = vec![records.first_page()];
+        for i in 0..40u8 {
+            let (pid, _) = records.append_record(&[i; 1000], &mut bufpool)?;
+            if page_ids.last() != Some(&pid) {
+                page_ids.push(pid);
+            }
+        }
+        assert!(page_ids.len() > 2);
+
+        // Walk the heap from its first page, checking the links both ways.
+        let mut walked = Vec::new();
+        let mut prev = None;
+        let mut next = Some(records.first_page());
+        while let Some(pid) = next {
+            let mut buffer = aligned::Buffer::new();
+            bufpool.read_page(pid, &mut buffer)?;
+            let pg = page::SlottedPage::from_aligned(buffer, Checksum::Crc32)?;
+            assert_eq!(pg.prev_page(), prev);
+            walked.push(pid);
+            prev = Some(pid);
+            next = pg.next_page();
+        }
+        assert_eq!(walked, page_ids);
+        Ok(())
+    }
+    #[test]
+    fn overflow_records() -> anyhow::Result<()> {
+        let path = create_test_path("test-potpotdb::record::overflow_records.data");
+        let mut bufpool = bufferpool::BufferPool::new(PagedFile::from_path(&path)?, 4);
+        bufpool.create_master_record()?;
+        let mut records = RecordManager::new(&mut bufpool)?;
+
+        let big: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+        let exact = vec![7; page::OverflowPage::capacity(crate::PAGESIZE) * 2];
+        let (small_pid, small_rid) = records.append_record(b"small", &mut bufpool)?;
+        let (big_pid, big_rid) = records.append_record(&big, &mut bufpool)?;
+        let (exact_pid, exact_rid) = records.append_record(&exact, &mut bufpool)?;
+        assert_eq!((small_pid, big_pid, exact_pid), (records.first_page(), records.first_page(), records.first_page()));
+
+        // Records read back the same, from the current page or from storage.
+        for _ in 0..2 {
+            assert_eq!(records.get_record(small_pid, small_rid, &mut bufpool)?, b"small");
+            assert_eq!(records.get_record(big_pid, big_rid, &mut bufpool)?, big);
+            assert_eq!(records.get_record(exact_pid, exact_rid, &mut bufpool)?, exact);
+            records.append_record(&vec![0; page::max_record_size(crate::PAGESIZE)], &mut bufpool)?;
+        }
+        assert!(records.get_record(small_pid, 9, &mut bufpool).is_err());
+        Ok(())
+    }
+    #[test]
+    fn delete_overflow_records() -> anyhow::Result<()> {
+        let path = create_test_path("test-potpotdb::record::delete_overflow_records.data");
+        let _ = std::fs::remove_file(&path);
+        let mut bufpool = bufferpool::BufferPool::new(PagedFile::from_path(&path)?, 4);
+        bufpool.create_master_record()?;
+        let mut records = RecordManager::new(&mut bufpool)?;
+
+        let big = vec![0xbb; 100_000];
+        let (small_pid, small_rid) = records.append_record(b"small", &mut bufpool)?;
+        let (big_pid, big_rid) = records.append_record(&big, &mut bufpool)?;
+        bufpool.flush_all()?;
+        let file_len = std::fs::metadata(&path)?.len();
+        records.delete_record(big_pid, big_rid, &mut bufpool)?;
+        records.delete_record(small_pid, small_rid, &mut bufpool)?;
+        assert!(records.get_record(big_pid, big_rid, &mut bufpool).is_err());
+        assert!(records.get_record(small_pid, small_rid, &mut bufpool).is_err());
+        assert!(records.delete_record(big_pid, big_rid, &mut bufpool).is_err());
+
+        // The freed overflow pages take the next big record, without the
+        // storage growing.
+        let (pid, rid) = records.append_record(&big, &mut bufpool)?;
+        bufpool.flush_all()?;
+        assert_eq!(std::fs::metadata(&path)?.len(), file_len);
+        assert_eq!(records.get_record(pid, rid, &mut bufpool)?, big);
+        Ok(())
+    }
+    #[test]
+    fn small_pages() -> anyhow::Result<()> {
+        let path = create_test_path("test-potpotdb::record::small_pages.data");
+        let config = PagedFileConfig {
+            page_size: PageSize::K4,
+            ..PagedFileConfig::default()
+        };
+        let mut bufpool = bufferpool::BufferPool::new(PagedFile::open_with(&path, config)?, 2);
+        bufpool.create_master_record()?;
+        let mut records = RecordManager::new(&mut bufpool)?;
+
+        let big = vec![0x5a; 15000];
+        let (big_pid, big_rid) = records.append_record(&big, &mut bufpool)?;
+        let written: Vec<_> = (0..8u8)
+            .map(|i| records.append_record(&[i; 1000], &mut bufpool))
+            .collect::<Result<_, _>>()?;
+
+        // Every page read back from storage, after the pool has evicted it.
+        assert_eq!(records.get_record(big_pid, big_rid, &mut bufpool)?, big);
+        for (i, &(pid, rid)) in written.iter().enumerate() {
+            assert_eq!(records.get_record(pid, rid, &mut bufpool)?, vec![i as u8; 1000]);
+        }
         Ok(())
     }
 }
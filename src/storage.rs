@@ -1,32 +1,439 @@
 use std::{
     fs::{File, OpenOptions},
-    io::{self, prelude::*, SeekFrom},
-    os::unix::fs::OpenOptionsExt,
-    path::Path,
+    io,
+    os::unix::{
+        fs::{FileExt, OpenOptionsExt},
+        io::AsRawFd,
+    },
+    ops::Range,
+    path::{Path, PathBuf},
 };
 
-use crate::aligned;
-use libc::O_DIRECT;
+use crate::{
+    aligned::{self, Checksum},
+    PageType,
+};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    convert::TryInto,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, OnceLock,
+    },
+};
+#[cfg(feature = "tokio")]
+use std::sync::Mutex;
+#[cfg(feature = "s3")]
+use {
+    hmac::{Hmac, Mac},
+    sha2::{Digest, Sha256},
+    std::{
+        io::Read,
+        time::{SystemTime, UNIX_EPOCH},
+    },
+};
+use memmap2::MmapMut;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use thiserror::Error;
+
+/// Somewhere to keep pages.  Pages are numbered from zero, in the order
+/// they were appended.
+pub trait StorageBackend {
+    /// Returns the page size of the storage.
+    fn page_size(&self) -> usize {
+        crate::PAGESIZE
+    }
+
+    /// Reads a single page into the provided buffer.
+    fn read_page(&mut self, page_number: u64, buf: &mut aligned::Buffer) -> io::Result<()>;
+
+    /// Reads consecutive pages, starting at the specified page, into the
+    /// provided buffers.
+    fn read_pages(&mut self, page_number: u64, bufs: &mut [&mut aligned::Buffer]) -> io::Result<()> {
+        for (page_number, buf) in (page_number..).zip(bufs) {
+            self.read_page(page_number, buf)?;
+        }
+        Ok(())
+    }
+
+    /// Writes one page from the provided buffer to the specified page.
+    fn write_page(&mut self, page_number: u64, buf: &[u8]) -> io::Result<()>;
+
+    /// Writes consecutive pages, starting at the specified page.
+    fn write_pages(&mut self, page_number: u64, bufs: &[&[u8]]) -> io::Result<()> {
+        for (page_number, buf) in (page_number..).zip(bufs) {
+            self.write_page(page_number, buf)?;
+        }
+        Ok(())
+    }
+
+    /// Writes a page after the last one, and returns its page number.
+    fn append_page(&mut self, buf: &[u8]) -> io::Result<u64>;
+
+    /// Writes pages after the last one, and returns their page numbers.
+    fn append_pages(&mut self, bufs: &[&[u8]]) -> io::Result<Range<u64>> {
+        let mut page_numbers = 0..0;
+        for (i, buf) in bufs.iter().enumerate() {
+            let page_number = self.append_page(buf)?;
+            if i == 0 {
+                page_numbers.start = page_number;
+            }
+            page_numbers.end = page_number + 1;
+        }
+        Ok(page_numbers)
+    }
+
+    /// Returns the number of pages in storage.
+    fn page_count(&mut self) -> io::Result<u64>;
+
+    /// Drops the pages from `pages` on, if there are more than that.
+    fn truncate(&mut self, _pages: u64) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "storage can't be truncated"))
+    }
+
+    /// Gives the space held by a page past its first `keep` bytes back to
+    /// the filesystem, where that is possible.  Those bytes may then read as
+    /// zeros.
+    fn discard(&mut self, _page_number: u64, _keep: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Makes every write so far durable.
+    fn sync(&mut self) -> io::Result<()>;
+}
+
+/// Storage that can hand out more handles to the same pages, so that
+/// several threads can use it at once, as the shards of a
+/// `ShardedBufferPool` do.
+pub trait SharedStorage: StorageBackend + Send + Sized {
+    /// Returns another handle to the same pages.  Writes through any handle
+    /// are seen by reads through the others.
+    fn try_clone(&self) -> io::Result<Self>;
+}
+
+/// Whether a `PagedFile` bypasses the operating system's page cache.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DirectIo {
+    /// Use direct I/O where the platform and filesystem support it, and
+    /// buffered I/O where they don't.
+    Prefer,
+    /// Fail to open the file if direct I/O isn't supported.
+    Require,
+    /// Always use buffered I/O.
+    Off,
+}
+
+/// When a `PagedFile` makes its writes durable.  However writes are synced,
+/// `StorageBackend::sync` makes them all durable.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Sync after every write, or every batch of pages written together.
+    EveryWrite,
+    /// Sync after every `n` writes.
+    EveryN(u32),
+    /// Only sync when asked to.
+    Manual,
+    /// Open the file with `O_DSYNC`, so writes are durable as soon as they
+    /// return, without a sync.
+    DSync,
+}
+
+/// The size of the pages in a file, which is fixed when the file is created.
+///
+/// Pages are read into `aligned::Buffer`s, so a page can't be larger than
+/// one, which rules out 64K pages for now: every buffer in the pool would
+/// have to grow to 64K, and slotted pages address their records with 16 bit
+/// offsets.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PageSize {
+    K4,
+    K8,
+    #[default]
+    K16,
+}
+
+impl PageSize {
+    /// Returns the page size in bytes.
+    pub fn bytes(self) -> usize {
+        match self {
+            PageSize::K4 => 4096,
+            PageSize::K8 => 8192,
+            PageSize::K16 => 16384,
+        }
+    }
+
+    /// Returns the page size with the given number of bytes, if there is one.
+    pub fn from_bytes(bytes: usize) -> Option<PageSize> {
+        match bytes {
+            4096 => Some(PageSize::K4),
+            8192 => Some(PageSize::K8),
+            16384 => Some(PageSize::K16),
+            _ => None,
+        }
+    }
+}
+
+/// A page read with `verify_checksums` on whose stored CRC doesn't match
+/// its contents.  It comes wrapped in an `io::Error` of kind `InvalidData`.
+#[derive(Debug, Error)]
+#[error("page {page_id} has CRC {actual:#010x}, but {expected:#010x} is stored")]
+pub struct ChecksumMismatch {
+    pub page_id: u64,
+    /// The CRC stored in the page.
+    pub expected: u32,
+    /// The CRC of the page's contents.
+    pub actual: u32,
+}
+
+/// A sync failed, so writes made since the last sync that succeeded may
+/// never reach the disk.  Retrying the sync can't help, as the operating
+/// system may already have dropped the pages it failed to write, and
+/// reported the failure once.  Storage poisoned this way refuses writes and
+/// syncs from then on; the database has to be reopened and recovered.  It
+/// comes wrapped in an `io::Error`.  See `is_poisoned`.
+#[derive(Debug, Error)]
+#[error("storage is poisoned: a sync failed ({cause})")]
+pub struct Poisoned {
+    /// The error the failed sync returned.
+    pub cause: String,
+}
+
+impl Poisoned {
+    pub(crate) fn error(cause: &str) -> io::Error {
+        io::Error::other(Poisoned {
+            cause: cause.to_string(),
+        })
+    }
+
+    /// Returns the `Poisoned` wrapped in an `io::Error`, if there is one.
+    pub fn of(err: &io::Error) -> Option<&Poisoned> {
+        err.get_ref().and_then(|err| err.downcast_ref::<Poisoned>())
+    }
+}
+
+/// Returns true if the error comes from poisoned storage, and so must not
+/// be retried.
+pub fn is_poisoned(err: &io::Error) -> bool {
+    Poisoned::of(err).is_some()
+}
+
+/// How to open a `PagedFile`, with `PagedFile::open_with`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PagedFileConfig {
+    pub direct_io: DirectIo,
+    pub sync_policy: SyncPolicy,
+    /// The size of the file's pages.  A file must always be opened with the
+    /// page size it was created with.  A file of pages other than the
+    /// default size starts with a header page recording the size, as a file
+    /// with extents does.
+    pub page_size: PageSize,
+    /// Grow the file this many pages at a time.  See
+    /// `PagedFile::with_extents`.
+    pub extent_pages: Option<u64>,
+    /// Check the CRC at the start of each page as it is read.  See
+    /// `PagedFile::read_page`.
+    pub verify_checksums: bool,
+    /// The algorithm the pages' checksums were computed with, which should
+    /// be the one the buffer pool's master record chooses.  The master
+    /// record itself is always checked with CRC32.
+    pub checksum: Checksum,
+}
+
+impl Default for PagedFileConfig {
+    fn default() -> PagedFileConfig {
+        PagedFileConfig {
+            direct_io: DirectIo::Prefer,
+            sync_policy: SyncPolicy::EveryWrite,
+            page_size: PageSize::K16,
+            extent_pages: None,
+            verify_checksums: false,
+            checksum: Checksum::Crc32,
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct PagedFile {
     file: File,
+    direct: bool,
+    extents: Option<Extents>,
+    page_size: usize,
+    sync_policy: SyncPolicy,
+    verify_checksums: bool,
+    checksum: Checksum,
+    // writes since the last sync, for SyncPolicy::EveryN, shared with
+    // handles from try_clone
+    unsynced: Arc<AtomicU64>,
+    // the error from the sync that failed, if one has, shared with handles
+    // from try_clone
+    poisoned: Arc<OnceLock<String>>,
+}
+
+// A file that grows by extents starts with a header page recording how many
+// of the pages after it are in use, as the length of the file doesn't say.
+// So does a file of pages other than the default size, to record the page
+// size; it grows a page at a time, as if by extents of one page.  A file
+// without a header page has pages of the default size.
+//
+//   0x0000  Magic (8 bytes) ("potpotx\0")
+//   0x0008  Pages in use (8 bytes)
+//   0x0010  Page size (4 bytes)  // 0 in files from before it was recorded
+#[derive(Debug)]
+struct Extents {
+    // the number of pages to grow the file by
+    pages: u64,
+    // shared with handles from try_clone
+    used: Arc<AtomicU64>,
 }
 
+const EXTENTS_MAGIC: &[u8; 8] = b"potpotx\0";
+
 impl PagedFile {
+    /// Opens the file, creating it if needed, with direct I/O if it is
+    /// available.
     pub fn from_path<P: AsRef<Path>>(filename: P) -> io::Result<PagedFile> {
-        let file = OpenOptions::new()
-            .create(true)
-            .read(true)
-            .write(true)
-            .custom_flags(O_DIRECT)
-            .open(filename)?;
-        Ok(PagedFile { file })
+        PagedFile::open(filename, DirectIo::Prefer)
+    }
+
+    /// Opens the file, creating it if needed, with direct I/O as requested.
+    pub fn open<P: AsRef<Path>>(filename: P, direct_io: DirectIo) -> io::Result<PagedFile> {
+        PagedFile::open_with(filename, PagedFileConfig {
+            direct_io,
+            ..PagedFileConfig::default()
+        })
+    }
+
+    /// Opens the file, creating it if needed, as configured.
+    pub fn open_with<P: AsRef<Path>>(filename: P, config: PagedFileConfig) -> io::Result<PagedFile> {
+        let filename = filename.as_ref();
+        let page_size = config.page_size.bytes();
+        let mut options = OpenOptions::new();
+        options.create(true).read(true).write(true);
+        let flags = match config.sync_policy {
+            SyncPolicy::DSync => libc::O_DSYNC,
+            _ => 0,
+        };
+        let (file, direct) = match config.direct_io {
+            DirectIo::Off => (open_buffered(&options, filename, flags)?, false),
+            direct_io => match open_direct(&options, filename, flags) {
+                Ok(file) => (file, true),
+                Err(err) if direct_io == DirectIo::Require || !is_unsupported(&err) => return Err(err),
+                Err(_) => (open_buffered(&options, filename, flags)?, false),
+            },
+        };
+        let mut paged_file = PagedFile {
+            file,
+            direct,
+            extents: None,
+            page_size,
+            sync_policy: config.sync_policy,
+            verify_checksums: config.verify_checksums,
+            checksum: config.checksum,
+            unsynced: Arc::new(AtomicU64::new(0)),
+            poisoned: Arc::new(OnceLock::new()),
+        };
+        match config.extent_pages {
+            Some(extent_pages) => paged_file.use_extents(extent_pages)?,
+            None if config.page_size != PageSize::default() => paged_file.use_extents(1)?,
+            None => paged_file.check_no_header()?,
+        }
+        Ok(paged_file)
+    }
+
+    /// Opens the file, creating it if needed, to grow `extent_pages` pages
+    /// at a time rather than a page per append, with the space allocated up
+    /// front where the filesystem allows.  The number of pages in use is kept
+    /// in a header page at the start of the file, so a file created this way
+    /// must always be opened this way.
+    ///
+    /// Panics if `extent_pages` is zero.
+    pub fn with_extents<P: AsRef<Path>>(filename: P, extent_pages: u64) -> io::Result<PagedFile> {
+        PagedFile::open_with(filename, PagedFileConfig {
+            extent_pages: Some(extent_pages),
+            ..PagedFileConfig::default()
+        })
+    }
+
+    fn use_extents(&mut self, extent_pages: u64) -> io::Result<()> {
+        assert!(extent_pages > 0, "extents must be at least a page");
+        let mut header = aligned::Buffer::new();
+        let used = if self.file.metadata()?.len() == 0 {
+            0
+        } else {
+            // The smallest page is read, so the page size can be checked
+            // before anything is read at the wrong size.
+            self.file.read_exact_at(&mut header[..PageSize::K4.bytes()], 0)?;
+            if &header[..8] != EXTENTS_MAGIC {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("file has no header page, so has {} byte pages and no extents", crate::PAGESIZE),
+                ));
+            }
+            let page_size = match u32::from_le_bytes(header[16..20].try_into().expect("four bytes")) {
+                0 => crate::PAGESIZE,
+                page_size => page_size as usize,
+            };
+            if page_size != self.page_size {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("file has {} byte pages, not {}", page_size, self.page_size),
+                ));
+            }
+            u64::from_le_bytes(header[8..16].try_into().expect("eight bytes"))
+        };
+        self.extents = Some(Extents {
+            pages: extent_pages,
+            used: Arc::new(AtomicU64::new(used)),
+        });
+        if used == 0 {
+            self.write_header(0)?;
+        }
+        Ok(())
+    }
+
+    // A file opened with default-sized pages and without extents must not
+    // have a header page, or the header would be read as page 0.
+    fn check_no_header(&self) -> io::Result<()> {
+        let mut header = aligned::Buffer::new();
+        if self.file.metadata()?.len() >= PageSize::K4.bytes() as u64 {
+            self.file.read_exact_at(&mut header[..PageSize::K4.bytes()], 0)?;
+            if &header[..8] == EXTENTS_MAGIC {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "file has a header page, so has pages of another size or grows by extents",
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns whether pages are read and written with direct I/O.
+    pub fn direct_io(&self) -> bool {
+        self.direct
+    }
+
+    /// Returns another handle to the same file.  Pages are read and written at
+    /// explicit offsets, so the handles can be used from different threads.
+    pub fn try_clone(&self) -> io::Result<PagedFile> {
+        Ok(PagedFile {
+            file: self.file.try_clone()?,
+            direct: self.direct,
+            extents: (self.extents.as_ref()).map(|extents| Extents {
+                pages: extents.pages,
+                used: Arc::clone(&extents.used),
+            }),
+            page_size: self.page_size,
+            sync_policy: self.sync_policy,
+            verify_checksums: self.verify_checksums,
+            checksum: self.checksum,
+            unsynced: Arc::clone(&self.unsynced),
+            poisoned: Arc::clone(&self.poisoned),
+        })
     }
 
     /// Returns the page size of the PagedFile.
     pub fn page_size(&self) -> usize {
-        crate::PAGESIZE
+        self.page_size
     }
 
     /// Reads a single page out of the PagedFile, using direct I/O.
@@ -54,80 +461,2473 @@ impl PagedFile {
     /// # Ok(())
     /// # }
     /// ```
+    ///
+    /// If the file was opened with `verify_checksums`, a page whose CRC
+    /// doesn't match is an `InvalidData` error wrapping a `ChecksumMismatch`.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip(self, buf)))]
     pub fn read_page(
-        &mut self,
+        &self,
         page_number: u64,
         buf: &mut aligned::Buffer,
     ) -> io::Result<()> {
-        (&self.file).seek(SeekFrom::Start(page_number * self.page_size() as u64))?;
-        (&self.file).read_exact(&mut buf[..self.page_size()])?;
+        self.read_page_raw(page_number, buf)?;
+        self.verify_checksum(page_number, buf)
+    }
+
+    /// Reads a page like `read_page`, but never checks its CRC, so damaged
+    /// pages can still be looked at.
+    pub fn read_page_raw(&self, page_number: u64, buf: &mut aligned::Buffer) -> io::Result<()> {
+        if self.extents.is_some() && page_number >= self.page_count()? {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "page past the end of storage"));
+        }
+        self.file
+            .read_exact_at(&mut buf[..self.page_size()], self.offset(page_number))?;
+        buf[self.page_size()..].fill(0);
+        Ok(())
+    }
+
+    /// Reads consecutive pages, starting at the specified page, with a
+    /// single vectored read, using direct I/O.
+    ///
+    /// Direct I/O requires that each of the provided buffers is properly
+    /// aligned.  CRCs are checked as `read_page` checks them.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip(self, bufs)))]
+    pub fn read_pages(&self, page_number: u64, bufs: &mut [&mut aligned::Buffer]) -> io::Result<()> {
+        if self.extents.is_some() && page_number + bufs.len() as u64 > self.page_count()? {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "page past the end of storage"));
+        }
+        let page_size = self.page_size();
+        let iovecs: Vec<_> = (bufs.iter_mut())
+            .map(|buf| libc::iovec {
+                iov_base: buf[..page_size].as_mut_ptr() as *mut libc::c_void,
+                iov_len: page_size,
+            })
+            .collect();
+
+        let mut read = 0;
+        while read < iovecs.len() {
+            let iovecs = &iovecs[read..iovecs.len().min(read + libc::UIO_MAXIOV as usize)];
+            let offset = self.offset(page_number + read as u64);
+            // Safety: the iovecs point to at least a page each of the buffers
+            // borrowed for the duration of the call.
+            let n = unsafe {
+                libc::preadv(self.file.as_raw_fd(), iovecs.as_ptr(), iovecs.len() as libc::c_int, offset as libc::off_t)
+            };
+            match n {
+                -1 if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => {}
+                -1 => return Err(io::Error::last_os_error()),
+                // Less than a page read means the file ends part way through
+                // one, as after a torn append, and reading again won't help.
+                n if (n as usize) < page_size => {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "page past the end of storage"))
+                }
+                // A page read in part is read again in full.
+                n => read += n as usize / page_size,
+            }
+        }
+        // The rest of each buffer is zeroed, so a smaller page reads the
+        // same as it was written from a fresh buffer.
+        for buf in bufs.iter_mut() {
+            buf[page_size..].fill(0);
+        }
+        for (page_number, buf) in (page_number..).zip(bufs.iter()) {
+            self.verify_checksum(page_number, buf)?;
+        }
         Ok(())
     }
 
+    fn verify_checksum(&self, page_number: u64, buf: &aligned::Buffer) -> io::Result<()> {
+        if !self.verify_checksums {
+            return Ok(());
+        }
+        let checksum = match aligned::page_type(buf) {
+            Some(PageType::MasterRecord) => Checksum::Crc32,
+            _ => self.checksum,
+        };
+        let (expected, actual) = (aligned::stored_crc(buf), checksum.compute(buf));
+        if expected == actual {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                ChecksumMismatch {
+                    page_id: page_number,
+                    expected,
+                    actual,
+                },
+            ))
+        }
+    }
+
     /// Writes one page from the provided buffer to the specified page of the PagedFile,
     /// using direct I/O.
     ///
     /// Direct I/O requires that the provided buffer is properly aligned.
-    pub fn write_page(&mut self, page_number: u64, buf: &[u8]) -> io::Result<()> {
-        (&self.file).seek(SeekFrom::Start(page_number * self.page_size() as u64))?;
-        (&self.file).write_all(&buf[..self.page_size()])?;
-        (&self.file).sync_data()?;
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip(self, buf)))]
+    pub fn write_page(&self, page_number: u64, buf: &[u8]) -> io::Result<()> {
+        self.check_poisoned()?;
+        self.allocate(page_number + 1)?;
+        self.file
+            .write_all_at(&buf[..self.page_size()], self.offset(page_number))?;
+        self.extend_used(page_number + 1)?;
+        self.written()?;
+        Ok(())
+    }
+
+    /// Writes consecutive pages, starting at the specified page, with a single
+    /// vectored write and one sync, using direct I/O.
+    ///
+    /// Direct I/O requires that each of the provided buffers is properly
+    /// aligned.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip(self, bufs)))]
+    pub fn write_pages(&self, page_number: u64, bufs: &[&[u8]]) -> io::Result<()> {
+        self.check_poisoned()?;
+        self.allocate(page_number + bufs.len() as u64)?;
+        let page_size = self.page_size();
+        let iovecs: Vec<_> = (bufs.iter())
+            .map(|buf| libc::iovec {
+                iov_base: buf[..page_size].as_ptr() as *mut libc::c_void,
+                iov_len: page_size,
+            })
+            .collect();
+
+        let mut written = 0;
+        while written < iovecs.len() {
+            let iovecs = &iovecs[written..iovecs.len().min(written + libc::UIO_MAXIOV as usize)];
+            let offset = self.offset(page_number + written as u64);
+            // Safety: the iovecs point to at least a page each of the buffers
+            // borrowed for the duration of the call.
+            let n = unsafe {
+                libc::pwritev(self.file.as_raw_fd(), iovecs.as_ptr(), iovecs.len() as libc::c_int, offset as libc::off_t)
+            };
+            match n {
+                -1 if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => {}
+                -1 => return Err(io::Error::last_os_error()),
+                0 => return Err(io::ErrorKind::WriteZero.into()),
+                // A page written in part is written again in full.
+                n => written += n as usize / page_size,
+            }
+        }
+        self.extend_used(page_number + bufs.len() as u64)?;
+        self.written()?;
         Ok(())
     }
 
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip(self, buf)))]
     pub fn append_page(&mut self, buf: &[u8]) -> io::Result<u64> {
-        let offset = (&self.file).seek(SeekFrom::End(0))?;
-        let pageno = offset / self.page_size() as u64;
-        (&self.file).write_all(&buf[..self.page_size()])?;
-        (&self.file).sync_data()?;
+        self.append(buf)
+    }
+
+    /// Writes pages after the last one, with a single vectored write and one
+    /// sync, and returns their page numbers.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip(self, bufs)))]
+    pub fn append_pages(&mut self, bufs: &[&[u8]]) -> io::Result<Range<u64>> {
+        let first = self.page_count()?;
+        self.write_pages(first, bufs)?;
+        Ok(first..first + bufs.len() as u64)
+    }
+
+    // Appending takes the page number from the length of the file, so
+    // appends must not run concurrently.
+    fn append(&self, buf: &[u8]) -> io::Result<u64> {
+        self.check_poisoned()?;
+        let pageno = self.page_count()?;
+        self.allocate(pageno + 1)?;
+        self.file.write_all_at(&buf[..self.page_size()], self.offset(pageno))?;
+        self.extend_used(pageno + 1)?;
+        self.written()?;
         Ok(pageno)
     }
-}
 
-// Page format, will be handled one layer up from this:
-//     u8 -> version
-//     u8 -> page_type
-//
-//     [u8; 3]-> [RESERVED]
-//     [slots]
-//     tuples
-//
-// slot format:
-//     u16 -> SlotId
-//     u16 -> offset (of data from start of page)
-//     u16 -> length
-//     u16 -> reserved?
+    // Sync after a write, if the sync policy calls for it.
+    fn written(&self) -> io::Result<()> {
+        match self.sync_policy {
+            SyncPolicy::EveryWrite => self.sync_data(),
+            SyncPolicy::EveryN(n) if self.unsynced.fetch_add(1, Ordering::AcqRel) + 1 >= n as u64 => self.sync(),
+            SyncPolicy::EveryN(_) | SyncPolicy::Manual | SyncPolicy::DSync => Ok(()),
+        }
+    }
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use crate::aligned;
-    use crate::testutils::create_test_path;
+    fn sync(&self) -> io::Result<()> {
+        self.unsynced.store(0, Ordering::Release);
+        self.sync_data()
+    }
 
-    #[test]
-    fn write_then_read() -> anyhow::Result<()> {
-        let filepath = create_test_path("test-potpot::storage::write_then_read.data");
-        let mut f = PagedFile::from_path(&filepath)?;
+    // A failed sync poisons the file, and every handle to it.
+    fn sync_data(&self) -> io::Result<()> {
+        self.check_poisoned()?;
+        self.file.sync_data().map_err(|err| {
+            let cause = self.poisoned.get_or_init(|| err.to_string());
+            Poisoned::error(cause)
+        })
+    }
 
-        let mut read_aligned = aligned::Buffer::new();
+    /// Returns true if a sync has failed.  See `Poisoned`.
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned.get().is_some()
+    }
 
-        for c in [b'A', b'B', b'C'].iter().copied() {
-            let write_aligned = aligned::Buffer::with_value(c);
-            let pageno = f.append_page(&write_aligned)?;
+    fn check_poisoned(&self) -> io::Result<()> {
+        match self.poisoned.get() {
+            Some(cause) => Err(Poisoned::error(cause)),
+            None => Ok(()),
+        }
+    }
 
-            f.read_page(pageno, &mut read_aligned)?;
+    /// Returns the number of pages in the file, not counting space
+    /// allocated ahead.
+    pub fn page_count(&self) -> io::Result<u64> {
+        match &self.extents {
+            Some(extents) => Ok(extents.used.load(Ordering::Acquire)),
+            None => Ok(self.file.metadata()?.len() / self.page_size() as u64),
+        }
+    }
 
-            for b in &*read_aligned {
-                assert_eq!(*b, c);
+    /// Returns an iterator over the pages of the file, front to back, with
+    /// their page numbers.  Pages are read ahead in batches, with one
+    /// vectored read each.  Pages appended after the iterator is created are
+    /// not visited, and iteration stops after the first error.
+    pub fn pages(&self) -> io::Result<Pages<'_>> {
+        Ok(Pages {
+            file: self,
+            next: 0,
+            end: self.page_count()?,
+            ahead: VecDeque::new(),
+        })
+    }
+
+    /// Cuts the file down to its first `pages` pages, if it has more.
+    pub fn truncate(&mut self, pages: u64) -> io::Result<()> {
+        self.check_poisoned()?;
+        if pages >= self.page_count()? {
+            return Ok(());
+        }
+        if let Some(extents) = &self.extents {
+            extents.used.store(pages, Ordering::Release);
+            self.write_header(pages)?;
+        }
+        self.file.set_len(self.offset(pages))?;
+        self.written()
+    }
+
+    /// Punches a hole in the file where a page is, past its first `keep`
+    /// bytes, so the filesystem can reuse the space.  That part of the page
+    /// reads as zeros until it is written again.  Where holes can't be
+    /// punched, the page is left as it is.
+    pub fn discard(&self, page_number: u64, keep: usize) -> io::Result<()> {
+        let keep = keep.min(self.page_size());
+        punch_hole(&self.file, self.offset(page_number) + keep as u64, (self.page_size() - keep) as u64)
+    }
+
+    // Where a page starts in the file, after the header if there is one.
+    fn offset(&self, page_number: u64) -> u64 {
+        let header_pages = self.extents.is_some() as u64;
+        (page_number + header_pages) * self.page_size() as u64
+    }
+
+    // Make sure there is space for the first `pages` pages, growing the file
+    // by whole extents if there isn't.
+    fn allocate(&self, pages: u64) -> io::Result<()> {
+        let extents = match &self.extents {
+            Some(extents) => extents,
+            None => return Ok(()),
+        };
+        let allocated = self.file.metadata()?.len();
+        if self.offset(pages) <= allocated {
+            return Ok(());
+        }
+        let len = self.offset(pages.div_ceil(extents.pages) * extents.pages);
+        preallocate(&self.file, allocated, len - allocated)
+    }
+
+    // Grow the file to `pages` pages, if it has fewer.  The new pages read
+    // as zeros.
+    fn extend_to(&self, pages: u64) -> io::Result<()> {
+        self.check_poisoned()?;
+        if pages <= self.page_count()? {
+            return Ok(());
+        }
+        self.allocate(pages)?;
+        if self.extents.is_none() {
+            self.file.set_len(self.offset(pages))?;
+        }
+        self.extend_used(pages)
+    }
+
+    // Record that the first `pages` pages are in use, if there are more of
+    // them than before.
+    fn extend_used(&self, pages: u64) -> io::Result<()> {
+        match &self.extents {
+            // Another handle may have gone further in the meantime.
+            Some(extents) if extents.used.fetch_max(pages, Ordering::AcqRel) < pages => {
+                self.write_header(extents.used.load(Ordering::Acquire))
             }
+            _ => Ok(()),
         }
-        let write_aligned = aligned::Buffer::with_value(b'z');
+    }
 
-        f.write_page(1, &write_aligned)?;
-        f.read_page(1, &mut read_aligned)?;
-        for b in &*read_aligned {
-            assert_eq!(*b, b'z');
+    fn write_header(&self, used: u64) -> io::Result<()> {
+        let mut header = aligned::Buffer::new();
+        header[..8].copy_from_slice(EXTENTS_MAGIC);
+        header[8..16].copy_from_slice(&used.to_le_bytes());
+        header[16..20].copy_from_slice(&(self.page_size as u32).to_le_bytes());
+        self.file.write_all_at(&header[..self.page_size()], 0)
+    }
+}
+
+/// An iterator over the pages of a `PagedFile`, returned by
+/// `PagedFile::pages`.
+pub struct Pages<'a> {
+    file: &'a PagedFile,
+    // the page number of the next page yielded
+    next: u64,
+    end: u64,
+    // pages read ahead, starting at `next`
+    ahead: VecDeque<Box<aligned::Buffer>>,
+}
+
+impl Pages<'_> {
+    /// The most pages read at a time.
+    pub const READAHEAD_PAGES: u64 = 32;
+
+    fn read_ahead(&mut self) -> io::Result<()> {
+        let count = (self.end - self.next).min(Self::READAHEAD_PAGES) as usize;
+        let mut bufs: Vec<_> = (0..count).map(|_| aligned::Buffer::new()).collect();
+        let mut refs: Vec<_> = bufs.iter_mut().map(|buf| &mut **buf).collect();
+        self.file.read_pages(self.next, &mut refs)?;
+        self.ahead.extend(bufs);
+        Ok(())
+    }
+}
+
+impl Iterator for Pages<'_> {
+    type Item = io::Result<(u64, Box<aligned::Buffer>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next >= self.end {
+            return None;
+        }
+        if self.ahead.is_empty() {
+            if let Err(err) = self.read_ahead() {
+                self.next = self.end;
+                return Some(Err(err));
+            }
+        }
+        let buf = self.ahead.pop_front()?;
+        self.next += 1;
+        Some(Ok((self.next - 1, buf)))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = (self.end - self.next) as usize;
+        (remaining, Some(remaining))
+    }
+}
+
+// Allocate space at the end of the file, so it isn't pieced together a page
+// at a time.  Where that isn't possible, the file is extended sparsely.
+#[cfg(target_os = "linux")]
+fn preallocate(file: &File, offset: u64, len: u64) -> io::Result<()> {
+    // Safety: the descriptor is open for the duration of the call.
+    let result = unsafe { libc::fallocate(file.as_raw_fd(), 0, offset as libc::off_t, len as libc::off_t) };
+    match result {
+        0 => Ok(()),
+        _ if io::Error::last_os_error().raw_os_error() == Some(libc::EOPNOTSUPP) => file.set_len(offset + len),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn preallocate(file: &File, offset: u64, len: u64) -> io::Result<()> {
+    file.set_len(offset + len)
+}
+
+#[cfg(target_os = "linux")]
+fn punch_hole(file: &File, offset: u64, len: u64) -> io::Result<()> {
+    let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+    // Safety: the descriptor is open for the duration of the call.
+    let result = unsafe { libc::fallocate(file.as_raw_fd(), mode, offset as libc::off_t, len as libc::off_t) };
+    match result {
+        0 => Ok(()),
+        _ if io::Error::last_os_error().raw_os_error() == Some(libc::EOPNOTSUPP) => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn punch_hole(_file: &File, _offset: u64, _len: u64) -> io::Result<()> {
+    Ok(())
+}
+
+fn open_buffered(options: &OpenOptions, filename: &Path, flags: libc::c_int) -> io::Result<File> {
+    options.clone().custom_flags(flags).open(filename)
+}
+
+#[cfg(target_os = "linux")]
+fn open_direct(options: &OpenOptions, filename: &Path, flags: libc::c_int) -> io::Result<File> {
+    options.clone().custom_flags(libc::O_DIRECT | flags).open(filename)
+}
+
+// macOS has no O_DIRECT, but turning the cache off for the file is close.
+#[cfg(target_os = "macos")]
+fn open_direct(options: &OpenOptions, filename: &Path, flags: libc::c_int) -> io::Result<File> {
+    let file = open_buffered(options, filename, flags)?;
+    // Safety: the descriptor is open for the duration of the call.
+    if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_NOCACHE, 1) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(file)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn open_direct(_options: &OpenOptions, _filename: &Path, _flags: libc::c_int) -> io::Result<File> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "direct I/O is not supported on this platform",
+    ))
+}
+
+// Filesystems without direct I/O (tmpfs, for one) refuse O_DIRECT with
+// EINVAL.
+fn is_unsupported(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::Unsupported || err.raw_os_error() == Some(libc::EINVAL)
+}
+
+impl SharedStorage for PagedFile {
+    fn try_clone(&self) -> io::Result<PagedFile> {
+        PagedFile::try_clone(self)
+    }
+}
+
+impl StorageBackend for PagedFile {
+    fn page_size(&self) -> usize {
+        PagedFile::page_size(self)
+    }
+
+    fn read_page(&mut self, page_number: u64, buf: &mut aligned::Buffer) -> io::Result<()> {
+        PagedFile::read_page(self, page_number, buf)
+    }
+
+    fn read_pages(&mut self, page_number: u64, bufs: &mut [&mut aligned::Buffer]) -> io::Result<()> {
+        PagedFile::read_pages(self, page_number, bufs)
+    }
+
+    fn write_page(&mut self, page_number: u64, buf: &[u8]) -> io::Result<()> {
+        PagedFile::write_page(self, page_number, buf)
+    }
+
+    fn write_pages(&mut self, page_number: u64, bufs: &[&[u8]]) -> io::Result<()> {
+        PagedFile::write_pages(self, page_number, bufs)
+    }
+
+    fn append_page(&mut self, buf: &[u8]) -> io::Result<u64> {
+        PagedFile::append_page(self, buf)
+    }
+
+    fn append_pages(&mut self, bufs: &[&[u8]]) -> io::Result<Range<u64>> {
+        PagedFile::append_pages(self, bufs)
+    }
+
+    fn page_count(&mut self) -> io::Result<u64> {
+        PagedFile::page_count(self)
+    }
+
+    fn truncate(&mut self, pages: u64) -> io::Result<()> {
+        PagedFile::truncate(self, pages)
+    }
+
+    fn discard(&mut self, page_number: u64, keep: usize) -> io::Result<()> {
+        PagedFile::discard(self, page_number, keep)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        PagedFile::sync(self)
+    }
+}
+
+/// How many segments past the first missing one `SegmentedFile::open` looks
+/// for, to catch a gap in the segments.
+const SEGMENT_GAP_LOOKAHEAD: usize = 64;
+
+/// Storage split across segment files of a fixed size, so no one file
+/// grows past a filesystem's limits.  Page ids run on from one segment to
+/// the next, and a segment is created when the one before it is full.
+pub struct SegmentedFile {
+    segments: Vec<PagedFile>,
+    segment_pages: u64,
+    path_for: Box<dyn Fn(usize) -> PathBuf + Send + Sync>,
+    // How each segment is opened
+    config: PagedFileConfig,
+}
+
+impl SegmentedFile {
+    /// Opens the segments `<base>.0000`, `<base>.0001` and so on, of
+    /// `segment_size` bytes each.
+    ///
+    /// Panics if `segment_size` is not a positive multiple of the page size.
+    pub fn from_path<P: AsRef<Path>>(base: P, segment_size: u64) -> io::Result<SegmentedFile> {
+        SegmentedFile::from_path_with(base, segment_size, PagedFileConfig::default())
+    }
+
+    /// Opens the segments as `from_path` does, each one as configured.
+    pub fn from_path_with<P: AsRef<Path>>(
+        base: P,
+        segment_size: u64,
+        config: PagedFileConfig,
+    ) -> io::Result<SegmentedFile> {
+        let base = base.as_ref().as_os_str().to_owned();
+        SegmentedFile::open_with(segment_size, config, move |segment| {
+            let mut path = base.clone();
+            path.push(format!(".{:04}", segment));
+            PathBuf::from(path)
+        })
+    }
+
+    /// Opens the segments at the paths given for each segment number, so
+    /// they can be placed on different disks.  The segments that exist are
+    /// opened now, up to the first missing one.  A segment found after that,
+    /// as when one of the disks isn't mounted, is an `InvalidData` error,
+    /// since appending would overwrite the pages in it.
+    ///
+    /// Panics if `segment_size` is not a positive multiple of the page size.
+    pub fn open(
+        segment_size: u64,
+        path_for: impl Fn(usize) -> PathBuf + Send + Sync + 'static,
+    ) -> io::Result<SegmentedFile> {
+        SegmentedFile::open_with(segment_size, PagedFileConfig::default(), path_for)
+    }
+
+    /// Opens the segments as `open` does, each one as configured.  The
+    /// segment size must be a multiple of the configured page size.
+    pub fn open_with(
+        segment_size: u64,
+        config: PagedFileConfig,
+        path_for: impl Fn(usize) -> PathBuf + Send + Sync + 'static,
+    ) -> io::Result<SegmentedFile> {
+        let page_size = config.page_size.bytes() as u64;
+        assert!(
+            segment_size > 0 && segment_size.is_multiple_of(page_size),
+            "segment size must be a multiple of the page size"
+        );
+        let mut storage = SegmentedFile {
+            segments: Vec::new(),
+            segment_pages: segment_size / page_size,
+            path_for: Box::new(path_for),
+            config,
+        };
+        while (storage.path_for)(storage.segments.len()).exists() {
+            storage.open_segment()?;
+        }
+        let missing = storage.segments.len();
+        if let Some(found) = (missing + 1..=missing + SEGMENT_GAP_LOOKAHEAD).find(|&n| (storage.path_for)(n).exists()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("segment {} is missing, but segment {} exists", missing, found),
+            ));
+        }
+        Ok(storage)
+    }
+
+    /// The number of segment files.
+    pub fn segment_count(&self) -> usize {
+        self.segments.len()
+    }
+
+    fn open_segment(&mut self) -> io::Result<()> {
+        let segment = PagedFile::open_with((self.path_for)(self.segments.len()), self.config)?;
+        self.segments.push(segment);
+        Ok(())
+    }
+
+    // The segment holding a page, and the page's number within it.
+    fn locate(&self, page_number: u64) -> (usize, u64) {
+        ((page_number / self.segment_pages) as usize, page_number % self.segment_pages)
+    }
+
+    // The segment to write a page to, creating it and any before it.  The
+    // segments before it are filled out, so that the pages skipped over read
+    // as zeros, as they would in a `PagedFile`, and page ids still run on
+    // from one segment to the next.
+    fn segment_for_write(&mut self, segment: usize) -> io::Result<&PagedFile> {
+        while self.segments.len() <= segment {
+            if let Some(last) = self.segments.last() {
+                last.extend_to(self.segment_pages)?;
+            }
+            self.open_segment()?;
+        }
+        Ok(&self.segments[segment])
+    }
+}
+
+impl StorageBackend for SegmentedFile {
+    fn page_size(&self) -> usize {
+        self.config.page_size.bytes()
+    }
+
+    fn read_page(&mut self, page_number: u64, buf: &mut aligned::Buffer) -> io::Result<()> {
+        let (segment, page_number) = self.locate(page_number);
+        (self.segments.get(segment))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "page past the end of storage"))?
+            .read_page(page_number, buf)
+    }
+
+    // The pages are read with one vectored read per segment.
+    fn read_pages(&mut self, mut page_number: u64, mut bufs: &mut [&mut aligned::Buffer]) -> io::Result<()> {
+        while !bufs.is_empty() {
+            let (segment, first) = self.locate(page_number);
+            let count = bufs.len().min((self.segment_pages - first) as usize);
+            let (these, rest) = bufs.split_at_mut(count);
+            (self.segments.get(segment))
+                .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "page past the end of storage"))?
+                .read_pages(first, these)?;
+            page_number += count as u64;
+            bufs = rest;
+        }
+        Ok(())
+    }
+
+    fn write_page(&mut self, page_number: u64, buf: &[u8]) -> io::Result<()> {
+        let (segment, page_number) = self.locate(page_number);
+        self.segment_for_write(segment)?.write_page(page_number, buf)
+    }
+
+    // The pages are written with one vectored write per segment.
+    fn write_pages(&mut self, mut page_number: u64, mut bufs: &[&[u8]]) -> io::Result<()> {
+        while !bufs.is_empty() {
+            let (segment, first) = self.locate(page_number);
+            let count = bufs.len().min((self.segment_pages - first) as usize);
+            self.segment_for_write(segment)?.write_pages(first, &bufs[..count])?;
+            page_number += count as u64;
+            bufs = &bufs[count..];
+        }
+        Ok(())
+    }
+
+    fn append_page(&mut self, buf: &[u8]) -> io::Result<u64> {
+        let last = match self.segments.last() {
+            Some(segment) if segment.page_count()? < self.segment_pages => {
+                self.segments.len() - 1
+            }
+            _ => {
+                self.open_segment()?;
+                self.segments.len() - 1
+            }
+        };
+        let page_number = self.segments[last].append(buf)?;
+        Ok(last as u64 * self.segment_pages + page_number)
+    }
+
+    fn append_pages(&mut self, bufs: &[&[u8]]) -> io::Result<Range<u64>> {
+        let first = self.page_count()?;
+        self.write_pages(first, bufs)?;
+        Ok(first..first + bufs.len() as u64)
+    }
+
+    fn page_count(&mut self) -> io::Result<u64> {
+        match self.segments.last() {
+            Some(segment) => Ok((self.segments.len() - 1) as u64 * self.segment_pages + segment.page_count()?),
+            None => Ok(0),
+        }
+    }
+
+    // Segments past the new end are removed, so the last segment is always
+    // the one appended to.
+    fn truncate(&mut self, pages: u64) -> io::Result<()> {
+        let (segment, in_segment) = self.locate(pages);
+        let keep = segment + (in_segment > 0) as usize;
+        while self.segments.len() > keep {
+            self.segments.pop();
+            std::fs::remove_file((self.path_for)(self.segments.len()))?;
+        }
+        match self.segments.get_mut(segment) {
+            Some(last) if in_segment > 0 => last.truncate(in_segment),
+            _ => Ok(()),
+        }
+    }
+
+    fn discard(&mut self, page_number: u64, keep: usize) -> io::Result<()> {
+        let (segment, page_number) = self.locate(page_number);
+        match self.segments.get(segment) {
+            Some(segment) => segment.discard(page_number, keep),
+            None => Ok(()),
+        }
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        for segment in &self.segments {
+            PagedFile::sync(segment)?;
+        }
+        Ok(())
+    }
+}
+
+/// A `PagedFile` for use from async code.  The I/O is done on tokio's
+/// blocking thread pool, so it doesn't hold up the executor.  Clones share
+/// the file.
+#[cfg(feature = "tokio")]
+#[derive(Clone)]
+pub struct AsyncPagedFile {
+    file: Arc<PagedFile>,
+    append_lock: Arc<Mutex<()>>,
+}
+
+#[cfg(feature = "tokio")]
+impl AsyncPagedFile {
+    pub fn new(file: PagedFile) -> AsyncPagedFile {
+        AsyncPagedFile {
+            file: Arc::new(file),
+            append_lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// Reads a single page into a new buffer.
+    pub async fn read_page(&self, page_number: u64) -> io::Result<Box<aligned::Buffer>> {
+        let file = Arc::clone(&self.file);
+        blocking(move || {
+            let mut buf = aligned::Buffer::new();
+            file.read_page(page_number, &mut buf)?;
+            Ok(buf)
+        })
+        .await
+    }
+
+    /// Writes one page to the specified page of the file.
+    pub async fn write_page(&self, page_number: u64, buf: Box<aligned::Buffer>) -> io::Result<()> {
+        let file = Arc::clone(&self.file);
+        blocking(move || file.write_page(page_number, &buf)).await
+    }
+
+    /// Writes a page after the last one, and returns its page number.
+    pub async fn append_page(&self, buf: Box<aligned::Buffer>) -> io::Result<u64> {
+        let file = Arc::clone(&self.file);
+        let append_lock = Arc::clone(&self.append_lock);
+        blocking(move || {
+            let _guard = append_lock.lock().expect("append lock poisoned");
+            file.append(&buf)
+        })
+        .await
+    }
+}
+
+/// Runs blocking I/O on tokio's blocking thread pool.
+#[cfg(feature = "tokio")]
+pub(crate) async fn blocking<T, F>(f: F) -> io::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> io::Result<T> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f).await.map_err(io::Error::other)?
+}
+
+/// Storage that maps the file into memory, and reads pages straight out of
+/// the page cache instead of keeping a second copy in the buffer pool.
+/// Writes only change the mapping, until `sync` writes them back with
+/// `msync`.
+///
+/// The file must not be changed by anything else while it is mapped.
+pub struct MmapStorage {
+    file: File,
+    // None while the file is empty, as an empty file can't be mapped.
+    map: Option<MmapMut>,
+}
+
+impl MmapStorage {
+    pub fn from_path<P: AsRef<Path>>(filename: P) -> io::Result<MmapStorage> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(filename)?;
+        let mut storage = MmapStorage { file, map: None };
+        storage.remap()?;
+        Ok(storage)
+    }
+
+    /// Returns a page straight out of the mapping, without copying it.
+    pub fn page(&self, page_number: u64) -> io::Result<&[u8]> {
+        let page_size = self.page_size();
+        let start = page_number as usize * page_size;
+        (self.map.as_deref())
+            .and_then(|map| map.get(start..start + page_size))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "page past the end of storage"))
+    }
+
+    fn pages_mapped(&self) -> u64 {
+        (self.map.as_ref().map_or(0, |map| map.len()) / self.page_size()) as u64
+    }
+
+    // Make the file at least `pages` pages long.
+    fn grow(&mut self, pages: u64) -> io::Result<()> {
+        if pages <= self.pages_mapped() {
+            return Ok(());
+        }
+        // The mapping's changes must reach the file before it is remapped.
+        if let Some(map) = &self.map {
+            map.flush_async()?;
+        }
+        self.file.set_len(pages * self.page_size() as u64)?;
+        self.remap()
+    }
+
+    // Map the whole of the file, as it is now.
+    fn remap(&mut self) -> io::Result<()> {
+        self.map = None;
+        if self.file.metadata()?.len() > 0 {
+            // Safety: the file is only changed through this mapping, as
+            // documented on the type.
+            self.map = Some(unsafe { MmapMut::map_mut(&self.file)? });
+        }
+        Ok(())
+    }
+}
+
+impl StorageBackend for MmapStorage {
+    fn read_page(&mut self, page_number: u64, buf: &mut aligned::Buffer) -> io::Result<()> {
+        buf.copy_from_slice(self.page(page_number)?);
+        Ok(())
+    }
+
+    fn write_page(&mut self, page_number: u64, buf: &[u8]) -> io::Result<()> {
+        let page_size = self.page_size();
+        self.grow(page_number + 1)?;
+        let start = page_number as usize * page_size;
+        let map = self.map.as_mut().expect("file is not empty");
+        map[start..start + page_size].copy_from_slice(&buf[..page_size]);
+        Ok(())
+    }
+
+    fn append_page(&mut self, buf: &[u8]) -> io::Result<u64> {
+        let page_number = self.pages_mapped();
+        self.write_page(page_number, buf)?;
+        Ok(page_number)
+    }
+
+    // The file is grown and remapped once for all of the pages.
+    fn append_pages(&mut self, bufs: &[&[u8]]) -> io::Result<Range<u64>> {
+        let first = self.pages_mapped();
+        self.grow(first + bufs.len() as u64)?;
+        self.write_pages(first, bufs)?;
+        Ok(first..first + bufs.len() as u64)
+    }
+
+    fn page_count(&mut self) -> io::Result<u64> {
+        Ok(self.pages_mapped())
+    }
+
+    fn truncate(&mut self, pages: u64) -> io::Result<()> {
+        if pages >= self.pages_mapped() {
+            return Ok(());
+        }
+        if let Some(map) = self.map.take() {
+            map.flush()?;
+        }
+        self.file.set_len(pages * self.page_size() as u64)?;
+        self.remap()
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        match &self.map {
+            Some(map) => map.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Storage that keeps pages in memory, for tests and databases that don't
+/// need to outlive the process.
+#[derive(Default)]
+pub struct MemStorage {
+    pages: Vec<Box<aligned::Buffer>>,
+    page_size: PageSize,
+}
+
+impl MemStorage {
+    pub fn new() -> MemStorage {
+        MemStorage::default()
+    }
+
+    /// Returns empty storage for pages of `page_size`.
+    pub fn with_page_size(page_size: PageSize) -> MemStorage {
+        MemStorage {
+            pages: Vec::new(),
+            page_size,
+        }
+    }
+}
+
+impl StorageBackend for MemStorage {
+    fn page_size(&self) -> usize {
+        self.page_size.bytes()
+    }
+
+    fn read_page(&mut self, page_number: u64, buf: &mut aligned::Buffer) -> io::Result<()> {
+        let page = (self.pages.get(page_number as usize))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "page past the end of storage"))?;
+        buf.copy_from_slice(page);
+        Ok(())
+    }
+
+    fn write_page(&mut self, page_number: u64, buf: &[u8]) -> io::Result<()> {
+        // As with a file, writing past the end leaves zeroed pages in between.
+        let page_number = page_number as usize;
+        let page_size = self.page_size();
+        if page_number >= self.pages.len() {
+            self.pages.resize_with(page_number + 1, aligned::Buffer::new);
+        }
+        self.pages[page_number].copy_from_slice(&buf[..page_size]);
+        Ok(())
+    }
+
+    fn append_page(&mut self, buf: &[u8]) -> io::Result<u64> {
+        let page_number = self.pages.len() as u64;
+        self.write_page(page_number, buf)?;
+        Ok(page_number)
+    }
+
+    fn page_count(&mut self) -> io::Result<u64> {
+        Ok(self.pages.len() as u64)
+    }
+
+    fn truncate(&mut self, pages: u64) -> io::Result<()> {
+        self.pages.truncate(pages as usize);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The faults a `FaultyStorage` injects.  Writes are counted from zero, a
+/// page at a time, from when the storage was wrapped, and so are syncs.
+/// The faults chosen at random are drawn from an RNG seeded with `seed`, so
+/// a run can be repeated exactly.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Faults {
+    /// Fail this write, without writing anything.  Later writes go ahead.
+    pub fail_write: Option<u64>,
+    /// Read only this many bytes of each page, and zeros for the rest of it,
+    /// as if the read had come up short.
+    pub short_reads: Option<usize>,
+    /// Crash part way through a write.  See `Crash`.
+    pub crash: Option<Crash>,
+    /// Fail this sync.  As with a failed `fsync`, the writes before it are
+    /// left as they were, and later syncs go ahead.
+    pub fail_sync: Option<u64>,
+    /// Flip one bit, chosen at random, in one in this many of the pages
+    /// written.
+    pub flip_bits: Option<u32>,
+    /// Only make writes durable when the storage is synced.  Until then,
+    /// reads see them, but a crash loses each one at random, and loses every
+    /// page appended since the last sync.
+    pub delay_sync: bool,
+    /// The seed for the faults chosen at random.
+    pub seed: u64,
+}
+
+/// A crash during a write: only the first `bytes` bytes of the page are
+/// written, and every operation after that fails, as if the process had
+/// died.  `FaultyStorage::into_inner` gets the storage back to recover it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Crash {
+    pub write: u64,
+    pub bytes: usize,
+}
+
+/// Storage that wraps another and injects faults into it, for testing how
+/// failed and torn writes are handled.  Pages written together are written
+/// one at a time, so a fault can land in the middle of a batch.
+pub struct FaultyStorage<S> {
+    inner: S,
+    faults: Faults,
+    rng: StdRng,
+    writes: u64,
+    syncs: u64,
+    crashed: bool,
+    // With `delay_sync`, the page count at the last sync, and what the pages
+    // written since then held at the time.
+    synced_pages: Option<u64>,
+    unsynced: BTreeMap<u64, Box<aligned::Buffer>>,
+}
+
+impl<S: StorageBackend> FaultyStorage<S> {
+    pub fn new(inner: S, faults: Faults) -> FaultyStorage<S> {
+        FaultyStorage {
+            inner,
+            faults,
+            rng: StdRng::seed_from_u64(faults.seed),
+            writes: 0,
+            syncs: 0,
+            crashed: false,
+            synced_pages: None,
+            unsynced: BTreeMap::new(),
+        }
+    }
+
+    /// Replaces the faults to inject, and reseeds the RNG.  The count of
+    /// writes carries on.
+    pub fn set_faults(&mut self, faults: Faults) {
+        self.faults = faults;
+        self.rng = StdRng::seed_from_u64(faults.seed);
+    }
+
+    /// Returns the number of writes so far, failed ones included.
+    pub fn writes(&self) -> u64 {
+        self.writes
+    }
+
+    /// Returns true once a `Crash` has happened.
+    pub fn crashed(&self) -> bool {
+        self.crashed
+    }
+
+    /// Crashes the storage between writes: every operation after this fails,
+    /// and with `delay_sync` the writes since the last sync may be lost.
+    pub fn crash(&mut self) -> io::Result<()> {
+        self.crashed = true;
+        self.lose_unsynced()
+    }
+
+    /// Returns the wrapped storage, as the crash left it.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    fn check_crashed(&self) -> io::Result<()> {
+        if self.crashed {
+            Err(io::Error::other("storage crashed"))
+        } else {
+            Ok(())
+        }
+    }
+
+    // Count a write, and return how many of its bytes to write, or an error
+    // if it is to fail.
+    fn next_write(&mut self) -> io::Result<usize> {
+        self.check_crashed()?;
+        let write = self.writes;
+        self.writes += 1;
+        if self.faults.fail_write == Some(write) {
+            return Err(io::Error::other(format!("injected failure of write {}", write)));
+        }
+        match self.faults.crash {
+            Some(crash) if crash.write == write => {
+                self.crashed = true;
+                Ok(crash.bytes.min(self.page_size()))
+            }
+            _ => Ok(self.page_size()),
+        }
+    }
+
+    // Build the page a write leaves behind: the first `bytes` bytes of `buf`,
+    // with the rest as the page was, and perhaps a bit flipped.
+    fn written_page(&mut self, page_number: Option<u64>, buf: &[u8], bytes: usize) -> io::Result<Box<aligned::Buffer>> {
+        let mut page = aligned::Buffer::new();
+        match page_number {
+            Some(page_number) if bytes < buf.len() && page_number < self.inner.page_count()? => {
+                self.inner.read_page(page_number, &mut page)?;
+            }
+            _ => {}
+        }
+        page[..bytes].copy_from_slice(&buf[..bytes]);
+        if let Some(one_in) = self.faults.flip_bits {
+            if bytes > 0 && self.rng.gen_range(0, one_in.max(1)) == 0 {
+                let bit = self.rng.gen_range(0, bytes * 8);
+                page[bit / 8] ^= 1 << (bit % 8);
+            }
+        }
+        Ok(page)
+    }
+
+    // With `delay_sync`, keep what a page held before its first write since
+    // the last sync, so that a crash can lose the write.
+    fn keep_unsynced(&mut self, page_number: u64) -> io::Result<()> {
+        if !self.faults.delay_sync {
+            return Ok(());
+        }
+        let synced_pages = match self.synced_pages {
+            Some(pages) => pages,
+            None => {
+                let pages = self.inner.page_count()?;
+                self.synced_pages = Some(pages);
+                pages
+            }
+        };
+        if page_number < synced_pages && !self.unsynced.contains_key(&page_number) {
+            let mut page = aligned::Buffer::new();
+            self.inner.read_page(page_number, &mut page)?;
+            self.unsynced.insert(page_number, page);
+        }
+        Ok(())
+    }
+
+    fn lose_unsynced(&mut self) -> io::Result<()> {
+        if let Some(pages) = self.synced_pages.take() {
+            self.inner.truncate(pages)?;
+        }
+        let page_size = self.page_size();
+        for (page_number, page) in std::mem::take(&mut self.unsynced) {
+            if self.rng.gen() {
+                self.inner.write_page(page_number, &page[..page_size])?;
+            }
+        }
+        Ok(())
+    }
+
+    fn after_write(&mut self) -> io::Result<()> {
+        if self.crashed {
+            self.lose_unsynced()?;
+        }
+        self.check_crashed()
+    }
+}
+
+impl<S: StorageBackend> StorageBackend for FaultyStorage<S> {
+    fn page_size(&self) -> usize {
+        self.inner.page_size()
+    }
+
+    fn read_page(&mut self, page_number: u64, buf: &mut aligned::Buffer) -> io::Result<()> {
+        self.check_crashed()?;
+        self.inner.read_page(page_number, buf)?;
+        if let Some(bytes) = self.faults.short_reads {
+            buf[bytes.min(self.page_size())..].fill(0);
+        }
+        Ok(())
+    }
+
+    fn write_page(&mut self, page_number: u64, buf: &[u8]) -> io::Result<()> {
+        let bytes = self.next_write()?;
+        self.keep_unsynced(page_number)?;
+        // A torn write leaves the rest of the page as it was.
+        let page = self.written_page(Some(page_number), buf, bytes)?;
+        self.inner.write_page(page_number, &page[..buf.len()])?;
+        self.after_write()
+    }
+
+    fn append_page(&mut self, buf: &[u8]) -> io::Result<u64> {
+        let bytes = self.next_write()?;
+        if self.faults.delay_sync && self.synced_pages.is_none() {
+            self.synced_pages = Some(self.inner.page_count()?);
+        }
+        let page = self.written_page(None, buf, bytes)?;
+        let page_number = self.inner.append_page(&page[..buf.len()])?;
+        self.after_write()?;
+        Ok(page_number)
+    }
+
+    fn page_count(&mut self) -> io::Result<u64> {
+        self.check_crashed()?;
+        self.inner.page_count()
+    }
+
+    fn truncate(&mut self, pages: u64) -> io::Result<()> {
+        self.check_crashed()?;
+        self.unsynced.split_off(&pages);
+        if let Some(synced_pages) = &mut self.synced_pages {
+            *synced_pages = (*synced_pages).min(pages);
+        }
+        self.inner.truncate(pages)
+    }
+
+    fn discard(&mut self, page_number: u64, keep: usize) -> io::Result<()> {
+        self.check_crashed()?;
+        self.inner.discard(page_number, keep)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.check_crashed()?;
+        let sync = self.syncs;
+        self.syncs += 1;
+        if self.faults.fail_sync == Some(sync) {
+            return Err(io::Error::other(format!("injected failure of sync {}", sync)));
+        }
+        self.inner.sync()?;
+        self.synced_pages = None;
+        self.unsynced.clear();
+        Ok(())
+    }
+}
+
+/// How `CompressedFile` compresses pages.
+#[cfg(any(feature = "lz4_flex", feature = "zstd"))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    #[cfg(feature = "lz4_flex")]
+    Lz4,
+    /// zstd, at the given compression level
+    #[cfg(feature = "zstd")]
+    Zstd(i32),
+}
+
+/// Storage that compresses pages, for data that compresses well.  Each page
+/// has a fixed-size slot, smaller than a page, and whatever doesn't fit in
+/// the slot spills into a second file, `<path>.spill`.  Pages are
+/// decompressed as they are read, so the buffer pool holds them whole.
+///
+/// Writes are only made durable by `sync`.
+//
+// Slot:
+//
+//   0x0000  Compressed length (4 bytes)
+//   0x0004  Codec (1 byte) (0 uncompressed, 1 LZ4, 2 zstd)
+//   0x0005  Page size in KiB (1 byte)  // 0 in files from before it was recorded
+//   0x0006  Reserved (2 bytes)
+//   0x0008  Spill chunk (8 bytes)  // u64::MAX if the page has none
+//   0x0010  Compressed page, or as much of it as fits
+//
+// Spill chunks are a page long, so even a page that doesn't compress fits,
+// and once a page has one it keeps it for later writes.
+#[cfg(any(feature = "lz4_flex", feature = "zstd"))]
+pub struct CompressedFile {
+    slots: File,
+    spill: File,
+    slot_size: usize,
+    page_size: usize,
+    compression: Compression,
+}
+
+#[cfg(any(feature = "lz4_flex", feature = "zstd"))]
+const SLOT_HEADER: usize = 16;
+#[cfg(any(feature = "lz4_flex", feature = "zstd"))]
+const NO_SPILL: u64 = u64::MAX;
+#[cfg(any(feature = "lz4_flex", feature = "zstd"))]
+const CODEC_NONE: u8 = 0;
+#[cfg(feature = "lz4_flex")]
+const CODEC_LZ4: u8 = 1;
+#[cfg(feature = "zstd")]
+const CODEC_ZSTD: u8 = 2;
+
+#[cfg(any(feature = "lz4_flex", feature = "zstd"))]
+impl CompressedFile {
+    /// Opens the file and its spill file, creating them if needed, with
+    /// `slot_size` bytes set aside for each page.
+    ///
+    /// Panics if `slot_size` leaves no room for data, or is more than a page.
+    pub fn from_path<P: AsRef<Path>>(
+        filename: P,
+        compression: Compression,
+        slot_size: usize,
+    ) -> io::Result<CompressedFile> {
+        CompressedFile::with_page_size(filename, compression, slot_size, PageSize::default())
+    }
+
+    /// Like `from_path`, for pages of `page_size`.  The page size is recorded
+    /// in each slot, and a page written at another size can't be read.
+    pub fn with_page_size<P: AsRef<Path>>(
+        filename: P,
+        compression: Compression,
+        slot_size: usize,
+        page_size: PageSize,
+    ) -> io::Result<CompressedFile> {
+        let page_size = page_size.bytes();
+        assert!(
+            slot_size > SLOT_HEADER && slot_size <= page_size,
+            "slot size must be more than the slot header, and at most a page"
+        );
+        let mut spill_name = filename.as_ref().as_os_str().to_owned();
+        spill_name.push(".spill");
+        let mut options = OpenOptions::new();
+        options.create(true).truncate(false).read(true).write(true);
+        Ok(CompressedFile {
+            slots: options.open(filename)?,
+            spill: options.open(spill_name)?,
+            slot_size,
+            page_size,
+            compression,
+        })
+    }
+
+    fn slot_offset(&self, page_number: u64) -> u64 {
+        page_number * self.slot_size as u64
+    }
+
+    // Compress a page, or leave it as it is if that isn't any smaller.
+    fn compress(&self, page: &[u8]) -> io::Result<(u8, Vec<u8>)> {
+        let (codec, compressed) = match self.compression {
+            #[cfg(feature = "lz4_flex")]
+            Compression::Lz4 => (CODEC_LZ4, lz4_flex::block::compress(page)),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(level) => (CODEC_ZSTD, zstd::bulk::compress(page, level)?),
+        };
+        if compressed.len() < page.len() {
+            Ok((codec, compressed))
+        } else {
+            Ok((CODEC_NONE, page.to_vec()))
+        }
+    }
+
+    // The spill chunk the page has, if any.
+    fn spill_chunk(&self, page_number: u64) -> io::Result<u64> {
+        let mut header = [0; SLOT_HEADER];
+        match self.slots.read_exact_at(&mut header, self.slot_offset(page_number)) {
+            Ok(()) => Ok(read_u64(&header[8..16])),
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(NO_SPILL),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+#[cfg(any(feature = "lz4_flex", feature = "zstd"))]
+fn decompress(codec: u8, data: &[u8], buf: &mut [u8]) -> io::Result<()> {
+    let invalid = |err| io::Error::new(io::ErrorKind::InvalidData, err);
+    let len = match codec {
+        CODEC_NONE if data.len() == buf.len() => {
+            buf.copy_from_slice(data);
+            data.len()
+        }
+        #[cfg(feature = "lz4_flex")]
+        CODEC_LZ4 => lz4_flex::block::decompress_into(data, buf).map_err(|err| invalid(err.to_string()))?,
+        #[cfg(feature = "zstd")]
+        CODEC_ZSTD => zstd::bulk::decompress_to_buffer(data, buf)?,
+        _ => return Err(invalid(format!("page stored with unknown or disabled codec {}", codec))),
+    };
+    if len != buf.len() {
+        return Err(invalid("page decompressed to the wrong size".to_string()));
+    }
+    Ok(())
+}
+
+#[cfg(any(feature = "lz4_flex", feature = "zstd"))]
+fn read_u64(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes(bytes.try_into().expect("eight bytes"))
+}
+
+#[cfg(any(feature = "lz4_flex", feature = "zstd"))]
+impl StorageBackend for CompressedFile {
+    fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    fn read_page(&mut self, page_number: u64, buf: &mut aligned::Buffer) -> io::Result<()> {
+        let mut slot = vec![0; self.slot_size];
+        self.slots.read_exact_at(&mut slot, self.slot_offset(page_number))?;
+        let page_size = match slot[5] {
+            0 => crate::PAGESIZE,
+            kib => kib as usize * 1024,
+        };
+        if page_size != self.page_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("page {} has {} bytes, not {}", page_number, page_size, self.page_size),
+            ));
+        }
+        let len = u32::from_le_bytes(slot[0..4].try_into().expect("four bytes")) as usize;
+        let in_slot = len.min(self.slot_size - SLOT_HEADER);
+        let mut data = slot[SLOT_HEADER..SLOT_HEADER + in_slot].to_vec();
+        if len > in_slot {
+            let chunk = read_u64(&slot[8..16]);
+            data.resize(len, 0);
+            self.spill
+                .read_exact_at(&mut data[in_slot..], chunk * self.page_size as u64)?;
+        }
+        buf[self.page_size..].fill(0);
+        decompress(slot[4], &data, &mut buf[..self.page_size])
+    }
+
+    fn write_page(&mut self, page_number: u64, buf: &[u8]) -> io::Result<()> {
+        let (codec, compressed) = self.compress(&buf[..self.page_size])?;
+        let room = self.slot_size - SLOT_HEADER;
+        let mut chunk = self.spill_chunk(page_number)?;
+        if compressed.len() > room {
+            if chunk == NO_SPILL {
+                chunk = self.spill.metadata()?.len().div_ceil(self.page_size as u64);
+            }
+            self.spill
+                .write_all_at(&compressed[room..], chunk * self.page_size as u64)?;
+        }
+        let mut slot = vec![0; self.slot_size];
+        slot[0..4].copy_from_slice(&(compressed.len() as u32).to_le_bytes());
+        slot[4] = codec;
+        slot[5] = (self.page_size / 1024) as u8;
+        slot[8..16].copy_from_slice(&chunk.to_le_bytes());
+        let in_slot = compressed.len().min(room);
+        slot[SLOT_HEADER..SLOT_HEADER + in_slot].copy_from_slice(&compressed[..in_slot]);
+        self.slots.write_all_at(&slot, self.slot_offset(page_number))
+    }
+
+    fn append_page(&mut self, buf: &[u8]) -> io::Result<u64> {
+        let page_number = self.page_count()?;
+        self.write_page(page_number, buf)?;
+        Ok(page_number)
+    }
+
+    fn page_count(&mut self) -> io::Result<u64> {
+        Ok(self.slots.metadata()?.len() / self.slot_size as u64)
+    }
+
+    // The spill chunks of the pages cut off aren't reclaimed.
+    fn truncate(&mut self, pages: u64) -> io::Result<()> {
+        if pages < self.page_count()? {
+            self.slots.set_len(self.slot_offset(pages))?;
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.spill.sync_data()?;
+        self.slots.sync_data()
+    }
+}
+
+/// Where `S3Storage` keeps its pages.
+#[cfg(feature = "s3")]
+#[derive(Clone, Debug)]
+pub struct S3Config {
+    /// The URL of the object store, such as `https://s3.us-east-1.amazonaws.com`
+    pub endpoint: String,
+    pub region: String,
+    pub bucket: String,
+    /// Put in front of the key of every object, to share a bucket
+    pub prefix: String,
+    pub access_key: String,
+    pub secret_key: String,
+}
+
+/// Storage that keeps each page as an object in an S3-compatible object
+/// store, so there's no need for a local disk.  Requests block, and are
+/// signed with AWS signature version 4.  Buckets are addressed path-style.
+///
+/// The number of pages is kept in a `<prefix>meta` object, which is only
+/// written by `sync`, so pages appended since the last sync are lost
+/// without one.
+#[cfg(feature = "s3")]
+pub struct S3Storage {
+    config: S3Config,
+    agent: ureq::Agent,
+    page_count: u64,
+    // whether page_count has changed since it was written
+    page_count_changed: bool,
+}
+
+#[cfg(feature = "s3")]
+impl S3Storage {
+    pub fn new(config: S3Config) -> io::Result<S3Storage> {
+        let mut storage = S3Storage {
+            config,
+            agent: ureq::AgentBuilder::new().build(),
+            page_count: 0,
+            page_count_changed: false,
+        };
+        storage.page_count = match storage.get("meta")? {
+            Some(meta) => u64::from_le_bytes(
+                (meta.as_slice().try_into())
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "malformed meta object"))?,
+            ),
+            None => 0,
+        };
+        Ok(storage)
+    }
+
+    fn page_key(page_number: u64) -> String {
+        format!("pages/{:016x}", page_number)
+    }
+
+    // The object's contents, or None if there is no such object.
+    fn get(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        match self.request("GET", key, &[])? {
+            Some(response) => {
+                let mut body = Vec::new();
+                response.into_reader().read_to_end(&mut body)?;
+                Ok(Some(body))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn put(&self, key: &str, body: &[u8]) -> io::Result<()> {
+        self.request("PUT", key, body)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "bucket not found"))?;
+        Ok(())
+    }
+
+    // Deleting an object that isn't there is fine.
+    fn delete(&self, key: &str) -> io::Result<()> {
+        self.request("DELETE", key, &[])?;
+        Ok(())
+    }
+
+    // Make a signed request for an object, giving None if it is not found.
+    fn request(&self, method: &str, key: &str, body: &[u8]) -> io::Result<Option<ureq::Response>> {
+        let endpoint = self.config.endpoint.trim_end_matches('/');
+        let host = endpoint.split("://").last().unwrap_or(endpoint);
+        let path = uri_encode(&format!("/{}/{}{}", self.config.bucket, self.config.prefix, key));
+        let date = amz_date(SystemTime::now());
+        let payload_hash = hex::encode(Sha256::digest(body));
+
+        let canonical_request = format!(
+            "{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method, path, host, payload_hash, date, SIGNED_HEADERS, payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", &date[..8], self.config.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            date,
+            scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+        let key = signing_key(&self.config.secret_key, &date[..8], &self.config.region, "s3");
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.config.access_key,
+            scope,
+            SIGNED_HEADERS,
+            hex::encode(hmac_sha256(&key, string_to_sign.as_bytes()))
+        );
+
+        let request = (self.agent.request(method, &format!("{}{}", endpoint, path)))
+            .set("x-amz-date", &date)
+            .set("x-amz-content-sha256", &payload_hash)
+            .set("authorization", &authorization);
+        let response = match method {
+            "PUT" => request.send_bytes(body),
+            _ => request.call(),
+        };
+        match response {
+            Ok(response) => Ok(Some(response)),
+            Err(ureq::Error::Status(404, _)) => Ok(None),
+            Err(err) => Err(io::Error::other(err)),
+        }
+    }
+}
+
+#[cfg(feature = "s3")]
+const SIGNED_HEADERS: &str = "host;x-amz-content-sha256;x-amz-date";
+
+#[cfg(feature = "s3")]
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+#[cfg(feature = "s3")]
+fn signing_key(secret_key: &str, day: &str, region: &str, service: &str) -> Vec<u8> {
+    let key = hmac_sha256(format!("AWS4{}", secret_key).as_bytes(), day.as_bytes());
+    let key = hmac_sha256(&key, region.as_bytes());
+    let key = hmac_sha256(&key, service.as_bytes());
+    hmac_sha256(&key, b"aws4_request")
+}
+
+// Percent-encode everything but unreserved characters and slashes.
+#[cfg(feature = "s3")]
+fn uri_encode(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+// The time in UTC, formatted as YYYYMMDDTHHMMSSZ.
+#[cfg(feature = "s3")]
+fn amz_date(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+    let (days, secs) = (secs / 86400, secs % 86400);
+    // Convert days since the epoch to a civil date, after Howard Hinnant's
+    // days_from_civil.
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+#[cfg(feature = "s3")]
+impl StorageBackend for S3Storage {
+    fn read_page(&mut self, page_number: u64, buf: &mut aligned::Buffer) -> io::Result<()> {
+        if page_number >= self.page_count {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "page past the end of storage"));
+        }
+        let page = (self.get(&S3Storage::page_key(page_number))?)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "page object is missing"))?;
+        if page.len() != self.page_size() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "page object is the wrong size"));
+        }
+        buf.copy_from_slice(&page);
+        Ok(())
+    }
+
+    fn write_page(&mut self, page_number: u64, buf: &[u8]) -> io::Result<()> {
+        self.put(&S3Storage::page_key(page_number), &buf[..self.page_size()])?;
+        if page_number >= self.page_count {
+            self.page_count = page_number + 1;
+            self.page_count_changed = true;
+        }
+        Ok(())
+    }
+
+    fn append_page(&mut self, buf: &[u8]) -> io::Result<u64> {
+        let page_number = self.page_count;
+        self.write_page(page_number, buf)?;
+        Ok(page_number)
+    }
+
+    fn page_count(&mut self) -> io::Result<u64> {
+        Ok(self.page_count)
+    }
+
+    fn truncate(&mut self, pages: u64) -> io::Result<()> {
+        while self.page_count > pages {
+            self.delete(&S3Storage::page_key(self.page_count - 1))?;
+            self.page_count -= 1;
+            self.page_count_changed = true;
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        if self.page_count_changed {
+            self.put("meta", &self.page_count.to_le_bytes())?;
+            self.page_count_changed = false;
+        }
+        Ok(())
+    }
+}
+
+// Page format, will be handled one layer up from this:
+//     u8 -> version
+//     u8 -> page_type
+//
+//     [u8; 3]-> [RESERVED]
+//     [slots]
+//     tuples
+//
+// slot format:
+//     u16 -> SlotId
+//     u16 -> offset (of data from start of page)
+//     u16 -> length
+//     u16 -> reserved?
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::aligned;
+    use crate::testutils::create_test_path;
+
+    #[test]
+    fn write_then_read() -> anyhow::Result<()> {
+        let filepath = create_test_path("test-potpot::storage::write_then_read.data");
+        let mut f = PagedFile::from_path(&filepath)?;
+
+        let mut read_aligned = aligned::Buffer::new();
+
+        for c in [b'A', b'B', b'C'].iter().copied() {
+            let write_aligned = aligned::Buffer::with_value(c);
+            let pageno = f.append_page(&write_aligned)?;
+
+            f.read_page(pageno, &mut read_aligned)?;
+
+            for b in &*read_aligned {
+                assert_eq!(*b, c);
+            }
+        }
+        let write_aligned = aligned::Buffer::with_value(b'z');
+
+        f.write_page(1, &write_aligned)?;
+        f.read_page(1, &mut read_aligned)?;
+        for b in &*read_aligned {
+            assert_eq!(*b, b'z');
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn direct_io_fallback() -> anyhow::Result<()> {
+        let filepath = create_test_path("test-potpot::storage::direct_io_fallback.data");
+        let buffered = PagedFile::open(&filepath, DirectIo::Off)?;
+        assert!(!buffered.direct_io());
+        buffered.write_page(0, &aligned::Buffer::with_value(b'A'))?;
+
+        // Whether direct I/O is available depends on where the tests run,
+        // but preferring it should only fall back where requiring it fails.
+        let preferred = PagedFile::open(&filepath, DirectIo::Prefer)?;
+        let required = PagedFile::open(&filepath, DirectIo::Require);
+        assert_eq!(preferred.direct_io(), required.is_ok());
+
+        let mut buf = aligned::Buffer::new();
+        preferred.read_page(0, &mut buf)?;
+        assert!(buf.iter().all(|&b| b == b'A'));
+        Ok(())
+    }
+
+    #[test]
+    fn extents() -> anyhow::Result<()> {
+        let filepath = create_test_path("test-potpot::storage::extents.data");
+        let page_size = crate::PAGESIZE as u64;
+        let f = PagedFile::with_extents(&filepath, 4)?;
+
+        let mut appender = f.try_clone()?;
+        for (expected, value) in (0..5).zip(b'A'..) {
+            assert_eq!(appender.append_page(&aligned::Buffer::with_value(value))?, expected);
+        }
+        // A header page, and two extents
+        assert_eq!(std::fs::metadata(&filepath)?.len(), 9 * page_size);
+
+        let mut buf = aligned::Buffer::new();
+        f.read_page(4, &mut buf)?;
+        assert!(buf.iter().all(|&b| b == b'E'));
+        // Pages allocated ahead aren't in use yet
+        assert_eq!(f.read_page(5, &mut buf).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        drop((f, appender));
+
+        let mut f = PagedFile::with_extents(&filepath, 4)?;
+        assert_eq!(f.append_page(&aligned::Buffer::with_value(b'F'))?, 5);
+        f.write_page(8, &aligned::Buffer::with_value(b'I'))?;
+        assert_eq!(f.append_page(&aligned::Buffer::with_value(b'J'))?, 9);
+        assert_eq!(std::fs::metadata(&filepath)?.len(), 13 * page_size);
+
+        let plain = create_test_path("test-potpot::storage::extents_plain.data");
+        PagedFile::from_path(&plain)?.append_page(&aligned::Buffer::with_value(b'A'))?;
+        assert_eq!(
+            PagedFile::with_extents(&plain, 4).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn page_size_header() -> anyhow::Result<()> {
+        let filepath = create_test_path("test-potpot::storage::page_size_header.data");
+        let _ = std::fs::remove_file(&filepath);
+        let config = PagedFileConfig {
+            page_size: PageSize::K8,
+            ..PagedFileConfig::default()
+        };
+        let mut f = PagedFile::open_with(&filepath, config)?;
+        f.append_page(&aligned::Buffer::with_value(b'A'))?;
+        // A header page records the page size, and the file grows a page at
+        // a time.
+        assert_eq!(std::fs::metadata(&filepath)?.len(), 2 * 8192);
+        drop(f);
+
+        let err = PagedFile::from_path(&filepath).expect_err("header page");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = PagedFile::open_with(&filepath, PagedFileConfig {
+            page_size: PageSize::K4,
+            ..config
+        })
+        .expect_err("wrong page size");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let f = PagedFile::open_with(&filepath, config)?;
+        assert_eq!(f.page_count()?, 1);
+        let mut buf = aligned::Buffer::new();
+        f.read_page(0, &mut buf)?;
+        assert!(buf[..8192].iter().all(|&b| b == b'A'));
+
+        let plain = create_test_path("test-potpot::storage::page_size_header.plain.data");
+        let _ = std::fs::remove_file(&plain);
+        PagedFile::from_path(&plain)?.append_page(&aligned::Buffer::with_value(b'B'))?;
+        let err = PagedFile::open_with(&plain, config).expect_err("no header page");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        Ok(())
+    }
+
+    #[test]
+    fn page_size() -> anyhow::Result<()> {
+        let filepath = create_test_path("test-potpot::storage::page_size.data");
+        let config = PagedFileConfig {
+            page_size: PageSize::K4,
+            extent_pages: Some(2),
+            ..PagedFileConfig::default()
+        };
+        let mut f = PagedFile::open_with(&filepath, config)?;
+        assert_eq!(StorageBackend::page_size(&f), 4096);
+        f.append_page(&aligned::Buffer::with_value(b'A'))?;
+        f.append_page(&aligned::Buffer::with_value(b'B'))?;
+        // A header page, and an extent
+        assert_eq!(std::fs::metadata(&filepath)?.len(), 3 * 4096);
+        drop(f);
+
+        let f = PagedFile::open_with(&filepath, config)?;
+        let mut buf = aligned::Buffer::new();
+        f.read_page(1, &mut buf)?;
+        assert!(buf[..4096].iter().all(|&b| b == b'B'));
+        assert_eq!(
+            PagedFile::with_extents(&filepath, 2).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn sync_policy() -> anyhow::Result<()> {
+        let filepath = create_test_path("test-potpot::storage::sync_policy.data");
+        let every_third = PagedFileConfig {
+            sync_policy: SyncPolicy::EveryN(3),
+            ..PagedFileConfig::default()
+        };
+        let mut f = PagedFile::open_with(&filepath, every_third)?;
+        for expected in [1, 2, 0, 1] {
+            f.append_page(&aligned::Buffer::with_value(b'A'))?;
+            assert_eq!(f.unsynced.load(Ordering::Acquire), expected);
+        }
+        StorageBackend::sync(&mut f)?;
+        assert_eq!(f.unsynced.load(Ordering::Acquire), 0);
+
+        for sync_policy in [SyncPolicy::Manual, SyncPolicy::DSync] {
+            let config = PagedFileConfig {
+                sync_policy,
+                ..PagedFileConfig::default()
+            };
+            let f = PagedFile::open_with(&filepath, config)?;
+            f.write_page(1, &aligned::Buffer::with_value(b'B'))?;
+            assert_eq!(f.unsynced.load(Ordering::Acquire), 0);
+            let mut buf = aligned::Buffer::new();
+            f.read_page(1, &mut buf)?;
+            assert!(buf.iter().all(|&b| b == b'B'));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn segmented_file() -> anyhow::Result<()> {
+        let segment_paths: Vec<_> = (0..3)
+            .map(|segment| create_test_path(format!("test-potpot::storage::segmented_file.data.{:04}", segment)))
+            .collect();
+        let base = create_test_path("test-potpot::storage::segmented_file.data");
+        let segment_size = 2 * crate::PAGESIZE as u64;
+        let mut f = SegmentedFile::from_path(&base, segment_size)?;
+
+        for (expected, value) in (0..5).zip(b'A'..) {
+            assert_eq!(f.append_page(&aligned::Buffer::with_value(value))?, expected);
+        }
+        assert_eq!(f.segment_count(), 3);
+        for path in &segment_paths {
+            assert!(std::fs::metadata(path)?.len() <= segment_size);
+        }
+
+        // A run of pages is split where it crosses into the next segment
+        let (x, y) = (aligned::Buffer::with_value(b'X'), aligned::Buffer::with_value(b'Y'));
+        f.write_pages(1, &[&x, &y])?;
+
+        let mut f = SegmentedFile::from_path(&base, segment_size)?;
+        assert_eq!(f.segment_count(), 3);
+        let mut buf = aligned::Buffer::new();
+        for (page_number, value) in (0..5).zip([b'A', b'X', b'Y', b'D', b'E']) {
+            f.read_page(page_number, &mut buf)?;
+            assert!(buf.iter().all(|&b| b == value));
+        }
+        assert_eq!(f.read_page(6, &mut buf).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(f.append_page(&aligned::Buffer::with_value(b'F'))?, 5);
+        Ok(())
+    }
+
+    #[test]
+    fn segmented_file_config() -> anyhow::Result<()> {
+        let segment_paths: Vec<_> = (0..2)
+            .map(|segment| {
+                create_test_path(format!("test-potpot::storage::segmented_file_config.data.{:04}", segment))
+            })
+            .collect();
+        let base = create_test_path("test-potpot::storage::segmented_file_config.data");
+        let config = PagedFileConfig {
+            page_size: PageSize::K4,
+            direct_io: DirectIo::Off,
+            ..PagedFileConfig::default()
+        };
+        let mut f = SegmentedFile::from_path_with(&base, 2 * 4096, config)?;
+        assert_eq!(StorageBackend::page_size(&f), 4096);
+        for value in b'A'..=b'C' {
+            f.append_page(&aligned::Buffer::with_value(value))?;
+        }
+        assert_eq!(f.segment_count(), 2);
+        // Each segment has a header page recording the page size.
+        assert_eq!(std::fs::metadata(&segment_paths[0])?.len(), 3 * 4096);
+
+        let mut buf = aligned::Buffer::new();
+        f.read_page(2, &mut buf)?;
+        assert!(buf[..4096].iter().all(|&b| b == b'C'));
+        assert!(buf[4096..].iter().all(|&b| b == 0));
+        Ok(())
+    }
+
+    #[test]
+    fn segmented_file_gap() -> anyhow::Result<()> {
+        let segment_paths: Vec<_> = (0..3)
+            .map(|segment| {
+                create_test_path(format!("test-potpot::storage::segmented_file_gap.data.{:04}", segment))
+            })
+            .collect();
+        let base = create_test_path("test-potpot::storage::segmented_file_gap.data");
+        let mut f = SegmentedFile::from_path(&base, crate::PAGESIZE as u64)?;
+        for value in b'A'..=b'C' {
+            f.append_page(&aligned::Buffer::with_value(value))?;
+        }
+        drop(f);
+
+        // As if the disk holding the middle segment weren't mounted
+        std::fs::rename(&segment_paths[1], base.with_extension("data.moved"))?;
+        let err = SegmentedFile::from_path(&base, crate::PAGESIZE as u64).err().expect("gap in segments");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        std::fs::rename(base.with_extension("data.moved"), &segment_paths[1])?;
+        assert_eq!(SegmentedFile::from_path(&base, crate::PAGESIZE as u64)?.page_count()?, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn segmented_file_write_past_end() -> anyhow::Result<()> {
+        let _segment_paths: Vec<_> = (0..5)
+            .map(|segment| {
+                create_test_path(format!("test-potpot::storage::segmented_file_write_past_end.data.{:04}", segment))
+            })
+            .collect();
+        let base = create_test_path("test-potpot::storage::segmented_file_write_past_end.data");
+        let mut f = SegmentedFile::from_path(&base, 2 * crate::PAGESIZE as u64)?;
+        f.append_page(&aligned::Buffer::with_value(b'A'))?;
+
+        // The pages skipped over read as zeros, and appends go after the
+        // page written.
+        f.write_page(6, &aligned::Buffer::with_value(b'G'))?;
+        assert_eq!((f.segment_count(), f.page_count()?), (4, 7));
+        let mut buf = aligned::Buffer::new();
+        for page_number in 1..6 {
+            f.read_page(page_number, &mut buf)?;
+            assert!(buf.iter().all(|&b| b == 0));
+        }
+        let (x, y) = (aligned::Buffer::with_value(b'X'), aligned::Buffer::with_value(b'Y'));
+        assert_eq!(f.append_pages(&[&x, &y])?, 7..9);
+        f.read_page(6, &mut buf)?;
+        assert!(buf.iter().all(|&b| b == b'G'));
+        Ok(())
+    }
+
+    #[test]
+    fn segmented_file_failed_sync() -> anyhow::Result<()> {
+        // Syncing a device that can't be synced fails, as a disk error would.
+        let mut f = SegmentedFile::open(2 * crate::PAGESIZE as u64, |segment| match segment {
+            0 => PathBuf::from("/dev/null"),
+            _ => PathBuf::from("/nonexistent/potpot-segment"),
+        })?;
+        assert_eq!(f.segment_count(), 1);
+        let err = StorageBackend::sync(&mut f).unwrap_err();
+        assert!(is_poisoned(&err));
+
+        // A retry doesn't report the lost writes as durable.
+        assert!(is_poisoned(&StorageBackend::sync(&mut f).unwrap_err()));
+        Ok(())
+    }
+
+    #[cfg(any(feature = "lz4_flex", feature = "zstd"))]
+    #[test]
+    fn compressed_file() -> anyhow::Result<()> {
+        let compressions = [
+            #[cfg(feature = "lz4_flex")]
+            Compression::Lz4,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(3),
+        ];
+        let mut noise = aligned::Buffer::new();
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        for byte in noise.iter_mut() {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            *byte = state as u8;
+        }
+        let mut text = aligned::Buffer::new();
+        for (byte, c) in text.iter_mut().zip(b"the quick brown fox jumps over the lazy dog ".iter().cycle()) {
+            *byte = *c;
+        }
+
+        for compression in compressions {
+            let filepath = create_test_path("test-potpot::storage::compressed_file.data");
+            let spill_path = create_test_path("test-potpot::storage::compressed_file.data.spill");
+            let mut f = CompressedFile::from_path(&filepath, compression, 1024)?;
+            let mut buf = aligned::Buffer::new();
+
+            // Pages that compress well fit in their slots
+            assert_eq!(f.append_page(&text)?, 0);
+            assert_eq!(f.append_page(&aligned::Buffer::with_value(b'A'))?, 1);
+            assert_eq!(std::fs::metadata(&filepath)?.len(), 2048);
+            assert_eq!(std::fs::metadata(&spill_path)?.len(), 0);
+
+            // Ones that don't spill, and keep their spill chunk
+            f.write_page(1, &noise)?;
+            f.write_page(1, &aligned::Buffer::with_value(b'B'))?;
+            f.write_page(1, &noise)?;
+            assert!(std::fs::metadata(&spill_path)?.len() <= crate::PAGESIZE as u64);
+
+            let mut f = CompressedFile::from_path(&filepath, compression, 1024)?;
+            f.read_page(0, &mut buf)?;
+            assert_eq!(&buf[..], &text[..]);
+            f.read_page(1, &mut buf)?;
+            assert_eq!(&buf[..], &noise[..]);
+            assert_eq!(f.read_page(2, &mut buf).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "lz4_flex")]
+    #[test]
+    fn compressed_file_page_size() -> anyhow::Result<()> {
+        let filepath = create_test_path("test-potpot::storage::compressed_file_page_size.data");
+        let _ = std::fs::remove_file(&filepath);
+        let mut f = CompressedFile::with_page_size(&filepath, Compression::Lz4, 256, PageSize::K4)?;
+        assert_eq!(f.page_size(), 4096);
+        f.append_page(&aligned::Buffer::with_value(b'A'))?;
+        let mut buf = aligned::Buffer::with_value(b'x');
+        f.read_page(0, &mut buf)?;
+        assert!(buf[..4096].iter().all(|&b| b == b'A'));
+        assert!(buf[4096..].iter().all(|&b| b == 0));
+
+        let mut f = CompressedFile::from_path(&filepath, Compression::Lz4, 256)?;
+        assert_eq!(f.read_page(0, &mut buf).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        Ok(())
+    }
+
+    #[cfg(feature = "s3")]
+    #[test]
+    fn s3_signing() {
+        // From the AWS documentation's signature version 4 examples
+        let key = signing_key("wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY", "20120215", "us-east-1", "iam");
+        assert_eq!(hex::encode(key), "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d");
+
+        let time = UNIX_EPOCH + std::time::Duration::from_secs(1_329_264_000 + 3661);
+        assert_eq!(amz_date(time), "20120215T010101Z");
+        assert_eq!(uri_encode("/bucket/my db/pages/0a"), "/bucket/my%20db/pages/0a");
+    }
+
+    // Serves GET, PUT and DELETE of objects kept in memory, enough to stand
+    // in for an object store.
+    #[cfg(feature = "s3")]
+    fn fake_object_store() -> std::io::Result<String> {
+        use std::{
+            collections::HashMap,
+            io::{BufRead, BufReader, Write},
+            net::TcpListener,
+            sync::{Arc, Mutex},
+            thread,
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let endpoint = format!("http://{}", listener.local_addr()?);
+        let objects = Arc::new(Mutex::new(HashMap::<String, Vec<u8>>::new()));
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let objects = Arc::clone(&objects);
+                thread::spawn(move || -> std::io::Result<()> {
+                    let mut reader = BufReader::new(stream.try_clone()?);
+                    let mut stream = stream;
+                    loop {
+                        let mut request_line = String::new();
+                        if reader.read_line(&mut request_line)? == 0 {
+                            return Ok(());
+                        }
+                        let mut parts = request_line.split_whitespace();
+                        let (method, path) = (parts.next().unwrap_or("").to_string(), parts.next().unwrap_or("").to_string());
+                        let (mut content_length, mut signed) = (0, false);
+                        loop {
+                            let mut header = String::new();
+                            reader.read_line(&mut header)?;
+                            let header = header.trim_end().to_ascii_lowercase();
+                            if header.is_empty() {
+                                break;
+                            } else if let Some(len) = header.strip_prefix("content-length:") {
+                                content_length = len.trim().parse().unwrap_or(0);
+                            } else if header.starts_with("authorization: aws4-hmac-sha256 credential=") {
+                                signed = true;
+                            }
+                        }
+                        let mut body = vec![0; content_length];
+                        reader.read_exact(&mut body)?;
+
+                        let mut objects = objects.lock().unwrap();
+                        let (status, body) = match method.as_str() {
+                            _ if !signed => ("403 Forbidden", Vec::new()),
+                            "GET" => match objects.get(&path) {
+                                Some(object) => ("200 OK", object.clone()),
+                                None => ("404 Not Found", Vec::new()),
+                            },
+                            "PUT" => {
+                                objects.insert(path, body);
+                                ("200 OK", Vec::new())
+                            }
+                            "DELETE" => {
+                                objects.remove(&path);
+                                ("204 No Content", Vec::new())
+                            }
+                            _ => ("405 Method Not Allowed", Vec::new()),
+                        };
+                        write!(stream, "HTTP/1.1 {}\r\nContent-Length: {}\r\n\r\n", status, body.len())?;
+                        stream.write_all(&body)?;
+                    }
+                });
+            }
+        });
+        Ok(endpoint)
+    }
+
+    #[cfg(feature = "s3")]
+    #[test]
+    fn s3_storage() -> anyhow::Result<()> {
+        let config = S3Config {
+            endpoint: fake_object_store()?,
+            region: "us-east-1".into(),
+            bucket: "potpot".into(),
+            prefix: "db/".into(),
+            access_key: "AKIDEXAMPLE".into(),
+            secret_key: "secret".into(),
+        };
+        let mut storage = S3Storage::new(config.clone())?;
+        let mut buf = aligned::Buffer::new();
+        for (expected, c) in (0..3).zip(b'A'..) {
+            assert_eq!(storage.append_page(&aligned::Buffer::with_value(c))?, expected);
+        }
+        storage.write_page(1, &aligned::Buffer::with_value(b'X'))?;
+        storage.sync()?;
+
+        let mut storage = S3Storage::new(config)?;
+        assert_eq!(storage.page_count()?, 3);
+        for (page_number, c) in [(0, b'A'), (1, b'X'), (2, b'C')] {
+            storage.read_page(page_number, &mut buf)?;
+            assert!(buf.iter().all(|&b| b == c));
+        }
+        storage.truncate(1)?;
+        assert_eq!(storage.read_page(1, &mut buf).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(storage.append_page(&aligned::Buffer::with_value(b'D'))?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn mem_storage() -> anyhow::Result<()> {
+        let mut storage = MemStorage::new();
+        let mut read_aligned = aligned::Buffer::new();
+
+        for c in [b'A', b'B'] {
+            let pageno = storage.append_page(&aligned::Buffer::with_value(c))?;
+            storage.read_page(pageno, &mut read_aligned)?;
+            assert!(read_aligned.iter().all(|&b| b == c));
+        }
+
+        storage.write_page(3, &aligned::Buffer::with_value(b'z'))?;
+        storage.read_page(2, &mut read_aligned)?;
+        assert!(read_aligned.iter().all(|&b| b == 0));
+        storage.read_page(3, &mut read_aligned)?;
+        assert!(read_aligned.iter().all(|&b| b == b'z'));
+        assert_eq!(storage.append_page(&aligned::Buffer::with_value(b'C'))?, 4);
+
+        let err = storage.read_page(5, &mut read_aligned).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        storage.sync()?;
+        Ok(())
+    }
+
+    #[test]
+    fn faulty_storage() -> anyhow::Result<()> {
+        let faults = Faults {
+            fail_write: Some(1),
+            short_reads: Some(100),
+            ..Faults::default()
+        };
+        let mut storage = FaultyStorage::new(MemStorage::new(), faults);
+        let mut read_aligned = aligned::Buffer::new();
+        assert_eq!(storage.append_page(&aligned::Buffer::with_value(b'A'))?, 0);
+        storage.write_page(0, &aligned::Buffer::with_value(b'B')).unwrap_err();
+        storage.read_page(0, &mut read_aligned)?;
+        assert!(read_aligned[..100].iter().all(|&b| b == b'A'));
+        assert!(read_aligned[100..].iter().all(|&b| b == 0));
+
+        storage.set_faults(Faults {
+            crash: Some(Crash { write: 3, bytes: 10 }),
+            ..Faults::default()
+        });
+        let pages = [&aligned::Buffer::with_value(b'C')[..], &aligned::Buffer::with_value(b'D')[..]];
+        storage.write_pages(0, &pages).unwrap_err();
+        assert_eq!(storage.writes(), 4);
+        assert!(storage.crashed());
+        assert!(storage.page_count().is_err());
+
+        let mut storage = storage.into_inner();
+        storage.read_page(0, &mut read_aligned)?;
+        assert!(read_aligned.iter().all(|&b| b == b'C'));
+        storage.read_page(1, &mut read_aligned)?;
+        assert!(read_aligned[..10].iter().all(|&b| b == b'D'));
+        assert!(read_aligned[10..].iter().all(|&b| b == 0));
+        Ok(())
+    }
+
+    #[test]
+    fn faulty_storage_flip_bits() -> anyhow::Result<()> {
+        let faults = Faults {
+            flip_bits: Some(1),
+            seed: 7,
+            ..Faults::default()
+        };
+        let flipped = |faults| -> anyhow::Result<Vec<usize>> {
+            let mut storage = FaultyStorage::new(MemStorage::new(), faults);
+            let mut read_aligned = aligned::Buffer::new();
+            let mut flipped = Vec::new();
+            for page in 0..4 {
+                storage.append_page(&aligned::Buffer::new())?;
+                storage.read_page(page, &mut read_aligned)?;
+                let bits: Vec<usize> = (0..read_aligned.len()).filter(|&i| read_aligned[i] != 0).collect();
+                assert_eq!(bits.len(), 1);
+                assert_eq!(read_aligned[bits[0]].count_ones(), 1);
+                flipped.extend(bits);
+            }
+            Ok(flipped)
+        };
+        assert_eq!(flipped(faults)?, flipped(faults)?);
+        assert_ne!(flipped(faults)?, flipped(Faults { seed: 8, ..faults })?);
+        Ok(())
+    }
+
+    #[test]
+    fn faulty_storage_delay_sync() -> anyhow::Result<()> {
+        let faults = Faults {
+            delay_sync: true,
+            ..Faults::default()
+        };
+        let mut storage = FaultyStorage::new(MemStorage::new(), faults);
+        let mut read_aligned = aligned::Buffer::new();
+        for c in b'A'..b'Q' {
+            storage.append_page(&aligned::Buffer::with_value(c))?;
+        }
+        storage.sync()?;
+        for page in 0..16 {
+            storage.write_page(page, &aligned::Buffer::with_value(b'z'))?;
+        }
+        storage.append_page(&aligned::Buffer::with_value(b'z'))?;
+        storage.read_page(3, &mut read_aligned)?;
+        assert!(read_aligned.iter().all(|&b| b == b'z'));
+        storage.crash()?;
+        assert!(storage.page_count().is_err());
+
+        let mut storage = storage.into_inner();
+        assert_eq!(storage.page_count()?, 16);
+        let mut lost = 0;
+        for (page, c) in (b'A'..b'Q').enumerate() {
+            storage.read_page(page as u64, &mut read_aligned)?;
+            if read_aligned.iter().all(|&b| b == c) {
+                lost += 1;
+            } else {
+                assert!(read_aligned.iter().all(|&b| b == b'z'));
+            }
+        }
+        assert!(lost > 0 && lost < 16);
+        Ok(())
+    }
+
+    #[test]
+    fn mmap_storage() -> anyhow::Result<()> {
+        let filepath = create_test_path("test-potpot::storage::mmap_storage.data");
+        let _ = std::fs::remove_file(&filepath);
+        let mut storage = MmapStorage::from_path(&filepath)?;
+        let mut read_aligned = aligned::Buffer::new();
+        assert!(storage.page(0).is_err());
+
+        for c in [b'A', b'B', b'C'] {
+            let pageno = storage.append_page(&aligned::Buffer::with_value(c))?;
+            assert!(storage.page(pageno)?.iter().all(|&b| b == c));
+        }
+        storage.write_page(1, &aligned::Buffer::with_value(b'z'))?;
+        storage.sync()?;
+
+        // The pages are in the file, where the direct I/O path can read them.
+        let f = PagedFile::from_path(&filepath)?;
+        for (pageno, c) in [(0, b'A'), (1, b'z'), (2, b'C')] {
+            f.read_page(pageno, &mut read_aligned)?;
+            assert!(read_aligned.iter().all(|&b| b == c));
+        }
+        drop(storage);
+        let mut storage = MmapStorage::from_path(&filepath)?;
+        StorageBackend::read_page(&mut storage, 1, &mut read_aligned)?;
+        assert!(read_aligned.iter().all(|&b| b == b'z'));
+        Ok(())
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn async_paged_file() -> anyhow::Result<()> {
+        let filepath = create_test_path("test-potpot::storage::async_paged_file.data");
+        let f = AsyncPagedFile::new(PagedFile::from_path(&filepath)?);
+        let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+
+        runtime.block_on(async {
+            let pageno = f.append_page(aligned::Buffer::with_value(b'A')).await?;
+            f.write_page(pageno, aligned::Buffer::with_value(b'B')).await?;
+            let page = f.read_page(pageno).await?;
+            assert!(page.iter().all(|&b| b == b'B'));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn write_pages() -> anyhow::Result<()> {
+        let filepath = create_test_path("test-potpot::storage::write_pages.data");
+        let mut f = PagedFile::from_path(&filepath)?;
+
+        let mut read_aligned = aligned::Buffer::new();
+        for _ in 0..4 {
+            f.append_page(&aligned::Buffer::with_value(b'A'))?;
+        }
+
+        let pages = [aligned::Buffer::with_value(b'x'), aligned::Buffer::with_value(b'y')];
+        f.write_pages(1, &[&pages[0], &pages[1]])?;
+        for (pageno, c) in [(0, b'A'), (1, b'x'), (2, b'y'), (3, b'A')] {
+            f.read_page(pageno, &mut read_aligned)?;
+            assert!(read_aligned.iter().all(|&b| b == c));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn append_pages() -> anyhow::Result<()> {
+        let pages = [aligned::Buffer::with_value(b'x'), aligned::Buffer::with_value(b'y')];
+        let bufs: [&[u8]; 2] = [&pages[0], &pages[1]];
+        let mut read_aligned = aligned::Buffer::new();
+
+        let filepath = create_test_path("test-potpot::storage::append_pages.data");
+        let mmap_path = create_test_path("test-potpot::storage::append_pages_mmap.data");
+        let _segments: Vec<_> = (0..2)
+            .map(|segment| create_test_path(format!("test-potpot::storage::append_pages_seg.data.{:04}", segment)))
+            .collect();
+        let base = create_test_path("test-potpot::storage::append_pages_seg.data");
+        let backends: Vec<Box<dyn StorageBackend>> = vec![
+            Box::new(PagedFile::from_path(&filepath)?),
+            Box::new(MmapStorage::from_path(&mmap_path)?),
+            Box::new(SegmentedFile::from_path(&base, 2 * crate::PAGESIZE as u64)?),
+            Box::new(MemStorage::new()),
+        ];
+        for mut storage in backends {
+            storage.append_page(&aligned::Buffer::with_value(b'A'))?;
+            assert_eq!(storage.append_pages(&bufs)?, 1..3);
+            assert!(storage.append_pages(&[])?.is_empty());
+            for (pageno, c) in [(0, b'A'), (1, b'x'), (2, b'y')] {
+                storage.read_page(pageno, &mut read_aligned)?;
+                assert!(read_aligned.iter().all(|&b| b == c));
+            }
+            assert_eq!(storage.append_page(&aligned::Buffer::with_value(b'B'))?, 3);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn truncate() -> anyhow::Result<()> {
+        let mut read_aligned = aligned::Buffer::new();
+        let filepath = create_test_path("test-potpot::storage::truncate.data");
+        let extents_path = create_test_path("test-potpot::storage::truncate_extents.data");
+        let mmap_path = create_test_path("test-potpot::storage::truncate_mmap.data");
+        let segments: Vec<_> = (0..3)
+            .map(|segment| create_test_path(format!("test-potpot::storage::truncate_seg.data.{:04}", segment)))
+            .collect();
+        let base = create_test_path("test-potpot::storage::truncate_seg.data");
+        let backends: Vec<Box<dyn StorageBackend>> = vec![
+            Box::new(PagedFile::from_path(&filepath)?),
+            Box::new(PagedFile::with_extents(&extents_path, 4)?),
+            Box::new(MmapStorage::from_path(&mmap_path)?),
+            Box::new(SegmentedFile::from_path(&base, 2 * crate::PAGESIZE as u64)?),
+            Box::new(MemStorage::new()),
+        ];
+        for mut storage in backends {
+            for c in b'A'..=b'E' {
+                storage.append_page(&aligned::Buffer::with_value(c))?;
+            }
+            storage.truncate(6)?;
+            assert_eq!(storage.page_count()?, 5);
+            storage.discard(1, 4096)?;
+            storage.truncate(3)?;
+            assert_eq!(storage.page_count()?, 3);
+            assert!(storage.read_page(3, &mut read_aligned).is_err());
+            storage.read_page(1, &mut read_aligned)?;
+            assert!(read_aligned[..4096].iter().all(|&b| b == b'B'));
+            assert_eq!(storage.append_page(&aligned::Buffer::with_value(b'F'))?, 3);
+        }
+        assert!(!segments[2].exists());
+        assert_eq!(std::fs::metadata(&filepath)?.len(), 4 * crate::PAGESIZE as u64);
+        Ok(())
+    }
+
+    #[test]
+    fn read_pages() -> anyhow::Result<()> {
+        let filepath = create_test_path("test-potpot::storage::read_pages.data");
+        let mut f = PagedFile::from_path(&filepath)?;
+        for c in b'A'..=b'D' {
+            f.append_page(&aligned::Buffer::with_value(c))?;
+        }
+
+        let (mut x, mut y) = (aligned::Buffer::new(), aligned::Buffer::new());
+        f.read_pages(1, &mut [&mut x, &mut y])?;
+        assert!(x.iter().all(|&b| b == b'B'));
+        assert!(y.iter().all(|&b| b == b'C'));
+        let err = f.read_pages(3, &mut [&mut x, &mut y]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        // A file ending part way through a page, as after a torn append
+        std::fs::OpenOptions::new().write(true).open(&filepath)?.set_len(crate::PAGESIZE as u64 * 3 / 2)?;
+        let err = f.read_pages(0, &mut [&mut x, &mut y]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        Ok(())
+    }
+
+    #[test]
+    fn verify_checksums() -> anyhow::Result<()> {
+        let filepath = create_test_path("test-potpot::storage::verify_checksums.data");
+        let config = PagedFileConfig {
+            verify_checksums: true,
+            ..PagedFileConfig::default()
+        };
+        let mut f = PagedFile::open_with(&filepath, config)?;
+        let mut page = aligned::Buffer::with_value(b'A');
+        let crc = Checksum::Crc32.compute(&page);
+        page[..4].copy_from_slice(&crc.to_le_bytes());
+        f.append_page(&page)?;
+        page[100] = b'B';
+        f.append_page(&page)?;
+
+        let mut buf = aligned::Buffer::new();
+        f.read_page(0, &mut buf)?;
+        let err = f.read_page(1, &mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let mismatch = err.get_ref().and_then(|err| err.downcast_ref::<ChecksumMismatch>()).expect("mismatch");
+        assert_eq!((mismatch.page_id, mismatch.expected), (1, crc));
+        assert_eq!(mismatch.actual, Checksum::Crc32.compute(&page));
+
+        let (mut x, mut y) = (aligned::Buffer::new(), aligned::Buffer::new());
+        assert_eq!(f.read_pages(0, &mut [&mut x, &mut y]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        f.read_page_raw(1, &mut buf)?;
+        assert_eq!(buf[100], b'B');
+        Ok(())
+    }
+
+    #[test]
+    fn pages() -> anyhow::Result<()> {
+        let filepath = create_test_path("test-potpot::storage::pages.data");
+        let mut f = PagedFile::with_extents(&filepath, 8)?;
+        let count = Pages::READAHEAD_PAGES + 3;
+        for i in 0..count {
+            f.append_page(&aligned::Buffer::with_value(i as u8))?;
+        }
+
+        let pages = f.pages()?;
+        assert_eq!(pages.size_hint(), (count as usize, Some(count as usize)));
+        let mut seen = 0;
+        for (i, page) in pages.enumerate() {
+            let (page_number, buf) = page?;
+            assert_eq!(page_number, i as u64);
+            assert!(buf.iter().all(|&b| b == i as u8));
+            seen += 1;
         }
+        assert_eq!(seen, count);
         Ok(())
     }
 }
@@ -36,6 +36,7 @@ pub(crate) fn create_test_path<P: AsRef<Path>>(p: P) -> TempPath {
     let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
     path.push("data");
     path.push("test");
+    std::fs::create_dir_all(&path).expect("create test data directory");
     path.push(p);
     TempPath::new(path)
 }